@@ -2,17 +2,23 @@ mod dev_att;
 use matter::core::{self, CommissioningData};
 use matter::data_model::cluster_basic_information::BasicInfoConfig;
 use matter::data_model::device_types::device_type_add_on_off_light;
+use matter::secure_channel::spake2p::VerifierData;
+use matter::transport::mgr::TransportConfig;
 use rand::prelude::*;
 
 fn main() {
     env_logger::init();
-    let mut comm_data = CommissioningData {
+    // A provisioned device would load its verifier with VerifierData::load() instead
+    let verifier = VerifierData::load().unwrap_or_else(|_| {
         // TODO: Hard-coded for now
-        passwd: 123456,
+        let mut salt = [0; 16];
+        rand::thread_rng().fill_bytes(&mut salt);
+        VerifierData::new_with_pw(123456, &salt, 1000).unwrap()
+    });
+    let comm_data = CommissioningData {
+        verifier,
         discriminator: 250,
-        ..Default::default()
     };
-    rand::thread_rng().fill_bytes(&mut comm_data.salt);
 
     // vid/pid should match those in the DAC
     let dev_info = BasicInfoConfig {
@@ -23,7 +29,8 @@ fn main() {
     };
     let dev_att = Box::new(dev_att::HardCodedDevAtt::new());
 
-    let mut matter = core::Matter::new(dev_info, dev_att, comm_data).unwrap();
+    let mut matter =
+        core::Matter::new(dev_info, dev_att, comm_data, TransportConfig::default()).unwrap();
     let dm = matter.get_data_model();
     {
         let mut node = dm.node.write().unwrap();
@@ -3,8 +3,8 @@ use std::sync::{Arc, Mutex, MutexGuard, RwLock};
 use crate::{
     data_model::objects::{Access, Privilege},
     error::Error,
-    fabric,
     interaction_model::messages::GenericPath,
+    limits::Limits,
     sys::Psm,
     tlv::{FromTLV, TLVElement, TLVList, TLVWriter, TagType, ToTLV},
     utils::writebuf::WriteBuf,
@@ -15,7 +15,6 @@ use num_derive::FromPrimitive;
 // Matter Minimum Requirements
 pub const SUBJECTS_PER_ENTRY: usize = 4;
 pub const TARGETS_PER_ENTRY: usize = 3;
-pub const ENTRIES_PER_FABRIC: usize = 3;
 
 // TODO: Check if this and the SessionMode can be combined into some generic data structure
 #[derive(FromPrimitive, Copy, Clone, PartialEq, Debug)]
@@ -244,36 +243,61 @@ impl AclEntry {
     }
 }
 
-const MAX_ACL_ENTRIES: usize = ENTRIES_PER_FABRIC * fabric::MAX_SUPPORTED_FABRICS;
-type AclEntries = [Option<AclEntry>; MAX_ACL_ENTRIES];
-
-#[derive(ToTLV, FromTLV, Debug)]
+#[derive(Debug)]
 struct AclMgrInner {
-    entries: AclEntries,
+    entries: Vec<Option<AclEntry>>,
+    entries_per_fabric: usize,
 }
 
 const ACL_KV_ENTRY: &str = "acl";
-const ACL_KV_MAX_SIZE: usize = 300;
+// The space that an entry takes in the persistent storage, at most
+const ACL_KV_ENTRY_MAX_SIZE: usize = 100;
 impl AclMgrInner {
+    fn new(limits: &Limits) -> Self {
+        Self {
+            entries: vec![None; limits.acl_entries_per_fabric * (limits.fabrics - 1)],
+            entries_per_fabric: limits.acl_entries_per_fabric,
+        }
+    }
+
+    // The entries are stored as an array of the ones present
     pub fn store(&self, psm: &MutexGuard<Psm>) -> Result<(), Error> {
-        let mut acl_tlvs = [0u8; ACL_KV_MAX_SIZE];
-        let mut wb = WriteBuf::new(&mut acl_tlvs, ACL_KV_MAX_SIZE);
+        let max_size = 2 + self.entries.len() * ACL_KV_ENTRY_MAX_SIZE;
+        let mut acl_tlvs = vec![0u8; max_size];
+        let mut wb = WriteBuf::new(&mut acl_tlvs, max_size);
         let mut tw = TLVWriter::new(&mut wb);
-        self.entries.to_tlv(&mut tw, TagType::Anonymous)?;
+        tw.start_array(TagType::Anonymous)?;
+        for entry in self.entries.iter().flatten() {
+            entry.to_tlv(&mut tw, TagType::Anonymous)?;
+        }
+        tw.end_container()?;
         psm.set_kv_slice(ACL_KV_ENTRY, wb.as_slice())
     }
 
-    pub fn load(psm: &MutexGuard<Psm>) -> Result<Self, Error> {
+    pub fn load(&mut self, psm: &MutexGuard<Psm>) -> Result<(), Error> {
         let mut acl_tlvs = Vec::new();
         psm.get_kv_slice(ACL_KV_ENTRY, &mut acl_tlvs)?;
         let root = TLVList::new(&acl_tlvs)
             .iter()
             .next()
             .ok_or(Error::Invalid)?;
-
-        Ok(Self {
-            entries: AclEntries::from_tlv(&root)?,
-        })
+        root.confirm_array()?;
+
+        let mut entries = vec![None; self.entries.len()];
+        if let Some(tlv_iter) = root.enter() {
+            for (index, element) in tlv_iter.enumerate() {
+                if index >= entries.len() {
+                    error!(
+                        "More ACL entries stored than the limit of {}",
+                        entries.len()
+                    );
+                    break;
+                }
+                entries[index] = Some(AclEntry::from_tlv(&element)?);
+            }
+        }
+        self.entries = entries;
+        Ok(())
     }
 
     /// Traverse fabric specific entries to find the index
@@ -313,28 +337,24 @@ impl AclMgr {
     }
 
     pub fn new_with(psm_support: bool) -> Result<Self, Error> {
-        const INIT: Option<AclEntry> = None;
+        AclMgr::new_with_limits(psm_support, &Limits::default())
+    }
+
+    pub fn new_with_limits(psm_support: bool, limits: &Limits) -> Result<Self, Error> {
         let mut psm = None;
+        let mut inner = AclMgrInner::new(limits);
 
-        let inner = if !psm_support {
-            AclMgrInner {
-                entries: [INIT; MAX_ACL_ENTRIES],
-            }
-        } else {
+        if psm_support {
             let psm_handle = Psm::get()?;
-            let inner = {
+            {
                 let psm_lock = psm_handle.lock().unwrap();
-                AclMgrInner::load(&psm_lock)
-            };
-
-            psm = Some(psm_handle);
-            inner.unwrap_or({
-                // Error loading from PSM
-                AclMgrInner {
-                    entries: [INIT; MAX_ACL_ENTRIES],
+                if inner.load(&psm_lock).is_err() {
+                    // Error loading from PSM
+                    inner = AclMgrInner::new(limits);
                 }
-            })
-        };
+            }
+            psm = Some(psm_handle);
+        }
         Ok(Self {
             inner: RwLock::new(inner),
             psm,
@@ -343,8 +363,8 @@ impl AclMgr {
 
     pub fn erase_all(&self) {
         let mut inner = self.inner.write().unwrap();
-        for i in 0..MAX_ACL_ENTRIES {
-            inner.entries[i] = None;
+        for entry in inner.entries.iter_mut() {
+            *entry = None;
         }
         if let Some(psm) = self.psm.as_ref() {
             let psm = psm.lock().unwrap();
@@ -362,7 +382,11 @@ impl AclMgr {
             .flatten()
             .filter(|a| a.fab_idx == entry.fab_idx)
             .count();
-        if cnt >= ENTRIES_PER_FABRIC {
+        if cnt >= inner.entries_per_fabric {
+            error!(
+                "Limit of {} ACL entries reached for the fabric",
+                inner.entries_per_fabric
+            );
             return Err(Error::NoSpace);
         }
         let index = inner
@@ -410,12 +434,9 @@ impl AclMgr {
     pub fn delete_for_fabric(&self, fab_idx: u8) -> Result<(), Error> {
         let mut inner = self.inner.write().unwrap();
 
-        for i in 0..MAX_ACL_ENTRIES {
-            if inner.entries[i]
-                .filter(|e| e.fab_idx == Some(fab_idx))
-                .is_some()
-            {
-                inner.entries[i] = None;
+        for entry in inner.entries.iter_mut() {
+            if entry.filter(|e| e.fab_idx == Some(fab_idx)).is_some() {
+                *entry = None;
             }
         }
 
@@ -427,6 +448,11 @@ impl AclMgr {
         }
     }
 
+    /// The entries that each fabric can have
+    pub fn entries_per_fabric(&self) -> usize {
+        self.inner.read().unwrap().entries_per_fabric
+    }
+
     pub fn for_each_acl<T>(&self, mut f: T) -> Result<(), Error>
     where
         T: FnMut(&AclEntry),
@@ -473,12 +499,35 @@ impl std::fmt::Display for AclMgr {
 mod tests {
     use crate::{
         data_model::objects::{Access, Privilege},
+        error::Error,
         interaction_model::messages::GenericPath,
+        limits::Limits,
     };
     use std::sync::Arc;
 
     use super::{AccessReq, Accessor, AclEntry, AclMgr, AuthMode, Target};
 
+    #[test]
+    fn test_entries_per_fabric() {
+        let limits = Limits {
+            acl_entries_per_fabric: 5,
+            ..Default::default()
+        };
+        let am = AclMgr::new_with_limits(false, &limits).unwrap();
+        assert_eq!(am.entries_per_fabric(), 5);
+        for _ in 0..5 {
+            am.add(AclEntry::new(1, Privilege::VIEW, AuthMode::Case))
+                .unwrap();
+        }
+        assert_eq!(
+            am.add(AclEntry::new(1, Privilege::VIEW, AuthMode::Case)),
+            Err(Error::NoSpace)
+        );
+        // The other fabrics have their own entries
+        am.add(AclEntry::new(2, Privilege::VIEW, AuthMode::Case))
+            .unwrap();
+    }
+
     #[test]
     fn test_basic_empty_subject_target() {
         let am = Arc::new(AclMgr::new_with(false).unwrap());
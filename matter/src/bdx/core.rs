@@ -0,0 +1,706 @@
+use async_channel::{bounded, Sender};
+use log::{error, info};
+use num;
+
+use crate::{
+    error::*,
+    secure_channel::{
+        common::PROTO_ID_SECURE_CHANNEL,
+        status_report::{create_status_report, GeneralCode, StatusReport},
+    },
+    transport::{
+        exchange::Exchange,
+        packet::Packet,
+        proto_demux::{self, ProtoCtx, ResponseRequired},
+        queue::{Msg, TxMsg, TxResp, WorkQ},
+    },
+    utils::writebuf::WriteBuf,
+};
+
+use super::messages::*;
+
+/// The largest block that we send or receive, so that a block fits in an IPv6
+/// packet of the minimum MTU
+pub const MAX_BLOCK_SIZE: u16 = 1024;
+
+/// Where the file that we send comes from
+pub trait BdxSource: Send {
+    /// The size of the file, if it is known up front
+    fn size(&self) -> Option<u64>;
+
+    /// Fills `buf` with the data at `offset` and returns how much was filled
+    ///
+    /// Filling less than the whole buffer means that the end of the file was reached.
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> Result<usize, Error>;
+}
+
+/// Where the file that we receive goes
+pub trait BdxSink: Send {
+    fn write(&mut self, offset: u64, data: &[u8]) -> Result<(), Error>;
+
+    /// Called once the whole file was received
+    fn finish(&mut self) -> Result<(), Error>;
+}
+
+/// Decides on the transfers that the peers initiate
+pub trait BdxProvider {
+    /// A peer wants to send us a file, this returns where the file goes
+    fn accept_send(&mut self, init: &TransferInit) -> Result<Box<dyn BdxSink>, StatusCode>;
+
+    /// A peer wants a file from us, this returns where the file comes from
+    fn accept_receive(&mut self, init: &TransferInit) -> Result<Box<dyn BdxSource>, StatusCode>;
+}
+
+enum Data {
+    Source(Box<dyn BdxSource>),
+    Sink(Box<dyn BdxSink>),
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+enum State {
+    AwaitAccept,
+    Transferring,
+    AwaitAckEOF,
+    Done,
+}
+
+// Why a transfer stopped, the peer is told with a status report
+struct Abort(StatusCode, Error);
+
+impl From<Error> for Abort {
+    fn from(e: Error) -> Self {
+        Abort(StatusCode::TransferFailedUnknownError, e)
+    }
+}
+
+fn abort<T>(status: StatusCode) -> Result<T, Abort> {
+    error!("Aborting the transfer: {:?}", status);
+    Err(Abort(status, Error::Invalid))
+}
+
+/// A transfer in progress, this is the data of its exchange
+///
+/// The initiator of a transfer drives it. Whoever sends the file drives it by
+/// sending the blocks (sender drive), whoever receives the file drives it by
+/// querying for them (receiver drive).
+pub struct Transfer {
+    data: Data,
+    drive_mode: TransferControl,
+    state: State,
+    max_block_size: u16,
+    // The counter of the next block to be sent or received
+    block_ctr: u32,
+    offset: u64,
+    len: Option<u64>,
+    transferred: u64,
+    // For the transfers that we initiate, where the outcome is reported
+    done_tx: Option<Sender<Result<(), Error>>>,
+}
+
+impl Transfer {
+    fn new(data: Data, drive_mode: TransferControl, offset: u64, len: Option<u64>) -> Self {
+        Self {
+            data,
+            drive_mode,
+            state: State::Transferring,
+            max_block_size: MAX_BLOCK_SIZE,
+            block_ctr: 0,
+            offset,
+            len,
+            transferred: 0,
+            done_tx: None,
+        }
+    }
+
+    fn handle(
+        &mut self,
+        opcode: OpCode,
+        rx: &mut [u8],
+        tx: &mut Packet,
+    ) -> Result<ResponseRequired, Abort> {
+        match (opcode, self.state) {
+            (OpCode::SendAccept, State::AwaitAccept)
+            | (OpCode::ReceiveAccept, State::AwaitAccept) => self.handle_accept(opcode, rx, tx),
+            (OpCode::BlockAck, State::Transferring) => {
+                self.check_block_ctr(rx, self.block_ctr.wrapping_sub(1))?;
+                self.send_block(tx)
+            }
+            (OpCode::BlockQuery, State::Transferring) => {
+                self.check_block_ctr(rx, self.block_ctr)?;
+                self.send_block(tx)
+            }
+            (OpCode::BlockAckEOF, State::AwaitAckEOF) => {
+                self.check_block_ctr(rx, self.block_ctr.wrapping_sub(1))?;
+                info!("Sent the file, {} bytes", self.transferred);
+                self.complete(Ok(()));
+                Ok(ResponseRequired::No)
+            }
+            (OpCode::Block, State::Transferring) | (OpCode::BlockEOF, State::Transferring) => {
+                self.recv_block(opcode, rx, tx)
+            }
+            _ => abort(StatusCode::UnexpectedMessage),
+        }
+    }
+
+    fn handle_accept(
+        &mut self,
+        opcode: OpCode,
+        rx: &mut [u8],
+        tx: &mut Packet,
+    ) -> Result<ResponseRequired, Abort> {
+        let accept = TransferAccept::parse(opcode, rx).or(abort(StatusCode::BadMessageContents))?;
+        if accept.max_block_size == 0 || accept.max_block_size > self.max_block_size {
+            return abort(StatusCode::BadMessageContents);
+        }
+        self.max_block_size = accept.max_block_size;
+        self.state = State::Transferring;
+        match self.data {
+            Data::Source(_) => {
+                if accept.drive_mode != TransferControl::SENDER_DRIVE {
+                    return abort(StatusCode::TransferMethodNotSupported);
+                }
+                self.send_block(tx)
+            }
+            Data::Sink(_) => {
+                if accept.drive_mode != TransferControl::RECEIVER_DRIVE {
+                    return abort(StatusCode::TransferMethodNotSupported);
+                }
+                self.len = accept.len;
+                Self::write_block_msg(tx, OpCode::BlockQuery, self.block_ctr)?;
+                Ok(ResponseRequired::Yes)
+            }
+        }
+    }
+
+    fn check_block_ctr(&self, rx: &mut [u8], expected: u32) -> Result<(), Abort> {
+        let (block_ctr, _) = parse_block(rx).or(abort(StatusCode::BadMessageContents))?;
+        if block_ctr != expected {
+            return abort(StatusCode::BadBlockCounter);
+        }
+        Ok(())
+    }
+
+    fn write_block_msg(tx: &mut Packet, opcode: OpCode, block_ctr: u32) -> Result<(), Error> {
+        tx.set_proto_opcode(opcode as u8);
+        write_block_ctr(tx.get_writebuf()?, block_ctr)
+    }
+
+    fn send_block(&mut self, tx: &mut Packet) -> Result<ResponseRequired, Abort> {
+        let source = match &mut self.data {
+            Data::Source(source) => source,
+            Data::Sink(_) => return abort(StatusCode::UnexpectedMessage),
+        };
+        let mut block_size = self.max_block_size as u64;
+        if let Some(len) = self.len {
+            block_size = block_size.min(len - self.transferred);
+        }
+        let mut buf = vec![0; block_size as usize];
+        let read = source.read(self.offset, &mut buf)?;
+        if read > buf.len() {
+            return Err(Error::NoSpace.into());
+        }
+        let eof = match self.len {
+            // The file is shorter than it was said to be
+            Some(_) if read < buf.len() => return abort(StatusCode::LengthTooShort),
+            Some(len) => self.transferred + read as u64 == len,
+            None => read < buf.len(),
+        };
+        self.offset += read as u64;
+        self.transferred += read as u64;
+
+        let opcode = if eof { OpCode::BlockEOF } else { OpCode::Block };
+        Self::write_block_msg(tx, opcode, self.block_ctr)?;
+        tx.get_writebuf()?.append(&buf[..read])?;
+        self.block_ctr = self.block_ctr.wrapping_add(1);
+        if eof {
+            self.state = State::AwaitAckEOF;
+        }
+        Ok(ResponseRequired::Yes)
+    }
+
+    fn recv_block(
+        &mut self,
+        opcode: OpCode,
+        rx: &mut [u8],
+        tx: &mut Packet,
+    ) -> Result<ResponseRequired, Abort> {
+        let (block_ctr, block) = parse_block(rx).or(abort(StatusCode::BadMessageContents))?;
+        if block_ctr != self.block_ctr {
+            return abort(StatusCode::BadBlockCounter);
+        }
+        if block.len() > self.max_block_size as usize {
+            return abort(StatusCode::BadMessageContents);
+        }
+        let transferred = self.transferred + block.len() as u64;
+        if matches!(self.len, Some(len) if transferred > len) {
+            return abort(StatusCode::LengthTooLarge);
+        }
+        let sink = match &mut self.data {
+            Data::Sink(sink) => sink,
+            Data::Source(_) => return abort(StatusCode::UnexpectedMessage),
+        };
+        sink.write(self.offset, block)?;
+        self.offset += block.len() as u64;
+        self.transferred = transferred;
+        self.block_ctr = self.block_ctr.wrapping_add(1);
+
+        if opcode == OpCode::Block {
+            // In sender drive the block is acknowledged, in receiver drive the next one
+            // is asked for
+            if self.drive_mode == TransferControl::RECEIVER_DRIVE {
+                Self::write_block_msg(tx, OpCode::BlockQuery, self.block_ctr)?;
+            } else {
+                Self::write_block_msg(tx, OpCode::BlockAck, block_ctr)?;
+            }
+            return Ok(ResponseRequired::Yes);
+        }
+
+        if matches!(self.len, Some(len) if transferred != len) {
+            return abort(StatusCode::LengthMismatch);
+        }
+        sink.finish()?;
+        info!("Received the file, {} bytes", self.transferred);
+        Self::write_block_msg(tx, OpCode::BlockAckEOF, block_ctr)?;
+        self.complete(Ok(()));
+        Ok(ResponseRequired::Yes)
+    }
+
+    fn complete(&mut self, result: Result<(), Error>) {
+        self.state = State::Done;
+        if let Some(done_tx) = self.done_tx.take() {
+            let _ = done_tx.try_send(result);
+        }
+    }
+}
+
+/// The handler of the Bulk Data Exchange protocol
+///
+/// This serves the transfers that the peers initiate, if it has a provider, and
+/// carries on the transfers that we initiate with send_file() and receive_file().
+///
+/// Only the initiator drives a transfer. A peer's SendInit must allow sender drive,
+/// and a peer's ReceiveInit must allow receiver drive.
+#[derive(Default)]
+pub struct Bdx {
+    provider: Option<Box<dyn BdxProvider>>,
+}
+
+impl Bdx {
+    /// A handler that only carries on the transfers that we initiate
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn new_with(provider: Box<dyn BdxProvider>) -> Self {
+        Self {
+            provider: Some(provider),
+        }
+    }
+
+    fn handle_init(
+        &mut self,
+        opcode: OpCode,
+        rx: &mut [u8],
+        tx: &mut Packet,
+    ) -> Result<Transfer, Abort> {
+        let init = TransferInit::parse(rx).or(abort(StatusCode::BadMessageContents))?;
+        info!(
+            "Peer initiated a transfer of {:?}",
+            String::from_utf8_lossy(&init.file_designator)
+        );
+        let provider = match &mut self.provider {
+            Some(provider) => provider,
+            None => return abort(StatusCode::FileDesignatorUnknown),
+        };
+        if init.max_block_size == 0 {
+            return abort(StatusCode::BadMessageContents);
+        }
+        let max_block_size = init.max_block_size.min(MAX_BLOCK_SIZE);
+        let offset = init.start_offset.unwrap_or(0);
+
+        let (mut transfer, accept) = if opcode == OpCode::SendInit {
+            if !init.drive_modes.contains(TransferControl::SENDER_DRIVE) {
+                return abort(StatusCode::TransferMethodNotSupported);
+            }
+            let sink = provider
+                .accept_send(&init)
+                .map_err(|s| Abort(s, Error::Invalid))?;
+            let transfer = Transfer::new(
+                Data::Sink(sink),
+                TransferControl::SENDER_DRIVE,
+                offset,
+                init.max_len,
+            );
+            let accept = TransferAccept {
+                version: BDX_VERSION,
+                drive_mode: TransferControl::SENDER_DRIVE,
+                max_block_size,
+                start_offset: None,
+                len: None,
+            };
+            (transfer, accept)
+        } else {
+            if !init.drive_modes.contains(TransferControl::RECEIVER_DRIVE) {
+                return abort(StatusCode::TransferMethodNotSupported);
+            }
+            let source = provider
+                .accept_receive(&init)
+                .map_err(|s| Abort(s, Error::Invalid))?;
+            let len = match (
+                source.size().map(|s| s.saturating_sub(offset)),
+                init.max_len,
+            ) {
+                (Some(size), Some(max_len)) => Some(size.min(max_len)),
+                (size, max_len) => size.or(max_len),
+            };
+            let transfer = Transfer::new(
+                Data::Source(source),
+                TransferControl::RECEIVER_DRIVE,
+                offset,
+                len,
+            );
+            let accept = TransferAccept {
+                version: BDX_VERSION,
+                drive_mode: TransferControl::RECEIVER_DRIVE,
+                max_block_size,
+                start_offset: init.start_offset,
+                len,
+            };
+            (transfer, accept)
+        };
+        transfer.max_block_size = max_block_size;
+
+        let accept_opcode = if opcode == OpCode::SendInit {
+            OpCode::SendAccept
+        } else {
+            OpCode::ReceiveAccept
+        };
+        tx.set_proto_opcode(accept_opcode as u8);
+        accept.write(accept_opcode, tx.get_writebuf()?)?;
+        Ok(transfer)
+    }
+}
+
+impl proto_demux::HandleProto for Bdx {
+    fn handle_proto_id(&mut self, ctx: &mut ProtoCtx) -> Result<ResponseRequired, Error> {
+        if ctx.rx.get_proto_id() == PROTO_ID_SECURE_CHANNEL as u16 {
+            // The peer ended the transfer with a status report
+            let report = StatusReport::parse(ctx.rx.as_borrow_slice())?;
+            error!("The peer aborted the transfer: {:?}", report);
+            if let Some(mut transfer) = ctx.exch_ctx.exch.take_exchange_data::<Transfer>() {
+                transfer.complete(Err(Error::Invalid));
+            }
+            ctx.exch_ctx.exch.close();
+            return Ok(ResponseRequired::No);
+        }
+        let opcode: OpCode =
+            num::FromPrimitive::from_u8(ctx.rx.get_proto_opcode()).ok_or(Error::Invalid)?;
+        ctx.tx.set_proto_id(PROTO_ID_BDX as u16);
+        let rx = ctx.rx.as_borrow_slice();
+
+        let result = if opcode == OpCode::SendInit || opcode == OpCode::ReceiveInit {
+            self.handle_init(opcode, rx, &mut ctx.tx)
+                .map(|transfer| (Box::new(transfer), ResponseRequired::Yes))
+        } else {
+            match ctx.exch_ctx.exch.take_exchange_data::<Transfer>() {
+                Some(mut transfer) => match transfer.handle(opcode, rx, &mut ctx.tx) {
+                    Ok(resp) => Ok((transfer, resp)),
+                    Err(Abort(status, e)) => {
+                        transfer.complete(Err(e));
+                        Err(Abort(status, e))
+                    }
+                },
+                None => abort(StatusCode::UnexpectedMessage),
+            }
+        };
+
+        match result {
+            Ok((transfer, resp)) => {
+                if transfer.state == State::Done {
+                    ctx.exch_ctx.exch.close();
+                } else {
+                    ctx.exch_ctx.exch.set_exchange_data(transfer);
+                }
+                Ok(resp)
+            }
+            Err(Abort(status, _)) => {
+                create_status_report(
+                    &mut ctx.tx,
+                    GeneralCode::Failure,
+                    PROTO_ID_BDX as u32,
+                    status as u16,
+                    None,
+                )?;
+                ctx.exch_ctx.exch.close();
+                Ok(ResponseRequired::Yes)
+            }
+        }
+    }
+
+    fn get_proto_id(&self) -> usize {
+        PROTO_ID_BDX
+    }
+
+    fn handle_response_timeout(&mut self, exch: &mut Exchange) {
+        if let Some(mut transfer) = exch.take_exchange_data::<Transfer>() {
+            error!("The peer went quiet during the transfer");
+            transfer.complete(Err(Error::Timeout));
+        }
+    }
+}
+
+// Initiates a transfer on a new exchange, and waits till it is done
+async fn initiate(
+    work_q: &WorkQ,
+    sess_id: u16,
+    opcode: OpCode,
+    init: TransferInit,
+    mut transfer: Transfer,
+) -> Result<(), Error> {
+    let mut buf = vec![0; 32 + init.file_designator.len()];
+    let len = buf.len();
+    let mut wb = WriteBuf::new(&mut buf, len);
+    init.write(&mut wb)?;
+    let payload = wb.as_borrow_slice().to_vec();
+
+    let (done_tx, done_rx) = bounded(1);
+    transfer.state = State::AwaitAccept;
+    transfer.done_tx = Some(done_tx);
+    work_q
+        .send(Msg::Tx(TxMsg {
+            sess_id,
+            proto_vendor_id: None,
+            proto_id: PROTO_ID_BDX as u16,
+            proto_opcode: opcode as u8,
+            payload,
+            resp: TxResp::Handler(Box::new(transfer)),
+        }))
+        .await?;
+    // The exchange drops the transfer if it fails before the transfer is done
+    done_rx.recv().await.map_err(|_| Error::NoExchange)?
+}
+
+/// Sends a file to the peer of the session `sess_id`, driving the transfer
+///
+/// The transport of `work_q` must have a Bdx handler registered. This returns once
+/// the peer acknowledged the whole file, it fails with Error::Timeout if the peer
+/// stops responding.
+pub async fn send_file(
+    work_q: &WorkQ,
+    sess_id: u16,
+    file_designator: &[u8],
+    source: Box<dyn BdxSource>,
+) -> Result<(), Error> {
+    let init = TransferInit {
+        version: BDX_VERSION,
+        drive_modes: TransferControl::SENDER_DRIVE,
+        max_block_size: MAX_BLOCK_SIZE,
+        start_offset: None,
+        max_len: source.size(),
+        file_designator: file_designator.to_vec(),
+    };
+    let transfer = Transfer::new(
+        Data::Source(source),
+        TransferControl::SENDER_DRIVE,
+        0,
+        init.max_len,
+    );
+    initiate(work_q, sess_id, OpCode::SendInit, init, transfer).await
+}
+
+/// Receives a file from the peer of the session `sess_id`, driving the transfer
+///
+/// The transport of `work_q` must have a Bdx handler registered. This returns once
+/// the whole file was handed over to the sink, it fails with Error::Timeout if
+/// the peer stops responding.
+pub async fn receive_file(
+    work_q: &WorkQ,
+    sess_id: u16,
+    file_designator: &[u8],
+    sink: Box<dyn BdxSink>,
+) -> Result<(), Error> {
+    let init = TransferInit {
+        version: BDX_VERSION,
+        drive_modes: TransferControl::RECEIVER_DRIVE,
+        max_block_size: MAX_BLOCK_SIZE,
+        start_offset: None,
+        max_len: None,
+        file_designator: file_designator.to_vec(),
+    };
+    let transfer = Transfer::new(Data::Sink(sink), TransferControl::RECEIVER_DRIVE, 0, None);
+    initiate(work_q, sess_id, OpCode::ReceiveInit, init, transfer).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{IpAddr, Ipv6Addr, SocketAddr},
+        sync::{Arc, Mutex},
+    };
+
+    use smol::future::FutureExt;
+
+    use crate::{
+        error::Error,
+        transport::{
+            loopback::LoopbackSwitch,
+            mgr::{Mgr, TransportConfig},
+            network::Address,
+            queue::Msg,
+            session::{CloneData, SessionMode},
+        },
+    };
+
+    use super::{
+        receive_file, send_file, Bdx, BdxProvider, BdxSink, BdxSource, StatusCode, TransferInit,
+        MAX_BLOCK_SIZE,
+    };
+
+    type File = Arc<Mutex<Vec<u8>>>;
+
+    struct MemSource(Vec<u8>);
+
+    impl BdxSource for MemSource {
+        fn size(&self) -> Option<u64> {
+            Some(self.0.len() as u64)
+        }
+
+        fn read(&mut self, offset: u64, buf: &mut [u8]) -> Result<usize, Error> {
+            let data = &self.0[(offset as usize).min(self.0.len())..];
+            let len = data.len().min(buf.len());
+            buf[..len].copy_from_slice(&data[..len]);
+            Ok(len)
+        }
+    }
+
+    struct MemSink(File);
+
+    impl BdxSink for MemSink {
+        fn write(&mut self, offset: u64, data: &[u8]) -> Result<(), Error> {
+            let mut file = self.0.lock().unwrap();
+            file.truncate(offset as usize);
+            file.extend_from_slice(data);
+            Ok(())
+        }
+
+        fn finish(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    // Serves "ota.bin", and stores whatever it is sent in `received`
+    struct Provider {
+        image: Vec<u8>,
+        received: File,
+    }
+
+    impl BdxProvider for Provider {
+        fn accept_send(&mut self, _init: &TransferInit) -> Result<Box<dyn BdxSink>, StatusCode> {
+            Ok(Box::new(MemSink(self.received.clone())))
+        }
+
+        fn accept_receive(
+            &mut self,
+            init: &TransferInit,
+        ) -> Result<Box<dyn BdxSource>, StatusCode> {
+            if init.file_designator != b"ota.bin" {
+                return Err(StatusCode::FileDesignatorUnknown);
+            }
+            Ok(Box::new(MemSource(self.image.clone())))
+        }
+    }
+
+    fn addr(node: u16) -> SocketAddr {
+        SocketAddr::new(
+            IpAddr::V6(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, node)),
+            5540,
+        )
+    }
+
+    fn node(switch: &LoopbackSwitch, local: u16, peer: u16, bdx: Bdx) -> Mgr {
+        let mut mgr = Mgr::new(TransportConfig {
+            bind_addrs: Vec::new(),
+            tcp: false,
+            interfaces: vec![Box::new(switch.connect(addr(local)).unwrap())],
+            ..Default::default()
+        })
+        .unwrap();
+        mgr.register_protocol(Box::new(bdx)).unwrap();
+        let clone_data = CloneData::new(
+            local as u64,
+            peer as u64,
+            peer,
+            local,
+            Address::Udp(addr(peer)),
+            SessionMode::Pase,
+        );
+        mgr.get_work_q()
+            .sync_send(Msg::NewSession(clone_data))
+            .unwrap();
+        mgr
+    }
+
+    fn file(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn test_transfers() {
+        let switch = LoopbackSwitch::new();
+        let image = file(MAX_BLOCK_SIZE as usize * 3);
+        let received = File::default();
+        let provider = Provider {
+            image: image.clone(),
+            received: received.clone(),
+        };
+        let mut controller = node(&switch, 1, 2, Bdx::new());
+        let mut device = node(&switch, 2, 1, Bdx::new_with(Box::new(provider)));
+
+        let logs = file(MAX_BLOCK_SIZE as usize * 2 + 100);
+        let downloaded = File::default();
+        let work_q = controller.get_work_q();
+        let result = smol::block_on(
+            async {
+                // Sender drive
+                let sent = send_file(&work_q, 1, b"logs.txt", Box::new(MemSource(logs.clone())));
+                // Receiver drive
+                let sink = Box::new(MemSink(downloaded.clone()));
+                let got = receive_file(&work_q, 1, b"ota.bin", sink).await;
+                Ok((sent.await, got))
+            }
+            .or(async {
+                controller.run().or(device.run()).await?;
+                Err(Error::Invalid)
+            }),
+        );
+        let (sent, got) = result.unwrap();
+        assert_eq!(sent, Ok(()));
+        assert_eq!(*received.lock().unwrap(), logs);
+        assert_eq!(got, Ok(()));
+        assert_eq!(*downloaded.lock().unwrap(), image);
+    }
+
+    #[test]
+    fn test_rejected() {
+        let switch = LoopbackSwitch::new();
+        let provider = Provider {
+            image: Vec::new(),
+            received: File::default(),
+        };
+        let mut controller = node(&switch, 1, 2, Bdx::new());
+        let mut device = node(&switch, 2, 1, Bdx::new_with(Box::new(provider)));
+
+        let work_q = controller.get_work_q();
+        let result = smol::block_on(
+            async {
+                let sink = Box::new(MemSink(File::default()));
+                Ok(receive_file(&work_q, 1, b"unknown.bin", sink).await)
+            }
+            .or(async {
+                controller.run().or(device.run()).await?;
+                Err(Error::Invalid)
+            }),
+        );
+        assert!(result.unwrap().is_err());
+    }
+}
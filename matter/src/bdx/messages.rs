@@ -0,0 +1,307 @@
+use bitflags::bitflags;
+use log::error;
+use num_derive::FromPrimitive;
+
+use crate::{
+    error::Error,
+    utils::{parsebuf::ParseBuf, writebuf::WriteBuf},
+};
+
+/* The Bulk Data Exchange protocol ID as per the Matter Spec */
+pub const PROTO_ID_BDX: usize = 0x02;
+
+/// The version of the protocol that we implement
+pub const BDX_VERSION: u8 = 0;
+
+#[derive(FromPrimitive, Debug, Copy, Clone, PartialEq)]
+pub enum OpCode {
+    SendInit = 0x01,
+    SendAccept = 0x02,
+    ReceiveInit = 0x04,
+    ReceiveAccept = 0x05,
+    BlockQuery = 0x10,
+    Block = 0x11,
+    BlockEOF = 0x12,
+    BlockAck = 0x13,
+    BlockAckEOF = 0x14,
+    BlockQueryWithSkip = 0x15,
+}
+
+/// The protocol specific codes of the status reports for BDX
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum StatusCode {
+    Overflow = 0x0011,
+    LengthTooLarge = 0x0012,
+    LengthTooShort = 0x0013,
+    LengthMismatch = 0x0014,
+    LengthRequired = 0x0015,
+    BadMessageContents = 0x0016,
+    BadBlockCounter = 0x0017,
+    UnexpectedMessage = 0x0018,
+    ResponderBusy = 0x0019,
+    TransferFailedUnknownError = 0x001F,
+    TransferMethodNotSupported = 0x0050,
+    FileDesignatorUnknown = 0x0051,
+    StartOffsetNotSupported = 0x0052,
+    VersionNotSupported = 0x0053,
+    Unknown = 0x005F,
+}
+
+bitflags! {
+    /// The Transfer Control field, the lower 4 bits hold the version
+    #[derive(Default)]
+    pub struct TransferControl: u8 {
+        const SENDER_DRIVE = 0x10;
+        const RECEIVER_DRIVE = 0x20;
+        const ASYNC = 0x40;
+    }
+}
+
+bitflags! {
+    /// The Range Control field
+    #[derive(Default)]
+    pub struct RangeControl: u8 {
+        const DEFLEN = 0x01;
+        const STARTOFS = 0x02;
+        const WIDERANGE = 0x10;
+    }
+}
+
+const VERSION_MASK: u8 = 0x0f;
+
+// Offsets and lengths take 8 bytes with WIDERANGE, 4 otherwise
+fn write_range(wb: &mut WriteBuf, wide: bool, val: u64) -> Result<(), Error> {
+    if wide {
+        wb.le_u64(val)
+    } else {
+        wb.le_u32(val as u32)
+    }
+}
+
+fn parse_range(pb: &mut ParseBuf, wide: bool) -> Result<u64, Error> {
+    if wide {
+        pb.le_u64()
+    } else {
+        pb.le_u32().map(|v| v as u64)
+    }
+}
+
+fn range_control(start_offset: Option<u64>, len: Option<u64>) -> RangeControl {
+    let mut range_ctrl = RangeControl::empty();
+    if start_offset.is_some() {
+        range_ctrl |= RangeControl::STARTOFS;
+    }
+    if len.is_some() {
+        range_ctrl |= RangeControl::DEFLEN;
+    }
+    let max = start_offset.unwrap_or(0).max(len.unwrap_or(0));
+    if max > u32::MAX as u64 {
+        range_ctrl |= RangeControl::WIDERANGE;
+    }
+    range_ctrl
+}
+
+/// The SendInit and ReceiveInit messages, that start a transfer
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransferInit {
+    /// The highest version that the initiator supports
+    pub version: u8,
+    /// The drive modes that the initiator supports
+    pub drive_modes: TransferControl,
+    pub max_block_size: u16,
+    pub start_offset: Option<u64>,
+    /// For SendInit the length of the file, for ReceiveInit the most that the
+    /// initiator wants
+    pub max_len: Option<u64>,
+    pub file_designator: Vec<u8>,
+}
+
+impl TransferInit {
+    pub fn write(&self, wb: &mut WriteBuf) -> Result<(), Error> {
+        let range_ctrl = range_control(self.start_offset, self.max_len);
+        let wide = range_ctrl.contains(RangeControl::WIDERANGE);
+        wb.le_u8(self.drive_modes.bits() | (self.version & VERSION_MASK))?;
+        wb.le_u8(range_ctrl.bits())?;
+        wb.le_u16(self.max_block_size)?;
+        if let Some(start_offset) = self.start_offset {
+            write_range(wb, wide, start_offset)?;
+        }
+        if let Some(max_len) = self.max_len {
+            write_range(wb, wide, max_len)?;
+        }
+        if self.file_designator.len() > u16::MAX as usize {
+            return Err(Error::Invalid);
+        }
+        wb.le_u16(self.file_designator.len() as u16)?;
+        wb.append(&self.file_designator)
+    }
+
+    pub fn parse(buf: &mut [u8]) -> Result<Self, Error> {
+        let len = buf.len();
+        let mut pb = ParseBuf::new(buf, len);
+        let transfer_ctrl = pb.le_u8()?;
+        let range_ctrl = RangeControl::from_bits_truncate(pb.le_u8()?);
+        let wide = range_ctrl.contains(RangeControl::WIDERANGE);
+        let max_block_size = pb.le_u16()?;
+        let start_offset = if range_ctrl.contains(RangeControl::STARTOFS) {
+            Some(parse_range(&mut pb, wide)?)
+        } else {
+            None
+        };
+        let max_len = if range_ctrl.contains(RangeControl::DEFLEN) {
+            Some(parse_range(&mut pb, wide)?)
+        } else {
+            None
+        };
+        let designator_len = pb.le_u16()? as usize;
+        // Any metadata after the designator is ignored
+        let rest = pb.as_borrow_slice();
+        if rest.len() < designator_len {
+            error!("File designator is truncated");
+            return Err(Error::TruncatedPacket);
+        }
+        Ok(Self {
+            version: transfer_ctrl & VERSION_MASK,
+            drive_modes: TransferControl::from_bits_truncate(transfer_ctrl),
+            max_block_size,
+            start_offset,
+            max_len,
+            file_designator: rest[..designator_len].to_vec(),
+        })
+    }
+}
+
+/// The SendAccept and ReceiveAccept messages, that settle the parameters of a
+/// transfer
+///
+/// SendAccept only carries the version, the drive mode and the block size.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransferAccept {
+    pub version: u8,
+    /// Only one of the drive modes
+    pub drive_mode: TransferControl,
+    pub max_block_size: u16,
+    pub start_offset: Option<u64>,
+    pub len: Option<u64>,
+}
+
+impl TransferAccept {
+    pub fn write(&self, opcode: OpCode, wb: &mut WriteBuf) -> Result<(), Error> {
+        wb.le_u8(self.drive_mode.bits() | (self.version & VERSION_MASK))?;
+        if opcode == OpCode::SendAccept {
+            return wb.le_u16(self.max_block_size);
+        }
+        let range_ctrl = range_control(self.start_offset, self.len);
+        let wide = range_ctrl.contains(RangeControl::WIDERANGE);
+        wb.le_u8(range_ctrl.bits())?;
+        wb.le_u16(self.max_block_size)?;
+        if let Some(start_offset) = self.start_offset {
+            write_range(wb, wide, start_offset)?;
+        }
+        if let Some(len) = self.len {
+            write_range(wb, wide, len)?;
+        }
+        Ok(())
+    }
+
+    pub fn parse(opcode: OpCode, buf: &mut [u8]) -> Result<Self, Error> {
+        let len = buf.len();
+        let mut pb = ParseBuf::new(buf, len);
+        let transfer_ctrl = pb.le_u8()?;
+        let mut accept = Self {
+            version: transfer_ctrl & VERSION_MASK,
+            drive_mode: TransferControl::from_bits_truncate(transfer_ctrl),
+            max_block_size: 0,
+            start_offset: None,
+            len: None,
+        };
+        if opcode == OpCode::SendAccept {
+            accept.max_block_size = pb.le_u16()?;
+            return Ok(accept);
+        }
+        let range_ctrl = RangeControl::from_bits_truncate(pb.le_u8()?);
+        let wide = range_ctrl.contains(RangeControl::WIDERANGE);
+        accept.max_block_size = pb.le_u16()?;
+        if range_ctrl.contains(RangeControl::STARTOFS) {
+            accept.start_offset = Some(parse_range(&mut pb, wide)?);
+        }
+        if range_ctrl.contains(RangeControl::DEFLEN) {
+            accept.len = Some(parse_range(&mut pb, wide)?);
+        }
+        Ok(accept)
+    }
+}
+
+/// The block messages start with the block counter, Block and BlockEOF follow it
+/// with the data
+pub fn write_block_ctr(wb: &mut WriteBuf, block_ctr: u32) -> Result<(), Error> {
+    wb.le_u32(block_ctr)
+}
+
+/// Returns the block counter, and the data that follows it
+pub fn parse_block(buf: &mut [u8]) -> Result<(u32, &[u8]), Error> {
+    let len = buf.len();
+    let mut pb = ParseBuf::new(buf, len);
+    let block_ctr = pb.le_u32()?;
+    Ok((block_ctr, pb.as_slice()))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::utils::writebuf::WriteBuf;
+
+    use super::{OpCode, TransferAccept, TransferControl, TransferInit};
+
+    #[test]
+    fn test_transfer_init() {
+        let init = TransferInit {
+            version: 0,
+            drive_modes: TransferControl::SENDER_DRIVE | TransferControl::RECEIVER_DRIVE,
+            max_block_size: 512,
+            start_offset: None,
+            max_len: Some(0x1_0000_0000),
+            file_designator: b"log.txt".to_vec(),
+        };
+        let mut buf = [0u8; 64];
+        let mut wb = WriteBuf::new(&mut buf, 64);
+        init.write(&mut wb).unwrap();
+        let mut encoded = wb.as_borrow_slice().to_vec();
+        assert_eq!(
+            encoded,
+            [
+                0x30, 0x11, 0x00, 0x02, 0, 0, 0, 0, 1, 0, 0, 0, 7, 0, b'l', b'o', b'g', b'.', b't',
+                b'x', b't'
+            ]
+        );
+        assert_eq!(TransferInit::parse(&mut encoded), Ok(init));
+        assert!(TransferInit::parse(&mut encoded[..14]).is_err());
+    }
+
+    #[test]
+    fn test_transfer_accept() {
+        let accept = TransferAccept {
+            version: 0,
+            drive_mode: TransferControl::RECEIVER_DRIVE,
+            max_block_size: 128,
+            start_offset: Some(10),
+            len: Some(1000),
+        };
+        let mut buf = [0u8; 64];
+        let mut wb = WriteBuf::new(&mut buf, 64);
+        accept.write(OpCode::ReceiveAccept, &mut wb).unwrap();
+        let mut encoded = wb.as_borrow_slice().to_vec();
+        assert_eq!(
+            encoded,
+            [0x20, 0x03, 0x80, 0x00, 10, 0, 0, 0, 0xe8, 0x03, 0, 0]
+        );
+        assert_eq!(
+            TransferAccept::parse(OpCode::ReceiveAccept, &mut encoded),
+            Ok(accept.clone())
+        );
+
+        let mut buf = [0u8; 64];
+        let mut wb = WriteBuf::new(&mut buf, 64);
+        accept.write(OpCode::SendAccept, &mut wb).unwrap();
+        assert_eq!(wb.as_borrow_slice(), [0x20, 0x80, 0x00]);
+    }
+}
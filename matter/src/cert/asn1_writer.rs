@@ -37,6 +37,16 @@ impl<'a> ASN1Writer<'a> {
         Err(Error::NoSpace)
     }
 
+    /// Appends bytes that are already DER encoded
+    pub fn append_raw(&mut self, data: &[u8]) -> Result<(), Error> {
+        if self.offset + data.len() <= self.buf.len() {
+            self.buf[self.offset..(self.offset + data.len())].copy_from_slice(data);
+            self.offset += data.len();
+            return Ok(());
+        }
+        Err(Error::NoSpace)
+    }
+
     pub fn append_tlv<F>(&mut self, tag: u8, len: usize, f: F) -> Result<(), Error>
     where
         F: FnOnce(&mut Self),
@@ -13,9 +13,9 @@ use self::{asn1_writer::ASN1Writer, printer::CertPrinter};
 
 // As per https://datatracker.ietf.org/doc/html/rfc5280
 
-const OID_PUB_KEY_ECPUBKEY: [u8; 7] = [0x2A, 0x86, 0x48, 0xCE, 0x3D, 0x02, 0x01];
-const OID_EC_TYPE_PRIME256V1: [u8; 8] = [0x2A, 0x86, 0x48, 0xCE, 0x3D, 0x03, 0x01, 0x07];
-const OID_ECDSA_WITH_SHA256: [u8; 8] = [0x2A, 0x86, 0x48, 0xCE, 0x3D, 0x04, 0x03, 0x02];
+pub const OID_PUB_KEY_ECPUBKEY: [u8; 7] = [0x2A, 0x86, 0x48, 0xCE, 0x3D, 0x02, 0x01];
+pub const OID_EC_TYPE_PRIME256V1: [u8; 8] = [0x2A, 0x86, 0x48, 0xCE, 0x3D, 0x03, 0x01, 0x07];
+pub const OID_ECDSA_WITH_SHA256: [u8; 8] = [0x2A, 0x86, 0x48, 0xCE, 0x3D, 0x04, 0x03, 0x02];
 
 #[derive(FromPrimitive)]
 pub enum CertTags {
@@ -561,7 +561,7 @@ pub trait CertConsumer {
 const MAX_DEPTH: usize = 10;
 const MAX_ASN1_CERT_SIZE: usize = 800;
 
-mod asn1_writer;
+pub mod asn1_writer;
 mod printer;
 
 #[cfg(test)]
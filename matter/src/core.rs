@@ -1,5 +1,6 @@
 use crate::{
     acl::AclMgr,
+    bdx::core::{Bdx, BdxProvider},
     data_model::{
         cluster_basic_information::BasicInfoConfig, core::DataModel,
         sdm::dev_att::DevAttDataFetcher,
@@ -7,20 +8,25 @@ use crate::{
     error::*,
     fabric::FabricMgr,
     interaction_model::InteractionModel,
+    limits::Limits,
     mdns::Mdns,
-    secure_channel::core::SecureChannel,
-    transport,
+    secure_channel::{core::SecureChannel, pake::PaseMgr, spake2p::VerifierData},
+    transport::{
+        self,
+        mgr::{ShutdownHandle, TransportConfig},
+        session::ExpiredSession,
+        stats::StatsHandle,
+    },
+    udc::{Udc, UdcCallback},
 };
+use async_channel::Receiver;
+use smol::future::FutureExt;
 use std::sync::Arc;
 
-#[derive(Default)]
 /// Device Commissioning Data
 pub struct CommissioningData {
-    /// The commissioning salt
-    pub salt: [u8; 16],
-    /// The password for commissioning the device
-    // TODO: We should replace this with verifier instead of password
-    pub passwd: u32,
+    /// The SPAKE2+ verifier of the passcode, along with its salt and iterations
+    pub verifier: VerifierData,
     /// The 12-bit discriminator used to differentiate between multiple devices
     pub discriminator: u16,
 }
@@ -30,6 +36,7 @@ pub struct Matter {
     transport_mgr: transport::mgr::Mgr,
     data_model: DataModel,
     fabric_mgr: Arc<FabricMgr>,
+    pase_mgr: PaseMgr,
 }
 
 impl Matter {
@@ -39,32 +46,71 @@ impl Matter {
     /// * dev_att: An object that implements the trait [DevAttDataFetcher]. Any Matter device
     /// requires a set of device attestation certificates and keys. It is the responsibility of
     /// this object to return the device attestation details when queried upon.
+    /// * transport_config: The port, local addresses etc that the device listens on. Use
+    ///   `TransportConfig::default()` to listen on the Matter port on all the interfaces.
     pub fn new(
         dev_det: BasicInfoConfig,
         dev_att: Box<dyn DevAttDataFetcher>,
         dev_comm: CommissioningData,
+        transport_config: TransportConfig,
+    ) -> Result<Box<Matter>, Error> {
+        Matter::new_with_limits(
+            dev_det,
+            dev_att,
+            dev_comm,
+            transport_config,
+            &Limits::default(),
+        )
+    }
+
+    /// Creates a new Matter object, with the tables sized by `limits`
+    ///
+    /// This is the same as new(), for devices that need more endpoints, sessions etc
+    /// than the defaults, or want to save memory with fewer. See [Limits].
+    pub fn new_with_limits(
+        dev_det: BasicInfoConfig,
+        dev_att: Box<dyn DevAttDataFetcher>,
+        dev_comm: CommissioningData,
+        transport_config: TransportConfig,
+        limits: &Limits,
     ) -> Result<Box<Matter>, Error> {
+        let transport_mgr = transport::mgr::Mgr::new_with_limits(transport_config, limits)?;
         let mdns = Mdns::get()?;
         mdns.set_values(dev_det.vid, dev_det.pid, dev_comm.discriminator);
+        mdns.set_port(transport_mgr.get_port());
 
-        let fabric_mgr = Arc::new(FabricMgr::new()?);
-        let acl_mgr = Arc::new(AclMgr::new()?);
-        let open_comm_window = fabric_mgr.is_empty();
-        let data_model = DataModel::new(dev_det, dev_att, fabric_mgr.clone(), acl_mgr)?;
+        let fabric_mgr = Arc::new(FabricMgr::new_with_limits(limits)?);
+        let acl_mgr = Arc::new(AclMgr::new_with_limits(true, limits)?);
+        let pase_mgr = PaseMgr::new(dev_comm.verifier);
+        if fabric_mgr.is_empty() {
+            // The window stays open until we are commissioned
+            pase_mgr.open_basic_comm_window(None, None)?;
+        }
+        let data_model = DataModel::new_with_limits(
+            dev_det,
+            dev_att,
+            fabric_mgr.clone(),
+            acl_mgr,
+            pase_mgr.clone(),
+            limits,
+        )?;
         let mut matter = Box::new(Matter {
-            transport_mgr: transport::mgr::Mgr::new()?,
+            transport_mgr,
             data_model,
             fabric_mgr,
+            pase_mgr,
         });
         let interaction_model =
             Box::new(InteractionModel::new(Box::new(matter.data_model.clone())));
         matter.transport_mgr.register_protocol(interaction_model)?;
-        let mut secure_channel = Box::new(SecureChannel::new(matter.fabric_mgr.clone()));
-        if open_comm_window {
-            secure_channel.open_comm_window(&dev_comm.salt, dev_comm.passwd)?;
-        }
-
+        let secure_channel = Box::new(SecureChannel::new(
+            matter.fabric_mgr.clone(),
+            matter.pase_mgr.clone(),
+        ));
         matter.transport_mgr.register_protocol(secure_channel)?;
+        matter
+            .transport_mgr
+            .register_protocol(Box::new(Bdx::new()))?;
         Ok(matter)
     }
 
@@ -77,13 +123,59 @@ impl Matter {
         self.data_model.clone()
     }
 
+    /// Sets the provider that serves the bulk data transfers that peers initiate
+    ///
+    /// Without a provider, the transfers that peers initiate are rejected.
+    pub fn set_bdx_provider(&mut self, provider: Box<dyn BdxProvider>) -> Result<(), Error> {
+        self.transport_mgr
+            .register_protocol(Box::new(Bdx::new_with(provider)))
+    }
+
+    /// Sets the callback for the nodes that ask us to commission them
+    ///
+    /// This makes us a User Directed Commissioning server, the nodes send their
+    /// IdentificationDeclarations to the port of our transport.
+    pub fn set_udc_callback(&mut self, callback: Box<dyn UdcCallback>) -> Result<(), Error> {
+        self.transport_mgr
+            .register_protocol(Box::new(Udc::new(callback)))
+    }
+
+    /// Returns a receiver for the sessions that expire, because their peers went quiet
+    ///
+    /// How long the peers may be quiet is set in TransportConfig::idle_timeouts.
+    pub fn subscribe_expired_sessions(&mut self) -> Receiver<ExpiredSession> {
+        self.transport_mgr.subscribe_expired_sessions()
+    }
+
+    /// Returns a handle to the statistics of the transport
+    ///
+    /// The counters can be read from any thread, while the Matter daemon runs.
+    pub fn get_stats_handle(&self) -> StatsHandle {
+        self.transport_mgr.get_stats_handle()
+    }
+
+    /// Returns a handle that can be used to stop the Matter daemon
+    pub fn get_shutdown_handle(&self) -> ShutdownHandle {
+        self.transport_mgr.get_shutdown_handle()
+    }
+
     /// Starts the Matter daemon
     ///
-    /// This call does NOT return
+    /// This call does NOT return, till the daemon is stopped through the handle from
+    /// get_shutdown_handle()
     ///
     /// This call starts the Matter daemon that starts communication with other Matter
     /// devices on the network.
     pub fn start_daemon(&mut self) -> Result<(), Error> {
-        self.transport_mgr.start()
+        smol::block_on(self.run())
+    }
+
+    /// Runs the Matter daemon as a future
+    ///
+    /// This is the same as start_daemon(), for applications that have their own async
+    /// executor.
+    pub async fn run(&mut self) -> Result<(), Error> {
+        // The commissioning windows time out while the transport runs
+        self.transport_mgr.run().or(self.pase_mgr.run()).await
     }
 }
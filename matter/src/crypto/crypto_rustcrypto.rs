@@ -0,0 +1,287 @@
+use crate::{
+    cert::{
+        asn1_writer::ASN1Writer, CertConsumer, OID_ECDSA_WITH_SHA256, OID_EC_TYPE_PRIME256V1,
+        OID_PUB_KEY_ECPUBKEY,
+    },
+    error::Error,
+};
+
+use super::CryptoKeyPair;
+use aes::Aes128;
+use ccm::{
+    aead::{generic_array::GenericArray, AeadInPlace, KeyInit},
+    consts::{U13, U16},
+    Ccm,
+};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use log::error;
+use p256::{
+    ecdsa::{
+        signature::{Signer, Verifier},
+        Signature, SigningKey, VerifyingKey,
+    },
+    elliptic_curve::sec1::ToEncodedPoint,
+    PublicKey, SecretKey,
+};
+use rand::rngs::OsRng;
+use sha2::Digest;
+
+type AesCcm = Ccm<Aes128, U16, U13>;
+
+const OID_ORG_NAME: [u8; 3] = [0x55, 0x04, 0x0A];
+const MAX_CSR_INFO_LEN: usize = 256;
+
+pub struct HmacSha256 {
+    ctx: Hmac<sha2::Sha256>,
+}
+
+impl HmacSha256 {
+    pub fn new(key: &[u8]) -> Result<Self, Error> {
+        Ok(Self {
+            ctx: <Hmac<sha2::Sha256> as Mac>::new_from_slice(key)
+                .map_err(|_x| Error::InvalidKeyLength)?,
+        })
+    }
+
+    pub fn update(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.ctx.update(data);
+        Ok(())
+    }
+
+    pub fn finish(self, out: &mut [u8]) -> Result<(), Error> {
+        let a = self.ctx.finalize().into_bytes();
+        out.copy_from_slice(a.as_slice());
+        Ok(())
+    }
+}
+
+pub enum KeyType {
+    Public(PublicKey),
+    Private(SecretKey),
+}
+pub struct KeyPair {
+    key: KeyType,
+}
+
+impl KeyPair {
+    pub fn new() -> Result<Self, Error> {
+        Ok(Self {
+            key: KeyType::Private(SecretKey::random(&mut OsRng)),
+        })
+    }
+
+    pub fn new_from_components(_pub_key: &[u8], priv_key: &[u8]) -> Result<Self, Error> {
+        // The public key is derived from the private key
+        Ok(Self {
+            key: KeyType::Private(SecretKey::from_slice(priv_key)?),
+        })
+    }
+
+    pub fn new_from_public(pub_key: &[u8]) -> Result<Self, Error> {
+        Ok(Self {
+            key: KeyType::Public(PublicKey::from_sec1_bytes(pub_key)?),
+        })
+    }
+
+    fn public_key(&self) -> PublicKey {
+        match &self.key {
+            KeyType::Public(k) => *k,
+            KeyType::Private(k) => k.public_key(),
+        }
+    }
+
+    fn private_key(&self) -> Result<&SecretKey, Error> {
+        match &self.key {
+            KeyType::Public(_) => Err(Error::Invalid),
+            KeyType::Private(k) => Ok(k),
+        }
+    }
+
+    // The CertificationRequestInfo of RFC 2986, with the O=CSR subject
+    fn write_csr_info(&self, w: &mut ASN1Writer) -> Result<(), Error> {
+        let pub_key = self.public_key().to_encoded_point(false);
+
+        w.start_seq("")?;
+        w.integer("version", &[0])?;
+        w.start_seq("subject")?;
+        w.start_set("")?;
+        w.start_seq("")?;
+        w.oid("O", &OID_ORG_NAME)?;
+        w.utf8str("", "CSR")?;
+        w.end_seq()?;
+        w.end_set()?;
+        w.end_seq()?;
+        w.start_seq("subjectPKInfo")?;
+        w.start_seq("")?;
+        w.oid("id-ecPublicKey", &OID_PUB_KEY_ECPUBKEY)?;
+        w.oid("prime256v1", &OID_EC_TYPE_PRIME256V1)?;
+        w.end_seq()?;
+        w.bitstr("", false, pub_key.as_bytes())?;
+        w.end_seq()?;
+        w.start_ctx("attributes", 0)?;
+        w.end_ctx()?;
+        w.end_seq()
+    }
+}
+
+impl CryptoKeyPair for KeyPair {
+    fn get_csr<'a>(&self, out_csr: &'a mut [u8]) -> Result<&'a [u8], Error> {
+        let mut info_buf = [0u8; MAX_CSR_INFO_LEN];
+        let mut info = ASN1Writer::new(&mut info_buf);
+        self.write_csr_info(&mut info)?;
+        let info = info.as_slice();
+
+        let signature: Signature = SigningKey::from(self.private_key()?).sign(info);
+        let signature = signature.to_der();
+
+        let mut w = ASN1Writer::new(out_csr);
+        w.start_seq("")?;
+        w.append_raw(info)?;
+        w.start_seq("signatureAlgorithm")?;
+        w.oid("ecdsa-with-SHA256", &OID_ECDSA_WITH_SHA256)?;
+        w.end_seq()?;
+        w.bitstr("", false, signature.as_bytes())?;
+        w.end_seq()?;
+        let len = w.as_slice().len();
+        Ok(&out_csr[..len])
+    }
+
+    fn get_public_key(&self, pub_key: &mut [u8]) -> Result<usize, Error> {
+        let point = self.public_key().to_encoded_point(false);
+        let s = point.as_bytes();
+        let len = s.len();
+        if pub_key.len() < len {
+            return Err(Error::NoSpace);
+        }
+        pub_key[..len].copy_from_slice(s);
+        Ok(len)
+    }
+
+    fn get_private_key(&self, priv_key: &mut [u8]) -> Result<usize, Error> {
+        let s = self.private_key()?.to_bytes();
+        let len = s.len();
+        if priv_key.len() < len {
+            return Err(Error::NoSpace);
+        }
+        priv_key[..len].copy_from_slice(s.as_slice());
+        Ok(len)
+    }
+
+    fn derive_secret(self, peer_pub_key: &[u8], secret: &mut [u8]) -> Result<usize, Error> {
+        let peer_pub_key = PublicKey::from_sec1_bytes(peer_pub_key)?;
+        let shared = p256::ecdh::diffie_hellman(
+            self.private_key()?.to_nonzero_scalar(),
+            peer_pub_key.as_affine(),
+        );
+        let s = shared.raw_secret_bytes();
+        let len = s.len();
+        if secret.len() < len {
+            return Err(Error::NoSpace);
+        }
+        secret[..len].copy_from_slice(s.as_slice());
+        Ok(len)
+    }
+
+    fn sign_msg(&self, msg: &[u8], signature: &mut [u8]) -> Result<usize, Error> {
+        if signature.len() < super::EC_SIGNATURE_LEN_BYTES {
+            return Err(Error::NoSpace);
+        }
+        // The SHA256 of the message is taken by the signer
+        let sig: Signature = SigningKey::from(self.private_key()?).sign(msg);
+        signature[..super::EC_SIGNATURE_LEN_BYTES].copy_from_slice(sig.to_bytes().as_slice());
+        Ok(super::EC_SIGNATURE_LEN_BYTES)
+    }
+
+    fn verify_msg(&self, msg: &[u8], signature: &[u8]) -> Result<(), Error> {
+        let sig = Signature::from_slice(signature).map_err(|_| Error::InvalidSignature)?;
+        VerifyingKey::from(&self.public_key())
+            .verify(msg, &sig)
+            .map_err(|_| Error::InvalidSignature)
+    }
+}
+
+pub fn pbkdf2_hmac(pass: &[u8], iter: usize, salt: &[u8], key: &mut [u8]) -> Result<(), Error> {
+    pbkdf2::pbkdf2_hmac::<sha2::Sha256>(pass, salt, iter as u32, key);
+    Ok(())
+}
+
+pub fn hkdf_sha256(salt: &[u8], ikm: &[u8], info: &[u8], key: &mut [u8]) -> Result<(), Error> {
+    Hkdf::<sha2::Sha256>::new(Some(salt), ikm)
+        .expand(info, key)
+        .map_err(|_e| Error::InvalidKeyLength)
+}
+
+pub fn encrypt_in_place(
+    key: &[u8],
+    nonce: &[u8],
+    ad: &[u8],
+    data: &mut [u8],
+    data_len: usize,
+) -> Result<usize, Error> {
+    if nonce.len() != super::AEAD_NONCE_LEN_BYTES {
+        error!("Invalid nonce length");
+        return Err(Error::Invalid);
+    }
+    if data.len() < data_len + super::AEAD_MIC_LEN_BYTES {
+        return Err(Error::NoSpace);
+    }
+    let cipher = AesCcm::new_from_slice(key).map_err(|_e| Error::InvalidKeyLength)?;
+    let (plain_text, tag) = data.split_at_mut(data_len);
+    let result = cipher
+        .encrypt_in_place_detached(GenericArray::from_slice(nonce), ad, plain_text)
+        .map_err(|_e| Error::Crypto)?;
+    tag[..super::AEAD_MIC_LEN_BYTES].copy_from_slice(result.as_slice());
+    Ok(data_len + super::AEAD_MIC_LEN_BYTES)
+}
+
+pub fn decrypt_in_place(
+    key: &[u8],
+    nonce: &[u8],
+    ad: &[u8],
+    data: &mut [u8],
+) -> Result<usize, Error> {
+    if nonce.len() != super::AEAD_NONCE_LEN_BYTES {
+        error!("Invalid nonce length");
+        return Err(Error::Invalid);
+    }
+    if data.len() < super::AEAD_MIC_LEN_BYTES {
+        return Err(Error::Invalid);
+    }
+    let cipher = AesCcm::new_from_slice(key).map_err(|_e| Error::InvalidKeyLength)?;
+    let tag_start = data.len() - super::AEAD_MIC_LEN_BYTES;
+    let (data, tag) = data.split_at_mut(tag_start);
+    cipher
+        .decrypt_in_place_detached(
+            GenericArray::from_slice(nonce),
+            ad,
+            data,
+            GenericArray::from_slice(tag),
+        )
+        .map_err(|_e| Error::Crypto)?;
+    Ok(tag_start)
+}
+
+#[derive(Clone)]
+pub struct Sha256 {
+    hasher: sha2::Sha256,
+}
+
+impl Sha256 {
+    pub fn new() -> Result<Self, Error> {
+        Ok(Self {
+            hasher: sha2::Sha256::new(),
+        })
+    }
+
+    pub fn update(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.hasher.update(data);
+        Ok(())
+    }
+
+    pub fn finish(self, data: &mut [u8]) -> Result<(), Error> {
+        let h = self.hasher.finalize();
+        data.copy_from_slice(h.as_slice());
+        Ok(())
+    }
+}
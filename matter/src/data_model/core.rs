@@ -19,6 +19,8 @@ use crate::{
         },
         InteractionConsumer, Transaction,
     },
+    limits::Limits,
+    secure_channel::pake::PaseMgr,
     tlv::{TLVArray, TLVWriter, TagType, ToTLV},
     transport::session::{Session, SessionMode},
 };
@@ -37,15 +39,41 @@ impl DataModel {
         dev_att: Box<dyn DevAttDataFetcher>,
         fabric_mgr: Arc<FabricMgr>,
         acl_mgr: Arc<AclMgr>,
+        pase_mgr: PaseMgr,
+    ) -> Result<Self, Error> {
+        DataModel::new_with_limits(
+            dev_details,
+            dev_att,
+            fabric_mgr,
+            acl_mgr,
+            pase_mgr,
+            &Limits::default(),
+        )
+    }
+
+    pub fn new_with_limits(
+        dev_details: BasicInfoConfig,
+        dev_att: Box<dyn DevAttDataFetcher>,
+        fabric_mgr: Arc<FabricMgr>,
+        acl_mgr: Arc<AclMgr>,
+        pase_mgr: PaseMgr,
+        limits: &Limits,
     ) -> Result<Self, Error> {
         let dm = DataModel {
-            node: Arc::new(RwLock::new(Node::new()?)),
+            node: Arc::new(RwLock::new(Node::new_with_limits(limits)?)),
             acl_mgr: acl_mgr.clone(),
         };
         {
             let mut node = dm.node.write()?;
             node.set_changes_cb(Box::new(dm.clone()));
-            device_type_add_root_node(&mut node, dev_details, dev_att, fabric_mgr, acl_mgr)?;
+            device_type_add_root_node(
+                &mut node,
+                dev_details,
+                dev_att,
+                fabric_mgr,
+                acl_mgr,
+                pase_mgr,
+            )?;
         }
         Ok(dm)
     }
@@ -201,6 +229,9 @@ impl DataModel {
             ),
             SessionMode::Pase => Accessor::new(0, 1, AuthMode::Pase, self.acl_mgr.clone()),
             SessionMode::PlainText => Accessor::new(0, 1, AuthMode::Invalid, self.acl_mgr.clone()),
+            SessionMode::Group(c, group_id) => {
+                Accessor::new(c, group_id as u64, AuthMode::Group, self.acl_mgr.clone())
+            }
         }
     }
 
@@ -13,7 +13,6 @@ use std::fmt::{self, Debug};
 
 use super::Encoder;
 
-pub const ATTRS_PER_CLUSTER: usize = 8;
 pub const CMDS_PER_CLUSTER: usize = 8;
 
 #[derive(FromPrimitive, Debug)]
@@ -70,6 +69,8 @@ pub trait ClusterType {
 pub struct Cluster {
     pub(super) id: u32,
     attributes: Vec<Attribute>,
+    // Unlimited until the cluster is added to an endpoint, see Limits::attrs_per_cluster
+    max_attrs: Option<usize>,
     feature_map: Option<u32>,
     data_ver: u32,
 }
@@ -78,7 +79,8 @@ impl Cluster {
     pub fn new(id: u32) -> Result<Cluster, Error> {
         let mut c = Cluster {
             id,
-            attributes: Vec::with_capacity(ATTRS_PER_CLUSTER),
+            attributes: Vec::new(),
+            max_attrs: None,
             feature_map: None,
             data_ver: rand::thread_rng().gen_range(0..0xFFFFFFFF),
         };
@@ -119,13 +121,35 @@ impl Cluster {
         )?)
     }
 
+    /// Limits the number of attributes that the cluster can have
+    ///
+    /// This fails if the cluster already has more attributes than that.
+    pub fn set_max_attrs(&mut self, max_attrs: usize) -> Result<(), Error> {
+        if self.attributes.len() > max_attrs {
+            error!(
+                "Cluster {:#x} has {} attributes, more than the limit of {}",
+                self.id,
+                self.attributes.len(),
+                max_attrs
+            );
+            return Err(Error::NoSpace);
+        }
+        self.max_attrs = Some(max_attrs);
+        Ok(())
+    }
+
     pub fn add_attribute(&mut self, attr: Attribute) -> Result<(), Error> {
-        if self.attributes.len() < self.attributes.capacity() {
-            self.attributes.push(attr);
-            Ok(())
-        } else {
-            Err(Error::NoSpace)
+        if let Some(max_attrs) = self.max_attrs {
+            if self.attributes.len() >= max_attrs {
+                error!(
+                    "Limit of {} attributes reached in cluster {:#x}",
+                    max_attrs, self.id
+                );
+                return Err(Error::NoSpace);
+            }
         }
+        self.attributes.push(attr);
+        Ok(())
     }
 
     fn get_attribute_index(&self, attr_id: u16) -> Option<usize> {
@@ -1,27 +1,38 @@
-use crate::{data_model::objects::ClusterType, error::*, interaction_model::core::IMStatusCode};
+use crate::{
+    data_model::objects::ClusterType, error::*, interaction_model::core::IMStatusCode,
+    limits::Limits,
+};
 
+use log::error;
 use std::fmt;
 
-pub const CLUSTERS_PER_ENDPT: usize = 7;
-
 pub struct Endpoint {
     clusters: Vec<Box<dyn ClusterType>>,
+    max_clusters: usize,
+    max_attrs: usize,
 }
 
 impl Endpoint {
     pub fn new() -> Result<Box<Endpoint>, Error> {
+        Endpoint::new_with_limits(&Limits::default())
+    }
+
+    pub fn new_with_limits(limits: &Limits) -> Result<Box<Endpoint>, Error> {
         Ok(Box::new(Endpoint {
-            clusters: Vec::with_capacity(CLUSTERS_PER_ENDPT),
+            clusters: Vec::new(),
+            max_clusters: limits.clusters_per_endpoint,
+            max_attrs: limits.attrs_per_cluster,
         }))
     }
 
-    pub fn add_cluster(&mut self, cluster: Box<dyn ClusterType>) -> Result<(), Error> {
-        if self.clusters.len() < self.clusters.capacity() {
-            self.clusters.push(cluster);
-            Ok(())
-        } else {
-            Err(Error::NoSpace)
+    pub fn add_cluster(&mut self, mut cluster: Box<dyn ClusterType>) -> Result<(), Error> {
+        if self.clusters.len() >= self.max_clusters {
+            error!("Limit of {} clusters reached", self.max_clusters);
+            return Err(Error::NoSpace);
         }
+        cluster.base_mut().set_max_attrs(self.max_attrs)?;
+        self.clusters.push(cluster);
+        Ok(())
     }
 
     fn get_cluster_index(&self, cluster_id: u32) -> Option<usize> {
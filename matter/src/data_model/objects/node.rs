@@ -2,20 +2,20 @@ use crate::{
     data_model::objects::{ClusterType, Endpoint},
     error::*,
     interaction_model::{core::IMStatusCode, messages::GenericPath},
+    limits::Limits,
     // TODO: This layer shouldn't really depend on the TLV layer, should create an abstraction layer
 };
+use log::error;
 use std::fmt;
 
 pub trait ChangeConsumer {
     fn endpoint_added(&self, id: u16, endpoint: &mut Endpoint) -> Result<(), Error>;
 }
 
-pub const ENDPTS_PER_ACC: usize = 3;
-
-#[derive(Default)]
 pub struct Node {
-    endpoints: [Option<Box<Endpoint>>; ENDPTS_PER_ACC],
+    endpoints: Vec<Option<Box<Endpoint>>>,
     changes_cb: Option<Box<dyn ChangeConsumer>>,
+    limits: Limits,
 }
 
 impl std::fmt::Display for Node {
@@ -32,7 +32,15 @@ impl std::fmt::Display for Node {
 
 impl Node {
     pub fn new() -> Result<Box<Node>, Error> {
-        let node = Box::new(Node::default());
+        Node::new_with_limits(&Limits::default())
+    }
+
+    pub fn new_with_limits(limits: &Limits) -> Result<Box<Node>, Error> {
+        let node = Box::new(Node {
+            endpoints: (0..limits.endpoints).map(|_| None).collect(),
+            changes_cb: None,
+            limits: *limits,
+        });
         Ok(node)
     }
 
@@ -45,8 +53,11 @@ impl Node {
             .endpoints
             .iter()
             .position(|x| x.is_none())
-            .ok_or(Error::NoSpace)?;
-        let mut endpoint = Endpoint::new()?;
+            .ok_or_else(|| {
+                error!("Limit of {} endpoints reached", self.endpoints.len());
+                Error::NoSpace
+            })?;
+        let mut endpoint = Endpoint::new_with_limits(&self.limits)?;
         if let Some(cb) = &self.changes_cb {
             cb.endpoint_added(index as u16, &mut endpoint)?;
         }
@@ -55,7 +66,7 @@ impl Node {
     }
 
     pub fn get_endpoint(&self, endpoint_id: u16) -> Result<&Endpoint, Error> {
-        if (endpoint_id as usize) < ENDPTS_PER_ACC {
+        if (endpoint_id as usize) < self.endpoints.len() {
             let endpoint = self.endpoints[endpoint_id as usize]
                 .as_ref()
                 .ok_or(Error::EndpointNotFound)?;
@@ -66,7 +77,7 @@ impl Node {
     }
 
     pub fn get_endpoint_mut(&mut self, endpoint_id: u16) -> Result<&mut Endpoint, Error> {
-        if (endpoint_id as usize) < ENDPTS_PER_ACC {
+        if (endpoint_id as usize) < self.endpoints.len() {
             let endpoint = self.endpoints[endpoint_id as usize]
                 .as_mut()
                 .ok_or(Error::EndpointNotFound)?;
@@ -90,7 +101,7 @@ impl Node {
         cluster: Box<dyn ClusterType>,
     ) -> Result<(), Error> {
         let endpoint_id = endpoint_id as usize;
-        if endpoint_id < ENDPTS_PER_ACC {
+        if endpoint_id < self.endpoints.len() {
             self.endpoints[endpoint_id]
                 .as_mut()
                 .ok_or(Error::NoEndpoint)?
@@ -271,3 +282,69 @@ impl Node {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        data_model::objects::{Access, AttrValue, Attribute, Cluster, ClusterType, Quality},
+        error::Error,
+        limits::Limits,
+    };
+
+    use super::Node;
+
+    struct TestCluster {
+        base: Cluster,
+    }
+
+    impl ClusterType for TestCluster {
+        fn base(&self) -> &Cluster {
+            &self.base
+        }
+        fn base_mut(&mut self) -> &mut Cluster {
+            &mut self.base
+        }
+    }
+
+    fn cluster(id: u32, attrs: u16) -> Box<TestCluster> {
+        let mut base = Cluster::new(id).unwrap();
+        for attr_id in 0..attrs {
+            let attr = Attribute::new(attr_id, AttrValue::Uint8(0), Access::RV, Quality::NONE);
+            base.add_attribute(attr.unwrap()).unwrap();
+        }
+        Box::new(TestCluster { base })
+    }
+
+    #[test]
+    fn test_limits() {
+        let limits = Limits {
+            endpoints: 10,
+            clusters_per_endpoint: 2,
+            attrs_per_cluster: 4,
+            ..Default::default()
+        };
+        let mut node = Node::new_with_limits(&limits).unwrap();
+        for id in 0..10 {
+            assert_eq!(node.add_endpoint(), Ok(id));
+        }
+        assert_eq!(node.add_endpoint(), Err(Error::NoSpace));
+
+        // The attribute list takes one of the attributes
+        node.add_cluster(9, cluster(1, 3)).unwrap();
+        assert_eq!(node.add_cluster(9, cluster(2, 4)), Err(Error::NoSpace));
+        node.add_cluster(9, cluster(2, 0)).unwrap();
+        assert_eq!(node.add_cluster(9, cluster(3, 0)), Err(Error::NoSpace));
+
+        let c = node.get_cluster_mut(9, 2).unwrap();
+        for attr_id in 0..3 {
+            let attr = Attribute::new(attr_id, AttrValue::Uint8(0), Access::RV, Quality::NONE);
+            c.base_mut().add_attribute(attr.unwrap()).unwrap();
+        }
+        let attr = Attribute::new(3, AttrValue::Uint8(0), Access::RV, Quality::NONE);
+        assert_eq!(
+            c.base_mut().add_attribute(attr.unwrap()),
+            Err(Error::NoSpace)
+        );
+        assert_eq!(node.add_cluster(10, cluster(1, 0)), Err(Error::Invalid));
+    }
+}
@@ -0,0 +1,283 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::cmd_enter;
+use crate::data_model::objects::*;
+use crate::data_model::sdm::failsafe::FailSafe;
+use crate::error::*;
+use crate::fabric::FabricMgr;
+use crate::interaction_model::command::CommandReq;
+use crate::interaction_model::core::IMStatusCode;
+use crate::interaction_model::messages::ib;
+use crate::secure_channel::pake::{PaseMgr, WindowAdmin, WindowMode, PAKE};
+use crate::secure_channel::spake2p::VerifierData;
+use crate::tlv::{FromTLV, OctetStr, TLVElement, TagType, ToTLV};
+use crate::transport::session::Session;
+use log::{error, info};
+use num_derive::FromPrimitive;
+
+// Administrator Commissioning Cluster
+
+pub const ID: u32 = 0x003C;
+
+// The commissioning timeouts that the administrators may ask for, in seconds
+const MIN_COMM_TIMEOUT: u16 = 180;
+const MAX_COMM_TIMEOUT: u16 = 900;
+const MAX_DISCRIMINATOR: u16 = 0xFFF;
+
+#[derive(Clone, Copy)]
+enum AdminCommStatus {
+    Busy = 2,
+    PakeParameterError = 3,
+    WindowNotOpen = 4,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WindowStatus {
+    WindowNotOpen = 0,
+    EnhancedWindowOpen = 1,
+    BasicWindowOpen = 2,
+}
+
+#[derive(FromPrimitive)]
+pub enum Attributes {
+    WindowStatus = 0,
+    AdminFabricIndex = 1,
+    AdminVendorId = 2,
+}
+
+#[derive(FromPrimitive)]
+pub enum Commands {
+    OpenCommWindow = 0x00,
+    OpenBasicCommWindow = 0x01,
+    RevokeComm = 0x02,
+}
+
+fn attr_window_status_new() -> Result<Attribute, Error> {
+    Attribute::new(
+        Attributes::WindowStatus as u16,
+        AttrValue::Custom,
+        Access::RV,
+        Quality::NONE,
+    )
+}
+
+fn attr_admin_fabric_index_new() -> Result<Attribute, Error> {
+    Attribute::new(
+        Attributes::AdminFabricIndex as u16,
+        AttrValue::Custom,
+        Access::RV,
+        Quality::NULLABLE,
+    )
+}
+
+fn attr_admin_vendor_id_new() -> Result<Attribute, Error> {
+    Attribute::new(
+        Attributes::AdminVendorId as u16,
+        AttrValue::Custom,
+        Access::RV,
+        Quality::NULLABLE,
+    )
+}
+
+pub struct AdminCommCluster {
+    pase_mgr: PaseMgr,
+    fabric_mgr: Arc<FabricMgr>,
+    failsafe: Arc<FailSafe>,
+    base: Cluster,
+}
+
+impl ClusterType for AdminCommCluster {
+    fn base(&self) -> &Cluster {
+        &self.base
+    }
+    fn base_mut(&mut self) -> &mut Cluster {
+        &mut self.base
+    }
+
+    fn read_custom_attribute(&self, encoder: &mut dyn Encoder, attr: &AttrDetails) {
+        match num::FromPrimitive::from_u16(attr.attr_id) {
+            Some(Attributes::WindowStatus) => {
+                let status = self.window_status() as u8;
+                encoder.encode(EncodeValue::Value(&status))
+            }
+            Some(Attributes::AdminFabricIndex) => {
+                let admin = self.pase_mgr.get_window_admin();
+                encoder.encode(EncodeValue::Closure(&|tag, tw| {
+                    let _ = match admin {
+                        Some(a) => tw.u8(tag, a.fabric_idx),
+                        None => tw.null(tag),
+                    };
+                }))
+            }
+            Some(Attributes::AdminVendorId) => {
+                let admin = self.pase_mgr.get_window_admin();
+                encoder.encode(EncodeValue::Closure(&|tag, tw| {
+                    let _ = match admin {
+                        Some(a) => tw.u16(tag, a.vendor_id),
+                        None => tw.null(tag),
+                    };
+                }))
+            }
+            _ => {
+                error!("Unsupported Attribute: this shouldn't happen");
+            }
+        }
+    }
+
+    fn handle_command(&mut self, cmd_req: &mut CommandReq) -> Result<(), IMStatusCode> {
+        let cmd = cmd_req
+            .cmd
+            .path
+            .leaf
+            .map(num::FromPrimitive::from_u32)
+            .ok_or(IMStatusCode::UnsupportedCommand)?
+            .ok_or(IMStatusCode::UnsupportedCommand)?;
+        match cmd {
+            Commands::OpenCommWindow => self.handle_command_opencomm_win(cmd_req),
+            Commands::OpenBasicCommWindow => self.handle_command_openbasiccomm_win(cmd_req),
+            Commands::RevokeComm => self.handle_command_revokecomm(cmd_req),
+        }
+    }
+}
+
+impl AdminCommCluster {
+    pub fn new(
+        pase_mgr: PaseMgr,
+        fabric_mgr: Arc<FabricMgr>,
+        failsafe: Arc<FailSafe>,
+    ) -> Result<Box<Self>, Error> {
+        let mut c = Box::new(AdminCommCluster {
+            pase_mgr,
+            fabric_mgr,
+            failsafe,
+            base: Cluster::new(ID)?,
+        });
+        c.base.add_attribute(attr_window_status_new()?)?;
+        c.base.add_attribute(attr_admin_fabric_index_new()?)?;
+        c.base.add_attribute(attr_admin_vendor_id_new()?)?;
+        Ok(c)
+    }
+
+    fn window_status(&self) -> WindowStatus {
+        match self.pase_mgr.get_window_mode() {
+            None => WindowStatus::WindowNotOpen,
+            Some(WindowMode::Basic) => WindowStatus::BasicWindowOpen,
+            Some(WindowMode::Enhanced(_)) => WindowStatus::EnhancedWindowOpen,
+        }
+    }
+
+    // A new window can't be opened while a window is open, or while a commissioning
+    // is in progress
+    fn is_busy(&self) -> bool {
+        self.pase_mgr.get_window_mode().is_some() || self.failsafe.is_armed()
+    }
+
+    fn get_admin(&self, session: &Session) -> Option<WindowAdmin> {
+        let fabric_idx = session.get_local_fabric_idx()?;
+        let fabric = self.fabric_mgr.get_fabric(fabric_idx as usize).ok()?;
+        let vendor_id = (*fabric).as_ref()?.get_vendor_id();
+        Some(WindowAdmin {
+            fabric_idx,
+            vendor_id,
+        })
+    }
+
+    fn get_timeout(timeout: u16) -> Result<Duration, IMStatusCode> {
+        if !(MIN_COMM_TIMEOUT..=MAX_COMM_TIMEOUT).contains(&timeout) {
+            error!("Invalid commissioning timeout {}", timeout);
+            return Err(IMStatusCode::InvalidCommand);
+        }
+        Ok(Duration::from_secs(timeout as u64))
+    }
+
+    fn cluster_status(
+        cmd_req: &mut CommandReq,
+        status: AdminCommStatus,
+    ) -> Result<(), IMStatusCode> {
+        let resp = ib::InvResp::status_new(cmd_req.cmd, IMStatusCode::Failure, status as u16);
+        let _ = resp.to_tlv(cmd_req.resp, TagType::Anonymous);
+        cmd_req.trans.complete();
+        Ok(())
+    }
+
+    fn handle_command_opencomm_win(
+        &mut self,
+        cmd_req: &mut CommandReq,
+    ) -> Result<(), IMStatusCode> {
+        cmd_enter!("Open Commissioning Window");
+        let req =
+            OpenCommWindowReq::from_tlv(&cmd_req.data).map_err(|_| IMStatusCode::InvalidCommand)?;
+        let timeout = Self::get_timeout(req.timeout)?;
+        if req.discriminator > MAX_DISCRIMINATOR {
+            error!("Invalid discriminator {}", req.discriminator);
+            return Err(IMStatusCode::InvalidCommand);
+        }
+        if self.is_busy() {
+            return Self::cluster_status(cmd_req, AdminCommStatus::Busy);
+        }
+        let verifier = match VerifierData::new(req.verifier.0, req.salt.0, req.iterations) {
+            Ok(verifier) => verifier,
+            Err(_) => return Self::cluster_status(cmd_req, AdminCommStatus::PakeParameterError),
+        };
+
+        let admin = self.get_admin(cmd_req.trans.session);
+        self.pase_mgr
+            .open_enhanced_comm_window(PAKE::new(verifier), req.discriminator, timeout, admin)
+            .map_err(|_| IMStatusCode::Failure)?;
+        cmd_req.trans.complete();
+        Err(IMStatusCode::Sucess)
+    }
+
+    fn handle_command_openbasiccomm_win(
+        &mut self,
+        cmd_req: &mut CommandReq,
+    ) -> Result<(), IMStatusCode> {
+        cmd_enter!("Open Basic Commissioning Window");
+        let req = OpenBasicCommWindowReq::from_tlv(&cmd_req.data)
+            .map_err(|_| IMStatusCode::InvalidCommand)?;
+        let timeout = Self::get_timeout(req.timeout)?;
+        if self.is_busy() {
+            return Self::cluster_status(cmd_req, AdminCommStatus::Busy);
+        }
+
+        let admin = self.get_admin(cmd_req.trans.session);
+        self.pase_mgr
+            .open_basic_comm_window(Some(timeout), admin)
+            .map_err(|_| IMStatusCode::Failure)?;
+        cmd_req.trans.complete();
+        Err(IMStatusCode::Sucess)
+    }
+
+    fn handle_command_revokecomm(&mut self, cmd_req: &mut CommandReq) -> Result<(), IMStatusCode> {
+        cmd_enter!("Revoke Commissioning");
+        // The commissioning in progress is revoked along with the window
+        if self.failsafe.is_armed() {
+            info!("Expiring the fail-safe");
+            self.failsafe.expire().map_err(|_| IMStatusCode::Failure)?;
+        }
+        self.pase_mgr.abort_pase();
+        if self.pase_mgr.get_window_mode().is_none() {
+            return Self::cluster_status(cmd_req, AdminCommStatus::WindowNotOpen);
+        }
+        info!("Closing the commissioning window");
+        self.pase_mgr.close_comm_window();
+        cmd_req.trans.complete();
+        Err(IMStatusCode::Sucess)
+    }
+}
+
+#[derive(FromTLV)]
+#[tlvargs(lifetime = "'a")]
+struct OpenCommWindowReq<'a> {
+    timeout: u16,
+    verifier: OctetStr<'a>,
+    discriminator: u16,
+    iterations: u32,
+    salt: OctetStr<'a>,
+}
+
+#[derive(FromTLV)]
+struct OpenBasicCommWindowReq {
+    timeout: u16,
+}
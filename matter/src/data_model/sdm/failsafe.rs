@@ -85,6 +85,13 @@ impl FailSafe {
         Ok(())
     }
 
+    /// Expires the fail-safe, as if its timer had run out
+    pub fn expire(&self) -> Result<(), Error> {
+        let mut inner = self.state.write()?;
+        inner.state = State::Idle;
+        Ok(())
+    }
+
     pub fn is_armed(&self) -> bool {
         self.state.read().unwrap().state != State::Idle
     }
@@ -1,3 +1,4 @@
+pub mod admin_commissioning;
 pub mod dev_att;
 pub mod failsafe;
 pub mod general_commissioning;
@@ -36,7 +36,9 @@ impl AccessControlCluster {
         c.base.add_attribute(attr_extension_new()?)?;
         c.base.add_attribute(attr_subjects_per_entry_new()?)?;
         c.base.add_attribute(attr_targets_per_entry_new()?)?;
-        c.base.add_attribute(attr_entries_per_fabric_new()?)?;
+        let entries_per_fabric = c.acl_mgr.entries_per_fabric();
+        c.base
+            .add_attribute(attr_entries_per_fabric_new(entries_per_fabric)?)?;
         Ok(c)
     }
 
@@ -163,10 +165,10 @@ fn attr_targets_per_entry_new() -> Result<Attribute, Error> {
     )
 }
 
-fn attr_entries_per_fabric_new() -> Result<Attribute, Error> {
+fn attr_entries_per_fabric_new(entries_per_fabric: usize) -> Result<Attribute, Error> {
     Attribute::new(
         Attributes::EntriesPerFabric as u16,
-        AttrValue::Uint16(acl::ENTRIES_PER_FABRIC as u16),
+        AttrValue::Uint16(entries_per_fabric as u16),
         Access::RV,
         Quality::FIXED,
     )
@@ -1,6 +1,6 @@
 use std::{array::TryFromSliceError, fmt, sync::PoisonError, time::SystemTimeError};
 
-use async_channel::{SendError, TryRecvError};
+use async_channel::{RecvError, SendError, TryRecvError, TrySendError};
 use log::error;
 
 #[derive(Debug, PartialEq, Clone, Copy)]
@@ -30,11 +30,13 @@ pub enum Error {
     PacketPoolExhaust,
     StdIoError,
     SysTimeFail,
+    Timeout,
     Invalid,
     InvalidAAD,
     InvalidData,
     InvalidKeyLength,
     InvalidOpcode,
+    InvalidParameter,
     InvalidPeerAddr,
     // Invalid Auth Key in the Matter Certificate
     InvalidAuthKey,
@@ -75,6 +77,14 @@ impl From<mbedtls::Error> for Error {
     }
 }
 
+#[cfg(feature = "crypto_rustcrypto")]
+impl From<p256::elliptic_curve::Error> for Error {
+    fn from(e: p256::elliptic_curve::Error) -> Self {
+        error!("Error in crypto: {}", e);
+        Self::Crypto
+    }
+}
+
 impl From<SystemTimeError> for Error {
     fn from(_e: SystemTimeError) -> Self {
         Self::SysTimeFail
@@ -94,6 +104,20 @@ impl<T> From<SendError<T>> for Error {
     }
 }
 
+impl<T> From<TrySendError<T>> for Error {
+    fn from(e: TrySendError<T>) -> Self {
+        error!("Error in channel try_send {}", e);
+        Self::Invalid
+    }
+}
+
+impl From<RecvError> for Error {
+    fn from(e: RecvError) -> Self {
+        error!("Error in channel recv {}", e);
+        Self::Invalid
+    }
+}
+
 impl From<TryRecvError> for Error {
     fn from(e: TryRecvError) -> Self {
         error!("Error in channel try_recv {}", e);
@@ -1,7 +1,7 @@
 use std::sync::{Arc, Mutex, MutexGuard, RwLock};
 
 use byteorder::{BigEndian, ByteOrder, LittleEndian};
-use log::info;
+use log::{error, info};
 use owning_ref::RwLockReadGuardRef;
 
 use crate::{
@@ -9,6 +9,7 @@ use crate::{
     crypto::{self, crypto_dummy::KeyPairDummy, hkdf_sha256, CryptoKeyPair, HmacSha256, KeyPair},
     error::Error,
     group_keys::KeySet,
+    limits::Limits,
     mdns::{self, Mdns},
     sys::{Psm, SysMdnsService},
 };
@@ -28,6 +29,7 @@ const ST_NOC: &str = "noc";
 const ST_IPK: &str = "ipk";
 const ST_PBKEY: &str = "pubkey";
 const ST_PRKEY: &str = "privkey";
+const ST_VID: &str = "vid";
 
 #[allow(dead_code)]
 pub struct Fabric {
@@ -38,6 +40,7 @@ pub struct Fabric {
     pub icac: Cert,
     pub noc: Cert,
     pub ipk: KeySet,
+    vendor_id: u16,
     compressed_id: [u8; COMPRESSED_FABRIC_ID_LEN],
     mdns_service: Option<SysMdnsService>,
 }
@@ -49,6 +52,7 @@ impl Fabric {
         icac: Cert,
         noc: Cert,
         ipk: &[u8],
+        vendor_id: u16,
     ) -> Result<Self, Error> {
         let node_id = noc.get_node_id()?;
         let fabric_id = noc.get_fabric_id()?;
@@ -61,6 +65,7 @@ impl Fabric {
             icac,
             noc,
             ipk: KeySet::default(),
+            vendor_id,
             compressed_id: [0; COMPRESSED_FABRIC_ID_LEN],
             mdns_service: None,
         };
@@ -93,6 +98,7 @@ impl Fabric {
             icac: Cert::default(),
             noc: Cert::default(),
             ipk: KeySet::default(),
+            vendor_id: 0,
             compressed_id: [0; COMPRESSED_FABRIC_ID_LEN],
             mdns_service: None,
         })
@@ -110,7 +116,9 @@ impl Fabric {
             .map_err(|_| Error::NoSpace)
     }
 
-    pub fn match_dest_id(&self, random: &[u8], target: &[u8]) -> Result<(), Error> {
+    /// The destination ID, that a CASE initiator identifies the node `node_id` of
+    /// this fabric with
+    pub fn get_dest_id(&self, random: &[u8], node_id: u64, out: &mut [u8]) -> Result<(), Error> {
         let mut mac = HmacSha256::new(self.ipk.op_key())?;
 
         mac.update(random)?;
@@ -120,11 +128,15 @@ impl Fabric {
         LittleEndian::write_u64(&mut buf, self.fabric_id);
         mac.update(&buf)?;
 
-        LittleEndian::write_u64(&mut buf, self.node_id);
+        LittleEndian::write_u64(&mut buf, node_id);
         mac.update(&buf)?;
 
+        mac.finish(out)
+    }
+
+    pub fn match_dest_id(&self, random: &[u8], target: &[u8]) -> Result<(), Error> {
         let mut id = [0_u8; crypto::SHA256_HASH_LEN_BYTES];
-        mac.finish(&mut id)?;
+        self.get_dest_id(random, self.node_id, &mut id)?;
         if id.as_slice() == target {
             Ok(())
         } else {
@@ -144,6 +156,11 @@ impl Fabric {
         self.fabric_id
     }
 
+    /// The vendor of the administrator that added this fabric
+    pub fn get_vendor_id(&self) -> u16 {
+        self.vendor_id
+    }
+
     fn store(&self, index: usize, psm: &MutexGuard<Psm>) -> Result<(), Error> {
         let mut key = [0u8; MAX_CERT_TLV_LEN];
         let len = self.root_ca.as_tlv(&mut key)?;
@@ -163,6 +180,7 @@ impl Fabric {
         let len = self.key_pair.get_private_key(&mut key)?;
         let key = &key[..len];
         psm.set_kv_slice(fb_key!(index, ST_PRKEY), key)?;
+        psm.set_kv_u64(fb_key!(index, ST_VID), self.vendor_id as u64)?;
 
         Ok(())
     }
@@ -189,16 +207,25 @@ impl Fabric {
         psm.get_kv_slice(fb_key!(index, ST_PRKEY), &mut priv_key)?;
         let keypair = KeyPair::new_from_components(pub_key.as_slice(), priv_key.as_slice())?;
 
-        Fabric::new(keypair, root_ca, icac, noc, ipk.as_slice())
+        // The fabrics that were stored before the vendor was, don't have one
+        let mut vendor_id = 0;
+        let _ = psm.get_kv_u64(fb_key!(index, ST_VID), &mut vendor_id);
+
+        Fabric::new(
+            keypair,
+            root_ca,
+            icac,
+            noc,
+            ipk.as_slice(),
+            vendor_id as u16,
+        )
     }
 }
 
-pub const MAX_SUPPORTED_FABRICS: usize = 3;
-#[derive(Default)]
 pub struct FabricMgrInner {
     // The outside world expects Fabric Index to be one more than the actual one
     // since 0 is not allowed. Need to handle this cleanly somehow
-    pub fabrics: [Option<Fabric>; MAX_SUPPORTED_FABRICS],
+    pub fabrics: Vec<Option<Fabric>>,
 }
 
 pub struct FabricMgr {
@@ -208,8 +235,15 @@ pub struct FabricMgr {
 
 impl FabricMgr {
     pub fn new() -> Result<Self, Error> {
+        FabricMgr::new_with_limits(&Limits::default())
+    }
+
+    pub fn new_with_limits(limits: &Limits) -> Result<Self, Error> {
+        limits.validate()?;
         let dummy_fabric = Fabric::dummy()?;
-        let mut mgr = FabricMgrInner::default();
+        let mut mgr = FabricMgrInner {
+            fabrics: (0..limits.fabrics).map(|_| None).collect(),
+        };
         mgr.fabrics[0] = Some(dummy_fabric);
         let mut fm = Self {
             inner: RwLock::new(mgr),
@@ -219,6 +253,19 @@ impl FabricMgr {
         Ok(fm)
     }
 
+    /// A manager of `fabrics`, that neither loads nor stores any
+    #[cfg(test)]
+    pub fn new_volatile(fabrics: Vec<Fabric>) -> Result<Self, Error> {
+        let mut mgr = FabricMgrInner {
+            fabrics: vec![Some(Fabric::dummy()?)],
+        };
+        mgr.fabrics.extend(fabrics.into_iter().map(Some));
+        Ok(Self {
+            inner: RwLock::new(mgr),
+            psm: Psm::get()?,
+        })
+    }
+
     fn store(&self, index: usize, fabric: &Fabric) -> Result<(), Error> {
         let psm = self.psm.lock().unwrap();
         fabric.store(index, &psm)
@@ -227,7 +274,7 @@ impl FabricMgr {
     fn load(&mut self) -> Result<(), Error> {
         let mut mgr = self.inner.write()?;
         let psm = self.psm.lock().unwrap();
-        for i in 0..MAX_SUPPORTED_FABRICS {
+        for i in 0..mgr.fabrics.len() {
             let result = Fabric::load(i, &psm);
             if let Ok(fabric) = result {
                 info!("Adding new fabric at index {}", i);
@@ -243,7 +290,10 @@ impl FabricMgr {
             .fabrics
             .iter()
             .position(|f| f.is_none())
-            .ok_or(Error::NoSpace)?;
+            .ok_or_else(|| {
+                error!("Limit of {} fabrics reached", mgr.fabrics.len() - 1);
+                Error::NoSpace
+            })?;
 
         self.store(index, &f)?;
 
@@ -253,8 +303,8 @@ impl FabricMgr {
 
     pub fn match_dest_id(&self, random: &[u8], target: &[u8]) -> Result<usize, Error> {
         let mgr = self.inner.read()?;
-        for i in 0..MAX_SUPPORTED_FABRICS {
-            if let Some(fabric) = &mgr.fabrics[i] {
+        for (i, fabric) in mgr.fabrics.iter().enumerate() {
+            if let Some(fabric) = fabric {
                 if fabric.match_dest_id(random, target).is_ok() {
                     return Ok(i);
                 }
@@ -267,16 +317,34 @@ impl FabricMgr {
         &'me self,
         idx: usize,
     ) -> Result<RwLockReadGuardRef<'ret, FabricMgrInner, Option<Fabric>>, Error> {
-        Ok(RwLockReadGuardRef::new(self.inner.read()?).map(|fm| &fm.fabrics[idx]))
+        let mgr = self.inner.read()?;
+        if idx >= mgr.fabrics.len() {
+            return Err(Error::NotFound);
+        }
+        Ok(RwLockReadGuardRef::new(mgr).map(|fm| &fm.fabrics[idx]))
     }
 
     pub fn is_empty(&self) -> bool {
         let mgr = self.inner.read().unwrap();
-        for i in 1..MAX_SUPPORTED_FABRICS {
-            if mgr.fabrics[i].is_some() {
-                return false;
-            }
-        }
-        true
+        mgr.fabrics.iter().skip(1).all(|f| f.is_none())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{error::Error, limits::Limits};
+
+    use super::FabricMgr;
+
+    #[test]
+    fn test_invalid_limits() {
+        let limits = Limits {
+            fabrics: 0,
+            ..Default::default()
+        };
+        assert!(matches!(
+            FabricMgr::new_with_limits(&limits),
+            Err(Error::Invalid)
+        ));
     }
 }
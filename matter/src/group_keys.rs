@@ -1,16 +1,35 @@
 use std::sync::{Arc, Mutex, Once};
 
+use byteorder::{BigEndian, ByteOrder};
+
 use crate::{crypto, error::Error};
 
-// This is just makeshift implementation for now, not used anywhere
-pub struct GroupKeys {}
+const MAX_GROUP_KEYS: usize = 8;
+
+/// The operational key of a group on a fabric
+#[derive(Debug, Clone, PartialEq)]
+pub struct GroupKey {
+    pub fab_idx: u8,
+    pub group_id: u16,
+    pub op_key: [u8; crypto::SYMM_KEY_LEN_BYTES],
+    /// The session id that the messages encrypted with this key carry
+    pub sess_id: u16,
+}
+
+/// The keys of all the groups that we are a member of
+///
+/// A group can have more than one key, while the keys are being rotated. The
+/// latest key is used for sending.
+pub struct GroupKeys {
+    keys: Vec<GroupKey>,
+}
 
 static mut G_GRP_KEYS: Option<Arc<Mutex<GroupKeys>>> = None;
 static INIT: Once = Once::new();
 
 impl GroupKeys {
     fn new() -> Self {
-        Self {}
+        Self { keys: Vec::new() }
     }
 
     pub fn get() -> Result<Arc<Mutex<Self>>, Error> {
@@ -22,9 +41,51 @@ impl GroupKeys {
         }
     }
 
-    pub fn insert_key() -> Result<(), Error> {
+    pub fn insert_key(
+        &mut self,
+        fab_idx: u8,
+        group_id: u16,
+        key_set: &KeySet,
+    ) -> Result<(), Error> {
+        let key = GroupKey {
+            fab_idx,
+            group_id,
+            op_key: key_set.op_key,
+            sess_id: key_set.group_session_id()?,
+        };
+        if let Some(index) = self.keys.iter().position(|k| *k == key) {
+            self.keys.remove(index);
+        } else if self.keys.len() >= MAX_GROUP_KEYS {
+            return Err(Error::NoSpace);
+        }
+        self.keys.push(key);
         Ok(())
     }
+
+    /// Removes all the keys of the group
+    pub fn remove_keys(&mut self, fab_idx: u8, group_id: u16) {
+        self.keys
+            .retain(|k| k.fab_idx != fab_idx || k.group_id != group_id);
+    }
+
+    /// Returns the key to send messages to the group with
+    pub fn get_key(&self, fab_idx: u8, group_id: u16) -> Option<GroupKey> {
+        self.keys
+            .iter()
+            .rev()
+            .find(|k| k.fab_idx == fab_idx && k.group_id == group_id)
+            .cloned()
+    }
+
+    /// Returns the keys that a message for the group, with the session id, could have
+    /// been encrypted with
+    pub fn get_candidates(&self, sess_id: u16, group_id: u16) -> Vec<GroupKey> {
+        self.keys
+            .iter()
+            .filter(|k| k.sess_id == sess_id && k.group_id == group_id)
+            .cloned()
+            .collect()
+    }
 }
 
 #[derive(Debug, Default)]
@@ -49,6 +110,18 @@ impl KeySet {
         crypto::hkdf_sha256(compressed_id, ipk, &GRP_KEY_INFO, opkey).map_err(|_| Error::NoSpace)
     }
 
+    /// The session id for the messages that are encrypted with the operational key
+    pub fn group_session_id(&self) -> Result<u16, Error> {
+        const GRP_KEY_HASH_INFO: [u8; 12] = [
+            0x47, 0x72, 0x6f, 0x75, 0x70, 0x4b, 0x65, 0x79, 0x48, 0x61, 0x73, 0x68,
+        ];
+
+        let mut hash = [0u8; 2];
+        crypto::hkdf_sha256(&[], &self.op_key, &GRP_KEY_HASH_INFO, &mut hash)
+            .map_err(|_| Error::NoSpace)?;
+        Ok(BigEndian::read_u16(&hash))
+    }
+
     pub fn op_key(&self) -> &[u8] {
         &self.op_key
     }
@@ -10,6 +10,8 @@
 //! use matter::{Matter, CommissioningData};
 //! use matter::data_model::device_types::device_type_add_on_off_light;
 //! use matter::data_model::cluster_basic_information::BasicInfoConfig;
+//! use matter::secure_channel::spake2p::VerifierData;
+//! use matter::transport::mgr::TransportConfig;
 //! use rand::prelude::*;
 //!
 //! # use matter::data_model::sdm::dev_att::{DataType, DevAttDataFetcher};
@@ -21,12 +23,12 @@
 //! # let dev_att = Box::new(DevAtt{});
 //!
 //! /// The commissioning data for this device
-//! let mut comm_data = CommissioningData {
-//!     passwd: 123456,
+//! let mut salt = [0; 16];
+//! rand::thread_rng().fill_bytes(&mut salt);
+//! let comm_data = CommissioningData {
+//!     verifier: VerifierData::new_with_pw(123456, &salt, 1000).unwrap(),
 //!     discriminator: 250,
-//!     ..Default::default()
 //! };
-//! rand::thread_rng().fill_bytes(&mut comm_data.salt);
 //!
 //! /// The basic information about this device
 //! let dev_info = BasicInfoConfig {
@@ -36,9 +38,15 @@
 //!     sw_ver: 1,
 //! };
 //!
+//! /// Listen on any free port, on all the interfaces
+//! let transport_config = TransportConfig {
+//!     port: 0,
+//!     ..Default::default()
+//! };
+//!
 //! /// Get the Matter Object
 //! /// The dev_att is an object that implements the DevAttDataFetcher trait.
-//! let mut matter = Matter::new(dev_info, dev_att, comm_data).unwrap();
+//! let mut matter = Matter::new(dev_info, dev_att, comm_data, transport_config).unwrap();
 //! let dm = matter.get_data_model();
 //! {
 //!     let mut node = dm.node.write().unwrap();
@@ -51,6 +59,7 @@
 //! Start off exploring by going to the [Matter] object.
 
 pub mod acl;
+pub mod bdx;
 pub mod cert;
 pub mod core;
 pub mod crypto;
@@ -59,11 +68,13 @@ pub mod error;
 pub mod fabric;
 pub mod group_keys;
 pub mod interaction_model;
+pub mod limits;
 pub mod mdns;
 pub mod secure_channel;
 pub mod sys;
 pub mod tlv;
 pub mod transport;
+pub mod udc;
 pub mod utils;
 
 pub use crate::core::*;
@@ -0,0 +1,118 @@
+use log::error;
+
+use crate::{error::Error, sys::MAX_PACKET_POOL_SIZE};
+
+/// The capacities of the tables that the stack keeps
+///
+/// The defaults are sized for a simple device. A bridge would want many more
+/// endpoints, while a constrained build could do with smaller tables. Once a table
+/// is full, adding to it fails with Error::NoSpace.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limits {
+    /// The sessions with peers, the least recently used one is evicted to make room
+    pub sessions: usize,
+    /// The exchanges that can be open at a time, across all the sessions
+    pub exchanges: usize,
+    /// The endpoints of the node, including the root endpoint
+    pub endpoints: usize,
+    pub clusters_per_endpoint: usize,
+    pub attrs_per_cluster: usize,
+    /// The size of the fabric table, the index 0 isn't used for a real fabric, so
+    /// this is one more than the fabrics that can be commissioned
+    pub fabrics: usize,
+    pub acl_entries_per_fabric: usize,
+    /// The packet buffers, these come from a static pool of sys::MAX_PACKET_POOL_SIZE
+    /// buffers that all the transports of the process share. Creating a transport
+    /// sets how many of them are handed out.
+    pub packets: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            sessions: 16,
+            exchanges: 8,
+            endpoints: 3,
+            clusters_per_endpoint: 8,
+            attrs_per_cluster: 8,
+            fabrics: 3,
+            acl_entries_per_fabric: 3,
+            packets: MAX_PACKET_POOL_SIZE,
+        }
+    }
+}
+
+impl Limits {
+    /// Checks that the stack can work within these limits
+    pub fn validate(&self) -> Result<(), Error> {
+        let too_small = [
+            ("sessions", self.sessions, 1),
+            ("exchanges", self.exchanges, 1),
+            // The root endpoint
+            ("endpoints", self.endpoints, 1),
+            ("clusters_per_endpoint", self.clusters_per_endpoint, 1),
+            // The attribute list that every cluster has
+            ("attrs_per_cluster", self.attrs_per_cluster, 1),
+            ("fabrics", self.fabrics, 2),
+            ("acl_entries_per_fabric", self.acl_entries_per_fabric, 1),
+            // A message in, and its response out
+            ("packets", self.packets, 2),
+        ];
+        for (name, val, min) in too_small {
+            if val < min {
+                error!("The limit {} must be at least {}, not {}", name, min, val);
+                return Err(Error::Invalid);
+            }
+        }
+        // The endpoint ids are 16-bit, and 0xFFFF is reserved
+        if self.endpoints > 0xFFFF {
+            error!("There can't be {} endpoints", self.endpoints);
+            return Err(Error::Invalid);
+        }
+        // The fabric indices are 8-bit
+        if self.fabrics > 0x100 {
+            error!("There can't be {} fabrics", self.fabrics);
+            return Err(Error::Invalid);
+        }
+        if self.packets > MAX_PACKET_POOL_SIZE {
+            error!(
+                "There can't be {} packets, the pool has {}",
+                self.packets, MAX_PACKET_POOL_SIZE
+            );
+            return Err(Error::Invalid);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sys::MAX_PACKET_POOL_SIZE;
+
+    use super::Limits;
+
+    #[test]
+    fn test_validate() {
+        assert!(Limits::default().validate().is_ok());
+        let limits = Limits {
+            endpoints: 64,
+            ..Default::default()
+        };
+        assert!(limits.validate().is_ok());
+        let limits = Limits {
+            exchanges: 0,
+            ..Default::default()
+        };
+        assert!(limits.validate().is_err());
+        let limits = Limits {
+            fabrics: 1,
+            ..Default::default()
+        };
+        assert!(limits.validate().is_err());
+        let limits = Limits {
+            packets: MAX_PACKET_POOL_SIZE + 1,
+            ..Default::default()
+        };
+        assert!(limits.validate().is_err());
+    }
+}
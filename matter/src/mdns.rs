@@ -15,6 +15,10 @@ pub struct MdnsInner {
     pid: u16,
     /// Discriminator
     discriminator: u16,
+    /// The port that the services are reachable on
+    port: u16,
+    /// The instance name of the commissionable service that we published last
+    instance_name: Option<String>,
 }
 
 pub struct Mdns {
@@ -29,13 +33,18 @@ static INIT: Once = Once::new();
 
 pub enum ServiceMode {
     Commissioned,
+    /// Commissionable with the passcode and the discriminator of the device
     Commissionable,
+    /// Commissionable with a verifier that an administrator supplied, and this
+    /// discriminator
+    EnhancedCommissionable(u16),
 }
 
 impl Mdns {
     fn new() -> Self {
         Self {
             inner: Mutex::new(MdnsInner {
+                port: MATTER_PORT,
                 ..Default::default()
             }),
         }
@@ -61,24 +70,58 @@ impl Mdns {
         inner.discriminator = discriminator;
     }
 
+    /// Set the port that the services are published with
+    pub fn set_port(&self, port: u16) {
+        self.inner.lock().unwrap().port = port;
+    }
+
+    /// The vendor and product ids that the services are published with
+    pub fn get_vid_pid(&self) -> (u16, u16) {
+        let inner = self.inner.lock().unwrap();
+        (inner.vid, inner.pid)
+    }
+
+    /// The instance name of the commissionable service, if one was published
+    pub fn get_instance_name(&self) -> Option<String> {
+        self.inner.lock().unwrap().instance_name.clone()
+    }
+
     /// Publish a mDNS service
     /// name - is the service name (comma separated subtypes may follow)
     /// mode - the current service mode
     pub fn publish_service(&self, name: &str, mode: ServiceMode) -> Result<SysMdnsService, Error> {
+        let mut inner = self.inner.lock().unwrap();
         match mode {
-            ServiceMode::Commissioned => {
-                sys_publish_service(name, "_matter._tcp", MATTER_PORT, &[])
-            }
+            ServiceMode::Commissioned => sys_publish_service(name, "_matter._tcp", inner.port, &[]),
             ServiceMode::Commissionable => {
-                let inner = self.inner.lock().unwrap();
-                let short =
-                    (inner.discriminator & SHORT_DISCRIMINATOR_MASK) >> SHORT_DISCRIMINATOR_SHIFT;
-                let serv_type = format!("_matterc._udp,_S{},_L{}", short, inner.discriminator);
-
-                let str_discriminator = format!("{}", inner.discriminator);
-                let txt_kvs = [["D", &str_discriminator], ["CM", "1"]];
-                sys_publish_service(name, &serv_type, MATTER_PORT, &txt_kvs)
+                let discriminator = inner.discriminator;
+                Self::publish_commissionable(&mut inner, name, discriminator, "1")
+            }
+            ServiceMode::EnhancedCommissionable(discriminator) => {
+                Self::publish_commissionable(&mut inner, name, discriminator, "2")
             }
         }
     }
+
+    /// Withdraws a commissionable service, that publish_service() returned
+    pub fn unpublish_commissionable(&self, _service: SysMdnsService) {
+        // The service is withdrawn as it is dropped
+        self.inner.lock().unwrap().instance_name = None;
+    }
+
+    fn publish_commissionable(
+        inner: &mut MdnsInner,
+        name: &str,
+        discriminator: u16,
+        comm_mode: &str,
+    ) -> Result<SysMdnsService, Error> {
+        let short = (discriminator & SHORT_DISCRIMINATOR_MASK) >> SHORT_DISCRIMINATOR_SHIFT;
+        let serv_type = format!("_matterc._udp,_S{},_L{}", short, discriminator);
+
+        let str_discriminator = format!("{}", discriminator);
+        let txt_kvs = [["D", &str_discriminator], ["CM", comm_mode]];
+        let service = sys_publish_service(name, &serv_type, inner.port, &txt_kvs)?;
+        inner.instance_name = Some(name.to_owned());
+        Ok(service)
+    }
 }
@@ -1,8 +1,11 @@
 use std::sync::Arc;
 
-use log::{error, trace};
+use async_channel::{bounded, Sender};
+use heapless::LinearMap;
+use log::{error, info, trace};
 use owning_ref::RwLockReadGuardRef;
 use rand::prelude::*;
+use subtle::ConstantTimeEq;
 
 use crate::{
     cert::Cert,
@@ -10,12 +13,16 @@ use crate::{
     error::Error,
     fabric::{Fabric, FabricMgr, FabricMgrInner},
     secure_channel::common,
-    secure_channel::common::SCStatusCodes,
-    tlv::{get_root_node_struct, FromTLV, OctetStr, TLVElement, TLVWriter, TagType},
+    secure_channel::common::{OpCode, SCStatusCodes},
+    secure_channel::status_report::StatusReport,
+    tlv::{get_root_node_struct, FromTLV, OctetStr, TLVElement, TLVWriter, TagType, ToTLV},
     transport::{
+        exchange::{Exchange, ExchangeCtx},
+        mrp::MrpParams,
         network::Address,
+        packet::Packet,
         proto_demux::ProtoCtx,
-        queue::{Msg, WorkQ},
+        queue::{Msg, TxResp, UnsecuredTxMsg, WorkQ},
         session::{CloneData, SessionMode},
     },
     utils::writebuf::WriteBuf,
@@ -25,8 +32,24 @@ use crate::{
 enum State {
     Sigma1Rx,
     Sigma3Rx,
+    Sigma2ResumeTx,
+    // We are the initiator, and sent Sigma3
+    Sigma3Tx,
 }
 
+const RESUMPTION_ID_LEN: usize = 16;
+const RANDOM_LEN: usize = 32;
+
+const S1RK_INFO: [u8; 13] = *b"Sigma1_Resume";
+const S2RK_INFO: [u8; 13] = *b"Sigma2_Resume";
+const SIGMA1_RESUME_NONCE: [u8; crypto::AEAD_NONCE_LEN_BYTES] = *b"NCASE_SigmaS1";
+const SIGMA2_RESUME_NONCE: [u8; crypto::AEAD_NONCE_LEN_BYTES] = *b"NCASE_SigmaS2";
+const SIGMA2_NONCE: [u8; crypto::AEAD_NONCE_LEN_BYTES] = *b"NCASE_Sigma2N";
+const SIGMA3_NONCE: [u8; crypto::AEAD_NONCE_LEN_BYTES] = *b"NCASE_Sigma3N";
+
+// The largest encrypted part of a Sigma2 or Sigma3
+const MAX_ENCRYPTED_SIZE: usize = 800;
+
 pub struct CaseSession {
     state: State,
     peer_sessid: u16,
@@ -36,6 +59,11 @@ pub struct CaseSession {
     our_pub_key: [u8; crypto::EC_POINT_LEN_BYTES],
     peer_pub_key: [u8; crypto::EC_POINT_LEN_BYTES],
     local_fabric_idx: usize,
+    // The ID that the peer can resume this session with
+    resumption_id: [u8; RESUMPTION_ID_LEN],
+    // Only for the resumed sessions, the full handshake learns these from Sigma3
+    initiator_random: [u8; RANDOM_LEN],
+    peer_nodeid: u64,
 }
 impl CaseSession {
     pub fn new(peer_sessid: u16, local_sessid: u16) -> Result<Self, Error> {
@@ -48,17 +76,135 @@ impl CaseSession {
             our_pub_key: [0; crypto::EC_POINT_LEN_BYTES],
             peer_pub_key: [0; crypto::EC_POINT_LEN_BYTES],
             local_fabric_idx: 0,
+            resumption_id: [0; RESUMPTION_ID_LEN],
+            initiator_random: [0; RANDOM_LEN],
+            peer_nodeid: 0,
+        })
+    }
+}
+
+/// A CASE session establishment that we initiated, with a node of one of our fabrics
+///
+/// This is the exchange data till the Sigma2 arrives.
+pub struct CaseInitiator {
+    local_fabric_idx: usize,
+    peer_nodeid: u64,
+    local_sessid: u16,
+    our_pub_key: [u8; crypto::EC_POINT_LEN_BYTES],
+    // The ephemeral key pair is kept as bytes, the exchange data must be Send
+    our_priv_key: Vec<u8>,
+    sigma1: Vec<u8>,
+    done_tx: Sender<Result<u16, Error>>,
+}
+
+impl CaseInitiator {
+    fn new(
+        fabric: &Fabric,
+        local_fabric_idx: usize,
+        peer_nodeid: u64,
+        local_sessid: u16,
+        done_tx: Sender<Result<u16, Error>>,
+    ) -> Result<Self, Error> {
+        // Create an ephemeral Key Pair
+        let key_pair = KeyPair::new()?;
+        let mut our_pub_key = [0; crypto::EC_POINT_LEN_BYTES];
+        key_pair.get_public_key(&mut our_pub_key)?;
+        let mut our_priv_key = [0; crypto::BIGNUM_LEN_BYTES];
+        let len = key_pair.get_private_key(&mut our_priv_key)?;
+
+        let mut initiator_random = [0; RANDOM_LEN];
+        rand::thread_rng().fill_bytes(&mut initiator_random);
+        let mut dest_id = [0; crypto::SHA256_HASH_LEN_BYTES];
+        fabric.get_dest_id(&initiator_random, peer_nodeid, &mut dest_id)?;
+
+        let mut buf = [0; 256];
+        let mut wb = WriteBuf::new(&mut buf, 256);
+        let req = Sigma1Req {
+            initiator_random: OctetStr(&initiator_random),
+            initiator_sessid: local_sessid,
+            dest_id: OctetStr(&dest_id),
+            peer_pub_key: OctetStr(&our_pub_key),
+            mrp_params: Some(MrpParams::local()),
+            resumption_id: None,
+            initiator_resume_mic: None,
+        };
+        req.to_tlv(&mut TLVWriter::new(&mut wb), TagType::Anonymous)?;
+        let sigma1 = wb.as_borrow_slice().to_vec();
+
+        Ok(Self {
+            local_fabric_idx,
+            peer_nodeid,
+            local_sessid,
+            our_pub_key,
+            our_priv_key: our_priv_key[..len].to_vec(),
+            sigma1,
+            done_tx,
         })
     }
 }
 
+// The rest of the establishment, once we sent Sigma3
+struct CaseInitiatorSession {
+    case_session: CaseSession,
+    session_keys: [u8; 3 * crypto::SYMM_KEY_LEN_BYTES],
+    done_tx: Sender<Result<u16, Error>>,
+}
+
+// The number of peers whose sessions we can resume
+const MAX_RESUMPTION_TICKETS: usize = 16;
+
+/// What we keep from an established CASE session, to resume it without the full
+/// handshake
+#[derive(Clone, Copy)]
+struct ResumptionTicket {
+    resumption_id: [u8; RESUMPTION_ID_LEN],
+    shared_secret: [u8; crypto::ECDH_SHARED_SECRET_LEN_BYTES],
+    local_fabric_idx: usize,
+    peer_nodeid: u64,
+}
+
+/// The resumption tickets, one per peer
+///
+/// A new session with a peer replaces its ticket, so each resumption ID is only good
+/// for a single resumption.
+#[derive(Default)]
+struct ResumptionTickets {
+    // keys: (fabric index, peer node id)
+    tickets: LinearMap<(usize, u64), ResumptionTicket, MAX_RESUMPTION_TICKETS>,
+}
+
+impl ResumptionTickets {
+    fn insert(&mut self, ticket: ResumptionTicket) {
+        let peer = (ticket.local_fabric_idx, ticket.peer_nodeid);
+        if !self.tickets.contains_key(&peer) && self.tickets.len() == self.tickets.capacity() {
+            // Forget some peer, it will go through the full handshake the next time
+            let evict = self.tickets.keys().next().cloned();
+            if let Some(evict) = evict {
+                self.tickets.remove(&evict);
+            }
+        }
+        let _ = self.tickets.insert(peer, ticket);
+    }
+
+    fn get(&self, resumption_id: &[u8]) -> Option<ResumptionTicket> {
+        self.tickets
+            .values()
+            .find(|t| t.resumption_id == resumption_id)
+            .cloned()
+    }
+}
+
 pub struct Case {
     fabric_mgr: Arc<FabricMgr>,
+    tickets: ResumptionTickets,
 }
 
 impl Case {
     pub fn new(fabric_mgr: Arc<FabricMgr>) -> Self {
-        Self { fabric_mgr }
+        Self {
+            fabric_mgr,
+            tickets: Default::default(),
+        }
     }
 
     pub fn handle_casesigma3(&mut self, ctx: &mut ProtoCtx) -> Result<(), Error> {
@@ -88,7 +234,7 @@ impl Case {
         let root = get_root_node_struct(ctx.rx.as_borrow_slice())?;
         let encrypted = root.find_tag(1)?.slice()?;
 
-        let mut decrypted: [u8; 800] = [0; 800];
+        let mut decrypted = [0u8; MAX_ENCRYPTED_SIZE];
         if encrypted.len() > decrypted.len() {
             error!("Data too large");
             return Err(Error::NoSpace);
@@ -115,7 +261,7 @@ impl Case {
             return Ok(());
         }
 
-        if Case::validate_sigma3_sign(
+        if Case::validate_peer_sign(
             d.initiator_noc.0,
             d.initiator_icac.0,
             &initiator_noc,
@@ -136,15 +282,31 @@ impl Case {
 
         // Only now do we add this message to the TT Hash
         case_session.tt_hash.update(ctx.rx.as_borrow_slice())?;
-        let clone_data = Case::get_session_clone_data(
+        let mut session_keys = [0_u8; 3 * crypto::SYMM_KEY_LEN_BYTES];
+        Case::get_session_keys(
             fabric.ipk.op_key(),
+            &case_session.tt_hash,
+            &case_session.shared_secret,
+            &mut session_keys,
+        )?;
+        let peer_nodeid = initiator_noc.get_node_id()?;
+        let mut clone_data = Case::get_session_clone_data(
+            &session_keys,
             fabric.get_node_id(),
-            initiator_noc.get_node_id()?,
+            peer_nodeid,
             ctx.exch_ctx.sess.get_peer_addr(),
             &case_session,
         )?;
+        clone_data.mrp_params = ctx.exch_ctx.sess.get_mrp_params();
+        clone_data.iface = ctx.exch_ctx.sess.get_iface();
         // Queue a transport mgr request to add a new session
-        WorkQ::get()?.sync_send(Msg::NewSession(clone_data))?;
+        ctx.work_q.sync_send(Msg::NewSession(clone_data))?;
+        self.tickets.insert(ResumptionTicket {
+            resumption_id: case_session.resumption_id,
+            shared_secret: case_session.shared_secret,
+            local_fabric_idx: case_session.local_fabric_idx,
+            peer_nodeid,
+        });
 
         common::create_sc_status_report(
             &mut ctx.tx,
@@ -157,10 +319,285 @@ impl Case {
         Ok(())
     }
 
+    /// Handles the status report that the initiator ends a resumption with, or that
+    /// the responder ends the establishment that we initiated with
+    pub fn handle_status_report(&mut self, ctx: &mut ProtoCtx) -> Result<(), Error> {
+        let report = StatusReport::parse(ctx.rx.as_borrow_slice())?;
+        if Case::is_initiator_exchange(ctx.exch_ctx.exch) {
+            return self.handle_initiator_status_report(ctx, &report);
+        }
+        let case_session = match ctx.exch_ctx.exch.take_exchange_data::<CaseSession>() {
+            Some(case_session) if case_session.state == State::Sigma2ResumeTx => case_session,
+            _ => {
+                info!("Status report outside a resumption: {:?}", report);
+                ctx.exch_ctx.exch.close();
+                return Ok(());
+            }
+        };
+        ctx.exch_ctx.exch.close();
+        if !report.is_success() {
+            error!("The peer didn't accept the resumption: {:?}", report);
+            return Ok(());
+        }
+
+        let fabric = self.fabric_mgr.get_fabric(case_session.local_fabric_idx)?;
+        let fabric = fabric.as_ref().as_ref().ok_or_else(|| {
+            error!("The fabric of the resumed session is gone");
+            Error::NotFound
+        })?;
+        let mut session_keys = [0_u8; 3 * crypto::SYMM_KEY_LEN_BYTES];
+        Case::get_resumption_session_keys(&case_session, &mut session_keys)?;
+        let mut clone_data = Case::get_session_clone_data(
+            &session_keys,
+            fabric.get_node_id(),
+            case_session.peer_nodeid,
+            ctx.exch_ctx.sess.get_peer_addr(),
+            &case_session,
+        )?;
+        clone_data.mrp_params = ctx.exch_ctx.sess.get_mrp_params();
+        clone_data.iface = ctx.exch_ctx.sess.get_iface();
+        ctx.work_q.sync_send(Msg::NewSession(clone_data))?;
+        self.tickets.insert(ResumptionTicket {
+            resumption_id: case_session.resumption_id,
+            shared_secret: case_session.shared_secret,
+            local_fabric_idx: case_session.local_fabric_idx,
+            peer_nodeid: case_session.peer_nodeid,
+        });
+        info!("Resumed the session with {:x}", case_session.peer_nodeid);
+        Ok(())
+    }
+
+    fn is_initiator_exchange(exch: &mut Exchange) -> bool {
+        exch.get_exchange_data::<CaseInitiator>().is_some()
+            || exch.get_exchange_data::<CaseInitiatorSession>().is_some()
+    }
+
+    /// Fails the establishment that we initiated on the exchange, the peer didn't
+    /// respond in time
+    pub fn handle_response_timeout(exch: &mut Exchange) {
+        if let Some(initiator) = exch.take_exchange_data::<CaseInitiator>() {
+            let _ = initiator.done_tx.try_send(Err(Error::Timeout));
+        } else if let Some(session) = exch.take_exchange_data::<CaseInitiatorSession>() {
+            let _ = session.done_tx.try_send(Err(Error::Timeout));
+        }
+    }
+
+    fn handle_initiator_status_report(
+        &mut self,
+        ctx: &mut ProtoCtx,
+        report: &StatusReport,
+    ) -> Result<(), Error> {
+        let exch = &mut ctx.exch_ctx.exch;
+        if exch.get_exchange_data::<CaseInitiator>().is_some() {
+            let initiator = exch.take_exchange_data::<CaseInitiator>();
+            exch.close();
+            error!("The peer didn't answer our Sigma1: {:?}", report);
+            if let Some(initiator) = initiator {
+                let _ = initiator.done_tx.try_send(Err(Error::Invalid));
+            }
+            return Ok(());
+        }
+        let session = exch
+            .take_exchange_data::<CaseInitiatorSession>()
+            .ok_or(Error::InvalidState)?;
+        exch.close();
+        if !report.is_success() {
+            error!("The peer didn't accept our Sigma3: {:?}", report);
+            let _ = session.done_tx.try_send(Err(Error::Invalid));
+            return Ok(());
+        }
+
+        let case_session = &session.case_session;
+        let fabric = self.fabric_mgr.get_fabric(case_session.local_fabric_idx)?;
+        let fabric = fabric.as_ref().as_ref().ok_or_else(|| {
+            error!("The fabric of the new session is gone");
+            Error::NotFound
+        })?;
+        let mut clone_data = Case::get_session_clone_data(
+            &session.session_keys,
+            fabric.get_node_id(),
+            case_session.peer_nodeid,
+            ctx.exch_ctx.sess.get_peer_addr(),
+            case_session,
+        )?;
+        // The keys are the other way around for the initiator
+        std::mem::swap(&mut clone_data.dec_key, &mut clone_data.enc_key);
+        clone_data.mrp_params = ctx.exch_ctx.sess.get_mrp_params();
+        clone_data.iface = ctx.exch_ctx.sess.get_iface();
+        ctx.work_q.sync_send(Msg::NewSession(clone_data))?;
+        info!(
+            "Established the CASE session {} with {:x}",
+            case_session.local_sessid, case_session.peer_nodeid
+        );
+        let _ = session.done_tx.try_send(Ok(case_session.local_sessid));
+        Ok(())
+    }
+
+    pub fn handle_casesigma2(&mut self, ctx: &mut ProtoCtx) -> Result<(), Error> {
+        let initiator = ctx
+            .exch_ctx
+            .exch
+            .take_exchange_data::<CaseInitiator>()
+            .ok_or(Error::InvalidState)?;
+        let tail = ctx.tx.get_writebuf()?.get_tail();
+        match self.handle_sigma2(&initiator, ctx.rx.as_borrow_slice(), ctx.tx.get_writebuf()?) {
+            Ok((session, mrp_params)) => {
+                if let Some(mrp_params) = mrp_params {
+                    ctx.exch_ctx.sess.set_mrp_params(mrp_params);
+                }
+                ctx.exch_ctx.exch.set_exchange_data(Box::new(session));
+            }
+            Err(e) => {
+                error!("Invalid Sigma2: {:?}", e);
+                let _ = initiator.done_tx.try_send(Err(e));
+                ctx.tx.get_writebuf()?.rewind_tail_to(tail);
+                common::create_sc_error_report(&mut ctx.tx, e)?;
+                ctx.exch_ctx.exch.close();
+            }
+        }
+        Ok(())
+    }
+
+    // Authenticates the responder with its Sigma2, and writes our Sigma3
+    fn handle_sigma2(
+        &self,
+        initiator: &CaseInitiator,
+        rx: &[u8],
+        tx: &mut WriteBuf,
+    ) -> Result<(CaseInitiatorSession, Option<MrpParams>), Error> {
+        let root = get_root_node_struct(rx)?;
+        let r = Sigma2Resp::from_tlv(&root)?;
+        if r.responder_pub_key.0.len() != crypto::EC_POINT_LEN_BYTES {
+            error!("Invalid public key length");
+            return Err(Error::Invalid);
+        }
+
+        let fabric = self.fabric_mgr.get_fabric(initiator.local_fabric_idx)?;
+        let fabric = fabric.as_ref().as_ref().ok_or(Error::NotFound)?;
+
+        let mut case_session = CaseSession::new(r.responder_sessid, initiator.local_sessid)?;
+        case_session.state = State::Sigma3Tx;
+        case_session.local_fabric_idx = initiator.local_fabric_idx;
+        case_session.peer_nodeid = initiator.peer_nodeid;
+        case_session.our_pub_key = initiator.our_pub_key;
+        case_session
+            .peer_pub_key
+            .copy_from_slice(r.responder_pub_key.0);
+        case_session.tt_hash.update(&initiator.sigma1)?;
+
+        // Derive the Shared Secret
+        let key_pair =
+            KeyPair::new_from_components(&initiator.our_pub_key, &initiator.our_priv_key)?;
+        let len = key_pair.derive_secret(r.responder_pub_key.0, &mut case_session.shared_secret)?;
+        if len != 32 {
+            error!("Derived secret length incorrect");
+            return Err(Error::Invalid);
+        }
+
+        let mut decrypted = [0u8; MAX_ENCRYPTED_SIZE];
+        if r.encrypted.0.len() > decrypted.len() {
+            error!("Data too large");
+            return Err(Error::NoSpace);
+        }
+        let decrypted = &mut decrypted[..r.encrypted.0.len()];
+        decrypted.copy_from_slice(r.encrypted.0);
+        let len = Case::get_sigma2_decryption(
+            fabric.ipk.op_key(),
+            r.responder_random.0,
+            &case_session,
+            decrypted,
+        )?;
+        let decrypted = &decrypted[..len];
+        let root = get_root_node_struct(decrypted)?;
+        let d = Sigma2Decrypt::from_tlv(&root)?;
+
+        let responder_noc = Cert::new(d.responder_noc.0)?;
+        let responder_icac = Cert::new(d.responder_icac.0)?;
+        if let Err(e) = Case::validate_certs(fabric, &responder_noc, &responder_icac) {
+            error!("Certificate Chain doesn't match: {}", e);
+            return Err(Error::Invalid);
+        }
+        if responder_noc.get_node_id()? != initiator.peer_nodeid {
+            error!("The responder isn't the node that we wanted");
+            return Err(Error::Invalid);
+        }
+        if Case::validate_peer_sign(
+            d.responder_noc.0,
+            d.responder_icac.0,
+            &responder_noc,
+            d.signature.0,
+            &case_session,
+        )
+        .is_err()
+        {
+            error!("Sigma2 Signature doesn't match");
+            return Err(Error::InvalidSignature);
+        }
+        case_session.tt_hash.update(rx)?;
+
+        // Generate Sigma3
+        let mut signature = [0u8; crypto::EC_SIGNATURE_LEN_BYTES];
+        let sign_len = Case::get_our_sign(
+            fabric,
+            &case_session.our_pub_key,
+            &case_session.peer_pub_key,
+            &mut signature,
+        )?;
+        let mut encrypted = [0u8; MAX_ENCRYPTED_SIZE];
+        let encrypted_len = Case::get_sigma3_encryption(
+            fabric,
+            &case_session,
+            &signature[..sign_len],
+            &mut encrypted,
+        )?;
+
+        let mut tw = TLVWriter::new(tx);
+        tw.start_struct(TagType::Anonymous)?;
+        tw.str16(TagType::Context(1), &encrypted[..encrypted_len])?;
+        tw.end_container()?;
+        case_session.tt_hash.update(tx.as_borrow_slice())?;
+
+        let mut session_keys = [0_u8; 3 * crypto::SYMM_KEY_LEN_BYTES];
+        Case::get_session_keys(
+            fabric.ipk.op_key(),
+            &case_session.tt_hash,
+            &case_session.shared_secret,
+            &mut session_keys,
+        )?;
+        let session = CaseInitiatorSession {
+            case_session,
+            session_keys,
+            done_tx: initiator.done_tx.clone(),
+        };
+        Ok((session, r.mrp_params))
+    }
+
     pub fn handle_casesigma1(&mut self, ctx: &mut ProtoCtx) -> Result<(), Error> {
         let rx_buf = ctx.rx.as_borrow_slice();
         let root = get_root_node_struct(rx_buf)?;
         let r = Sigma1Req::from_tlv(&root)?;
+        if let Some(mrp_params) = r.mrp_params {
+            ctx.exch_ctx.sess.set_mrp_params(mrp_params);
+        }
+
+        if let (Some(resumption_id), Some(mic)) = (r.resumption_id, r.initiator_resume_mic) {
+            match self.tickets.get(resumption_id.0) {
+                Some(ticket) => {
+                    if Case::validate_sigma1_resume_mic(r.initiator_random.0, &ticket, mic.0)
+                        .is_ok()
+                    {
+                        return Case::send_sigma2_resume(
+                            &mut ctx.exch_ctx,
+                            &mut ctx.tx,
+                            &r,
+                            &ticket,
+                        );
+                    }
+                    error!("Sigma1 resume MIC doesn't match, doing the full handshake");
+                }
+                None => info!("Unknown resumption ID, doing the full handshake"),
+            }
+        }
 
         let local_fabric_idx = self
             .fabric_mgr
@@ -185,6 +622,7 @@ impl Case {
             return Err(Error::Invalid);
         }
         case_session.peer_pub_key.copy_from_slice(r.peer_pub_key.0);
+        rand::thread_rng().fill_bytes(&mut case_session.resumption_id);
         trace!(
             "Destination ID matched to fabric index {}",
             case_session.local_fabric_idx
@@ -206,8 +644,6 @@ impl Case {
         rand::thread_rng().fill_bytes(&mut our_random);
 
         // Derive the Encrypted Part
-        const MAX_ENCRYPTED_SIZE: usize = 800;
-
         let mut encrypted: [u8; MAX_ENCRYPTED_SIZE] = [0; MAX_ENCRYPTED_SIZE];
         let encrypted_len = {
             let mut signature = [0u8; crypto::EC_SIGNATURE_LEN_BYTES];
@@ -222,8 +658,9 @@ impl Case {
                 return Ok(());
             }
 
-            let sign_len = Case::get_sigma2_sign(
-                &fabric,
+            // We are guaranteed this unwrap will work
+            let sign_len = Case::get_our_sign(
+                fabric.as_ref().as_ref().unwrap(),
                 &case_session.our_pub_key,
                 &case_session.peer_pub_key,
                 &mut signature,
@@ -247,27 +684,127 @@ impl Case {
         tw.u16(TagType::Context(2), local_sessid)?;
         tw.str8(TagType::Context(3), &case_session.our_pub_key)?;
         tw.str16(TagType::Context(4), encrypted)?;
+        MrpParams::local().to_tlv(&mut tw, TagType::Context(5))?;
         tw.end_container()?;
         case_session.tt_hash.update(ctx.tx.as_borrow_slice())?;
         ctx.exch_ctx.exch.set_exchange_data(case_session);
         Ok(())
     }
 
+    fn send_sigma2_resume(
+        exch_ctx: &mut ExchangeCtx,
+        tx: &mut Packet,
+        r: &Sigma1Req,
+        ticket: &ResumptionTicket,
+    ) -> Result<(), Error> {
+        if r.initiator_random.0.len() != RANDOM_LEN {
+            error!("Invalid initiator random length");
+            return Err(Error::Invalid);
+        }
+        let local_sessid = exch_ctx.sess.reserve_new_sess_id();
+        let mut case_session = Box::new(CaseSession::new(r.initiator_sessid, local_sessid)?);
+        case_session.state = State::Sigma2ResumeTx;
+        case_session.shared_secret = ticket.shared_secret;
+        case_session.local_fabric_idx = ticket.local_fabric_idx;
+        case_session.peer_nodeid = ticket.peer_nodeid;
+        case_session
+            .initiator_random
+            .copy_from_slice(r.initiator_random.0);
+        // The new session gets a new resumption ID
+        rand::thread_rng().fill_bytes(&mut case_session.resumption_id);
+
+        let mut mic = [0u8; crypto::AEAD_MIC_LEN_BYTES];
+        Case::get_resume_mic(
+            &S2RK_INFO,
+            &SIGMA2_RESUME_NONCE,
+            r.initiator_random.0,
+            &case_session.resumption_id,
+            &case_session.shared_secret,
+            &mut mic,
+        )?;
+
+        tx.set_proto_opcode(OpCode::CASESigma2Resume as u8);
+        let mut tw = TLVWriter::new(tx.get_writebuf()?);
+        tw.start_struct(TagType::Anonymous)?;
+        tw.str8(TagType::Context(1), &case_session.resumption_id)?;
+        tw.str8(TagType::Context(2), &mic)?;
+        tw.u16(TagType::Context(3), local_sessid)?;
+        MrpParams::local().to_tlv(&mut tw, TagType::Context(4))?;
+        tw.end_container()?;
+        exch_ctx.exch.set_exchange_data(case_session);
+        Ok(())
+    }
+
+    fn validate_sigma1_resume_mic(
+        initiator_random: &[u8],
+        ticket: &ResumptionTicket,
+        mic: &[u8],
+    ) -> Result<(), Error> {
+        if mic.len() != crypto::AEAD_MIC_LEN_BYTES {
+            return Err(Error::Invalid);
+        }
+        let mut expected = [0u8; crypto::AEAD_MIC_LEN_BYTES];
+        Case::get_resume_mic(
+            &S1RK_INFO,
+            &SIGMA1_RESUME_NONCE,
+            initiator_random,
+            &ticket.resumption_id,
+            &ticket.shared_secret,
+            &mut expected,
+        )?;
+        // The MIC authenticates the initiator, it is compared in constant time
+        if mic.ct_eq(&expected).unwrap_u8() == 1 {
+            Ok(())
+        } else {
+            Err(Error::Invalid)
+        }
+    }
+
+    // The MIC of an empty message, with a key from the shared secret of the session
+    // being resumed
+    fn get_resume_mic(
+        info: &[u8],
+        nonce: &[u8],
+        initiator_random: &[u8],
+        resumption_id: &[u8],
+        shared_secret: &[u8],
+        mic: &mut [u8],
+    ) -> Result<(), Error> {
+        let mut salt = Vec::<u8>::with_capacity(RANDOM_LEN + RESUMPTION_ID_LEN);
+        salt.extend_from_slice(initiator_random);
+        salt.extend_from_slice(resumption_id);
+        let mut key = [0_u8; crypto::SYMM_KEY_LEN_BYTES];
+        crypto::hkdf_sha256(salt.as_slice(), shared_secret, info, &mut key)
+            .map_err(|_x| Error::NoSpace)?;
+
+        crypto::encrypt_in_place(&key, nonce, &[], mic, 0)?;
+        Ok(())
+    }
+
+    fn get_resumption_session_keys(
+        case_session: &CaseSession,
+        key: &mut [u8],
+    ) -> Result<(), Error> {
+        const RSEKEYS_INFO: &[u8] = b"SessionResumptionKeys";
+        let mut salt = Vec::<u8>::with_capacity(RANDOM_LEN + RESUMPTION_ID_LEN);
+        salt.extend_from_slice(&case_session.initiator_random);
+        salt.extend_from_slice(&case_session.resumption_id);
+        crypto::hkdf_sha256(
+            salt.as_slice(),
+            &case_session.shared_secret,
+            RSEKEYS_INFO,
+            key,
+        )
+        .map_err(|_x| Error::NoSpace)
+    }
+
     fn get_session_clone_data(
-        ipk: &[u8],
+        session_keys: &[u8],
         local_nodeid: u64,
         peer_nodeid: u64,
         peer_addr: Address,
         case_session: &CaseSession,
     ) -> Result<CloneData, Error> {
-        let mut session_keys = [0_u8; 3 * crypto::SYMM_KEY_LEN_BYTES];
-        Case::get_session_keys(
-            ipk,
-            &case_session.tt_hash,
-            &case_session.shared_secret,
-            &mut session_keys,
-        )?;
-
         let mut clone_data = CloneData::new(
             local_nodeid,
             peer_nodeid,
@@ -285,10 +822,11 @@ impl Case {
         Ok(clone_data)
     }
 
-    fn validate_sigma3_sign(
-        initiator_noc: &[u8],
-        initiator_icac: &[u8],
-        initiator_noc_cert: &Cert,
+    // The peer signs the same data in Sigma2 and Sigma3
+    fn validate_peer_sign(
+        peer_noc: &[u8],
+        peer_icac: &[u8],
+        peer_noc_cert: &Cert,
         sign: &[u8],
         case_session: &CaseSession,
     ) -> Result<(), Error> {
@@ -297,13 +835,13 @@ impl Case {
         let mut write_buf = WriteBuf::new(&mut buf, MAX_TBS_SIZE);
         let mut tw = TLVWriter::new(&mut write_buf);
         tw.start_struct(TagType::Anonymous)?;
-        tw.str8(TagType::Context(1), initiator_noc)?;
-        tw.str8(TagType::Context(2), initiator_icac)?;
+        tw.str8(TagType::Context(1), peer_noc)?;
+        tw.str8(TagType::Context(2), peer_icac)?;
         tw.str8(TagType::Context(3), &case_session.peer_pub_key)?;
         tw.str8(TagType::Context(4), &case_session.our_pub_key)?;
         tw.end_container()?;
 
-        let key = KeyPair::new_from_public(initiator_noc_cert.get_pubkey())?;
+        let key = KeyPair::new_from_public(peer_noc_cert.get_pubkey())?;
         key.verify_msg(write_buf.as_slice(), sign)?;
         Ok(())
     }
@@ -368,12 +906,65 @@ impl Case {
         )?;
         // println!("Sigma3 Key: {:x?}", sigma3_key);
 
-        let nonce: [u8; 13] = [
-            0x4e, 0x43, 0x41, 0x53, 0x45, 0x5f, 0x53, 0x69, 0x67, 0x6d, 0x61, 0x33, 0x4e,
-        ];
+        let encrypted_len = encrypted.len();
+        crypto::decrypt_in_place(&sigma3_key, &SIGMA3_NONCE, &[], encrypted)?;
+        Ok(encrypted_len - crypto::AEAD_MIC_LEN_BYTES)
+    }
+
+    fn get_sigma3_encryption(
+        fabric: &Fabric,
+        case_session: &CaseSession,
+        signature: &[u8],
+        out: &mut [u8],
+    ) -> Result<usize, Error> {
+        let mut sigma3_key = [0_u8; crypto::SYMM_KEY_LEN_BYTES];
+        Case::get_sigma3_key(
+            fabric.ipk.op_key(),
+            &case_session.tt_hash,
+            &case_session.shared_secret,
+            &mut sigma3_key,
+        )?;
+
+        let mut write_buf = WriteBuf::new(out, out.len());
+        let mut tw = TLVWriter::new(&mut write_buf);
+        tw.start_struct(TagType::Anonymous)?;
+        tw.str16_as(TagType::Context(1), |buf| fabric.noc.as_tlv(buf))?;
+        tw.str16_as(TagType::Context(2), |buf| fabric.icac.as_tlv(buf))?;
+        tw.str8(TagType::Context(3), signature)?;
+        tw.end_container()?;
+        write_buf.append(&[0u8; crypto::AEAD_MIC_LEN_BYTES])?;
+        let cipher_text = write_buf.as_mut_slice();
+
+        crypto::encrypt_in_place(
+            &sigma3_key,
+            &SIGMA3_NONCE,
+            &[],
+            cipher_text,
+            cipher_text.len() - crypto::AEAD_MIC_LEN_BYTES,
+        )?;
+        Ok(write_buf.as_slice().len())
+    }
+
+    fn get_sigma2_decryption(
+        ipk: &[u8],
+        responder_random: &[u8],
+        case_session: &CaseSession,
+        encrypted: &mut [u8],
+    ) -> Result<usize, Error> {
+        if encrypted.len() < crypto::AEAD_MIC_LEN_BYTES {
+            return Err(Error::Invalid);
+        }
+        let mut sigma2_key = [0_u8; crypto::SYMM_KEY_LEN_BYTES];
+        Case::get_sigma2_key(
+            ipk,
+            responder_random,
+            &case_session.peer_pub_key,
+            case_session,
+            &mut sigma2_key,
+        )?;
 
         let encrypted_len = encrypted.len();
-        crypto::decrypt_in_place(&sigma3_key, &nonce, &[], encrypted)?;
+        crypto::decrypt_in_place(&sigma2_key, &SIGMA2_NONCE, &[], encrypted)?;
         Ok(encrypted_len - crypto::AEAD_MIC_LEN_BYTES)
     }
 
@@ -406,8 +997,9 @@ impl Case {
 
     fn get_sigma2_key(
         ipk: &[u8],
-        our_random: &[u8],
-        case_session: &mut CaseSession,
+        responder_random: &[u8],
+        responder_pub_key: &[u8],
+        case_session: &CaseSession,
         key: &mut [u8],
     ) -> Result<(), Error> {
         const S2K_INFO: [u8; 6] = [0x53, 0x69, 0x67, 0x6d, 0x61, 0x32];
@@ -416,8 +1008,8 @@ impl Case {
         }
         let mut salt = Vec::<u8>::with_capacity(256);
         salt.extend_from_slice(ipk);
-        salt.extend_from_slice(our_random);
-        salt.extend_from_slice(&case_session.our_pub_key);
+        salt.extend_from_slice(responder_random);
+        salt.extend_from_slice(responder_pub_key);
 
         let tt = case_session.tt_hash.clone();
 
@@ -440,9 +1032,6 @@ impl Case {
         signature: &[u8],
         out: &mut [u8],
     ) -> Result<usize, Error> {
-        let mut resumption_id: [u8; 16] = [0; 16];
-        rand::thread_rng().fill_bytes(&mut resumption_id);
-
         // We are guaranteed this unwrap will work
         let fabric = fabric.as_ref().as_ref().unwrap();
 
@@ -450,6 +1039,7 @@ impl Case {
         Case::get_sigma2_key(
             fabric.ipk.op_key(),
             our_random,
+            &case_session.our_pub_key,
             case_session,
             &mut sigma2_key,
         )?;
@@ -460,12 +1050,9 @@ impl Case {
         tw.str16_as(TagType::Context(1), |buf| fabric.noc.as_tlv(buf))?;
         tw.str16_as(TagType::Context(2), |buf| fabric.icac.as_tlv(buf))?;
         tw.str8(TagType::Context(3), signature)?;
-        tw.str8(TagType::Context(4), &resumption_id)?;
+        tw.str8(TagType::Context(4), &case_session.resumption_id)?;
         tw.end_container()?;
         //println!("TBE is {:x?}", write_buf.as_borrow_slice());
-        let nonce: [u8; crypto::AEAD_NONCE_LEN_BYTES] = [
-            0x4e, 0x43, 0x41, 0x53, 0x45, 0x5f, 0x53, 0x69, 0x67, 0x6d, 0x61, 0x32, 0x4e,
-        ];
         //        let nonce = GenericArray::from_slice(&nonce);
         //        type AesCcm = Ccm<Aes128, U16, U13>;
         //        let cipher = AesCcm::new(GenericArray::from_slice(key));
@@ -476,7 +1063,7 @@ impl Case {
 
         crypto::encrypt_in_place(
             &sigma2_key,
-            &nonce,
+            &SIGMA2_NONCE,
             &[],
             cipher_text,
             cipher_text.len() - TAG_LEN,
@@ -484,14 +1071,13 @@ impl Case {
         Ok(write_buf.as_slice().len())
     }
 
-    fn get_sigma2_sign(
-        fabric: &RwLockReadGuardRef<FabricMgrInner, Option<Fabric>>,
+    // We sign the same data in Sigma2 as the responder, and in Sigma3 as the initiator
+    fn get_our_sign(
+        fabric: &Fabric,
         our_pub_key: &[u8],
         peer_pub_key: &[u8],
         signature: &mut [u8],
     ) -> Result<usize, Error> {
-        // We are guaranteed this unwrap will work
-        let fabric = fabric.as_ref().as_ref().unwrap();
         const MAX_TBS_SIZE: usize = 800;
         let mut buf: [u8; MAX_TBS_SIZE] = [0; MAX_TBS_SIZE];
         let mut write_buf = WriteBuf::new(&mut buf, MAX_TBS_SIZE);
@@ -507,13 +1093,35 @@ impl Case {
     }
 }
 
-#[derive(FromTLV)]
+#[derive(FromTLV, ToTLV)]
 #[tlvargs(start = 1, lifetime = "'a")]
 struct Sigma1Req<'a> {
     initiator_random: OctetStr<'a>,
     initiator_sessid: u16,
     dest_id: OctetStr<'a>,
     peer_pub_key: OctetStr<'a>,
+    mrp_params: Option<MrpParams>,
+    resumption_id: Option<OctetStr<'a>>,
+    initiator_resume_mic: Option<OctetStr<'a>>,
+}
+
+#[derive(FromTLV)]
+#[tlvargs(start = 1, lifetime = "'a")]
+struct Sigma2Resp<'a> {
+    responder_random: OctetStr<'a>,
+    responder_sessid: u16,
+    responder_pub_key: OctetStr<'a>,
+    encrypted: OctetStr<'a>,
+    mrp_params: Option<MrpParams>,
+}
+
+#[derive(FromTLV)]
+#[tlvargs(start = 1, lifetime = "'a")]
+struct Sigma2Decrypt<'a> {
+    responder_noc: OctetStr<'a>,
+    responder_icac: OctetStr<'a>,
+    signature: OctetStr<'a>,
+    // The resumption ID follows, we don't resume the sessions that we initiate
 }
 
 #[derive(FromTLV)]
@@ -523,3 +1131,445 @@ struct Sigma3Decrypt<'a> {
     initiator_icac: OctetStr<'a>,
     signature: OctetStr<'a>,
 }
+
+/// Establishes a CASE session with the node `peer_nodeid` of our fabric
+/// `local_fabric_idx`, that is at `peer`
+///
+/// The transport of `work_q` must have a SecureChannel handler registered. This
+/// returns the local id of the new session, or Error::Timeout if the peer stops
+/// responding.
+pub async fn initiate_case(
+    work_q: &WorkQ,
+    fabric_mgr: &FabricMgr,
+    local_fabric_idx: usize,
+    peer: Address,
+    peer_nodeid: u64,
+) -> Result<u16, Error> {
+    let local_sessid = work_q.reserve_sess_id().await?;
+    let (done_tx, done_rx) = bounded(1);
+    let initiator = {
+        let fabric = fabric_mgr.get_fabric(local_fabric_idx)?;
+        let fabric = fabric.as_ref().as_ref().ok_or(Error::NotFound)?;
+        CaseInitiator::new(fabric, local_fabric_idx, peer_nodeid, local_sessid, done_tx)?
+    };
+    work_q
+        .send(Msg::UnsecuredTx(UnsecuredTxMsg {
+            peer,
+            proto_id: common::PROTO_ID_SECURE_CHANNEL as u16,
+            proto_opcode: OpCode::CASESigma1 as u8,
+            payload: initiator.sigma1.clone(),
+            resp: Some(TxResp::Handler(Box::new(initiator))),
+        }))
+        .await?;
+    // The exchange drops the initiator if it fails before the session is established
+    done_rx.recv().await.map_err(|_| Error::NoExchange)?
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use async_channel::{bounded, Receiver};
+
+    use crate::{
+        cert::Cert,
+        crypto::{self, CryptoKeyPair, KeyPair},
+        error::Error,
+        fabric::{Fabric, FabricMgr},
+        secure_channel::{
+            case_test_vectors::test_vectors::*,
+            common::{self, OpCode, SCStatusCodes},
+            status_report::StatusReport,
+        },
+        tlv::{get_root_node_struct, FromTLV, OctetStr, TLVWriter, TagType, ToTLV},
+        transport::{
+            exchange::{Exchange, ExchangeCtx},
+            network::Address,
+            packet::Packet,
+            proto_demux::ProtoCtx,
+            queue::{Msg, WorkQ},
+            session::{CloneData, SessionMgr},
+        },
+        utils::writebuf::WriteBuf,
+    };
+
+    use super::{
+        Case, CaseInitiator, CaseInitiatorSession, CaseSession, ResumptionTicket,
+        ResumptionTickets, Sigma1Req, MAX_RESUMPTION_TICKETS, S1RK_INFO, S2RK_INFO,
+        SIGMA1_RESUME_NONCE, SIGMA2_RESUME_NONCE,
+    };
+
+    fn ticket(fab_idx: usize, peer_nodeid: u64, id: u8) -> ResumptionTicket {
+        ResumptionTicket {
+            resumption_id: [id; 16],
+            shared_secret: [id; 32],
+            local_fabric_idx: fab_idx,
+            peer_nodeid,
+        }
+    }
+
+    #[test]
+    fn test_tickets() {
+        let mut tickets = ResumptionTickets::default();
+        tickets.insert(ticket(1, 100, 1));
+        tickets.insert(ticket(2, 100, 2));
+        assert_eq!(tickets.get(&[1; 16]).unwrap().local_fabric_idx, 1);
+        assert_eq!(tickets.get(&[2; 16]).unwrap().local_fabric_idx, 2);
+
+        // A new session with the peer replaces its ticket
+        tickets.insert(ticket(1, 100, 3));
+        assert!(tickets.get(&[1; 16]).is_none());
+        assert_eq!(tickets.get(&[3; 16]).unwrap().peer_nodeid, 100);
+
+        for i in 0..MAX_RESUMPTION_TICKETS as u8 {
+            tickets.insert(ticket(3, i as u64, 10 + i));
+        }
+        assert_eq!(tickets.tickets.len(), MAX_RESUMPTION_TICKETS);
+        assert!(tickets
+            .get(&[10 + MAX_RESUMPTION_TICKETS as u8 - 1; 16])
+            .is_some());
+    }
+
+    #[test]
+    fn test_sigma1_resume_mic() {
+        let ticket = ticket(1, 100, 1);
+        let initiator_random = [0x55; 32];
+        // What the initiator sends
+        let mut mic = [0u8; crypto::AEAD_MIC_LEN_BYTES];
+        Case::get_resume_mic(
+            &S1RK_INFO,
+            &SIGMA1_RESUME_NONCE,
+            &initiator_random,
+            &ticket.resumption_id,
+            &ticket.shared_secret,
+            &mut mic,
+        )
+        .unwrap();
+        assert!(Case::validate_sigma1_resume_mic(&initiator_random, &ticket, &mic).is_ok());
+
+        assert!(Case::validate_sigma1_resume_mic(&[0x56; 32], &ticket, &mic).is_err());
+        let other = self::ticket(1, 100, 2);
+        assert!(Case::validate_sigma1_resume_mic(&initiator_random, &other, &mic).is_err());
+        assert!(Case::validate_sigma1_resume_mic(&initiator_random, &ticket, &mic[1..]).is_err());
+    }
+
+    #[test]
+    fn test_sigma1() {
+        let fabric = Fabric::dummy().unwrap();
+        let (done_tx, _done_rx) = bounded(1);
+        let initiator = CaseInitiator::new(&fabric, 1, 0, 10, done_tx).unwrap();
+
+        let root = get_root_node_struct(&initiator.sigma1).unwrap();
+        let r = Sigma1Req::from_tlv(&root).unwrap();
+        assert_eq!(r.initiator_sessid, 10);
+        assert_eq!(r.peer_pub_key.0, initiator.our_pub_key);
+        assert!(r.resumption_id.is_none());
+        // The node of the fabric that we address finds itself with the destination ID
+        assert!(fabric
+            .match_dest_id(r.initiator_random.0, r.dest_id.0)
+            .is_ok());
+
+        let (done_tx, _done_rx) = bounded(1);
+        let initiator = CaseInitiator::new(&fabric, 1, 1, 10, done_tx).unwrap();
+        let root = get_root_node_struct(&initiator.sigma1).unwrap();
+        let r = Sigma1Req::from_tlv(&root).unwrap();
+        assert!(fabric
+            .match_dest_id(r.initiator_random.0, r.dest_id.0)
+            .is_err());
+    }
+
+    fn fabric(noc: &[u8], pubkey: &[u8], privkey: &[u8]) -> Fabric {
+        let key_pair = KeyPair::new_from_components(pubkey, privkey).unwrap();
+        Fabric::new(
+            key_pair,
+            Cert::new(&RCA).unwrap(),
+            Cert::new(&ICAC).unwrap(),
+            Cert::new(noc).unwrap(),
+            &[0x42; 16],
+            0xFFF1,
+        )
+        .unwrap()
+    }
+
+    // A node of the fabric, that the tests pass the messages of its peer to by hand
+    struct Node {
+        case: Case,
+        fabric_mgr: Arc<FabricMgr>,
+        sess_mgr: SessionMgr,
+        sess_idx: usize,
+        exch: Exchange,
+        work_q: WorkQ,
+        work_rx: Receiver<Msg>,
+    }
+
+    impl Node {
+        fn new(fabric: Fabric) -> Self {
+            let fabric_mgr = Arc::new(FabricMgr::new_volatile(vec![fabric]).unwrap());
+            let mut sess_mgr = SessionMgr::new();
+            let sess_idx = sess_mgr
+                .get_or_add(0, Address::default(), None, false)
+                .unwrap();
+            let (work_q, work_rx) = WorkQ::new();
+            Self {
+                case: Case::new(fabric_mgr.clone()),
+                fabric_mgr,
+                sess_mgr,
+                sess_idx,
+                exch: Exchange::default(),
+                work_q,
+                work_rx,
+            }
+        }
+
+        // Starts an establishment with the node `peer_nodeid` on a new exchange, and
+        // returns the Sigma1
+        fn initiate(&mut self, peer_nodeid: u64) -> (Vec<u8>, Receiver<Result<u16, Error>>) {
+            let (done_tx, done_rx) = bounded(1);
+            let fabric = self.fabric_mgr.get_fabric(1).unwrap();
+            let fabric = fabric.as_ref().as_ref().unwrap();
+            let initiator = CaseInitiator::new(fabric, 1, peer_nodeid, 10, done_tx).unwrap();
+            let sigma1 = initiator.sigma1.clone();
+            self.exch = Exchange::default();
+            self.exch.set_exchange_data(Box::new(initiator));
+            (sigma1, done_rx)
+        }
+
+        // Handles the message like SecureChannel does, and returns the opcode and the
+        // payload of the reply
+        fn recv(&mut self, opcode: OpCode, rx: &[u8]) -> (u8, Vec<u8>) {
+            let mut rx_pkt = Packet::alloc_rx().unwrap();
+            rx_pkt.as_borrow_slice()[..rx.len()].copy_from_slice(rx);
+            rx_pkt.get_parsebuf().unwrap().set_len(rx.len());
+            let exch_ctx = ExchangeCtx {
+                exch: &mut self.exch,
+                sess: self.sess_mgr.get_session_handle(self.sess_idx),
+            };
+            let tx = Packet::alloc_tx().unwrap();
+            let mut ctx = ProtoCtx::new(exch_ctx, rx_pkt, tx, &self.work_q);
+            match opcode {
+                OpCode::CASESigma1 => {
+                    ctx.tx.set_proto_opcode(OpCode::CASESigma2 as u8);
+                    self.case.handle_casesigma1(&mut ctx)
+                }
+                OpCode::CASESigma2 => {
+                    ctx.tx.set_proto_opcode(OpCode::CASESigma3 as u8);
+                    self.case.handle_casesigma2(&mut ctx)
+                }
+                OpCode::CASESigma3 => self.case.handle_casesigma3(&mut ctx),
+                OpCode::StatusReport => self.case.handle_status_report(&mut ctx),
+                opcode => panic!("Unexpected {:?}", opcode),
+            }
+            .unwrap();
+            (ctx.tx.get_proto_opcode(), ctx.tx.as_borrow_slice().to_vec())
+        }
+
+        // The session that the node queued up for its transport
+        fn new_session(&self) -> CloneData {
+            match self.work_rx.try_recv() {
+                Ok(Msg::NewSession(clone_data)) => clone_data,
+                _ => panic!("No new session"),
+            }
+        }
+    }
+
+    // A Sigma1 that asks to resume the session with the ticket `resumption_id`
+    fn resume_sigma1(
+        fabric: &Fabric,
+        peer_nodeid: u64,
+        initiator_random: &[u8],
+        resumption_id: &[u8],
+        shared_secret: &[u8],
+    ) -> Vec<u8> {
+        let mut dest_id = [0; crypto::SHA256_HASH_LEN_BYTES];
+        fabric
+            .get_dest_id(initiator_random, peer_nodeid, &mut dest_id)
+            .unwrap();
+        let mut pub_key = [0; crypto::EC_POINT_LEN_BYTES];
+        KeyPair::new()
+            .unwrap()
+            .get_public_key(&mut pub_key)
+            .unwrap();
+        let mut mic = [0; crypto::AEAD_MIC_LEN_BYTES];
+        Case::get_resume_mic(
+            &S1RK_INFO,
+            &SIGMA1_RESUME_NONCE,
+            initiator_random,
+            resumption_id,
+            shared_secret,
+            &mut mic,
+        )
+        .unwrap();
+        let req = Sigma1Req {
+            initiator_random: OctetStr(initiator_random),
+            initiator_sessid: 20,
+            dest_id: OctetStr(&dest_id),
+            peer_pub_key: OctetStr(&pub_key),
+            mrp_params: None,
+            resumption_id: Some(OctetStr(resumption_id)),
+            initiator_resume_mic: Some(OctetStr(&mic)),
+        };
+        let mut buf = [0; 256];
+        let mut wb = WriteBuf::new(&mut buf, 256);
+        req.to_tlv(&mut TLVWriter::new(&mut wb), TagType::Anonymous)
+            .unwrap();
+        wb.as_borrow_slice().to_vec()
+    }
+
+    #[test]
+    fn test_case_loopback() {
+        let mut initiator = Node::new(fabric(&NOC1, &NOC1_PUBKEY, &NOC1_PRIVKEY));
+        let mut responder = Node::new(fabric(&NOC2, &NOC2_PUBKEY, &NOC2_PRIVKEY));
+
+        let (sigma1, done_rx) = initiator.initiate(0x2222);
+        let (opcode, sigma2) = responder.recv(OpCode::CASESigma1, &sigma1);
+        assert_eq!(opcode, OpCode::CASESigma2 as u8);
+        let (opcode, sigma3) = initiator.recv(OpCode::CASESigma2, &sigma2);
+        assert_eq!(opcode, OpCode::CASESigma3 as u8);
+        let (opcode, report) = responder.recv(OpCode::CASESigma3, &sigma3);
+        assert_eq!(opcode, OpCode::StatusReport as u8);
+        assert!(StatusReport::parse(&report).unwrap().is_success());
+        let (_, reply) = initiator.recv(OpCode::StatusReport, &report);
+        assert!(reply.is_empty());
+        assert!(done_rx.try_recv().unwrap().is_ok());
+
+        // What one side encrypts with, the other decrypts with
+        let ours = initiator.new_session();
+        let theirs = responder.new_session();
+        assert_eq!(ours.enc_key, theirs.dec_key);
+        assert_eq!(ours.dec_key, theirs.enc_key);
+        assert_eq!(ours.att_challenge, theirs.att_challenge);
+        assert_ne!(ours.enc_key, ours.dec_key);
+    }
+
+    #[test]
+    fn test_case_wrong_node() {
+        let mut initiator = Node::new(fabric(&NOC1, &NOC1_PUBKEY, &NOC1_PRIVKEY));
+        let mut responder = Node::new(fabric(&NOC2, &NOC2_PUBKEY, &NOC2_PRIVKEY));
+
+        // The responder doesn't find itself with the destination ID
+        let (sigma1, done_rx) = initiator.initiate(0x3333);
+        let (opcode, report) = responder.recv(OpCode::CASESigma1, &sigma1);
+        assert_eq!(opcode, OpCode::StatusReport as u8);
+        assert_eq!(
+            StatusReport::parse(&report).unwrap().proto_code,
+            SCStatusCodes::NoSharedTrustRoots as u16
+        );
+        initiator.recv(OpCode::StatusReport, &report);
+        assert_eq!(done_rx.try_recv().unwrap(), Err(Error::Invalid));
+        assert!(initiator.work_rx.is_empty());
+        assert!(responder.work_rx.is_empty());
+    }
+
+    #[test]
+    fn test_case_bad_signature() {
+        // The responder signs with a key that isn't the one of its NOC
+        let key_pair = KeyPair::new().unwrap();
+        let mut pubkey = [0; crypto::EC_POINT_LEN_BYTES];
+        key_pair.get_public_key(&mut pubkey).unwrap();
+        let mut privkey = [0; crypto::BIGNUM_LEN_BYTES];
+        key_pair.get_private_key(&mut privkey).unwrap();
+        let mut initiator = Node::new(fabric(&NOC1, &NOC1_PUBKEY, &NOC1_PRIVKEY));
+        let mut responder = Node::new(fabric(&NOC2, &pubkey, &privkey));
+
+        let (sigma1, done_rx) = initiator.initiate(0x2222);
+        let (_, sigma2) = responder.recv(OpCode::CASESigma1, &sigma1);
+        let (opcode, report) = initiator.recv(OpCode::CASESigma2, &sigma2);
+        assert_eq!(opcode, OpCode::StatusReport as u8);
+        assert!(!StatusReport::parse(&report).unwrap().is_success());
+        assert_eq!(done_rx.try_recv().unwrap(), Err(Error::InvalidSignature));
+
+        // Same for the initiator, that the responder turns down at Sigma3
+        let mut initiator = Node::new(fabric(&NOC1, &pubkey, &privkey));
+        let mut responder = Node::new(fabric(&NOC2, &NOC2_PUBKEY, &NOC2_PRIVKEY));
+        let (sigma1, done_rx) = initiator.initiate(0x2222);
+        let (_, sigma2) = responder.recv(OpCode::CASESigma1, &sigma1);
+        let (_, sigma3) = initiator.recv(OpCode::CASESigma2, &sigma2);
+        let (_, report) = responder.recv(OpCode::CASESigma3, &sigma3);
+        assert!(!StatusReport::parse(&report).unwrap().is_success());
+        initiator.recv(OpCode::StatusReport, &report);
+        assert_eq!(done_rx.try_recv().unwrap(), Err(Error::Invalid));
+        assert!(initiator.work_rx.is_empty());
+        assert!(responder.work_rx.is_empty());
+    }
+
+    #[test]
+    fn test_resumption() {
+        let mut initiator = Node::new(fabric(&NOC1, &NOC1_PUBKEY, &NOC1_PRIVKEY));
+        let mut responder = Node::new(fabric(&NOC2, &NOC2_PUBKEY, &NOC2_PRIVKEY));
+
+        // The full handshake gives the initiator a ticket with the responder
+        let (sigma1, done_rx) = initiator.initiate(0x2222);
+        let (_, sigma2) = responder.recv(OpCode::CASESigma1, &sigma1);
+        let (_, sigma3) = initiator.recv(OpCode::CASESigma2, &sigma2);
+        let shared_secret = initiator
+            .exch
+            .get_exchange_data::<CaseInitiatorSession>()
+            .unwrap()
+            .case_session
+            .shared_secret;
+        let (_, report) = responder.recv(OpCode::CASESigma3, &sigma3);
+        initiator.recv(OpCode::StatusReport, &report);
+        assert!(done_rx.try_recv().unwrap().is_ok());
+        responder.new_session();
+        let ticket = *responder.case.tickets.tickets.values().next().unwrap();
+        assert_eq!(ticket.peer_nodeid, 0x1111);
+        assert_eq!(ticket.shared_secret, shared_secret);
+
+        // Sigma1 with the resumption ID -> Sigma2_Resume
+        let initiator_random = [0x77; 32];
+        let sigma1 = {
+            let fabric = initiator.fabric_mgr.get_fabric(1).unwrap();
+            resume_sigma1(
+                fabric.as_ref().as_ref().unwrap(),
+                0x2222,
+                &initiator_random,
+                &ticket.resumption_id,
+                &shared_secret,
+            )
+        };
+        responder.exch = Exchange::default();
+        let (opcode, sigma2_resume) = responder.recv(OpCode::CASESigma1, &sigma1);
+        assert_eq!(opcode, OpCode::CASESigma2Resume as u8);
+        let root = get_root_node_struct(&sigma2_resume).unwrap();
+        let resumption_id = root.find_tag(1).unwrap().slice().unwrap();
+        assert_ne!(resumption_id, ticket.resumption_id);
+        let mut mic = [0; crypto::AEAD_MIC_LEN_BYTES];
+        Case::get_resume_mic(
+            &S2RK_INFO,
+            &SIGMA2_RESUME_NONCE,
+            &initiator_random,
+            resumption_id,
+            &shared_secret,
+            &mut mic,
+        )
+        .unwrap();
+        assert_eq!(root.find_tag(2).unwrap().slice().unwrap(), mic);
+        assert!(responder.work_rx.is_empty());
+
+        // StatusReport -> the resumed session
+        let mut report = Packet::alloc_tx().unwrap();
+        common::create_sc_status_report(
+            &mut report,
+            SCStatusCodes::SessionEstablishmentSuccess,
+            None,
+        )
+        .unwrap();
+        let (_, reply) = responder.recv(OpCode::StatusReport, report.as_borrow_slice());
+        assert!(reply.is_empty());
+        let mut resumed = CaseSession::new(20, 0).unwrap();
+        resumed.initiator_random = initiator_random;
+        resumed.resumption_id.copy_from_slice(resumption_id);
+        resumed.shared_secret = shared_secret;
+        let mut keys = [0; 3 * crypto::SYMM_KEY_LEN_BYTES];
+        Case::get_resumption_session_keys(&resumed, &mut keys).unwrap();
+        let clone_data = responder.new_session();
+        assert_eq!(clone_data.dec_key, keys[0..16]);
+        assert_eq!(clone_data.enc_key, keys[16..32]);
+
+        // The old resumption ID is spent, it only gets the full handshake
+        assert!(responder.case.tickets.get(&ticket.resumption_id).is_none());
+        responder.exch = Exchange::default();
+        let (opcode, _) = responder.recv(OpCode::CASESigma1, &sigma1);
+        assert_eq!(opcode, OpCode::CASESigma2 as u8);
+    }
+}
@@ -0,0 +1,103 @@
+#[cfg(test)]
+pub mod test_vectors {
+    // A fabric 0xabcd, with a root CA, an intermediate CA and the NOCs of the nodes
+    // 0x1111 and 0x2222
+    pub const RCA: [u8; 231] = [
+        0x15, 0x30, 0x01, 0x01, 0x01, 0x24, 0x02, 0x01, 0x37, 0x03, 0x24, 0x14, 0x01, 0x18, 0x26,
+        0x04, 0x80, 0x22, 0x81, 0x27, 0x26, 0x05, 0x80, 0x25, 0x4d, 0x3a, 0x37, 0x06, 0x24, 0x14,
+        0x01, 0x18, 0x24, 0x07, 0x01, 0x24, 0x08, 0x01, 0x30, 0x09, 0x41, 0x04, 0xd2, 0x34, 0x73,
+        0x8b, 0x82, 0xed, 0x45, 0x05, 0xcf, 0x3f, 0x5c, 0xd4, 0xdf, 0x14, 0x6f, 0x18, 0x81, 0x3d,
+        0x45, 0x88, 0x2e, 0x34, 0x1e, 0xb7, 0xe5, 0x93, 0xd7, 0xfd, 0x3e, 0xf2, 0x35, 0x38, 0x67,
+        0x56, 0xb2, 0x3a, 0x7c, 0xfc, 0x40, 0xda, 0x4c, 0x8a, 0x22, 0xf4, 0xa5, 0x7d, 0x42, 0xc6,
+        0x56, 0x67, 0x84, 0xf8, 0xa1, 0xfb, 0x0d, 0xee, 0x32, 0x05, 0x6f, 0xa8, 0x19, 0xce, 0x40,
+        0x54, 0x37, 0x0a, 0x35, 0x01, 0x29, 0x01, 0x18, 0x24, 0x02, 0x60, 0x30, 0x04, 0x14, 0x11,
+        0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11,
+        0x11, 0x11, 0x11, 0x11, 0x30, 0x05, 0x14, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11,
+        0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x18, 0x30, 0x0b,
+        0x40, 0x72, 0x8a, 0x26, 0xd6, 0x43, 0xd1, 0x72, 0x54, 0xc2, 0x1d, 0x93, 0x86, 0xbc, 0x92,
+        0x2b, 0x8b, 0xb0, 0xed, 0x49, 0xb2, 0x82, 0x16, 0x8a, 0x1e, 0x16, 0xbb, 0x46, 0xcf, 0x47,
+        0x56, 0xd5, 0x95, 0xcf, 0xed, 0x8a, 0xb1, 0x1f, 0x50, 0xb0, 0x8f, 0x42, 0x4d, 0xb4, 0x6b,
+        0x14, 0x09, 0xea, 0x5b, 0xe9, 0xf2, 0x3d, 0x4e, 0x47, 0x58, 0xe1, 0x4e, 0xcc, 0xf5, 0x00,
+        0x2f, 0x28, 0xf7, 0xb7, 0x0c, 0x18,
+    ];
+    pub const ICAC: [u8; 231] = [
+        0x15, 0x30, 0x01, 0x01, 0x02, 0x24, 0x02, 0x01, 0x37, 0x03, 0x24, 0x14, 0x01, 0x18, 0x26,
+        0x04, 0x80, 0x22, 0x81, 0x27, 0x26, 0x05, 0x80, 0x25, 0x4d, 0x3a, 0x37, 0x06, 0x24, 0x13,
+        0x02, 0x18, 0x24, 0x07, 0x01, 0x24, 0x08, 0x01, 0x30, 0x09, 0x41, 0x04, 0xab, 0xdf, 0x7e,
+        0xba, 0xd9, 0x22, 0xc0, 0x7a, 0x96, 0xce, 0xeb, 0x20, 0xac, 0x39, 0xfb, 0x40, 0x11, 0xde,
+        0x4b, 0x2b, 0xca, 0xf4, 0x59, 0x8c, 0x7f, 0xc8, 0xdc, 0x74, 0x94, 0xbb, 0x9e, 0x36, 0xb3,
+        0xcc, 0xf3, 0x7b, 0x1f, 0xd4, 0x5c, 0x3a, 0xaf, 0x8f, 0x4b, 0x08, 0xc4, 0xda, 0x81, 0x01,
+        0xaf, 0x0b, 0xc6, 0x02, 0xfe, 0x1f, 0x31, 0x4e, 0xd5, 0xcc, 0xcf, 0x11, 0xf3, 0x7d, 0x18,
+        0x30, 0x37, 0x0a, 0x35, 0x01, 0x29, 0x01, 0x18, 0x24, 0x02, 0x60, 0x30, 0x04, 0x14, 0x22,
+        0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22,
+        0x22, 0x22, 0x22, 0x22, 0x30, 0x05, 0x14, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11,
+        0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x18, 0x30, 0x0b,
+        0x40, 0xcb, 0xd2, 0x61, 0x86, 0xa8, 0x97, 0xd6, 0xe0, 0xc6, 0x38, 0xaa, 0x5d, 0x03, 0x0c,
+        0x57, 0x6c, 0x6d, 0x01, 0x86, 0x7d, 0x2e, 0x69, 0x36, 0xbd, 0x0b, 0xb3, 0xc8, 0xce, 0xad,
+        0x81, 0x01, 0x90, 0x58, 0x7b, 0x95, 0xcb, 0xdf, 0x49, 0x7a, 0x42, 0xf7, 0xf6, 0x9f, 0x51,
+        0x12, 0x8b, 0xff, 0xd1, 0xf2, 0xcf, 0x02, 0x09, 0x4d, 0x04, 0x11, 0xa3, 0x8e, 0xa9, 0xeb,
+        0xf2, 0x00, 0x9c, 0x6e, 0xe5, 0x18,
+    ];
+    pub const NOC1: [u8; 243] = [
+        0x15, 0x30, 0x01, 0x01, 0x03, 0x24, 0x02, 0x01, 0x37, 0x03, 0x24, 0x13, 0x02, 0x18, 0x26,
+        0x04, 0x80, 0x22, 0x81, 0x27, 0x26, 0x05, 0x80, 0x25, 0x4d, 0x3a, 0x37, 0x06, 0x25, 0x11,
+        0x11, 0x11, 0x25, 0x15, 0xcd, 0xab, 0x18, 0x24, 0x07, 0x01, 0x24, 0x08, 0x01, 0x30, 0x09,
+        0x41, 0x04, 0xbe, 0xfc, 0x50, 0x15, 0x43, 0x1e, 0x25, 0x0c, 0x3e, 0x8f, 0x84, 0xcf, 0xd0,
+        0x13, 0x64, 0xe0, 0x45, 0x7b, 0x66, 0x2e, 0x49, 0x26, 0x67, 0xb1, 0x72, 0x70, 0x18, 0x71,
+        0x9b, 0x60, 0xff, 0xbe, 0x3f, 0x0d, 0xe3, 0xfb, 0x67, 0x2d, 0x2e, 0x6d, 0xfc, 0xfb, 0x2a,
+        0xb7, 0x7f, 0xec, 0x3b, 0x6d, 0x13, 0x8b, 0x53, 0x05, 0xe3, 0x98, 0x70, 0xd1, 0xd1, 0xe6,
+        0x6c, 0xd4, 0x88, 0x79, 0x96, 0x9a, 0x37, 0x0a, 0x35, 0x01, 0x28, 0x01, 0x18, 0x24, 0x02,
+        0x01, 0x36, 0x03, 0x04, 0x02, 0x04, 0x01, 0x18, 0x30, 0x04, 0x14, 0x33, 0x33, 0x33, 0x33,
+        0x33, 0x33, 0x33, 0x33, 0x33, 0x33, 0x33, 0x33, 0x33, 0x33, 0x33, 0x33, 0x33, 0x33, 0x33,
+        0x33, 0x30, 0x05, 0x14, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22,
+        0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x18, 0x30, 0x0b, 0x40, 0xcc, 0xd4,
+        0xc7, 0xd3, 0x3b, 0x64, 0x3b, 0xc6, 0xbb, 0x52, 0xbb, 0x5c, 0xd1, 0xcb, 0x23, 0xfe, 0xae,
+        0x1f, 0x7b, 0x22, 0xd9, 0xb9, 0x4e, 0xe4, 0xa7, 0xb7, 0x8e, 0xdb, 0xdd, 0x98, 0xab, 0x0b,
+        0x9f, 0xf5, 0x2b, 0x2d, 0xfe, 0xaf, 0x1c, 0x59, 0x5c, 0x56, 0xcf, 0x3b, 0xae, 0x34, 0x2e,
+        0x2f, 0x53, 0x8b, 0x55, 0x3a, 0x4e, 0x18, 0x20, 0xe7, 0x5b, 0x71, 0xca, 0x95, 0x04, 0x6f,
+        0x26, 0x18, 0x18,
+    ];
+    pub const NOC1_PUBKEY: [u8; 65] = [
+        0x04, 0xbe, 0xfc, 0x50, 0x15, 0x43, 0x1e, 0x25, 0x0c, 0x3e, 0x8f, 0x84, 0xcf, 0xd0, 0x13,
+        0x64, 0xe0, 0x45, 0x7b, 0x66, 0x2e, 0x49, 0x26, 0x67, 0xb1, 0x72, 0x70, 0x18, 0x71, 0x9b,
+        0x60, 0xff, 0xbe, 0x3f, 0x0d, 0xe3, 0xfb, 0x67, 0x2d, 0x2e, 0x6d, 0xfc, 0xfb, 0x2a, 0xb7,
+        0x7f, 0xec, 0x3b, 0x6d, 0x13, 0x8b, 0x53, 0x05, 0xe3, 0x98, 0x70, 0xd1, 0xd1, 0xe6, 0x6c,
+        0xd4, 0x88, 0x79, 0x96, 0x9a,
+    ];
+    pub const NOC1_PRIVKEY: [u8; 32] = [
+        0x15, 0x36, 0x6d, 0x31, 0x53, 0xaf, 0x40, 0x5a, 0x18, 0xa8, 0xb0, 0x21, 0x54, 0x7d, 0xd8,
+        0xf6, 0x9a, 0x5d, 0x4b, 0x81, 0x25, 0x39, 0x64, 0xb4, 0x78, 0x8f, 0x83, 0xa9, 0x89, 0x3e,
+        0x1c, 0x52,
+    ];
+    pub const NOC2: [u8; 243] = [
+        0x15, 0x30, 0x01, 0x01, 0x04, 0x24, 0x02, 0x01, 0x37, 0x03, 0x24, 0x13, 0x02, 0x18, 0x26,
+        0x04, 0x80, 0x22, 0x81, 0x27, 0x26, 0x05, 0x80, 0x25, 0x4d, 0x3a, 0x37, 0x06, 0x25, 0x11,
+        0x22, 0x22, 0x25, 0x15, 0xcd, 0xab, 0x18, 0x24, 0x07, 0x01, 0x24, 0x08, 0x01, 0x30, 0x09,
+        0x41, 0x04, 0xc4, 0x17, 0x07, 0x0d, 0xcf, 0xca, 0x06, 0x11, 0xb1, 0x6b, 0x74, 0xed, 0x74,
+        0x8f, 0xb5, 0x90, 0xd4, 0xa7, 0xab, 0xb9, 0x72, 0xdd, 0xf4, 0x25, 0xda, 0xc2, 0x33, 0x0a,
+        0xfb, 0x00, 0x35, 0x93, 0x0a, 0x06, 0x59, 0xce, 0x45, 0x6f, 0x8a, 0x40, 0xce, 0xa8, 0xd7,
+        0xec, 0x8a, 0xf5, 0x1c, 0x03, 0xce, 0xf8, 0x9a, 0xa4, 0xe9, 0xf4, 0x75, 0x69, 0xda, 0x5e,
+        0xd9, 0x81, 0x79, 0x2d, 0x8c, 0x9f, 0x37, 0x0a, 0x35, 0x01, 0x28, 0x01, 0x18, 0x24, 0x02,
+        0x01, 0x36, 0x03, 0x04, 0x02, 0x04, 0x01, 0x18, 0x30, 0x04, 0x14, 0x44, 0x44, 0x44, 0x44,
+        0x44, 0x44, 0x44, 0x44, 0x44, 0x44, 0x44, 0x44, 0x44, 0x44, 0x44, 0x44, 0x44, 0x44, 0x44,
+        0x44, 0x30, 0x05, 0x14, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22,
+        0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x18, 0x30, 0x0b, 0x40, 0x77, 0x1b,
+        0xfa, 0xfd, 0x21, 0x1a, 0x48, 0x24, 0x4a, 0x1f, 0x0c, 0x93, 0x75, 0x4b, 0x04, 0x5b, 0xdd,
+        0x9b, 0xde, 0xd7, 0xac, 0x2f, 0x76, 0xe3, 0x7a, 0xec, 0x63, 0xbe, 0xa3, 0x89, 0x49, 0x9d,
+        0xd9, 0xd7, 0x6e, 0x12, 0x46, 0x85, 0x40, 0x80, 0xf4, 0x02, 0xc2, 0x1d, 0x6b, 0xfa, 0x66,
+        0x3f, 0xf4, 0x21, 0x13, 0x99, 0xbc, 0xb9, 0xf4, 0x2c, 0xbf, 0xa9, 0xf8, 0x43, 0x18, 0xfa,
+        0x25, 0xa3, 0x18,
+    ];
+    pub const NOC2_PUBKEY: [u8; 65] = [
+        0x04, 0xc4, 0x17, 0x07, 0x0d, 0xcf, 0xca, 0x06, 0x11, 0xb1, 0x6b, 0x74, 0xed, 0x74, 0x8f,
+        0xb5, 0x90, 0xd4, 0xa7, 0xab, 0xb9, 0x72, 0xdd, 0xf4, 0x25, 0xda, 0xc2, 0x33, 0x0a, 0xfb,
+        0x00, 0x35, 0x93, 0x0a, 0x06, 0x59, 0xce, 0x45, 0x6f, 0x8a, 0x40, 0xce, 0xa8, 0xd7, 0xec,
+        0x8a, 0xf5, 0x1c, 0x03, 0xce, 0xf8, 0x9a, 0xa4, 0xe9, 0xf4, 0x75, 0x69, 0xda, 0x5e, 0xd9,
+        0x81, 0x79, 0x2d, 0x8c, 0x9f,
+    ];
+    pub const NOC2_PRIVKEY: [u8; 32] = [
+        0x2a, 0x38, 0x91, 0xf4, 0x10, 0x07, 0x37, 0x39, 0x62, 0x5b, 0x19, 0x91, 0xd2, 0x98, 0x4e,
+        0x35, 0xe8, 0x53, 0xeb, 0x70, 0x12, 0x1a, 0x01, 0x3f, 0x5f, 0x2f, 0x3f, 0x32, 0x52, 0x79,
+        0xf2, 0x33,
+    ];
+}
@@ -32,6 +32,8 @@ pub enum SCStatusCodes {
     CloseSession = 3,
     Busy = 4,
     SessionNotFound = 5,
+    /// Only the general code of the status report says what went wrong
+    GeneralFailure = 0xFFFF,
 }
 
 pub fn create_sc_status_report(
@@ -47,10 +49,11 @@ pub fn create_sc_status_report(
             // the session will be closed soon
             GeneralCode::Success
         }
-        SCStatusCodes::Busy
-        | SCStatusCodes::InvalidParameter
+        SCStatusCodes::Busy => GeneralCode::Busy,
+        SCStatusCodes::InvalidParameter
         | SCStatusCodes::NoSharedTrustRoots
-        | SCStatusCodes::SessionNotFound => GeneralCode::Failure,
+        | SCStatusCodes::SessionNotFound
+        | SCStatusCodes::GeneralFailure => GeneralCode::Failure,
     };
     create_status_report(
         proto_tx,
@@ -61,8 +64,79 @@ pub fn create_sc_status_report(
     )
 }
 
+/// Creates the status report that tells the peer why a leg of the session
+/// establishment failed with `e`
+pub fn create_sc_error_report(proto_tx: &mut Packet, e: Error) -> Result<(), Error> {
+    let general_code = match e {
+        Error::NotFound | Error::NoFabricId => {
+            return create_sc_status_report(proto_tx, SCStatusCodes::NoSharedTrustRoots, None)
+        }
+        Error::NoSession => {
+            return create_sc_status_report(proto_tx, SCStatusCodes::SessionNotFound, None)
+        }
+        Error::InvalidState | Error::InvalidOpcode => GeneralCode::Unexpected,
+        Error::NoSpace
+        | Error::NoSpaceAckTable
+        | Error::NoSpaceRetransTable
+        | Error::PacketPoolExhaust => GeneralCode::ResourceExhausted,
+        _ => return create_sc_status_report(proto_tx, SCStatusCodes::InvalidParameter, None),
+    };
+    create_status_report(
+        proto_tx,
+        general_code,
+        PROTO_ID_SECURE_CHANNEL as u32,
+        SCStatusCodes::GeneralFailure as u16,
+        None,
+    )
+}
+
 pub fn create_mrp_standalone_ack(proto_tx: &mut Packet) {
     proto_tx.set_proto_id(PROTO_ID_SECURE_CHANNEL as u16);
     proto_tx.set_proto_opcode(OpCode::MRPStandAloneAck as u8);
     proto_tx.unset_reliable();
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        error::Error,
+        secure_channel::status_report::{GeneralCode, StatusReport},
+        transport::packet::Packet,
+    };
+
+    use super::{create_sc_error_report, create_sc_status_report, SCStatusCodes};
+
+    fn error_report(e: Error) -> StatusReport {
+        let mut tx = Packet::new_tx().unwrap();
+        create_sc_error_report(&mut tx, e).unwrap();
+        StatusReport::parse(tx.as_borrow_slice()).unwrap()
+    }
+
+    #[test]
+    fn test_sc_error_report() {
+        let report = error_report(Error::NotFound);
+        assert_eq!(report.general_code, GeneralCode::Failure as u16);
+        assert_eq!(report.proto_code, SCStatusCodes::NoSharedTrustRoots as u16);
+
+        let report = error_report(Error::NoSession);
+        assert_eq!(report.proto_code, SCStatusCodes::SessionNotFound as u16);
+
+        let report = error_report(Error::InvalidSignature);
+        assert_eq!(report.general_code, GeneralCode::Failure as u16);
+        assert_eq!(report.proto_code, SCStatusCodes::InvalidParameter as u16);
+
+        let report = error_report(Error::InvalidState);
+        assert_eq!(report.general_code, GeneralCode::Unexpected as u16);
+        assert_eq!(report.proto_code, SCStatusCodes::GeneralFailure as u16);
+
+        let report = error_report(Error::NoSpace);
+        assert_eq!(report.general_code, GeneralCode::ResourceExhausted as u16);
+        assert!(!report.is_success());
+
+        let mut tx = Packet::new_tx().unwrap();
+        create_sc_status_report(&mut tx, SCStatusCodes::Busy, Some(&[0xf4, 0x01])).unwrap();
+        let report = StatusReport::parse(tx.as_borrow_slice()).unwrap();
+        assert_eq!(report.general_code, GeneralCode::Busy as u16);
+        assert_eq!(report.proto_code, SCStatusCodes::Busy as u16);
+    }
+}
@@ -3,14 +3,17 @@ use std::sync::Arc;
 use crate::{
     error::*,
     fabric::FabricMgr,
-    mdns::{self, Mdns},
-    secure_channel::{common::*, pake::PAKE},
-    sys::SysMdnsService,
-    transport::proto_demux::{self, ProtoCtx, ResponseRequired},
+    secure_channel::{
+        common::*,
+        pake::{PaseInitiator, PaseMgr},
+    },
+    transport::{
+        exchange::Exchange,
+        proto_demux::{self, ProtoCtx, ResponseRequired},
+    },
 };
 use log::{error, info};
 use num;
-use rand::prelude::*;
 
 use super::case::Case;
 
@@ -19,27 +22,29 @@ use super::case::Case;
 
 pub struct SecureChannel {
     case: Case,
-    pake: Option<(PAKE, SysMdnsService)>,
+    pase_mgr: PaseMgr,
 }
 
 impl SecureChannel {
-    pub fn new(fabric_mgr: Arc<FabricMgr>) -> SecureChannel {
+    pub fn new(fabric_mgr: Arc<FabricMgr>, pase_mgr: PaseMgr) -> SecureChannel {
         SecureChannel {
-            pake: None,
+            pase_mgr,
             case: Case::new(fabric_mgr),
         }
     }
 
-    pub fn open_comm_window(&mut self, salt: &[u8; 16], passwd: u32) -> Result<(), Error> {
-        let name: u64 = rand::thread_rng().gen_range(0..0xFFFFFFFFFFFFFFFF);
-        let name = format!("{:016X}", name);
-        let mdns = Mdns::get()?.publish_service(&name, mdns::ServiceMode::Commissionable)?;
-        self.pake = Some((PAKE::new(salt, passwd), mdns));
-        Ok(())
-    }
-
-    pub fn close_comm_window(&mut self) {
-        self.pake = None;
+    // Answers a failed leg with a status report, and drops the exchange along with
+    // the state of the establishment
+    fn report_failure(
+        ctx: &mut ProtoCtx,
+        tail: usize,
+        e: Error,
+    ) -> Result<ResponseRequired, Error> {
+        error!("Session establishment failed: {:?}", e);
+        ctx.tx.get_writebuf()?.rewind_tail_to(tail);
+        create_sc_error_report(&mut ctx.tx, e)?;
+        ctx.exch_ctx.exch.close();
+        Ok(ResponseRequired::Yes)
     }
 
     fn mrpstandaloneack_handler(&mut self, _ctx: &mut ProtoCtx) -> Result<ResponseRequired, Error> {
@@ -50,11 +55,15 @@ impl SecureChannel {
     fn pbkdfparamreq_handler(&mut self, ctx: &mut ProtoCtx) -> Result<ResponseRequired, Error> {
         info!("In PBKDF Param Request Handler");
         ctx.tx.set_proto_opcode(OpCode::PBKDFParamResponse as u8);
-        if let Some((pake, _)) = &mut self.pake {
-            pake.handle_pbkdfparamrequest(ctx)?;
+        if let Some(result) = self
+            .pase_mgr
+            .with_pake(|pake| pake.handle_pbkdfparamrequest(ctx))
+        {
+            result?;
         } else {
             error!("PASE Not enabled");
             create_sc_status_report(&mut ctx.tx, SCStatusCodes::InvalidParameter, None)?;
+            ctx.exch_ctx.exch.close();
         }
         Ok(ResponseRequired::Yes)
     }
@@ -62,28 +71,44 @@ impl SecureChannel {
     fn pasepake1_handler(&mut self, ctx: &mut ProtoCtx) -> Result<ResponseRequired, Error> {
         info!("In PASE Pake1 Handler");
         ctx.tx.set_proto_opcode(OpCode::PASEPake2 as u8);
-        if let Some((pake, _)) = &mut self.pake {
-            pake.handle_pasepake1(ctx)?;
+        if let Some(result) = self.pase_mgr.with_pake(|pake| pake.handle_pasepake1(ctx)) {
+            result?;
         } else {
             error!("PASE Not enabled");
             create_sc_status_report(&mut ctx.tx, SCStatusCodes::InvalidParameter, None)?;
+            ctx.exch_ctx.exch.close();
         }
         Ok(ResponseRequired::Yes)
     }
 
     fn pasepake3_handler(&mut self, ctx: &mut ProtoCtx) -> Result<ResponseRequired, Error> {
         info!("In PASE Pake3 Handler");
-        if let Some((pake, _)) = &mut self.pake {
-            pake.handle_pasepake3(ctx)?;
+        if let Some(result) = self.pase_mgr.with_pake(|pake| pake.handle_pasepake3(ctx)) {
+            result?;
             // TODO: Currently we assume that PAKE is not successful and reset the PAKE object
-            self.pake = None;
+            self.pase_mgr.close_comm_window();
         } else {
             error!("PASE Not enabled");
             create_sc_status_report(&mut ctx.tx, SCStatusCodes::InvalidParameter, None)?;
+            ctx.exch_ctx.exch.close();
         }
         Ok(ResponseRequired::Yes)
     }
 
+    fn pbkdfparamresp_handler(&mut self, ctx: &mut ProtoCtx) -> Result<ResponseRequired, Error> {
+        info!("In PBKDF Param Response Handler");
+        ctx.tx.set_proto_opcode(OpCode::PASEPake1 as u8);
+        PaseInitiator::handle_pbkdfparamresponse(ctx)?;
+        Ok(ResponseRequired::Yes)
+    }
+
+    fn pasepake2_handler(&mut self, ctx: &mut ProtoCtx) -> Result<ResponseRequired, Error> {
+        info!("In PASE Pake2 Handler");
+        ctx.tx.set_proto_opcode(OpCode::PASEPake3 as u8);
+        PaseInitiator::handle_pasepake2(ctx)?;
+        Ok(ResponseRequired::Yes)
+    }
+
     fn casesigma1_handler(&mut self, ctx: &mut ProtoCtx) -> Result<ResponseRequired, Error> {
         info!("In CASE Sigma1 Handler");
         ctx.tx.set_proto_opcode(OpCode::CASESigma2 as u8);
@@ -91,11 +116,28 @@ impl SecureChannel {
         Ok(ResponseRequired::Yes)
     }
 
+    fn casesigma2_handler(&mut self, ctx: &mut ProtoCtx) -> Result<ResponseRequired, Error> {
+        info!("In CASE Sigma2 Handler");
+        ctx.tx.set_proto_opcode(OpCode::CASESigma3 as u8);
+        self.case.handle_casesigma2(ctx)?;
+        Ok(ResponseRequired::Yes)
+    }
+
     fn casesigma3_handler(&mut self, ctx: &mut ProtoCtx) -> Result<ResponseRequired, Error> {
         info!("In CASE Sigma3 Handler");
         self.case.handle_casesigma3(ctx)?;
         Ok(ResponseRequired::Yes)
     }
+
+    fn status_report_handler(&mut self, ctx: &mut ProtoCtx) -> Result<ResponseRequired, Error> {
+        info!("In Status Report Handler");
+        if PaseInitiator::is_initiator_exchange(ctx.exch_ctx.exch) {
+            PaseInitiator::handle_status_report(ctx)?;
+        } else {
+            self.case.handle_status_report(ctx)?;
+        }
+        Ok(ResponseRequired::No)
+    }
 }
 
 impl proto_demux::HandleProto for SecureChannel {
@@ -104,20 +146,38 @@ impl proto_demux::HandleProto for SecureChannel {
             num::FromPrimitive::from_u8(ctx.rx.get_proto_opcode()).ok_or(Error::Invalid)?;
         ctx.tx.set_proto_id(PROTO_ID_SECURE_CHANNEL as u16);
         match proto_opcode {
-            OpCode::MRPStandAloneAck => self.mrpstandaloneack_handler(ctx),
+            // Nothing answers these
+            OpCode::MRPStandAloneAck => return self.mrpstandaloneack_handler(ctx),
+            OpCode::StatusReport => return self.status_report_handler(ctx),
+            _ => (),
+        }
+        let tail = ctx.tx.get_writebuf()?.get_tail();
+        let result = match proto_opcode {
             OpCode::PBKDFParamRequest => self.pbkdfparamreq_handler(ctx),
+            OpCode::PBKDFParamResponse => self.pbkdfparamresp_handler(ctx),
             OpCode::PASEPake1 => self.pasepake1_handler(ctx),
+            OpCode::PASEPake2 => self.pasepake2_handler(ctx),
             OpCode::PASEPake3 => self.pasepake3_handler(ctx),
             OpCode::CASESigma1 => self.casesigma1_handler(ctx),
+            OpCode::CASESigma2 => self.casesigma2_handler(ctx),
             OpCode::CASESigma3 => self.casesigma3_handler(ctx),
             _ => {
                 error!("OpCode Not Handled: {:?}", proto_opcode);
                 Err(Error::InvalidOpcode)
             }
-        }
+        };
+        result.or_else(|e| SecureChannel::report_failure(ctx, tail, e))
     }
 
     fn get_proto_id(&self) -> usize {
         PROTO_ID_SECURE_CHANNEL as usize
     }
+
+    fn handle_response_timeout(&mut self, exch: &mut Exchange) {
+        if PaseInitiator::is_initiator_exchange(exch) {
+            PaseInitiator::handle_response_timeout(exch);
+        } else {
+            Case::handle_response_timeout(exch);
+        }
+    }
 }
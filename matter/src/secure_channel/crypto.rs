@@ -1,15 +1,29 @@
 use crate::error::Error;
 
 // This trait allows us to switch between crypto providers like OpenSSL and mbedTLS for Spake2
-// Currently this is only validate for a verifier(responder)
+// It serves both the verifier(responder) and the prover(initiator)
 
 // A verifier will typically do:
 // Step 1: w0 and L
 //      set_w0_from_w0s
 //      set_L
+//   or, if the w0 and L of a verifier are already known
+//      set_w0
+//      set_L_direct
+//
+// The w0 and L of a verifier are computed with set_w0_from_w0s and set_L, and
+// read back with get_w0 and get_L
 // Step 2: get_pB
 // Step 3: get_TT_as_verifier(pA)
 // Step 4: Computation of cA and cB happens outside since it doesn't use either BigNum or EcPoint
+//
+// A prover will typically do:
+// Step 1: w0 and w1
+//      set_w0_from_w0s
+//      set_w1_from_w1s
+// Step 2: get_pA
+// Step 3: get_TT_as_prover(pB)
+// Step 4: Computation of cA and cB happens outside, as for the verifier
 pub trait CryptoSpake2 {
     fn new() -> Result<Self, Error>
     where
@@ -23,8 +37,23 @@ pub trait CryptoSpake2 {
     #[allow(non_snake_case)]
     fn set_L(&mut self, w1s: &[u8]) -> Result<(), Error>;
     #[allow(non_snake_case)]
+    fn set_L_direct(&mut self, L: &[u8]) -> Result<(), Error>;
+    fn get_w0(&mut self, w0: &mut [u8]) -> Result<(), Error>;
+    #[allow(non_snake_case)]
+    fn get_L(&mut self, L: &mut [u8]) -> Result<(), Error>;
+    #[allow(non_snake_case)]
+    fn get_pA(&mut self, pA: &mut [u8]) -> Result<(), Error>;
+    #[allow(non_snake_case)]
     fn get_pB(&mut self, pB: &mut [u8]) -> Result<(), Error>;
     #[allow(non_snake_case)]
+    fn get_TT_as_prover(
+        &mut self,
+        context: &[u8],
+        pA: &[u8],
+        pB: &[u8],
+        out: &mut [u8],
+    ) -> Result<(), Error>;
+    #[allow(non_snake_case)]
     fn get_TT_as_verifier(
         &mut self,
         context: &[u8],
@@ -61,6 +61,33 @@ impl CryptoSpake2 for CryptoEspMbedTls {
         Ok(())
     }
 
+    #[allow(non_snake_case)]
+    fn set_L_direct(&mut self, L: &[u8]) -> Result<(), Error> {
+        // Not implemented yet, so no verifier is made up
+        Err(Error::Invalid)
+    }
+
+    fn get_w0(&mut self, w0: &mut [u8]) -> Result<(), Error> {
+        Err(Error::Invalid)
+    }
+
+    #[allow(non_snake_case)]
+    fn get_L(&mut self, L: &mut [u8]) -> Result<(), Error> {
+        Err(Error::Invalid)
+    }
+
+    #[allow(non_snake_case)]
+    fn get_pA(&mut self, pA: &mut [u8]) -> Result<(), Error> {
+        // From the SPAKE2+ spec (https://datatracker.ietf.org/doc/draft-bar-cfrg-spake2plus/)
+        //   for x
+        //   - select random x between 0 to p
+        //   - X = x*P + w0*M
+        //   - pA = X
+
+        // Not implemented yet, so no initiator starts with a zero pA
+        Err(Error::Invalid)
+    }
+
     #[allow(non_snake_case)]
     fn get_pB(&mut self, pB: &mut [u8]) -> Result<(), Error> {
         // From the SPAKE2+ spec (https://datatracker.ietf.org/doc/draft-bar-cfrg-spake2plus/)
@@ -72,6 +99,17 @@ impl CryptoSpake2 for CryptoEspMbedTls {
         Ok(())
     }
 
+    #[allow(non_snake_case)]
+    fn get_TT_as_prover(
+        &mut self,
+        context: &[u8],
+        pA: &[u8],
+        pB: &[u8],
+        out: &mut [u8],
+    ) -> Result<(), Error> {
+        Err(Error::Invalid)
+    }
+
     #[allow(non_snake_case)]
     fn get_TT_as_verifier(
         &mut self,
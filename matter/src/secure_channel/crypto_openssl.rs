@@ -112,6 +112,68 @@ impl CryptoSpake2 for CryptoOpenSSL {
         Ok(())
     }
 
+    #[allow(non_snake_case)]
+    fn set_L_direct(&mut self, L: &[u8]) -> Result<(), Error> {
+        self.L = EcPoint::from_bytes(&self.group, L, &mut self.bn_ctx)?;
+        Ok(())
+    }
+
+    fn get_w0(&mut self, w0: &mut [u8]) -> Result<(), Error> {
+        let w0_internal = self.w0.to_vec_padded(w0.len() as i32)?;
+        if w0_internal.len() != w0.len() {
+            error!("w0 length mismatch");
+            return Err(Error::Invalid);
+        }
+        w0.copy_from_slice(&w0_internal);
+        Ok(())
+    }
+
+    #[allow(non_snake_case)]
+    fn get_L(&mut self, L: &mut [u8]) -> Result<(), Error> {
+        let L_internal = self.L.to_bytes(
+            &self.group,
+            PointConversionForm::UNCOMPRESSED,
+            &mut self.bn_ctx,
+        )?;
+        if L_internal.len() != L.len() {
+            error!("L length mismatch");
+            return Err(Error::Invalid);
+        }
+        L.copy_from_slice(&L_internal);
+        Ok(())
+    }
+
+    #[allow(non_snake_case)]
+    fn get_pA(&mut self, pA: &mut [u8]) -> Result<(), Error> {
+        // From the SPAKE2+ spec (https://datatracker.ietf.org/doc/draft-bar-cfrg-spake2plus/)
+        //   for x
+        //   - select random x between 0 to p
+        //   - X = x*P + w0*M
+        //   - pA = X
+        self.order.rand_range(&mut self.xy)?;
+        let P = self.group.generator();
+        let pA_internal = CryptoOpenSSL::do_add_mul(
+            P,
+            &self.xy,
+            &self.M,
+            &self.w0,
+            &self.group,
+            &mut self.bn_ctx,
+        )?;
+        let pA_internal = pA_internal.to_bytes(
+            &self.group,
+            PointConversionForm::UNCOMPRESSED,
+            &mut self.bn_ctx,
+        )?;
+        let pA_internal = pA_internal.as_slice();
+        if pA_internal.len() != pA.len() {
+            error!("pA length mismatch");
+            return Err(Error::Invalid);
+        }
+        pA.copy_from_slice(pA_internal);
+        Ok(())
+    }
+
     #[allow(non_snake_case)]
     fn get_pB(&mut self, pB: &mut [u8]) -> Result<(), Error> {
         // From the SPAKE2+ spec (https://datatracker.ietf.org/doc/draft-bar-cfrg-spake2plus/)
@@ -143,6 +205,28 @@ impl CryptoSpake2 for CryptoOpenSSL {
         Ok(())
     }
 
+    #[allow(non_snake_case)]
+    fn get_TT_as_prover(
+        &mut self,
+        context: &[u8],
+        pA: &[u8],
+        pB: &[u8],
+        TT_hash: &mut [u8],
+    ) -> Result<(), Error> {
+        let Y = EcPoint::from_bytes(&self.group, pB, &mut self.bn_ctx)?;
+        let (Z, V) = CryptoOpenSSL::get_ZV_as_prover(
+            &self.w0,
+            &self.w1,
+            &mut self.N,
+            &Y,
+            &self.xy,
+            &self.order,
+            &self.group,
+            &mut self.bn_ctx,
+        )?;
+        self.get_TT(context, pA, pB, &Z, &V, TT_hash)
+    }
+
     #[allow(non_snake_case)]
     fn get_TT_as_verifier(
         &mut self,
@@ -150,6 +234,34 @@ impl CryptoSpake2 for CryptoOpenSSL {
         pA: &[u8],
         pB: &[u8],
         TT_hash: &mut [u8],
+    ) -> Result<(), Error> {
+        let X = EcPoint::from_bytes(&self.group, pA, &mut self.bn_ctx)?;
+        let (Z, V) = CryptoOpenSSL::get_ZV_as_verifier(
+            &self.w0,
+            &self.L,
+            &mut self.M,
+            &X,
+            &self.xy,
+            &self.order,
+            &self.group,
+            &mut self.bn_ctx,
+        )?;
+        self.get_TT(context, pA, pB, &Z, &V, TT_hash)
+    }
+}
+
+impl CryptoOpenSSL {
+    // The TT is the same for both the prover and the verifier, only Z and V are
+    // computed differently
+    #[allow(non_snake_case)]
+    fn get_TT(
+        &mut self,
+        context: &[u8],
+        pA: &[u8],
+        pB: &[u8],
+        Z: &EcPoint,
+        V: &EcPoint,
+        TT_hash: &mut [u8],
     ) -> Result<(), Error> {
         let mut TT = Hasher::new(MessageDigest::sha256())?;
         // context
@@ -166,18 +278,6 @@ impl CryptoSpake2 for CryptoOpenSSL {
         // Y = pB
         CryptoOpenSSL::add_to_tt(&mut TT, pB)?;
 
-        let X = EcPoint::from_bytes(&self.group, pA, &mut self.bn_ctx)?;
-        let (Z, V) = CryptoOpenSSL::get_ZV_as_verifier(
-            &self.w0,
-            &self.L,
-            &mut self.M,
-            &X,
-            &self.xy,
-            &self.order,
-            &self.group,
-            &mut self.bn_ctx,
-        )?;
-
         // Z
         let tmp = Z.to_bytes(
             &self.group,
@@ -205,9 +305,7 @@ impl CryptoSpake2 for CryptoOpenSSL {
         TT_hash.copy_from_slice(h.as_ref());
         Ok(())
     }
-}
 
-impl CryptoOpenSSL {
     fn add_to_tt(tt: &mut Hasher, buf: &[u8]) -> Result<(), Error> {
         let mut len_buf: [u8; 8] = [0; 8];
         LittleEndian::write_u64(&mut len_buf, buf.len() as u64);
@@ -239,7 +337,6 @@ impl CryptoOpenSSL {
 
     #[inline(always)]
     #[allow(non_snake_case)]
-    #[allow(dead_code)]
     fn get_ZV_as_prover(
         w0: &BigNum,
         w1: &BigNum,
@@ -312,6 +409,7 @@ impl CryptoOpenSSL {
 mod tests {
 
     use super::CryptoOpenSSL;
+    use crate::crypto;
     use crate::secure_channel::crypto::CryptoSpake2;
     use crate::secure_channel::spake2p_test_vectors::test_vectors::*;
     use openssl::bn::BigNum;
@@ -425,4 +523,62 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn test_get_TT() {
+        // The only vector without identifiers, as the Matter TT has none
+        let t = &RFC_T[3];
+        let context = b"SPAKE2+-P256-SHA256-HKDF draft-01";
+        let mut expected = [0u8; crypto::SHA256_HASH_LEN_BYTES];
+        let mut h = crypto::Sha256::new().unwrap();
+        h.update(&t.TT[0..t.TT_len]).unwrap();
+        h.finish(&mut expected).unwrap();
+
+        let mut prover = CryptoOpenSSL::new().unwrap();
+        prover.set_w0(&t.w0).unwrap();
+        prover.set_w1(&t.w1).unwrap();
+        prover.xy = BigNum::from_slice(&t.x).unwrap();
+        let mut TT_hash = [0u8; crypto::SHA256_HASH_LEN_BYTES];
+        prover
+            .get_TT_as_prover(context, &t.X, &t.Y, &mut TT_hash)
+            .unwrap();
+        assert_eq!(TT_hash, expected);
+
+        let mut verifier = CryptoOpenSSL::new().unwrap();
+        verifier.set_w0(&t.w0).unwrap();
+        verifier.set_L_direct(&t.L).unwrap();
+        verifier.xy = BigNum::from_slice(&t.y).unwrap();
+        let mut TT_hash = [0u8; crypto::SHA256_HASH_LEN_BYTES];
+        verifier
+            .get_TT_as_verifier(context, &t.X, &t.Y, &mut TT_hash)
+            .unwrap();
+        assert_eq!(TT_hash, expected);
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn test_prover_and_verifier() {
+        let t = &RFC_T[0];
+        let mut prover = CryptoOpenSSL::new().unwrap();
+        prover.set_w0(&t.w0).unwrap();
+        prover.set_w1(&t.w1).unwrap();
+        let mut verifier = CryptoOpenSSL::new().unwrap();
+        verifier.set_w0(&t.w0).unwrap();
+        verifier.set_L_direct(&t.L).unwrap();
+
+        let mut pA = [0u8; 65];
+        let mut pB = [0u8; 65];
+        prover.get_pA(&mut pA).unwrap();
+        verifier.get_pB(&mut pB).unwrap();
+        let mut prover_TT = [0u8; crypto::SHA256_HASH_LEN_BYTES];
+        let mut verifier_TT = [0u8; crypto::SHA256_HASH_LEN_BYTES];
+        prover
+            .get_TT_as_prover(&[], &pA, &pB, &mut prover_TT)
+            .unwrap();
+        verifier
+            .get_TT_as_verifier(&[], &pA, &pB, &mut verifier_TT)
+            .unwrap();
+        assert_eq!(prover_TT, verifier_TT);
+    }
 }
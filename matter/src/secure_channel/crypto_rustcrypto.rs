@@ -0,0 +1,409 @@
+use crate::error::Error;
+
+use super::crypto::CryptoSpake2;
+use byteorder::{ByteOrder, LittleEndian};
+use log::error;
+use p256::{
+    elliptic_curve::{
+        ff::PrimeField,
+        sec1::{FromEncodedPoint, ToEncodedPoint},
+    },
+    EncodedPoint, NonZeroScalar, ProjectivePoint, Scalar,
+};
+use rand::rngs::OsRng;
+use sha2::{Digest, Sha256};
+
+const MATTER_M_BIN: [u8; 65] = [
+    0x04, 0x88, 0x6e, 0x2f, 0x97, 0xac, 0xe4, 0x6e, 0x55, 0xba, 0x9d, 0xd7, 0x24, 0x25, 0x79, 0xf2,
+    0x99, 0x3b, 0x64, 0xe1, 0x6e, 0xf3, 0xdc, 0xab, 0x95, 0xaf, 0xd4, 0x97, 0x33, 0x3d, 0x8f, 0xa1,
+    0x2f, 0x5f, 0xf3, 0x55, 0x16, 0x3e, 0x43, 0xce, 0x22, 0x4e, 0x0b, 0x0e, 0x65, 0xff, 0x02, 0xac,
+    0x8e, 0x5c, 0x7b, 0xe0, 0x94, 0x19, 0xc7, 0x85, 0xe0, 0xca, 0x54, 0x7d, 0x55, 0xa1, 0x2e, 0x2d,
+    0x20,
+];
+const MATTER_N_BIN: [u8; 65] = [
+    0x04, 0xd8, 0xbb, 0xd6, 0xc6, 0x39, 0xc6, 0x29, 0x37, 0xb0, 0x4d, 0x99, 0x7f, 0x38, 0xc3, 0x77,
+    0x07, 0x19, 0xc6, 0x29, 0xd7, 0x01, 0x4d, 0x49, 0xa2, 0x4b, 0x4f, 0x98, 0xba, 0xa1, 0x29, 0x2b,
+    0x49, 0x07, 0xd6, 0x0a, 0xa6, 0xbf, 0xad, 0xe4, 0x50, 0x08, 0xa6, 0x36, 0x33, 0x7f, 0x51, 0x68,
+    0xc6, 0x4d, 0x9b, 0xd3, 0x60, 0x34, 0x80, 0x8c, 0xd5, 0x64, 0x49, 0x0b, 0x1e, 0x65, 0x6e, 0xdb,
+    0xe7,
+];
+
+#[allow(non_snake_case)]
+pub struct CryptoRustCrypto {
+    // Stores the randomly generated x or y depending upon who we are
+    xy: Scalar,
+    w0: Scalar,
+    w1: Scalar,
+    M: ProjectivePoint,
+    N: ProjectivePoint,
+    L: ProjectivePoint,
+    pB: ProjectivePoint,
+}
+
+impl CryptoSpake2 for CryptoRustCrypto {
+    #[allow(non_snake_case)]
+    fn new() -> Result<Self, Error> {
+        let M = CryptoRustCrypto::point_from_bytes(&MATTER_M_BIN)?;
+        let N = CryptoRustCrypto::point_from_bytes(&MATTER_N_BIN)?;
+
+        Ok(CryptoRustCrypto {
+            xy: Scalar::ZERO,
+            w0: Scalar::ZERO,
+            w1: Scalar::ZERO,
+            M,
+            N,
+            L: ProjectivePoint::IDENTITY,
+            pB: N,
+        })
+    }
+
+    // Computes w0 from w0s respectively
+    fn set_w0_from_w0s(&mut self, w0s: &[u8]) -> Result<(), Error> {
+        // From the Matter Spec,
+        //         w0 = w0s mod p
+        //   where p is the order of the curve
+        self.w0 = CryptoRustCrypto::scalar_from_bytes(w0s);
+        Ok(())
+    }
+
+    fn set_w1_from_w1s(&mut self, w1s: &[u8]) -> Result<(), Error> {
+        // From the Matter Spec,
+        //         w1 = w1s mod p
+        //   where p is the order of the curve
+        self.w1 = CryptoRustCrypto::scalar_from_bytes(w1s);
+        Ok(())
+    }
+
+    fn set_w0(&mut self, w0: &[u8]) -> Result<(), Error> {
+        self.w0 = CryptoRustCrypto::scalar_from_bytes(w0);
+        Ok(())
+    }
+
+    fn set_w1(&mut self, w1: &[u8]) -> Result<(), Error> {
+        self.w1 = CryptoRustCrypto::scalar_from_bytes(w1);
+        Ok(())
+    }
+
+    #[allow(non_snake_case)]
+    #[allow(dead_code)]
+    fn set_L(&mut self, w1s: &[u8]) -> Result<(), Error> {
+        // From the Matter spec,
+        //        L = w1 * P
+        //    where P is the generator of the underlying elliptic curve
+        self.set_w1_from_w1s(w1s)?;
+        self.L = ProjectivePoint::GENERATOR * self.w1;
+        Ok(())
+    }
+
+    #[allow(non_snake_case)]
+    fn set_L_direct(&mut self, L: &[u8]) -> Result<(), Error> {
+        self.L = CryptoRustCrypto::point_from_bytes(L)?;
+        Ok(())
+    }
+
+    fn get_w0(&mut self, w0: &mut [u8]) -> Result<(), Error> {
+        let w0_internal = self.w0.to_repr();
+        if w0_internal.len() != w0.len() {
+            error!("w0 length mismatch");
+            return Err(Error::Invalid);
+        }
+        w0.copy_from_slice(&w0_internal);
+        Ok(())
+    }
+
+    #[allow(non_snake_case)]
+    fn get_L(&mut self, L: &mut [u8]) -> Result<(), Error> {
+        CryptoRustCrypto::point_to_bytes(&self.L, L)
+    }
+
+    #[allow(non_snake_case)]
+    fn get_pA(&mut self, pA: &mut [u8]) -> Result<(), Error> {
+        // From the SPAKE2+ spec (https://datatracker.ietf.org/doc/draft-bar-cfrg-spake2plus/)
+        //   for x
+        //   - select random x between 0 to p
+        //   - X = x*P + w0*M
+        //   - pA = X
+        self.xy = *NonZeroScalar::random(&mut OsRng);
+        let pA_internal = ProjectivePoint::GENERATOR * self.xy + self.M * self.w0;
+        CryptoRustCrypto::point_to_bytes(&pA_internal, pA)
+    }
+
+    #[allow(non_snake_case)]
+    fn get_pB(&mut self, pB: &mut [u8]) -> Result<(), Error> {
+        // From the SPAKE2+ spec (https://datatracker.ietf.org/doc/draft-bar-cfrg-spake2plus/)
+        //   for y
+        //   - select random y between 0 to p
+        //   - Y = y*P + w0*N
+        //   - pB = Y
+        self.xy = *NonZeroScalar::random(&mut OsRng);
+        self.pB = ProjectivePoint::GENERATOR * self.xy + self.N * self.w0;
+        CryptoRustCrypto::point_to_bytes(&self.pB, pB)
+    }
+
+    #[allow(non_snake_case)]
+    fn get_TT_as_prover(
+        &mut self,
+        context: &[u8],
+        pA: &[u8],
+        pB: &[u8],
+        TT_hash: &mut [u8],
+    ) -> Result<(), Error> {
+        let Y = CryptoRustCrypto::point_from_bytes(pB)?;
+        let (Z, V) = CryptoRustCrypto::get_ZV_as_prover(&self.w0, &self.w1, &self.N, &Y, &self.xy);
+        self.get_TT(context, pA, pB, &Z, &V, TT_hash)
+    }
+
+    #[allow(non_snake_case)]
+    fn get_TT_as_verifier(
+        &mut self,
+        context: &[u8],
+        pA: &[u8],
+        pB: &[u8],
+        TT_hash: &mut [u8],
+    ) -> Result<(), Error> {
+        let X = CryptoRustCrypto::point_from_bytes(pA)?;
+        let (Z, V) = CryptoRustCrypto::get_ZV_as_verifier(&self.w0, &self.L, &self.M, &X, &self.xy);
+        self.get_TT(context, pA, pB, &Z, &V, TT_hash)
+    }
+}
+
+impl CryptoRustCrypto {
+    // The TT is the same for both the prover and the verifier, only Z and V are
+    // computed differently
+    #[allow(non_snake_case)]
+    fn get_TT(
+        &mut self,
+        context: &[u8],
+        pA: &[u8],
+        pB: &[u8],
+        Z: &ProjectivePoint,
+        V: &ProjectivePoint,
+        TT_hash: &mut [u8],
+    ) -> Result<(), Error> {
+        let mut TT = Sha256::new();
+        // context
+        CryptoRustCrypto::add_to_tt(&mut TT, context);
+        // 2 empty identifiers
+        CryptoRustCrypto::add_to_tt(&mut TT, &[]);
+        CryptoRustCrypto::add_to_tt(&mut TT, &[]);
+        // M
+        CryptoRustCrypto::add_to_tt(&mut TT, &MATTER_M_BIN);
+        // N
+        CryptoRustCrypto::add_to_tt(&mut TT, &MATTER_N_BIN);
+        // X = pA
+        CryptoRustCrypto::add_to_tt(&mut TT, pA);
+        // Y = pB
+        CryptoRustCrypto::add_to_tt(&mut TT, pB);
+
+        // Z
+        let tmp = Z.to_affine().to_encoded_point(false);
+        CryptoRustCrypto::add_to_tt(&mut TT, tmp.as_bytes());
+
+        // V
+        let tmp = V.to_affine().to_encoded_point(false);
+        CryptoRustCrypto::add_to_tt(&mut TT, tmp.as_bytes());
+
+        // w0, without the leading zeroes, like the BigNum of the other backends
+        let tmp = self.w0.to_repr();
+        let start = tmp.iter().position(|b| *b != 0).unwrap_or(tmp.len());
+        CryptoRustCrypto::add_to_tt(&mut TT, &tmp[start..]);
+
+        TT_hash.copy_from_slice(TT.finalize().as_slice());
+        Ok(())
+    }
+
+    fn add_to_tt(tt: &mut Sha256, buf: &[u8]) {
+        let mut len_buf: [u8; 8] = [0; 8];
+        LittleEndian::write_u64(&mut len_buf, buf.len() as u64);
+        tt.update(len_buf);
+        if !buf.is_empty() {
+            tt.update(buf);
+        }
+    }
+
+    // A big-endian number of any length, reduced modulo the order of the curve
+    fn scalar_from_bytes(buf: &[u8]) -> Scalar {
+        let base = Scalar::from(256u64);
+        buf.iter()
+            .fold(Scalar::ZERO, |acc, b| acc * base + Scalar::from(*b as u64))
+    }
+
+    fn point_from_bytes(buf: &[u8]) -> Result<ProjectivePoint, Error> {
+        let encoded = EncodedPoint::from_bytes(buf).map_err(|_e| Error::Invalid)?;
+        Option::from(ProjectivePoint::from_encoded_point(&encoded)).ok_or(Error::Invalid)
+    }
+
+    fn point_to_bytes(point: &ProjectivePoint, out: &mut [u8]) -> Result<(), Error> {
+        let encoded = point.to_affine().to_encoded_point(false);
+        let encoded = encoded.as_bytes();
+        if encoded.len() != out.len() {
+            error!("EC point length mismatch");
+            return Err(Error::Invalid);
+        }
+        out.copy_from_slice(encoded);
+        Ok(())
+    }
+
+    #[inline(always)]
+    #[allow(non_snake_case)]
+    fn get_ZV_as_prover(
+        w0: &Scalar,
+        w1: &Scalar,
+        N: &ProjectivePoint,
+        Y: &ProjectivePoint,
+        x: &Scalar,
+    ) -> (ProjectivePoint, ProjectivePoint) {
+        // As per the RFC, the operation here is:
+        //   Z = h*x*(Y - w0*N)
+        //   V = h*w1*(Y - w0*N)
+        // Cofactor for P256 is 1, so that is a No-Op
+        let tmp = *Y - *N * w0;
+        (tmp * x, tmp * w1)
+    }
+
+    #[inline(always)]
+    #[allow(non_snake_case)]
+    fn get_ZV_as_verifier(
+        w0: &Scalar,
+        L: &ProjectivePoint,
+        M: &ProjectivePoint,
+        X: &ProjectivePoint,
+        y: &Scalar,
+    ) -> (ProjectivePoint, ProjectivePoint) {
+        // As per the RFC, the operation here is:
+        //   Z = h*y*(X - w0*M)
+        //   V = h*y*L
+        // Cofactor for P256 is 1, so that is a No-Op
+        let Z = (*X - *M * w0) * y;
+        (Z, *L * y)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::CryptoRustCrypto;
+    use crate::crypto;
+    use crate::secure_channel::crypto::CryptoSpake2;
+    use crate::secure_channel::spake2p_test_vectors::test_vectors::*;
+    use p256::elliptic_curve::sec1::ToEncodedPoint;
+    use p256::ProjectivePoint;
+
+    fn to_bytes(p: &ProjectivePoint) -> Vec<u8> {
+        p.to_affine().to_encoded_point(false).as_bytes().to_vec()
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn test_get_X() {
+        for t in RFC_T {
+            let mut c = CryptoRustCrypto::new().unwrap();
+            let x = CryptoRustCrypto::scalar_from_bytes(&t.x);
+            c.set_w0(&t.w0).unwrap();
+            let r = ProjectivePoint::GENERATOR * x + c.M * c.w0;
+            assert_eq!(t.X, to_bytes(&r).as_slice());
+        }
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn test_get_Y() {
+        for t in RFC_T {
+            let mut c = CryptoRustCrypto::new().unwrap();
+            let y = CryptoRustCrypto::scalar_from_bytes(&t.y);
+            c.set_w0(&t.w0).unwrap();
+            let r = ProjectivePoint::GENERATOR * y + c.N * c.w0;
+            assert_eq!(t.Y, to_bytes(&r).as_slice());
+        }
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn test_get_ZV_as_prover() {
+        for t in RFC_T {
+            let mut c = CryptoRustCrypto::new().unwrap();
+            let x = CryptoRustCrypto::scalar_from_bytes(&t.x);
+            c.set_w0(&t.w0).unwrap();
+            c.set_w1(&t.w1).unwrap();
+            let Y = CryptoRustCrypto::point_from_bytes(&t.Y).unwrap();
+            let (Z, V) = CryptoRustCrypto::get_ZV_as_prover(&c.w0, &c.w1, &c.N, &Y, &x);
+
+            assert_eq!(t.Z, to_bytes(&Z).as_slice());
+            assert_eq!(t.V, to_bytes(&V).as_slice());
+        }
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn test_get_ZV_as_verifier() {
+        for t in RFC_T {
+            let mut c = CryptoRustCrypto::new().unwrap();
+            let y = CryptoRustCrypto::scalar_from_bytes(&t.y);
+            c.set_w0(&t.w0).unwrap();
+            let X = CryptoRustCrypto::point_from_bytes(&t.X).unwrap();
+            let L = CryptoRustCrypto::point_from_bytes(&t.L).unwrap();
+            let (Z, V) = CryptoRustCrypto::get_ZV_as_verifier(&c.w0, &L, &c.M, &X, &y);
+
+            assert_eq!(t.Z, to_bytes(&Z).as_slice());
+            assert_eq!(t.V, to_bytes(&V).as_slice());
+        }
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn test_get_TT() {
+        // The only vector without identifiers, as the Matter TT has none
+        let t = &RFC_T[3];
+        let context = b"SPAKE2+-P256-SHA256-HKDF draft-01";
+        let mut expected = [0u8; crypto::SHA256_HASH_LEN_BYTES];
+        let mut h = crypto::Sha256::new().unwrap();
+        h.update(&t.TT[0..t.TT_len]).unwrap();
+        h.finish(&mut expected).unwrap();
+
+        let mut prover = CryptoRustCrypto::new().unwrap();
+        prover.set_w0(&t.w0).unwrap();
+        prover.set_w1(&t.w1).unwrap();
+        prover.xy = CryptoRustCrypto::scalar_from_bytes(&t.x);
+        let mut TT_hash = [0u8; crypto::SHA256_HASH_LEN_BYTES];
+        prover
+            .get_TT_as_prover(context, &t.X, &t.Y, &mut TT_hash)
+            .unwrap();
+        assert_eq!(TT_hash, expected);
+
+        let mut verifier = CryptoRustCrypto::new().unwrap();
+        verifier.set_w0(&t.w0).unwrap();
+        verifier.set_L_direct(&t.L).unwrap();
+        verifier.xy = CryptoRustCrypto::scalar_from_bytes(&t.y);
+        let mut TT_hash = [0u8; crypto::SHA256_HASH_LEN_BYTES];
+        verifier
+            .get_TT_as_verifier(context, &t.X, &t.Y, &mut TT_hash)
+            .unwrap();
+        assert_eq!(TT_hash, expected);
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn test_prover_and_verifier() {
+        let t = &RFC_T[0];
+        let mut prover = CryptoRustCrypto::new().unwrap();
+        prover.set_w0(&t.w0).unwrap();
+        prover.set_w1(&t.w1).unwrap();
+        let mut verifier = CryptoRustCrypto::new().unwrap();
+        verifier.set_w0(&t.w0).unwrap();
+        verifier.set_L_direct(&t.L).unwrap();
+
+        let mut pA = [0u8; 65];
+        let mut pB = [0u8; 65];
+        prover.get_pA(&mut pA).unwrap();
+        verifier.get_pB(&mut pB).unwrap();
+        let mut prover_TT = [0u8; crypto::SHA256_HASH_LEN_BYTES];
+        let mut verifier_TT = [0u8; crypto::SHA256_HASH_LEN_BYTES];
+        prover
+            .get_TT_as_prover(&[], &pA, &pB, &mut prover_TT)
+            .unwrap();
+        verifier
+            .get_TT_as_verifier(&[], &pA, &pB, &mut verifier_TT)
+            .unwrap();
+        assert_eq!(prover_TT, verifier_TT);
+    }
+}
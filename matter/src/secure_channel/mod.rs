@@ -1,4 +1,5 @@
 pub mod case;
+pub mod case_test_vectors;
 pub mod common;
 #[cfg(feature = "crypto_esp_mbedtls")]
 pub mod crypto_esp_mbedtls;
@@ -6,6 +7,8 @@ pub mod crypto_esp_mbedtls;
 pub mod crypto_mbedtls;
 #[cfg(feature = "crypto_openssl")]
 pub mod crypto_openssl;
+#[cfg(feature = "crypto_rustcrypto")]
+pub mod crypto_rustcrypto;
 
 pub mod core;
 pub mod crypto;
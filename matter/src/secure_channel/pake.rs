@@ -1,24 +1,37 @@
-use std::time::{Duration, SystemTime};
+use std::{
+    cell::RefCell,
+    rc::Rc,
+    time::{Duration, Instant, SystemTime},
+};
 
 use super::{
-    common::{create_sc_status_report, SCStatusCodes},
-    spake2p::Spake2P,
+    common::{
+        create_sc_error_report, create_sc_status_report, OpCode, SCStatusCodes,
+        PROTO_ID_SECURE_CHANNEL,
+    },
+    spake2p::{Spake2P, VerifierData, SPAKE2P_ITERATIONS, SPAKE2P_SALT_LEN},
+    status_report::StatusReport,
 };
 use crate::{
     crypto,
     error::Error,
-    sys::SPAKE2_ITERATION_COUNT,
+    mdns::{self, Mdns},
+    sys::SysMdnsService,
     tlv::{self, get_root_node_struct, FromTLV, OctetStr, TLVElement, TLVWriter, TagType, ToTLV},
     transport::{
-        exchange::ExchangeCtx,
+        exchange::{Exchange, ExchangeCtx},
+        mrp::MrpParams,
         network::Address,
         proto_demux::ProtoCtx,
-        queue::{Msg, WorkQ},
+        queue::{Msg, TxResp, UnsecuredTxMsg, WorkQ},
         session::{CloneData, SessionMode},
     },
+    utils::writebuf::WriteBuf,
 };
+use async_channel::{bounded, Receiver, RecvError, Sender};
 use log::{error, info};
 use rand::prelude::*;
+use smol::{future::FutureExt, Timer};
 
 // This file basically deals with the handlers for the PASE secure channel protocol
 // TLV extraction and encoding is done in this file.
@@ -57,7 +70,7 @@ impl PakeState {
         if let PakeState::InProgress(s) = new {
             Ok(s)
         } else {
-            Err(Error::InvalidSignature)
+            Err(Error::InvalidState)
         }
     }
 
@@ -68,6 +81,8 @@ impl PakeState {
     fn take_sess_data(&mut self, exch_ctx: &ExchangeCtx) -> Result<SessionData, Error> {
         let sd = self.take()?;
         if sd.exch_id != exch_ctx.exch.get_id() || sd.peer_addr != exch_ctx.sess.get_peer_addr() {
+            // The session in progress isn't this peer's, it goes on
+            self.set_sess_data(sd);
             Err(Error::InvalidState)
         } else {
             Ok(sd)
@@ -94,20 +109,16 @@ impl Default for PakeState {
     }
 }
 
-#[derive(Default)]
 pub struct PAKE {
-    salt: [u8; 16],
-    passwd: u32,
+    verifier: VerifierData,
     state: PakeState,
 }
 
 impl PAKE {
-    pub fn new(salt: &[u8; 16], passwd: u32) -> Self {
-        // TODO: Can any PBKDF2 calculation be pre-computed here
+    pub fn new(verifier: VerifierData) -> Self {
         PAKE {
-            passwd,
-            salt: *salt,
-            ..Default::default()
+            verifier,
+            state: PakeState::Idle,
         }
     }
 
@@ -142,9 +153,11 @@ impl PAKE {
             clone_data
                 .att_challenge
                 .copy_from_slice(&session_keys[32..48]);
+            clone_data.mrp_params = ctx.exch_ctx.sess.get_mrp_params();
+            clone_data.iface = ctx.exch_ctx.sess.get_iface();
 
             // Queue a transport mgr request to add a new session
-            WorkQ::get()?.sync_send(Msg::NewSession(clone_data))?;
+            ctx.work_q.sync_send(Msg::NewSession(clone_data))?;
         }
 
         create_sc_status_report(&mut ctx.tx, status_code, None)?;
@@ -159,8 +172,7 @@ impl PAKE {
         let pA = extract_pasepake_1_or_3_params(ctx.rx.as_borrow_slice())?;
         let mut pB: [u8; 65] = [0; 65];
         let mut cB: [u8; 32] = [0; 32];
-        sd.spake2p
-            .start_verifier(self.passwd, SPAKE2_ITERATION_COUNT, &self.salt)?;
+        sd.spake2p.start_verifier(&self.verifier)?;
         sd.spake2p.handle_pA(pA, &mut pB, &mut cB)?;
 
         let mut tw = TLVWriter::new(ctx.tx.get_writebuf()?);
@@ -185,6 +197,7 @@ impl PAKE {
                 info!("Previous session in-progress, denying new request");
                 // little-endian timeout (here we've hardcoded 500ms)
                 create_sc_status_report(&mut ctx.tx, SCStatusCodes::Busy, Some(&[0xf4, 0x01]))?;
+                ctx.exch_ctx.exch.close();
                 return Ok(());
             }
         }
@@ -195,6 +208,9 @@ impl PAKE {
             error!("Can't yet handle passcode_id != 0");
             return Err(Error::Invalid);
         }
+        if let Some(mrp_params) = a.mrp_params {
+            ctx.exch_ctx.sess.set_mrp_params(mrp_params);
+        }
 
         let mut our_random: [u8; 32] = [0; 32];
         rand::thread_rng().fill_bytes(&mut our_random);
@@ -211,11 +227,12 @@ impl PAKE {
             our_random: OctetStr(&our_random),
             local_sessid,
             params: None,
+            mrp_params: Some(MrpParams::local()),
         };
         if !a.has_params {
             let params_resp = PBKDFParamRespParams {
-                count: SPAKE2_ITERATION_COUNT,
-                salt: OctetStr(&self.salt),
+                count: self.verifier.iterations,
+                salt: OctetStr(&self.verifier.salt),
             };
             resp.params = Some(params_resp);
         }
@@ -228,8 +245,486 @@ impl PAKE {
     }
 }
 
+/// How a commissioning window was opened
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WindowMode {
+    /// With the passcode of the device
+    Basic,
+    /// With a verifier that an administrator supplied, advertised with this
+    /// discriminator
+    Enhanced(u16),
+}
+
+/// The administrator that opened a commissioning window
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WindowAdmin {
+    pub fabric_idx: u8,
+    pub vendor_id: u16,
+}
+
+struct CommWindow {
+    pake: PAKE,
+    mode: WindowMode,
+    admin: Option<WindowAdmin>,
+    deadline: Option<Instant>,
+    mdns: SysMdnsService,
+}
+
+struct PaseMgrInner {
+    verifier: VerifierData,
+    window: Option<CommWindow>,
+}
+
+impl PaseMgrInner {
+    // Closes the window, if it timed out
+    fn expire(&mut self) {
+        let expired = matches!(
+            &self.window,
+            Some(CommWindow { deadline: Some(d), .. }) if *d <= Instant::now()
+        );
+        if expired {
+            info!("Commissioning window timed out");
+            self.close();
+        }
+    }
+
+    fn close(&mut self) {
+        if let Some(window) = self.window.take() {
+            if let Ok(mdns) = Mdns::get() {
+                mdns.unpublish_commissionable(window.mdns);
+            }
+        }
+    }
+}
+
+/// The commissioning windows, in which the peers can establish PASE sessions
+///
+/// This is shared by the secure channel, that runs PASE, and the Administrator
+/// Commissioning cluster, that opens and closes the windows.
+#[derive(Clone)]
+pub struct PaseMgr {
+    inner: Rc<RefCell<PaseMgrInner>>,
+    changed_tx: Sender<()>,
+    changed_rx: Receiver<()>,
+}
+
+impl PaseMgr {
+    /// A manager for a device with the verifier `verifier`, that the basic windows
+    /// are opened with
+    pub fn new(verifier: VerifierData) -> Self {
+        let (changed_tx, changed_rx) = bounded(1);
+        Self {
+            inner: Rc::new(RefCell::new(PaseMgrInner {
+                verifier,
+                window: None,
+            })),
+            changed_tx,
+            changed_rx,
+        }
+    }
+
+    /// Opens a window for the passcode of the device, that closes after `timeout`
+    /// if it has one
+    pub fn open_basic_comm_window(
+        &self,
+        timeout: Option<Duration>,
+        admin: Option<WindowAdmin>,
+    ) -> Result<(), Error> {
+        let pake = PAKE::new(self.inner.borrow().verifier.clone());
+        self.open(pake, WindowMode::Basic, timeout, admin)
+    }
+
+    /// Opens a window for `pake`, advertised with `discriminator`, that closes
+    /// after `timeout`
+    pub fn open_enhanced_comm_window(
+        &self,
+        pake: PAKE,
+        discriminator: u16,
+        timeout: Duration,
+        admin: Option<WindowAdmin>,
+    ) -> Result<(), Error> {
+        self.open(
+            pake,
+            WindowMode::Enhanced(discriminator),
+            Some(timeout),
+            admin,
+        )
+    }
+
+    fn open(
+        &self,
+        pake: PAKE,
+        mode: WindowMode,
+        timeout: Option<Duration>,
+        admin: Option<WindowAdmin>,
+    ) -> Result<(), Error> {
+        let mut inner = self.inner.borrow_mut();
+        inner.expire();
+        if inner.window.is_some() {
+            error!("A commissioning window is already open");
+            return Err(Error::InvalidState);
+        }
+
+        let name: u64 = rand::thread_rng().gen_range(0..0xFFFFFFFFFFFFFFFF);
+        let name = format!("{:016X}", name);
+        let service_mode = match mode {
+            WindowMode::Basic => mdns::ServiceMode::Commissionable,
+            WindowMode::Enhanced(d) => mdns::ServiceMode::EnhancedCommissionable(d),
+        };
+        let mdns = Mdns::get()?.publish_service(&name, service_mode)?;
+        info!("Opened a commissioning window: {:?}", mode);
+        inner.window = Some(CommWindow {
+            pake,
+            mode,
+            admin,
+            deadline: timeout.map(|t| Instant::now() + t),
+            mdns,
+        });
+        let _ = self.changed_tx.try_send(());
+        Ok(())
+    }
+
+    /// Closes the commissioning window, if one is open
+    ///
+    /// The PASE sessions that were already established stay.
+    pub fn close_comm_window(&self) {
+        self.inner.borrow_mut().close();
+        let _ = self.changed_tx.try_send(());
+    }
+
+    /// Drops the PASE session establishment in progress, if there is one
+    ///
+    /// The window stays open, for the peers to start over.
+    pub fn abort_pase(&self) {
+        let mut inner = self.inner.borrow_mut();
+        if let Some(window) = inner.window.as_mut() {
+            if !window.pake.state.is_idle() {
+                info!("Aborting the PASE session establishment in progress");
+                window.pake.state = PakeState::Idle;
+            }
+        }
+    }
+
+    /// How the commissioning window was opened, None if no window is open
+    pub fn get_window_mode(&self) -> Option<WindowMode> {
+        let mut inner = self.inner.borrow_mut();
+        inner.expire();
+        inner.window.as_ref().map(|w| w.mode)
+    }
+
+    /// The administrator that opened the commissioning window, if an administrator
+    /// opened it
+    pub fn get_window_admin(&self) -> Option<WindowAdmin> {
+        let mut inner = self.inner.borrow_mut();
+        inner.expire();
+        inner.window.as_ref().and_then(|w| w.admin)
+    }
+
+    /// Calls `f` with the PAKE of the commissioning window, None if no window is open
+    pub fn with_pake<F, T>(&self, f: F) -> Option<T>
+    where
+        F: FnOnce(&mut PAKE) -> T,
+    {
+        let mut inner = self.inner.borrow_mut();
+        inner.expire();
+        inner.window.as_mut().map(|w| f(&mut w.pake))
+    }
+
+    /// Closes the commissioning windows as they time out
+    ///
+    /// This never returns, it runs along with the transport.
+    pub async fn run(&self) -> Result<(), Error> {
+        loop {
+            let deadline = {
+                let mut inner = self.inner.borrow_mut();
+                inner.expire();
+                inner.window.as_ref().and_then(|w| w.deadline)
+            };
+            let timer = async {
+                match deadline {
+                    Some(d) => Timer::at(d).await,
+                    None => Timer::never().await,
+                };
+                Ok::<(), RecvError>(())
+            };
+            timer.or(self.changed_rx.recv()).await?;
+        }
+    }
+}
+
+/// A PASE session establishment that we initiated, with the passcode of the peer
+///
+/// This is the exchange data till the PBKDFParamResponse arrives.
+pub struct PaseInitiator {
+    passcode: u32,
+    local_sessid: u16,
+    init_random: [u8; 32],
+    request: Vec<u8>,
+    done_tx: Sender<Result<u16, Error>>,
+}
+
+// The rest of the establishment, once the peer sent its PBKDF parameters
+#[allow(non_snake_case)]
+struct PaseInitiatorSession {
+    local_sessid: u16,
+    peer_sessid: u16,
+    spake2p: Box<Spake2P>,
+    pA: [u8; 65],
+    session_keys: Option<[u8; 48]>,
+    done_tx: Sender<Result<u16, Error>>,
+}
+
+impl PaseInitiator {
+    fn new(
+        passcode: u32,
+        local_sessid: u16,
+        done_tx: Sender<Result<u16, Error>>,
+    ) -> Result<Self, Error> {
+        let mut init_random = [0; 32];
+        rand::thread_rng().fill_bytes(&mut init_random);
+
+        let mut buf = [0; 128];
+        let len = buf.len();
+        let mut wb = WriteBuf::new(&mut buf, len);
+        let mut tw = TLVWriter::new(&mut wb);
+        let req = PBKDFParamReq {
+            initiator_random: OctetStr(&init_random),
+            initiator_ssid: local_sessid,
+            passcode_id: 0,
+            has_params: false,
+            mrp_params: Some(MrpParams::local()),
+        };
+        req.to_tlv(&mut tw, TagType::Anonymous)?;
+        let request = wb.as_borrow_slice().to_vec();
+
+        Ok(Self {
+            passcode,
+            local_sessid,
+            init_random,
+            request,
+            done_tx,
+        })
+    }
+
+    /// Whether the exchange belongs to a PASE session establishment that we
+    /// initiated
+    pub fn is_initiator_exchange(exch: &mut Exchange) -> bool {
+        exch.get_exchange_data::<PaseInitiator>().is_some()
+            || exch.get_exchange_data::<PaseInitiatorSession>().is_some()
+    }
+
+    /// Fails the establishment that we initiated on the exchange, the peer didn't
+    /// respond in time
+    pub fn handle_response_timeout(exch: &mut Exchange) {
+        if let Some(initiator) = exch.take_exchange_data::<PaseInitiator>() {
+            let _ = initiator.done_tx.try_send(Err(Error::Timeout));
+        } else if let Some(session) = exch.take_exchange_data::<PaseInitiatorSession>() {
+            let _ = session.done_tx.try_send(Err(Error::Timeout));
+        }
+    }
+
+    pub fn handle_pbkdfparamresponse(ctx: &mut ProtoCtx) -> Result<(), Error> {
+        let initiator = ctx
+            .exch_ctx
+            .exch
+            .take_exchange_data::<PaseInitiator>()
+            .ok_or(Error::InvalidState)?;
+        let tail = ctx.tx.get_writebuf()?.get_tail();
+        match initiator.handle_params(ctx.rx.as_borrow_slice(), ctx.tx.get_writebuf()?) {
+            Ok((session, mrp_params)) => {
+                if let Some(mrp_params) = mrp_params {
+                    ctx.exch_ctx.sess.set_mrp_params(mrp_params);
+                }
+                ctx.exch_ctx.exch.set_exchange_data(Box::new(session));
+            }
+            Err(e) => {
+                error!("Invalid PBKDFParamResponse: {:?}", e);
+                let _ = initiator.done_tx.try_send(Err(e));
+                ctx.tx.get_writebuf()?.rewind_tail_to(tail);
+                create_sc_error_report(&mut ctx.tx, e)?;
+                ctx.exch_ctx.exch.close();
+            }
+        }
+        Ok(())
+    }
+
+    pub fn handle_pasepake2(ctx: &mut ProtoCtx) -> Result<(), Error> {
+        let mut session = ctx
+            .exch_ctx
+            .exch
+            .take_exchange_data::<PaseInitiatorSession>()
+            .ok_or(Error::InvalidState)?;
+        let tail = ctx.tx.get_writebuf()?.get_tail();
+        match session.handle_pake2(ctx.rx.as_borrow_slice(), ctx.tx.get_writebuf()?) {
+            Ok(()) => ctx.exch_ctx.exch.set_exchange_data(session),
+            Err(e) => {
+                error!("PASE failed: {:?}", e);
+                let _ = session.done_tx.try_send(Err(e));
+                ctx.tx.get_writebuf()?.rewind_tail_to(tail);
+                create_sc_error_report(&mut ctx.tx, e)?;
+                ctx.exch_ctx.exch.close();
+            }
+        }
+        Ok(())
+    }
+
+    /// Handles the peer's status report, that either confirms the session or ends
+    /// the establishment
+    pub fn handle_status_report(ctx: &mut ProtoCtx) -> Result<(), Error> {
+        let report = StatusReport::parse(ctx.rx.as_borrow_slice())?;
+        let exch = &mut ctx.exch_ctx.exch;
+        if exch.get_exchange_data::<PaseInitiator>().is_some() {
+            let initiator = exch.take_exchange_data::<PaseInitiator>();
+            exch.close();
+            error!("The peer didn't send its PBKDF parameters: {:?}", report);
+            if let Some(initiator) = initiator {
+                let _ = initiator.done_tx.try_send(Err(Error::Invalid));
+            }
+            return Ok(());
+        }
+        let session = exch.take_exchange_data::<PaseInitiatorSession>();
+        exch.close();
+        let session = match session {
+            Some(session) => session,
+            None => return Ok(()),
+        };
+        let session_keys = match session.session_keys {
+            Some(session_keys) if report.is_success() => session_keys,
+            _ => {
+                error!("The peer didn't accept the PASE session: {:?}", report);
+                let _ = session.done_tx.try_send(Err(Error::Invalid));
+                return Ok(());
+            }
+        };
+
+        let mut clone_data = CloneData::new(
+            0,
+            0,
+            session.peer_sessid,
+            session.local_sessid,
+            ctx.exch_ctx.sess.get_peer_addr(),
+            SessionMode::Pase,
+        );
+        // The keys are the other way around for the responder
+        clone_data.enc_key.copy_from_slice(&session_keys[0..16]);
+        clone_data.dec_key.copy_from_slice(&session_keys[16..32]);
+        clone_data
+            .att_challenge
+            .copy_from_slice(&session_keys[32..48]);
+        clone_data.mrp_params = ctx.exch_ctx.sess.get_mrp_params();
+        clone_data.iface = ctx.exch_ctx.sess.get_iface();
+        ctx.work_q.sync_send(Msg::NewSession(clone_data))?;
+        info!("Established the PASE session {}", session.local_sessid);
+        let _ = session.done_tx.try_send(Ok(session.local_sessid));
+        Ok(())
+    }
+
+    // Starts the prover with the PBKDF parameters of the peer, and writes our Pake1
+    #[allow(non_snake_case)]
+    fn handle_params(
+        &self,
+        rx: &[u8],
+        tx: &mut WriteBuf,
+    ) -> Result<(PaseInitiatorSession, Option<MrpParams>), Error> {
+        let root = tlv::get_root_node(rx)?;
+        let resp = PBKDFParamResp::from_tlv(&root)?;
+        if resp.init_random.0 != self.init_random {
+            error!("The PBKDFParamResponse isn't for our request");
+            return Err(Error::Invalid);
+        }
+        // We didn't claim to have the parameters, so the peer must send them
+        let params = resp.params.ok_or(Error::Invalid)?;
+        // These are checked before the PBKDF2, that a huge count would stall
+        if !SPAKE2P_ITERATIONS.contains(&params.count)
+            || !SPAKE2P_SALT_LEN.contains(&params.salt.0.len())
+        {
+            error!(
+                "Invalid PBKDF parameters: {} iterations, {} bytes of salt",
+                params.count,
+                params.salt.0.len()
+            );
+            return Err(Error::InvalidParameter);
+        }
+
+        let mut spake2p = Box::new(Spake2P::new());
+        spake2p.set_context(&self.request, rx)?;
+        spake2p.start_prover(self.passcode, params.count, params.salt.0)?;
+        let mut pA = [0; 65];
+        spake2p.get_pA(&mut pA)?;
+
+        let mut tw = TLVWriter::new(tx);
+        let req = Pake1Req { pa: OctetStr(&pA) };
+        req.to_tlv(&mut tw, TagType::Anonymous)?;
+
+        let session = PaseInitiatorSession {
+            local_sessid: self.local_sessid,
+            peer_sessid: resp.local_sessid,
+            spake2p,
+            pA,
+            session_keys: None,
+            done_tx: self.done_tx.clone(),
+        };
+        Ok((session, resp.mrp_params))
+    }
+}
+
+impl PaseInitiatorSession {
+    // Confirms the peer's pB and cB, and writes our Pake3
+    #[allow(non_snake_case)]
+    fn handle_pake2(&mut self, rx: &[u8], tx: &mut WriteBuf) -> Result<(), Error> {
+        let root = tlv::get_root_node(rx)?;
+        let resp = Pake1Resp::from_tlv(&root)?;
+        let mut cA = [0; 32];
+        let (status_code, Ke) = self
+            .spake2p
+            .handle_pB(&self.pA, resp.pb.0, resp.cb.0, &mut cA)?;
+        if status_code != SCStatusCodes::SessionEstablishmentSuccess {
+            error!("The peer's cB doesn't match, the passcode is probably wrong");
+            return Err(Error::InvalidAuthKey);
+        }
+        let Ke = Ke.ok_or(Error::Invalid)?;
+        let mut session_keys = [0; 48];
+        crypto::hkdf_sha256(&[], Ke, &SPAKE2_SESSION_KEYS_INFO, &mut session_keys)
+            .map_err(|_x| Error::NoSpace)?;
+        self.session_keys = Some(session_keys);
+
+        let mut tw = TLVWriter::new(tx);
+        let req = Pake3Req { ca: OctetStr(&cA) };
+        req.to_tlv(&mut tw, TagType::Anonymous)
+    }
+}
+
+/// Establishes a PASE session with the commissionable device at `peer`, that has
+/// the passcode `passcode`
+///
+/// The transport of `work_q` must have a SecureChannel handler registered. This
+/// returns the local id of the new session, or Error::Timeout if the peer stops
+/// responding.
+pub async fn initiate_pase(work_q: &WorkQ, peer: Address, passcode: u32) -> Result<u16, Error> {
+    let local_sessid = work_q.reserve_sess_id().await?;
+    let (done_tx, done_rx) = bounded(1);
+    let initiator = PaseInitiator::new(passcode, local_sessid, done_tx)?;
+    work_q
+        .send(Msg::UnsecuredTx(UnsecuredTxMsg {
+            peer,
+            proto_id: PROTO_ID_SECURE_CHANNEL as u16,
+            proto_opcode: OpCode::PBKDFParamRequest as u8,
+            payload: initiator.request.clone(),
+            resp: Some(TxResp::Handler(Box::new(initiator))),
+        }))
+        .await?;
+    // The exchange drops the initiator if it fails before the session is established
+    done_rx.recv().await.map_err(|_| Error::NoExchange)?
+}
+
 #[derive(ToTLV)]
 #[tlvargs(start = 1)]
+struct Pake1Req<'a> {
+    pa: OctetStr<'a>,
+}
+
+#[derive(ToTLV, FromTLV)]
+#[tlvargs(lifetime = "'a", start = 1)]
 struct Pake1Resp<'a> {
     pb: OctetStr<'a>,
     cb: OctetStr<'a>,
@@ -237,18 +732,25 @@ struct Pake1Resp<'a> {
 
 #[derive(ToTLV)]
 #[tlvargs(start = 1)]
+struct Pake3Req<'a> {
+    ca: OctetStr<'a>,
+}
+
+#[derive(ToTLV, FromTLV)]
+#[tlvargs(lifetime = "'a", start = 1)]
 struct PBKDFParamRespParams<'a> {
     count: u32,
     salt: OctetStr<'a>,
 }
 
-#[derive(ToTLV)]
-#[tlvargs(start = 1)]
+#[derive(ToTLV, FromTLV)]
+#[tlvargs(lifetime = "'a", start = 1)]
 struct PBKDFParamResp<'a> {
     init_random: OctetStr<'a>,
     our_random: OctetStr<'a>,
     local_sessid: u16,
     params: Option<PBKDFParamRespParams<'a>>,
+    mrp_params: Option<MrpParams>,
 }
 
 #[allow(non_snake_case)]
@@ -258,11 +760,295 @@ fn extract_pasepake_1_or_3_params(buf: &[u8]) -> Result<&[u8], Error> {
     Ok(pA)
 }
 
-#[derive(FromTLV)]
+#[derive(ToTLV, FromTLV)]
 #[tlvargs(lifetime = "'a", start = 1)]
 struct PBKDFParamReq<'a> {
     initiator_random: OctetStr<'a>,
     initiator_ssid: u16,
     passcode_id: u16,
     has_params: bool,
+    mrp_params: Option<MrpParams>,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{IpAddr, Ipv6Addr, SocketAddr},
+        sync::Arc,
+        time::{Duration, SystemTime},
+    };
+
+    use async_channel::bounded;
+    use smol::future::{self, FutureExt};
+
+    use crate::{
+        crypto,
+        error::Error,
+        fabric::FabricMgr,
+        secure_channel::{
+            common::SCStatusCodes,
+            core::SecureChannel,
+            spake2p::{Spake2P, VerifierData},
+        },
+        tlv::{self, FromTLV, OctetStr, TLVWriter, TagType, ToTLV},
+        transport::{
+            loopback::LoopbackSwitch,
+            mgr::{Mgr, TransportConfig},
+            network::Address,
+        },
+        utils::writebuf::WriteBuf,
+    };
+
+    use super::{
+        extract_pasepake_1_or_3_params, initiate_pase, PBKDFParamReq, PBKDFParamResp,
+        PBKDFParamRespParams, Pake1Resp, PakeState, PaseInitiator, PaseMgr, SessionData,
+        WindowAdmin, WindowMode, PAKE, SPAKE2_SESSION_KEYS_INFO,
+    };
+
+    fn verifier() -> VerifierData {
+        VerifierData::new_with_pw(123456, &[0; 16], 1000).unwrap()
+    }
+
+    #[test]
+    fn test_comm_window() {
+        let pase_mgr = PaseMgr::new(verifier());
+        assert_eq!(pase_mgr.get_window_mode(), None);
+        assert!(pase_mgr.with_pake(|_| ()).is_none());
+
+        pase_mgr.open_basic_comm_window(None, None).unwrap();
+        assert_eq!(pase_mgr.get_window_mode(), Some(WindowMode::Basic));
+        assert_eq!(pase_mgr.get_window_admin(), None);
+        assert!(pase_mgr.with_pake(|_| ()).is_some());
+
+        // Only one window is open at a time
+        let admin = WindowAdmin {
+            fabric_idx: 1,
+            vendor_id: 0xFFF1,
+        };
+        assert_eq!(
+            pase_mgr.open_enhanced_comm_window(
+                PAKE::new(verifier()),
+                0xABC,
+                Duration::from_secs(180),
+                Some(admin)
+            ),
+            Err(Error::InvalidState)
+        );
+        pase_mgr.close_comm_window();
+        assert_eq!(pase_mgr.get_window_mode(), None);
+
+        // The window closes by itself, once it times out
+        pase_mgr
+            .open_enhanced_comm_window(
+                PAKE::new(verifier()),
+                0xABC,
+                Duration::from_millis(50),
+                Some(admin),
+            )
+            .unwrap();
+        assert_eq!(
+            pase_mgr.get_window_mode(),
+            Some(WindowMode::Enhanced(0xABC))
+        );
+        assert_eq!(pase_mgr.get_window_admin(), Some(admin));
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(pase_mgr.get_window_mode(), None);
+        assert_eq!(pase_mgr.get_window_admin(), None);
+    }
+
+    #[test]
+    fn test_abort_pase() {
+        let pase_mgr = PaseMgr::new(verifier());
+        pase_mgr.open_basic_comm_window(None, None).unwrap();
+        pase_mgr.with_pake(|pake| {
+            pake.state = PakeState::InProgress(SessionData {
+                start_time: SystemTime::now(),
+                exch_id: 1,
+                peer_addr: Address::Udp(addr(2)),
+                spake2p: Box::new(Spake2P::new()),
+            })
+        });
+
+        pase_mgr.abort_pase();
+        assert_eq!(pase_mgr.with_pake(|pake| pake.state.is_idle()), Some(true));
+        assert_eq!(pase_mgr.get_window_mode(), Some(WindowMode::Basic));
+    }
+
+    // Runs the initiator against a responder that is driven by hand, and returns
+    // the session keys of both
+    #[allow(non_snake_case)]
+    fn run_pase(passcode: u32) -> Result<([u8; 48], [u8; 48]), Error> {
+        let verifier = VerifierData::new_with_pw(20202021, &[0x53; 16], 1000)?;
+        let (done_tx, _done_rx) = bounded(1);
+        let initiator = PaseInitiator::new(passcode, 10, done_tx)?;
+
+        // PBKDFParamRequest -> PBKDFParamResponse
+        let req = PBKDFParamReq::from_tlv(&tlv::get_root_node(&initiator.request)?)?;
+        assert_eq!(req.initiator_ssid, 10);
+        assert!(!req.has_params);
+        let mut buf = [0; 512];
+        let mut wb = WriteBuf::new(&mut buf, 512);
+        let resp = PBKDFParamResp {
+            init_random: req.initiator_random,
+            our_random: OctetStr(&[0x11; 32]),
+            local_sessid: 20,
+            params: Some(PBKDFParamRespParams {
+                count: verifier.iterations,
+                salt: OctetStr(&verifier.salt),
+            }),
+            mrp_params: None,
+        };
+        resp.to_tlv(&mut TLVWriter::new(&mut wb), TagType::Anonymous)?;
+        let params_resp = wb.as_borrow_slice().to_vec();
+        let mut responder = Spake2P::new();
+        responder.set_context(&initiator.request, &params_resp)?;
+
+        // Pake1 -> Pake2
+        let mut buf = [0; 512];
+        let mut wb = WriteBuf::new(&mut buf, 512);
+        let (mut session, _) = initiator.handle_params(&params_resp, &mut wb)?;
+        assert_eq!(session.peer_sessid, 20);
+        let pA = extract_pasepake_1_or_3_params(wb.as_borrow_slice())?;
+        let mut pB = [0; 65];
+        let mut cB = [0; 32];
+        responder.start_verifier(&verifier)?;
+        responder.handle_pA(pA, &mut pB, &mut cB)?;
+        let mut buf = [0; 512];
+        let mut wb = WriteBuf::new(&mut buf, 512);
+        let resp = Pake1Resp {
+            pb: OctetStr(&pB),
+            cb: OctetStr(&cB),
+        };
+        resp.to_tlv(&mut TLVWriter::new(&mut wb), TagType::Anonymous)?;
+        let pake2 = wb.as_borrow_slice().to_vec();
+
+        // Pake3
+        let mut buf = [0; 512];
+        let mut wb = WriteBuf::new(&mut buf, 512);
+        session.handle_pake2(&pake2, &mut wb)?;
+        let cA = extract_pasepake_1_or_3_params(wb.as_borrow_slice())?;
+        let (status_code, Ke) = responder.handle_cA(cA);
+        assert!(status_code == SCStatusCodes::SessionEstablishmentSuccess);
+        let mut responder_keys = [0; 48];
+        crypto::hkdf_sha256(
+            &[],
+            Ke.unwrap(),
+            &SPAKE2_SESSION_KEYS_INFO,
+            &mut responder_keys,
+        )?;
+        Ok((session.session_keys.unwrap(), responder_keys))
+    }
+
+    #[test]
+    fn test_pase_initiator() {
+        let (initiator_keys, responder_keys) = run_pase(20202021).unwrap();
+        assert_eq!(initiator_keys, responder_keys);
+
+        assert_eq!(run_pase(20202022), Err(Error::InvalidAuthKey));
+    }
+
+    #[test]
+    fn test_pase_initiator_bad_params() {
+        let (done_tx, _done_rx) = bounded(1);
+        let initiator = PaseInitiator::new(20202021, 10, done_tx).unwrap();
+        let req =
+            PBKDFParamReq::from_tlv(&tlv::get_root_node(&initiator.request).unwrap()).unwrap();
+        for (count, salt_len) in [(999, 16), (100001, 16), (1000, 15), (1000, 33)] {
+            let salt = vec![0x53; salt_len];
+            let mut buf = [0; 512];
+            let mut wb = WriteBuf::new(&mut buf, 512);
+            let resp = PBKDFParamResp {
+                init_random: req.initiator_random,
+                our_random: OctetStr(&[0x11; 32]),
+                local_sessid: 20,
+                params: Some(PBKDFParamRespParams {
+                    count,
+                    salt: OctetStr(&salt),
+                }),
+                mrp_params: None,
+            };
+            resp.to_tlv(&mut TLVWriter::new(&mut wb), TagType::Anonymous)
+                .unwrap();
+            let params_resp = wb.as_borrow_slice().to_vec();
+
+            let mut buf = [0; 512];
+            let mut wb = WriteBuf::new(&mut buf, 512);
+            assert_eq!(
+                initiator.handle_params(&params_resp, &mut wb).err(),
+                Some(Error::InvalidParameter)
+            );
+        }
+    }
+
+    fn addr(node: u16) -> SocketAddr {
+        SocketAddr::new(
+            IpAddr::V6(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, node)),
+            5540,
+        )
+    }
+
+    // A transport with the SecureChannel protocol, that takes PASE sessions if
+    // `commissionable`
+    fn stack(switch: &LoopbackSwitch, node: u16, commissionable: bool) -> Mgr {
+        let mut mgr = Mgr::new(TransportConfig {
+            bind_addrs: Vec::new(),
+            tcp: false,
+            interfaces: vec![Box::new(switch.connect(addr(node)).unwrap())],
+            ..Default::default()
+        })
+        .unwrap();
+        let verifier = VerifierData::new_with_pw(20202021, &[0x53; 16], 1000).unwrap();
+        let pase_mgr = PaseMgr::new(verifier);
+        if commissionable {
+            pase_mgr.open_basic_comm_window(None, None).unwrap();
+        }
+        let fabric_mgr = Arc::new(FabricMgr::new().unwrap());
+        let secure_channel = SecureChannel::new(fabric_mgr, pase_mgr);
+        mgr.register_protocol(Box::new(secure_channel)).unwrap();
+        mgr
+    }
+
+    #[test]
+    fn test_commission_two_stacks() {
+        let switch = LoopbackSwitch::new();
+        let mut controller = stack(&switch, 1, false);
+        let mut device1 = stack(&switch, 2, true);
+        let mut device2 = stack(&switch, 3, true);
+        let stats = [
+            controller.get_stats_handle(),
+            device1.get_stats_handle(),
+            device2.get_stats_handle(),
+        ];
+        let work_qs = [
+            controller.get_work_q(),
+            device1.get_work_q(),
+            device2.get_work_q(),
+        ];
+
+        let result = smol::block_on(
+            async {
+                let sessions = future::zip(
+                    initiate_pase(&work_qs[0], Address::Udp(addr(2)), 20202021),
+                    initiate_pase(&work_qs[0], Address::Udp(addr(3)), 20202021),
+                )
+                .await;
+                // Each transport has added its new session, once it gets to
+                // anything queued up after it
+                for work_q in &work_qs {
+                    work_q.reserve_sess_id().await?;
+                }
+                Ok(sessions)
+            }
+            .or(async {
+                controller.run().or(device1.run()).or(device2.run()).await?;
+                Err(Error::Invalid)
+            }),
+        );
+        let (sess1, sess2) = result.unwrap();
+        assert_ne!(sess1.unwrap(), sess2.unwrap());
+
+        // Every session landed in the transport that established it
+        let opened: Vec<u64> = stats.iter().map(|s| s.get().pase.sessions_opened).collect();
+        assert_eq!(opened, vec![2, 1, 1]);
+    }
 }
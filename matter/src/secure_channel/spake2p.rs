@@ -1,10 +1,14 @@
+use std::ops::RangeInclusive;
+
 use crate::crypto::{self, HmacSha256};
 use byteorder::{ByteOrder, LittleEndian};
+use log::error;
 use subtle::ConstantTimeEq;
 
 use crate::{
     crypto::{pbkdf2_hmac, Sha256},
     error::Error,
+    sys::Psm,
 };
 
 #[cfg(feature = "crypto_openssl")]
@@ -16,6 +20,9 @@ use super::crypto_mbedtls::CryptoMbedTLS;
 #[cfg(feature = "crypto_esp_mbedtls")]
 use super::crypto_esp_mbedtls::CryptoEspMbedTls;
 
+#[cfg(feature = "crypto_rustcrypto")]
+use super::crypto_rustcrypto::CryptoRustCrypto;
+
 use super::{common::SCStatusCodes, crypto::CryptoSpake2};
 
 // This file handle Spake2+ specific instructions. In itself, this file is
@@ -24,7 +31,8 @@ use super::{common::SCStatusCodes, crypto::CryptoSpake2};
 // out the specific implementations.
 //
 // In the case of the verifier, we don't actually release the Ke until we
-// validate that the cA is confirmed.
+// validate that the cA is confirmed. In the case of the prover, likewise, until
+// we validate that the cB is confirmed.
 
 #[derive(PartialEq, Copy, Clone, Debug)]
 pub enum Spake2VerifierState {
@@ -36,10 +44,20 @@ pub enum Spake2VerifierState {
     Confirmed,
 }
 
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum Spake2ProverState {
+    // Initialised - w0, w1 are set
+    Init,
+    // Pending Confirmation - pA was sent, pending the verifier's pB and cB
+    PendingConfirmation,
+    // Confirmed
+    Confirmed,
+}
+
 #[derive(PartialEq, Debug)]
 pub enum Spake2Mode {
     Unknown,
-    Prover,
+    Prover(Spake2ProverState),
     Verifier(Spake2VerifierState),
 }
 
@@ -57,6 +75,23 @@ const SPAKE2P_KEY_CONFIRM_INFO: [u8; 16] = *b"ConfirmationKeys";
 const SPAKE2P_CONTEXT_PREFIX: [u8; 26] = *b"CHIP PAKE V1 Commissioning";
 const CRYPTO_GROUP_SIZE_BYTES: usize = 32;
 const CRYPTO_W_SIZE_BYTES: usize = CRYPTO_GROUP_SIZE_BYTES + 8;
+const CRYPTO_PUBLIC_KEY_SIZE_BYTES: usize = (2 * CRYPTO_GROUP_SIZE_BYTES) + 1;
+
+/// The length of a verifier, that is w0 followed by L
+pub const SPAKE2P_VERIFIER_LEN: usize = CRYPTO_GROUP_SIZE_BYTES + CRYPTO_PUBLIC_KEY_SIZE_BYTES;
+// The salts and the iterations that the Matter spec allows
+pub const SPAKE2P_SALT_LEN: RangeInclusive<usize> = 16..=32;
+pub const SPAKE2P_ITERATIONS: RangeInclusive<u32> = 1000..=100000;
+// The passcodes that the Matter spec allows, except for the trivial ones
+const SPAKE2P_MAX_PASSCODE: u32 = 99999998;
+const SPAKE2P_INVALID_PASSCODES: [u32; 12] = [
+    0, 11111111, 22222222, 33333333, 44444444, 55555555, 66666666, 77777777, 88888888, 99999999,
+    12345678, 87654321,
+];
+
+const ST_VERIFIER: &str = "spake2p_verifier";
+const ST_SALT: &str = "spake2p_salt";
+const ST_ITERATIONS: &str = "spake2p_iterations";
 
 #[cfg(feature = "crypto_openssl")]
 fn crypto_spake2_new() -> Result<Box<dyn CryptoSpake2>, Error> {
@@ -73,6 +108,108 @@ fn crypto_spake2_new() -> Result<Box<dyn CryptoSpake2>, Error> {
     Ok(Box::new(CryptoEspMbedTls::new()?))
 }
 
+#[cfg(feature = "crypto_rustcrypto")]
+fn crypto_spake2_new() -> Result<Box<dyn CryptoSpake2>, Error> {
+    Ok(Box::new(CryptoRustCrypto::new()?))
+}
+
+/// Whether the Matter spec allows `pw` as a passcode
+pub fn is_valid_passcode(pw: u32) -> bool {
+    pw <= SPAKE2P_MAX_PASSCODE && !SPAKE2P_INVALID_PASSCODES.contains(&pw)
+}
+
+/// A SPAKE2+ verifier, and the salt and the PBKDF2 iterations that it was computed with
+///
+/// This is all that a device needs for PASE, the passcode itself stays with whoever
+/// computed the verifier.
+#[derive(Clone, Debug, PartialEq)]
+pub struct VerifierData {
+    /// w0 followed by L
+    pub verifier: [u8; SPAKE2P_VERIFIER_LEN],
+    pub salt: Vec<u8>,
+    pub iterations: u32,
+}
+
+impl VerifierData {
+    /// A verifier that was computed elsewhere, for instance by the factory line
+    ///
+    /// This fails with Error::InvalidData if any of the parameters is out of range.
+    pub fn new(verifier: &[u8], salt: &[u8], iterations: u32) -> Result<Self, Error> {
+        if verifier.len() != SPAKE2P_VERIFIER_LEN {
+            error!("Invalid verifier length {}", verifier.len());
+            return Err(Error::InvalidData);
+        }
+        Self::check_params(salt, iterations)?;
+        let mut v = [0; SPAKE2P_VERIFIER_LEN];
+        v.copy_from_slice(verifier);
+        Ok(Self {
+            verifier: v,
+            salt: salt.to_vec(),
+            iterations,
+        })
+    }
+
+    /// Computes the verifier of the passcode `pw`
+    ///
+    /// This is what the host tools use to provision the devices. The passcode isn't
+    /// kept.
+    #[allow(non_snake_case)]
+    pub fn new_with_pw(pw: u32, salt: &[u8], iterations: u32) -> Result<Self, Error> {
+        if !is_valid_passcode(pw) {
+            error!("Invalid passcode");
+            return Err(Error::InvalidData);
+        }
+        Self::check_params(salt, iterations)?;
+        let mut w0w1s = [0; 2 * CRYPTO_W_SIZE_BYTES];
+        Spake2P::get_w0w1s(pw, iterations, salt, &mut w0w1s);
+
+        let mut crypto_spake2 = crypto_spake2_new()?;
+        let (w0s, w1s) = w0w1s.split_at(CRYPTO_W_SIZE_BYTES);
+        crypto_spake2.set_w0_from_w0s(w0s)?;
+        crypto_spake2.set_L(w1s)?;
+
+        let mut v = [0; SPAKE2P_VERIFIER_LEN];
+        let (w0, L) = v.split_at_mut(CRYPTO_GROUP_SIZE_BYTES);
+        crypto_spake2.get_w0(w0)?;
+        crypto_spake2.get_L(L)?;
+        Ok(Self {
+            verifier: v,
+            salt: salt.to_vec(),
+            iterations,
+        })
+    }
+
+    /// Loads the verifier that was provisioned into the persistent storage
+    pub fn load() -> Result<Self, Error> {
+        let psm = Psm::get()?;
+        let psm = psm.lock().unwrap();
+        let mut verifier = Vec::new();
+        psm.get_kv_slice(ST_VERIFIER, &mut verifier)?;
+        let mut salt = Vec::new();
+        psm.get_kv_slice(ST_SALT, &mut salt)?;
+        let mut iterations = 0;
+        psm.get_kv_u64(ST_ITERATIONS, &mut iterations)?;
+        Self::new(&verifier, &salt, iterations as u32)
+    }
+
+    /// Provisions the verifier into the persistent storage, for load() to find
+    pub fn store(&self) -> Result<(), Error> {
+        let psm = Psm::get()?;
+        let psm = psm.lock().unwrap();
+        psm.set_kv_slice(ST_VERIFIER, &self.verifier)?;
+        psm.set_kv_slice(ST_SALT, &self.salt)?;
+        psm.set_kv_u64(ST_ITERATIONS, self.iterations as u64)
+    }
+
+    fn check_params(salt: &[u8], iterations: u32) -> Result<(), Error> {
+        if !SPAKE2P_SALT_LEN.contains(&salt.len()) || !SPAKE2P_ITERATIONS.contains(&iterations) {
+            error!("Invalid salt or iterations");
+            return Err(Error::InvalidData);
+        }
+        Ok(())
+    }
+}
+
 impl Default for Spake2P {
     fn default() -> Self {
         Self::new()
@@ -115,21 +252,76 @@ impl Spake2P {
         let _ = pbkdf2_hmac(&pw_str, iter as usize, salt, w0w1s);
     }
 
-    pub fn start_verifier(&mut self, pw: u32, iter: u32, salt: &[u8]) -> Result<(), Error> {
-        let mut w0w1s: [u8; (2 * CRYPTO_W_SIZE_BYTES)] = [0; (2 * CRYPTO_W_SIZE_BYTES)];
+    #[allow(non_snake_case)]
+    pub fn start_verifier(&mut self, verifier: &VerifierData) -> Result<(), Error> {
+        let (w0, L) = verifier.verifier.split_at(CRYPTO_GROUP_SIZE_BYTES);
+        let mut crypto_spake2 = crypto_spake2_new()?;
+        crypto_spake2.set_w0(w0)?;
+        crypto_spake2.set_L_direct(L)?;
+        self.crypto_spake2 = Some(crypto_spake2);
+
+        self.mode = Spake2Mode::Verifier(Spake2VerifierState::Init);
+        Ok(())
+    }
+
+    /// Starts the prover, with the passcode `pw` of the peer and the PBKDF2
+    /// parameters that the peer sent
+    pub fn start_prover(&mut self, pw: u32, iter: u32, salt: &[u8]) -> Result<(), Error> {
+        let mut w0w1s = [0; 2 * CRYPTO_W_SIZE_BYTES];
         Spake2P::get_w0w1s(pw, iter, salt, &mut w0w1s);
-        self.crypto_spake2 = Some(crypto_spake2_new()?);
 
-        let w0s_len = w0w1s.len() / 2;
-        if let Some(crypto_spake2) = &mut self.crypto_spake2 {
-            crypto_spake2.set_w0_from_w0s(&w0w1s[0..w0s_len])?;
-            crypto_spake2.set_L(&w0w1s[w0s_len..])?;
-        }
+        let mut crypto_spake2 = crypto_spake2_new()?;
+        let (w0s, w1s) = w0w1s.split_at(CRYPTO_W_SIZE_BYTES);
+        crypto_spake2.set_w0_from_w0s(w0s)?;
+        crypto_spake2.set_w1_from_w1s(w1s)?;
+        self.crypto_spake2 = Some(crypto_spake2);
 
-        self.mode = Spake2Mode::Verifier(Spake2VerifierState::Init);
+        self.mode = Spake2Mode::Prover(Spake2ProverState::Init);
+        Ok(())
+    }
+
+    #[allow(non_snake_case)]
+    pub fn get_pA(&mut self, pA: &mut [u8]) -> Result<(), Error> {
+        if self.mode != Spake2Mode::Prover(Spake2ProverState::Init) {
+            return Err(Error::InvalidState);
+        }
+        let crypto_spake2 = self.crypto_spake2.as_mut().ok_or(Error::InvalidState)?;
+        crypto_spake2.get_pA(pA)?;
+        self.mode = Spake2Mode::Prover(Spake2ProverState::PendingConfirmation);
         Ok(())
     }
 
+    /// Handles the verifier's pB and cB, and computes our cA
+    ///
+    /// The Ke is only released if the cB is confirmed.
+    #[allow(non_snake_case)]
+    pub fn handle_pB(
+        &mut self,
+        pA: &[u8],
+        pB: &[u8],
+        cB: &[u8],
+        cA: &mut [u8],
+    ) -> Result<(SCStatusCodes, Option<&[u8]>), Error> {
+        if self.mode != Spake2Mode::Prover(Spake2ProverState::PendingConfirmation) {
+            return Err(Error::InvalidState);
+        }
+        let mut crypto_spake2 = self.crypto_spake2.take().ok_or(Error::InvalidState)?;
+        let context = self.context.take().ok_or(Error::InvalidState)?;
+        let mut hash = [0u8; crypto::SHA256_HASH_LEN_BYTES];
+        context.finish(&mut hash)?;
+        let mut TT = [0u8; crypto::SHA256_HASH_LEN_BYTES];
+        crypto_spake2.get_TT_as_prover(&hash, pA, pB, &mut TT)?;
+
+        let mut our_cB = [0u8; 32];
+        Spake2P::get_Ke_and_cAcB(&TT, pA, pB, &mut self.Ke, cA, &mut our_cB)?;
+        self.mode = Spake2Mode::Prover(Spake2ProverState::Confirmed);
+        if cB.ct_eq(&our_cB).unwrap_u8() == 1 {
+            Ok((SCStatusCodes::SessionEstablishmentSuccess, Some(&self.Ke)))
+        } else {
+            Ok((SCStatusCodes::InvalidParameter, None))
+        }
+    }
+
     #[allow(non_snake_case)]
     pub fn handle_pA(&mut self, pA: &[u8], pB: &mut [u8], cB: &mut [u8]) -> Result<(), Error> {
         if self.mode != Spake2Mode::Verifier(Spake2VerifierState::Init) {
@@ -168,7 +360,6 @@ impl Spake2P {
 
     #[inline(always)]
     #[allow(non_snake_case)]
-    #[allow(dead_code)]
     fn get_Ke_and_cAcB(
         TT: &[u8],
         pA: &[u8],
@@ -211,10 +402,14 @@ impl Spake2P {
 #[cfg(test)]
 mod tests {
 
-    use super::Spake2P;
+    use super::{Spake2P, VerifierData, SPAKE2P_VERIFIER_LEN};
     use crate::{
         crypto,
-        secure_channel::{spake2p::CRYPTO_W_SIZE_BYTES, spake2p_test_vectors::test_vectors::*},
+        error::Error,
+        secure_channel::{
+            common::SCStatusCodes, spake2p::CRYPTO_W_SIZE_BYTES,
+            spake2p_test_vectors::test_vectors::*,
+        },
     };
 
     #[test]
@@ -256,4 +451,88 @@ mod tests {
             assert_eq!(cB, t.cB);
         }
     }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn test_prover_and_verifier() {
+        let salt = b"SPAKE2P Key Salt";
+        let verifier_data = VerifierData::new_with_pw(20202021, salt, 1000).unwrap();
+        for pw in [20202021, 20202022] {
+            let mut prover = Spake2P::new();
+            let mut verifier = Spake2P::new();
+            prover.set_context(b"request", b"response").unwrap();
+            verifier.set_context(b"request", b"response").unwrap();
+            prover.start_prover(pw, 1000, salt).unwrap();
+            verifier.start_verifier(&verifier_data).unwrap();
+
+            let mut pA = [0u8; 65];
+            let mut pB = [0u8; 65];
+            let mut cA = [0u8; 32];
+            let mut cB = [0u8; 32];
+            prover.get_pA(&mut pA).unwrap();
+            verifier.handle_pA(&pA, &mut pB, &mut cB).unwrap();
+            let (status, prover_Ke) = prover.handle_pB(&pA, &pB, &cB, &mut cA).unwrap();
+            let prover_Ke = prover_Ke.map(|k| k.to_vec());
+            let (verifier_status, verifier_Ke) = verifier.handle_cA(&cA);
+            if pw == 20202021 {
+                assert!(status == SCStatusCodes::SessionEstablishmentSuccess);
+                assert!(verifier_status == SCStatusCodes::SessionEstablishmentSuccess);
+                assert!(prover_Ke.is_some());
+                assert_eq!(prover_Ke.as_deref(), verifier_Ke);
+            } else {
+                // Neither side gets a key with the wrong passcode
+                assert!(status == SCStatusCodes::InvalidParameter);
+                assert!(verifier_status == SCStatusCodes::InvalidParameter);
+                assert_eq!(prover_Ke, None);
+                assert_eq!(verifier_Ke, None);
+            }
+        }
+    }
+
+    #[test]
+    fn test_verifier() {
+        // The test verifier of the Matter SDK, for the passcode 20202021
+        let verifier = VerifierData::new_with_pw(20202021, b"SPAKE2P Key Salt", 1000).unwrap();
+        assert_eq!(
+            verifier.verifier,
+            [
+                0xb9, 0x61, 0x70, 0xaa, 0xe8, 0x03, 0x34, 0x68, 0x84, 0x72, 0x4f, 0xe9, 0xa3, 0xb2,
+                0x87, 0xc3, 0x03, 0x30, 0xc2, 0xa6, 0x60, 0x37, 0x5d, 0x17, 0xbb, 0x20, 0x5a, 0x8c,
+                0xf1, 0xae, 0xcb, 0x35, 0x04, 0x57, 0xf8, 0xab, 0x79, 0xee, 0x25, 0x3a, 0xb6, 0xa8,
+                0xe4, 0x6b, 0xb0, 0x9e, 0x54, 0x3a, 0xe4, 0x22, 0x73, 0x6d, 0xe5, 0x01, 0xe3, 0xdb,
+                0x37, 0xd4, 0x41, 0xfe, 0x34, 0x49, 0x20, 0xd0, 0x95, 0x48, 0xe4, 0xc1, 0x82, 0x40,
+                0x63, 0x0c, 0x4f, 0xf4, 0x91, 0x3c, 0x53, 0x51, 0x38, 0x39, 0xb7, 0xc0, 0x7f, 0xcc,
+                0x06, 0x27, 0xa1, 0xb8, 0x57, 0x3a, 0x14, 0x9f, 0xcd, 0x1f, 0xa4, 0x66, 0xcf
+            ]
+        );
+        assert_eq!(
+            VerifierData::new(&verifier.verifier, &verifier.salt, verifier.iterations),
+            Ok(verifier)
+        );
+    }
+
+    #[test]
+    fn test_verifier_params() {
+        let verifier = [0; SPAKE2P_VERIFIER_LEN];
+        assert!(VerifierData::new(&verifier, &[0; 32], 100000).is_ok());
+        assert_eq!(
+            VerifierData::new(&verifier[1..], &[0; 16], 1000),
+            Err(Error::InvalidData)
+        );
+        assert_eq!(
+            VerifierData::new(&verifier, &[0; 15], 1000),
+            Err(Error::InvalidData)
+        );
+        assert_eq!(
+            VerifierData::new_with_pw(123456, &[0; 16], 999),
+            Err(Error::InvalidData)
+        );
+        for pw in [0, 33333333, 12345678, 87654321, 99999999, 100000000] {
+            assert_eq!(
+                VerifierData::new_with_pw(pw, &[0; 16], 1000),
+                Err(Error::InvalidData)
+            );
+        }
+        assert!(VerifierData::new_with_pw(99999998, &[0; 16], 1000).is_ok());
+    }
 }
@@ -1,5 +1,6 @@
 use super::common::*;
 use crate::{error::Error, transport::packet::Packet};
+use byteorder::{ByteOrder, LittleEndian};
 
 #[allow(dead_code)]
 #[derive(Debug, Copy, Clone)]
@@ -41,3 +42,31 @@ pub fn create_status_report(
 
     Ok(())
 }
+
+/// A status report that was received
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct StatusReport {
+    pub general_code: u16,
+    pub proto_id: u32,
+    pub proto_code: u16,
+}
+
+impl StatusReport {
+    pub fn parse(buf: &[u8]) -> Result<Self, Error> {
+        // Any protocol specific data after this is ignored
+        if buf.len() < 8 {
+            return Err(Error::Invalid);
+        }
+        Ok(Self {
+            general_code: LittleEndian::read_u16(&buf[0..]),
+            proto_id: LittleEndian::read_u32(&buf[2..]),
+            proto_code: LittleEndian::read_u16(&buf[6..]),
+        })
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.general_code == GeneralCode::Success as u16
+            && self.proto_id == PROTO_ID_SECURE_CHANNEL as u32
+            && self.proto_code == SCStatusCodes::SessionEstablishmentSuccess as u16
+    }
+}
@@ -1,7 +1,7 @@
 use std::{
     convert::TryInto,
-    fs::{DirBuilder, File},
-    io::{Read, Write},
+    fs::{self, DirBuilder, File},
+    io::{ErrorKind, Read, Write},
     sync::{Arc, Mutex, Once},
 };
 
@@ -47,26 +47,46 @@ impl Psm {
         }
     }
 
-    pub fn set_kv_slice(&self, key: &str, val: &[u8]) -> Result<(), Error> {
-        let mut f = File::create(psm_path!(key))?;
+    // Either the old or the new value is stored, whenever we crash. The value is
+    // written to a temporary file, that then takes the place of the old one.
+    fn write(key: &str, val: &[u8]) -> Result<(), Error> {
+        let path = psm_path!(key);
+        let tmp_path = format!("{}.tmp", path);
+        let mut f = File::create(&tmp_path)?;
         f.write_all(val)?;
+        f.sync_all()?;
+        fs::rename(&tmp_path, &path)?;
+        // The rename itself is only durable once the directory is synced
+        File::open(PSM_DIR)?.sync_all()?;
         Ok(())
     }
 
+    // Fails with Error::NotFound if nothing is stored for the key
+    fn open(key: &str) -> Result<File, Error> {
+        File::open(psm_path!(key)).map_err(|e| match e.kind() {
+            ErrorKind::NotFound => Error::NotFound,
+            _ => e.into(),
+        })
+    }
+
+    pub fn set_kv_slice(&self, key: &str, val: &[u8]) -> Result<(), Error> {
+        Psm::write(key, val)
+    }
+
     pub fn get_kv_slice(&self, key: &str, val: &mut Vec<u8>) -> Result<usize, Error> {
-        let mut f = File::open(psm_path!(key))?;
+        let mut f = Psm::open(key)?;
         let len = f.read_to_end(val)?;
         Ok(len)
     }
 
     pub fn set_kv_u64(&self, key: &str, val: u64) -> Result<(), Error> {
-        let mut f = File::create(psm_path!(key))?;
-        f.write_all(&val.to_be_bytes())?;
-        Ok(())
+        Psm::write(key, &val.to_be_bytes())
     }
 
+    /// Fails with Error::NotFound if nothing is stored for the key, and with
+    /// Error::Invalid if what is stored isn't a u64
     pub fn get_kv_u64(&self, key: &str, val: &mut u64) -> Result<(), Error> {
-        let mut f = File::open(psm_path!(key))?;
+        let mut f = Psm::open(key)?;
         let mut vec = Vec::new();
         let _ = f.read_to_end(&mut vec)?;
         *val = u64::from_be_bytes(vec.as_slice().try_into()?);
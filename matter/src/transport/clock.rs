@@ -0,0 +1,204 @@
+//! The time that the transport runs on
+//!
+//! This is the monotonic clock of the system, except for the futures that run with
+//! simulate(). Their time only moves on when they have nothing left to do but wait
+//! for a timer, and then it jumps to that timer. So the tests of the transport
+//! don't sleep, and run the same way every time.
+
+use std::{
+    cell::RefCell,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{Context, Poll, Wake, Waker},
+    time::{Duration, Instant},
+};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+struct SimClock {
+    now: Instant,
+    // The deadlines that are waited for, and the wakers to wake at them
+    timers: Vec<(Instant, Waker)>,
+    rng: StdRng,
+}
+
+thread_local! {
+    static SIM_CLOCK: RefCell<Option<SimClock>> = const { RefCell::new(None) };
+}
+
+/// The current time
+pub fn now() -> Instant {
+    SIM_CLOCK
+        .with(|sim| sim.borrow().as_ref().map(|sim| sim.now))
+        .unwrap_or_else(Instant::now)
+}
+
+/// A random number in 0..1, for the jitter of the timers
+///
+/// On a simulated clock, this is drawn from a PRNG with the seed of the simulation.
+pub fn random_fraction() -> f64 {
+    SIM_CLOCK
+        .with(|sim| {
+            sim.borrow_mut()
+                .as_mut()
+                .map(|sim| sim.rng.gen_range(0.0..1.0))
+        })
+        .unwrap_or_else(|| rand::thread_rng().gen_range(0.0..1.0))
+}
+
+/// A timer that goes off at its deadline, on the clock of whoever polls it
+pub struct Timer {
+    deadline: Option<Instant>,
+    // The timer of the system clock, once it is polled there
+    timer: Option<smol::Timer>,
+}
+
+impl Timer {
+    pub fn never() -> Self {
+        Self {
+            deadline: None,
+            timer: None,
+        }
+    }
+
+    pub fn at(deadline: Instant) -> Self {
+        Self {
+            deadline: Some(deadline),
+            timer: None,
+        }
+    }
+
+    pub fn after(duration: Duration) -> Self {
+        Self::at(now() + duration)
+    }
+
+    pub fn set_at(&mut self, deadline: Instant) {
+        self.deadline = Some(deadline);
+        self.timer = None;
+    }
+
+    pub fn set_after(&mut self, duration: Duration) {
+        self.set_at(now() + duration)
+    }
+}
+
+impl Future for Timer {
+    type Output = Instant;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Instant> {
+        let deadline = match self.deadline {
+            Some(deadline) => deadline,
+            None => return Poll::Pending,
+        };
+        let simulated = SIM_CLOCK.with(|sim| {
+            let mut sim = sim.borrow_mut();
+            let sim = sim.as_mut()?;
+            if sim.now >= deadline {
+                return Some(Poll::Ready(deadline));
+            }
+            sim.timers.push((deadline, cx.waker().clone()));
+            Some(Poll::Pending)
+        });
+        if let Some(poll) = simulated {
+            return poll;
+        }
+        let timer = self.timer.get_or_insert_with(|| smol::Timer::at(deadline));
+        Pin::new(timer).poll(cx)
+    }
+}
+
+struct Woken(AtomicBool);
+
+impl Wake for Woken {
+    fn wake(self: Arc<Self>) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Runs the future on the current thread, on a simulated clock that starts at the
+/// current time
+///
+/// This is for the tests. Whatever the future waits on must be driven by the future
+/// itself, or by a Timer, as nothing else can wake it up. The jitter of the timers
+/// is drawn from a PRNG with `seed`.
+pub fn simulate<T>(seed: u64, fut: impl Future<Output = T>) -> T {
+    SIM_CLOCK.with(|sim| {
+        *sim.borrow_mut() = Some(SimClock {
+            now: Instant::now(),
+            timers: Vec::new(),
+            rng: StdRng::seed_from_u64(seed),
+        })
+    });
+    let woken = Arc::new(Woken(AtomicBool::new(false)));
+    let waker = Waker::from(woken.clone());
+    let mut cx = Context::from_waker(&waker);
+    let mut fut = Box::pin(fut);
+    loop {
+        if let Poll::Ready(result) = fut.as_mut().poll(&mut cx) {
+            SIM_CLOCK.with(|sim| *sim.borrow_mut() = None);
+            return result;
+        }
+        if woken.0.swap(false, Ordering::SeqCst) {
+            continue;
+        }
+        // Nothing to do but wait, on to the earliest timer
+        let due = SIM_CLOCK.with(|sim| {
+            let mut sim = sim.borrow_mut();
+            let sim = sim.as_mut().unwrap();
+            let next = sim
+                .timers
+                .iter()
+                .map(|(deadline, _)| *deadline)
+                .min()
+                .expect("Nothing left to wait for on the simulated clock");
+            sim.now = sim.now.max(next);
+            let now = sim.now;
+            let (due, timers): (Vec<_>, Vec<_>) =
+                sim.timers.drain(..).partition(|(d, _)| *d <= now);
+            sim.timers = timers;
+            due
+        });
+        for (_, waker) in due {
+            waker.wake();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use smol::future::FutureExt;
+
+    use super::{now, random_fraction, simulate, Timer};
+
+    #[test]
+    fn test_simulated_time() {
+        let (first, elapsed) = simulate(1, async {
+            let start = now();
+            let first = async {
+                Timer::after(Duration::from_secs(3600)).await;
+                1
+            }
+            .or(async {
+                Timer::after(Duration::from_secs(60)).await;
+                2
+            })
+            .await;
+            (first, now() - start)
+        });
+        assert_eq!(first, 2);
+        assert_eq!(elapsed, Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_seeded_jitter() {
+        let draw = |seed| simulate(seed, async { [random_fraction(), random_fraction()] });
+        assert_eq!(draw(5), draw(5));
+        assert_ne!(draw(5), draw(6));
+    }
+}
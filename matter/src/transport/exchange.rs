@@ -1,17 +1,31 @@
-use boxslab::{BoxSlab, Slab};
+use boxslab::BoxSlab;
 use colored::*;
 use log::{error, info, trace};
 use std::any::Any;
+use std::collections::BTreeMap;
 use std::fmt;
+use std::time::{Duration, Instant};
 
 use crate::error::Error;
+use crate::group_keys::GroupKeys;
+use crate::limits::Limits;
 use crate::secure_channel;
 
 use heapless::LinearMap;
 
+use super::clock;
+use super::group::GroupSessionMgr;
+use super::network::Address;
 use super::packet::PacketPool;
-use super::session::CloneData;
-use super::{mrp::ReliableMessage, packet::Packet, session::SessionHandle, session::SessionMgr};
+use super::queue::RespSender;
+use super::session::{CloneData, ExpiredSession, IdleTimeouts, SessionMode};
+use super::stats::StatsHandle;
+use super::{
+    mrp::{ReliableMessage, RetransAction},
+    packet::Packet,
+    session::SessionHandle,
+    session::SessionMgr,
+};
 
 pub struct ExchangeCtx<'a> {
     pub exch: &'a mut Exchange,
@@ -42,12 +56,19 @@ impl Default for State {
     }
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct Exchange {
     id: u16,
     sess_idx: usize,
     role: Role,
     state: State,
+    // When something was last sent or received on the exchange
+    last_activity: Instant,
+    // Since when we wait for the peer to respond, on an exchange that we initiated
+    awaiting_resp: Option<Instant>,
+    // The (vendor id, protocol id) of what we last sent, its handler carries on the
+    // exchange
+    proto: (u16, u16),
     // Currently I see this primarily used in PASE and CASE. If that is the limited use
     // of this, we might move this into a separate data structure, so as not to burden
     // all 'exchanges'.
@@ -62,6 +83,9 @@ impl Exchange {
             sess_idx,
             role,
             state: State::Open,
+            last_activity: clock::now(),
+            awaiting_resp: None,
+            proto: (0, 0),
             data: None,
             mrp: ReliableMessage::new(),
         }
@@ -89,6 +113,33 @@ impl Exchange {
         self.role
     }
 
+    /// The (vendor id, protocol id) of the message that we last sent on the exchange
+    pub fn get_proto(&self) -> (u16, u16) {
+        self.proto
+    }
+
+    // The time left at `now`, till the peer is late with its response
+    fn get_time_to_resp_timeout(&self, timeouts: &IdleTimeouts, now: Instant) -> Option<Duration> {
+        let since = self.awaiting_resp?;
+        Some(
+            timeouts
+                .response?
+                .saturating_sub(now.saturating_duration_since(since)),
+        )
+    }
+
+    // The time left at `now`, till the exchange expires because it was idle or
+    // the peer is late with its response
+    fn get_time_to_expiry(&self, timeouts: &IdleTimeouts, now: Instant) -> Option<Duration> {
+        let idle = timeouts
+            .exchange
+            .map(|t| t.saturating_sub(now.saturating_duration_since(self.last_activity)));
+        match (idle, self.get_time_to_resp_timeout(timeouts, now)) {
+            (Some(t1), Some(t2)) => Some(t1.min(t2)),
+            (t1, t2) => t1.or(t2),
+        }
+    }
+
     pub fn set_exchange_data(&mut self, data: Box<dyn Any>) {
         self.data = Some(data);
     }
@@ -119,13 +170,34 @@ impl Exchange {
         );
 
         proto_tx.proto.exch_id = self.id;
+        if session.get_peer_addr().is_reliable() {
+            // The transport takes care of delivery, no MRP for such sessions
+            proto_tx.unset_reliable();
+        }
         if self.role == Role::Initiator {
             proto_tx.proto.set_initiator();
         }
 
         session.pre_send(&mut proto_tx)?;
         self.mrp.pre_send(&mut proto_tx)?;
-        session.send(proto_tx)
+        session.send(&mut proto_tx)?;
+        self.last_activity = clock::now();
+        if !proto_tx.is_standalone_ack() {
+            self.proto = proto_tx.get_proto();
+            if self.role == Role::Initiator && self.data.is_some() {
+                // Whoever holds the data expects to hear back
+                self.awaiting_resp = Some(self.last_activity);
+            }
+        }
+        // Keep the encoded message around, in case it has to be retransmitted
+        self.mrp.post_send(proto_tx, session.get_mrp_interval());
+        Ok(())
+    }
+}
+
+impl Default for Exchange {
+    fn default() -> Self {
+        Exchange::new(0, 0, Role::default())
     }
 }
 
@@ -155,22 +227,94 @@ pub fn get_complementary_role(is_initiator: bool) -> Role {
     }
 }
 
-const MAX_EXCHANGES: usize = 8;
+/// The open exchanges, keyed by their id
+pub struct ExchangeTable {
+    exchanges: BTreeMap<u16, Exchange>,
+    capacity: usize,
+}
+
+impl Default for ExchangeTable {
+    fn default() -> Self {
+        Self::new(Limits::default().exchanges)
+    }
+}
+
+impl ExchangeTable {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            exchanges: BTreeMap::new(),
+            capacity,
+        }
+    }
+
+    pub fn contains_key(&self, id: &u16) -> bool {
+        self.exchanges.contains_key(id)
+    }
+
+    /// Adds an exchange, this hands it back if the table is full
+    pub fn insert(&mut self, id: u16, exchange: Exchange) -> Result<(), Box<Exchange>> {
+        if self.exchanges.len() >= self.capacity && !self.contains_key(&id) {
+            error!("Limit of {} exchanges reached", self.capacity);
+            return Err(Box::new(exchange));
+        }
+        self.exchanges.insert(id, exchange);
+        Ok(())
+    }
+
+    pub fn get_mut(&mut self, id: &u16) -> Option<&mut Exchange> {
+        self.exchanges.get_mut(id)
+    }
+
+    pub fn remove(&mut self, id: &u16) -> Option<Exchange> {
+        self.exchanges.remove(id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&u16, &Exchange)> {
+        self.exchanges.iter()
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&u16, &mut Exchange)> {
+        self.exchanges.iter_mut()
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &Exchange> {
+        self.exchanges.values()
+    }
+}
 
 #[derive(Default)]
 pub struct ExchangeMgr {
     // keys: exch-id
-    exchanges: LinearMap<u16, Exchange, MAX_EXCHANGES>,
+    exchanges: ExchangeTable,
     sess_mgr: SessionMgr,
+    group: GroupSessionMgr,
+    next_exch_id: u16,
 }
 
 pub const MAX_MRP_ENTRIES: usize = 4;
 
 impl ExchangeMgr {
     pub fn new(sess_mgr: SessionMgr) -> Self {
+        Self::new_with_limits(sess_mgr, &Limits::default())
+    }
+
+    pub fn new_with_limits(sess_mgr: SessionMgr, limits: &Limits) -> Self {
         Self {
             sess_mgr,
-            exchanges: Default::default(),
+            group: GroupSessionMgr::new_with_limits(limits),
+            exchanges: ExchangeTable::new(limits.exchanges),
+            // The spec requires the first exchange id that we initiate to be random
+            next_exch_id: rand::random(),
+        }
+    }
+
+    fn get_next_exch_id(&mut self) -> u16 {
+        loop {
+            let exch_id = self.next_exch_id;
+            self.next_exch_id = self.next_exch_id.wrapping_add(1);
+            if !self.exchanges.contains_key(&exch_id) {
+                return exch_id;
+            }
         }
     }
 
@@ -178,10 +322,7 @@ impl ExchangeMgr {
         &mut self.sess_mgr
     }
 
-    pub fn _get_with_id(
-        exchanges: &mut LinearMap<u16, Exchange, MAX_EXCHANGES>,
-        exch_id: u16,
-    ) -> Option<&mut Exchange> {
+    pub fn _get_with_id(exchanges: &mut ExchangeTable, exch_id: u16) -> Option<&mut Exchange> {
         exchanges.get_mut(&exch_id)
     }
 
@@ -190,7 +331,7 @@ impl ExchangeMgr {
     }
 
     fn _get(
-        exchanges: &mut LinearMap<u16, Exchange, MAX_EXCHANGES>,
+        exchanges: &mut ExchangeTable,
         sess_idx: usize,
         id: u16,
         role: Role,
@@ -224,12 +365,102 @@ impl ExchangeMgr {
         }
     }
 
-    /// The Exchange Mgr receive is like a big processing function
-    pub fn recv(&mut self) -> Result<Option<(BoxSlab<PacketPool>, ExchangeCtx)>, Error> {
-        // Get the session
-        let (mut proto_rx, index) = self.sess_mgr.recv()?;
+    /// Opens a new exchange as the initiator on the session with the local id `sess_id`,
+    /// and sends `proto_tx` on it
+    ///
+    /// The exchange data is set to `data`, so that the response can be matched
+    /// with the request. This returns the id of the new exchange.
+    pub fn initiate(
+        &mut self,
+        sess_id: u16,
+        proto_tx: BoxSlab<PacketPool>,
+        data: Box<dyn Any>,
+    ) -> Result<u16, Error> {
+        let index = self
+            .sess_mgr
+            .get_index_with_id(sess_id)
+            .ok_or(Error::NoSession)?;
+        self.initiate_on(index, proto_tx, data)
+    }
+
+    /// The same as initiate(), on the unsecured session with `peer`
+    ///
+    /// The session is created if there isn't one yet.
+    pub fn initiate_unsecured(
+        &mut self,
+        peer: Address,
+        proto_tx: BoxSlab<PacketPool>,
+        data: Box<dyn Any>,
+    ) -> Result<u16, Error> {
+        let index = self.get_or_add_unsecured(peer)?;
+        self.initiate_on(index, proto_tx, data)
+    }
+
+    fn initiate_on(
+        &mut self,
+        index: usize,
+        proto_tx: BoxSlab<PacketPool>,
+        data: Box<dyn Any>,
+    ) -> Result<u16, Error> {
+        let exch_id = self.get_next_exch_id();
+        let exchange =
+            ExchangeMgr::_get(&mut self.exchanges, index, exch_id, Role::Initiator, true)?;
+        exchange.set_exchange_data(data);
+
+        let stats = self.sess_mgr.get_stats_handle();
+        let mut session = self.sess_mgr.get_session_handle(index);
+        let proto = Some(proto_tx.get_proto());
+        stats.count(session.get_session_mode(), proto, |c| {
+            c.exchanges_opened += 1
+        });
+        if let Err(e) = exchange.send(proto_tx, &mut session) {
+            exchange.close();
+            return Err(e);
+        }
+        Ok(exch_id)
+    }
+
+    // The unsecured session with the peer, another session is evicted to make
+    // room for it if needed
+    fn get_or_add_unsecured(&mut self, peer: Address) -> Result<usize, Error> {
+        match self.sess_mgr.get_or_add(0, peer, None, false) {
+            Ok(index) => Ok(index),
+            Err(Error::NoSpace) => {
+                let evict_index = self.sess_mgr.get_lru();
+                self.evict_session(evict_index)?;
+                self.sess_mgr.get_or_add(0, peer, None, false)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Waits for a message from the network
+    pub async fn recv(&self) -> Result<BoxSlab<PacketPool>, Error> {
+        self.sess_mgr.recv().await
+    }
 
-        let index = match index {
+    /// The Exchange Mgr post-receive is like a big processing function
+    ///
+    /// This returns None if there is nothing further to process for the message
+    pub fn post_recv(
+        &mut self,
+        mut proto_rx: BoxSlab<PacketPool>,
+    ) -> Result<Option<(BoxSlab<PacketPool>, ExchangeCtx)>, Error> {
+        if proto_rx.plain.is_group() {
+            let stats = self.sess_mgr.get_stats_handle();
+            let result = self.group.post_recv(proto_rx);
+            // The group sessions are all counted together
+            let mode = SessionMode::Group(0, 0);
+            match &result {
+                Ok(Some((rx, _))) => stats.count(mode, Some(rx.get_proto()), |c| c.rx += 1),
+                Ok(None) => stats.count(mode, None, |c| c.duplicates += 1),
+                Err(_) => stats.count(mode, None, |c| c.decrypt_failures += 1),
+            }
+            return result;
+        }
+
+        // Get the session
+        let index = match self.sess_mgr.post_recv(&proto_rx)? {
             Some(s) => s,
             None => {
                 // The sessions were full, evict one session, and re-perform post-recv
@@ -239,12 +470,20 @@ impl ExchangeMgr {
                 self.sess_mgr.post_recv(&proto_rx)?.ok_or(Error::Invalid)?
             }
         };
+        let stats = self.sess_mgr.get_stats_handle();
         let mut session = self.sess_mgr.get_session_handle(index);
+        let mode = session.get_session_mode();
 
         // Decrypt the message
-        session.recv(&mut proto_rx)?;
+        if let Err(e) = session.recv(&mut proto_rx) {
+            stats.count(mode, None, |c| c.decrypt_failures += 1);
+            return Err(e);
+        }
+        let proto = Some(proto_rx.get_proto());
+        stats.count(mode, proto, |c| c.rx += 1);
 
         // Get the exchange
+        let exch_created = !self.exchanges.contains_key(&proto_rx.proto.exch_id);
         let exch = ExchangeMgr::_get(
             &mut self.exchanges,
             index,
@@ -256,6 +495,24 @@ impl ExchangeMgr {
 
         // Message Reliability Protocol
         exch.mrp.recv(&proto_rx)?;
+        exch.last_activity = clock::now();
+        if !proto_rx.is_standalone_ack() {
+            exch.awaiting_resp = None;
+        }
+
+        if exch_created {
+            stats.count(mode, proto, |c| c.exchanges_opened += 1);
+        }
+
+        if proto_rx.is_duplicate() {
+            stats.count(mode, proto, |c| c.duplicates += 1);
+            // MRP would have taken care of acknowledging the duplicate, it must not be
+            // processed any further. If the exchange was created only for this, get rid of it
+            if exch_created {
+                exch.close();
+            }
+            return Ok(None);
+        }
 
         if exch.is_state_open() {
             Ok(Some((
@@ -272,6 +529,55 @@ impl ExchangeMgr {
         }
     }
 
+    /// Sends a message from us (`local_nodeid`) to a group, on a new exchange
+    ///
+    /// Nobody responds to a message to a group, nor acknowledges it.
+    pub fn send_group(
+        &mut self,
+        fab_idx: u8,
+        group_id: u16,
+        local_nodeid: u64,
+        dst: Address,
+        mut proto_tx: BoxSlab<PacketPool>,
+    ) -> Result<(), Error> {
+        let key = GroupKeys::get()?
+            .lock()?
+            .get_key(fab_idx, group_id)
+            .ok_or(Error::NotFound)?;
+        let mut session = self.group.get_tx_session(&key, local_nodeid, dst);
+        proto_tx.proto.exch_id = self.get_next_exch_id();
+        proto_tx.proto.set_initiator();
+        proto_tx.unset_reliable();
+
+        session.pre_send(&mut proto_tx)?;
+        session.do_send(&mut proto_tx)?;
+        self.sess_mgr.send_encoded(&mut proto_tx)?;
+        let proto = Some(proto_tx.get_proto());
+        self.sess_mgr
+            .get_stats_handle()
+            .count(session.get_session_mode(), proto, |c| c.tx += 1);
+        Ok(())
+    }
+
+    /// Sends a message to `peer` outside of any secure session, on a new exchange
+    ///
+    /// The message goes on the unsecured session with the peer, that is created if
+    /// there isn't one yet. It is neither acknowledged nor responded to.
+    pub fn send_unsecured(
+        &mut self,
+        peer: Address,
+        mut proto_tx: BoxSlab<PacketPool>,
+    ) -> Result<(), Error> {
+        let index = self.get_or_add_unsecured(peer)?;
+        proto_tx.proto.exch_id = self.get_next_exch_id();
+        proto_tx.proto.set_initiator();
+        proto_tx.unset_reliable();
+
+        let mut session = self.sess_mgr.get_session_handle(index);
+        session.pre_send(&mut proto_tx)?;
+        session.send(&mut proto_tx)
+    }
+
     pub fn send(&mut self, exch_id: u16, proto_tx: BoxSlab<PacketPool>) -> Result<(), Error> {
         let exchange =
             ExchangeMgr::_get_with_id(&mut self.exchanges, exch_id).ok_or(Error::NoExchange)?;
@@ -280,24 +586,210 @@ impl ExchangeMgr {
     }
 
     pub fn purge(&mut self) {
-        let mut to_purge: LinearMap<u16, (), MAX_EXCHANGES> = LinearMap::new();
+        let to_purge: Vec<u16> = self
+            .exchanges
+            .iter()
+            .filter(|(_, exchange)| exchange.is_purgeable())
+            .map(|(exch_id, _)| *exch_id)
+            .collect();
+        for exch_id in to_purge {
+            self.exchanges.remove(&exch_id);
+        }
+    }
 
+    pub fn pending_acks(&mut self, expired_entries: &mut LinearMap<u16, (), MAX_MRP_ENTRIES>) {
         for (exch_id, exchange) in self.exchanges.iter() {
-            if exchange.is_purgeable() {
-                let _ = to_purge.insert(*exch_id, ());
+            if exchange.mrp.is_ack_ready() && expired_entries.insert(*exch_id, ()).is_err() {
+                // The rest will be picked up in the next round
+                break;
+            }
+        }
+    }
+
+    /// Returns the time left till the earliest pending ACK or retransmission is due
+    pub fn get_next_timeout(&self) -> Option<Duration> {
+        let now = clock::now();
+        self.exchanges
+            .values()
+            .filter_map(|e| e.mrp.get_next_timeout())
+            .min()
+            .map(|t| t.saturating_duration_since(now))
+    }
+
+    /// Resend all the reliable messages whose acknowledgement is overdue
+    ///
+    /// If a peer hasn't acknowledged a message even after the maximum number of
+    /// transmissions, we consider it gone and tear down the exchange and the session.
+    pub fn pending_retrans(&mut self) {
+        let mut unresponsive: Vec<usize> = Vec::new();
+        for (exch_id, exchange) in self.exchanges.iter_mut() {
+            match exchange.mrp.get_retrans() {
+                Some(RetransAction::Resend(proto_tx)) => {
+                    if let Err(e) = self.sess_mgr.retransmit(exchange.sess_idx, proto_tx) {
+                        error!("Error in retransmitting on exch {}: {:?}", exch_id, e);
+                    }
+                }
+                Some(RetransAction::GiveUp) => {
+                    error!("Peer unresponsive, closing exch {}", exch_id);
+                    exchange.close();
+                    unresponsive.push(exchange.sess_idx);
+                }
+                None => (),
             }
         }
-        for (exch_id, _) in to_purge.iter() {
-            self.exchanges.remove(&*exch_id);
+        for sess_idx in unresponsive {
+            if let Some(session) = self.sess_mgr.mut_by_index(sess_idx) {
+                let mode = session.get_session_mode();
+                self.sess_mgr
+                    .get_stats_handle()
+                    .count(mode, None, |c| c.ack_timeouts += 1);
+            }
+            self.remove_session(sess_idx);
         }
     }
 
-    pub fn pending_acks(&mut self, expired_entries: &mut LinearMap<u16, (), MAX_MRP_ENTRIES>) {
-        for (exch_id, exchange) in self.exchanges.iter() {
-            if exchange.mrp.is_ack_ready() {
-                expired_entries.insert(*exch_id, ()).unwrap();
+    /// Sends out all the pending acknowledgements right away, instead of waiting for
+    /// them to be piggybacked
+    pub fn flush_acks(&mut self) {
+        for (exch_id, exchange) in self.exchanges.iter_mut() {
+            if !exchange.mrp.has_pending_ack() {
+                continue;
+            }
+            let mut session = self.sess_mgr.get_session_handle(exchange.sess_idx);
+            if let Err(e) = ExchangeMgr::send_ack(*exch_id, exchange, &mut session) {
+                error!("Error in sending Ack for exch {}: {:?}", exch_id, e);
+            }
+        }
+    }
+
+    fn send_ack(
+        exch_id: u16,
+        exchange: &mut Exchange,
+        session: &mut SessionHandle,
+    ) -> Result<(), Error> {
+        let mut tx = Packet::alloc_tx()?;
+        ReliableMessage::prepare_ack(exch_id, &mut tx);
+        exchange.send(tx, session)
+    }
+
+    /// Removes the sessions that were idle for longer than `timeouts` allow, and
+    /// returns them
+    ///
+    /// The peers of the secure sessions among them are sent a CloseSession.
+    pub fn expire_sessions(&mut self, timeouts: &IdleTimeouts) -> Vec<ExpiredSession> {
+        let mut expired = Vec::new();
+        let stats = self.sess_mgr.get_stats_handle();
+        for index in self.sess_mgr.get_expired(timeouts) {
+            let session = match self.sess_mgr.mut_by_index(index) {
+                Some(session) => session,
+                None => continue,
+            };
+            info!("Session expired: {}", session);
+            let is_encrypted = session.is_encrypted();
+            let mode = session.get_session_mode();
+            expired.push(ExpiredSession {
+                local_sess_id: session.get_local_sess_id(),
+                peer_nodeid: session.get_peer_node_id(),
+                peer_addr: session.get_peer_addr(),
+                mode,
+            });
+            stats.count(mode, None, |c| c.sessions_expired += 1);
+            if is_encrypted {
+                if let Err(e) = self.send_close_session(index) {
+                    error!("Error in sending Close Session: {:?}", e);
+                }
+            }
+            self.remove_session(index);
+        }
+        expired
+    }
+
+    pub fn get_stats_handle(&self) -> StatsHandle {
+        self.sess_mgr.get_stats_handle()
+    }
+
+    /// Returns the time left till the earliest session or exchange expires
+    pub fn get_next_expiry(&self, timeouts: &IdleTimeouts) -> Option<Duration> {
+        let sess_expiry = self.sess_mgr.get_next_expiry(timeouts);
+        match (
+            sess_expiry,
+            self.get_next_exch_expiry(timeouts, clock::now()),
+        ) {
+            (Some(t1), Some(t2)) => Some(t1.min(t2)),
+            (t1, t2) => t1.or(t2),
+        }
+    }
+
+    fn get_next_exch_expiry(&self, timeouts: &IdleTimeouts, now: Instant) -> Option<Duration> {
+        self.exchanges
+            .values()
+            .filter(|e| e.is_state_open())
+            .filter_map(|e| e.get_time_to_expiry(timeouts, now))
+            .min()
+    }
+
+    /// Closes the open exchanges that had nothing sent or received on them for as
+    /// long as `timeouts` allow, at `now`
+    ///
+    /// This returns the ids of the exchanges that we initiated, whose peer didn't
+    /// respond in time. They are left open, so that whoever waits on them can be
+    /// told of the timeout before they are closed.
+    pub fn expire_exchanges(&mut self, timeouts: &IdleTimeouts, now: Instant) -> Vec<u16> {
+        let mut late = Vec::new();
+        for (exch_id, exchange) in self.exchanges.iter_mut() {
+            if !exchange.is_state_open() {
+                continue;
+            }
+            if exchange.get_time_to_resp_timeout(timeouts, now) == Some(Duration::ZERO) {
+                error!("No response on exch {} in time", exch_id);
+                late.push(*exch_id);
+            } else if exchange.get_time_to_expiry(timeouts, now) == Some(Duration::ZERO) {
+                info!("Closing exch {}, it was idle for too long", exch_id);
+                exchange.close();
             }
         }
+        late
+    }
+
+    /// Sends a CloseSession to the peers of all the secure sessions, and then removes
+    /// all the sessions, along with their exchanges
+    pub fn close_sessions(&mut self) {
+        for index in 0..self.sess_mgr.capacity() {
+            match self.sess_mgr.mut_by_index(index) {
+                Some(session) if session.is_encrypted() => {
+                    if let Err(e) = self.send_close_session(index) {
+                        error!("Error in sending Close Session: {:?}", e);
+                    }
+                }
+                Some(_) => (),
+                None => continue,
+            }
+            self.remove_session(index);
+        }
+    }
+
+    // Sends a CloseSession on a new exchange, after dropping any other exchange on
+    // the session
+    fn send_close_session(&mut self, index: usize) -> Result<(), Error> {
+        self.remove_exchanges(index);
+        let exch_id = self.get_next_exch_id();
+        let exchange =
+            ExchangeMgr::_get(&mut self.exchanges, index, exch_id, Role::Initiator, true)?;
+
+        let mut tx = Packet::alloc_tx()?;
+        secure_channel::common::create_sc_status_report(
+            &mut tx,
+            secure_channel::common::SCStatusCodes::CloseSession,
+            None,
+        )?;
+        info!("Sending Close Session on exch {}", exch_id);
+        let stats = self.sess_mgr.get_stats_handle();
+        let mut session = self.sess_mgr.get_session_handle(index);
+        let proto = Some(tx.get_proto());
+        stats.count(session.get_session_mode(), proto, |c| {
+            c.exchanges_opened += 1
+        });
+        exchange.send(tx, &mut session)
     }
 
     pub fn evict_session(&mut self, index: usize) -> Result<(), Error> {
@@ -305,8 +797,21 @@ impl ExchangeMgr {
         // If we enter here, we have an LRU session that needs to be reclaimed
         // As per the spec, we need to send a CLOSE here
 
+        if let Some(session) = self.sess_mgr.mut_by_index(index) {
+            let mode = session.get_session_mode();
+            let exchanges = self
+                .exchanges
+                .values()
+                .filter(|e| e.sess_idx == index)
+                .count() as u64;
+            self.sess_mgr.get_stats_handle().count(mode, None, |c| {
+                c.sessions_evicted += 1;
+                c.exchanges_evicted += exchanges;
+            });
+        }
+
         let mut session = self.sess_mgr.get_session_handle(index);
-        let mut tx = Slab::<PacketPool>::new(Packet::new_tx()?).ok_or(Error::NoSpace)?;
+        let mut tx = Packet::alloc_tx()?;
         secure_channel::common::create_sc_status_report(
             &mut tx,
             secure_channel::common::SCStatusCodes::CloseSession,
@@ -321,11 +826,46 @@ impl ExchangeMgr {
             // TODO: This wouldn't actually send it out, because 'transport' isn't owned yet.
         }
 
+        self.remove_session(index);
+        Ok(())
+    }
+
+    /// Removes the sessions with the peers that the network interfaces lost their
+    /// connection to
+    ///
+    /// Whoever waits for a response on such a session gets Error::Network.
+    pub fn drop_unreachable(&mut self) {
+        for peer in self.sess_mgr.take_unreachable() {
+            for index in self.sess_mgr.get_with_peer_addr(peer) {
+                info!(
+                    "Dropping session {}, its peer {} is unreachable",
+                    index, peer
+                );
+                for (_, exchange) in self.exchanges.iter_mut() {
+                    if exchange.sess_idx != index {
+                        continue;
+                    }
+                    if let Some(resp_tx) = exchange.take_exchange_data::<RespSender>() {
+                        let _ = resp_tx.try_send(Err(Error::Network));
+                    }
+                }
+                self.remove_session(index);
+            }
+        }
+    }
+
+    /// Removes the session along with all the exchanges on it, without informing the peer
+    fn remove_session(&mut self, index: usize) {
+        self.remove_exchanges(index);
+        self.sess_mgr.remove(index);
+    }
+
+    fn remove_exchanges(&mut self, sess_idx: usize) {
         let remove_exchanges: Vec<u16> = self
             .exchanges
             .iter()
             .filter_map(|(eid, e)| {
-                if e.sess_idx == index {
+                if e.sess_idx == sess_idx {
                     Some(*eid)
                 } else {
                     None
@@ -340,8 +880,6 @@ impl ExchangeMgr {
             // Remove from exchange list
             self.exchanges.remove(&exch_id);
         }
-        self.sess_mgr.remove(index);
-        Ok(())
     }
 
     pub fn add_session(&mut self, clone_data: CloneData) -> Result<SessionHandle, Error> {
@@ -364,7 +902,7 @@ impl fmt::Display for ExchangeMgr {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         writeln!(f, "{{  Session Mgr: {},", self.sess_mgr)?;
         writeln!(f, "  Exchanges: [")?;
-        for s in &self.exchanges {
+        for s in self.exchanges.iter() {
             writeln!(f, "{{ {}, }},", s.1)?;
         }
         writeln!(f, "  ]")?;
@@ -375,16 +913,47 @@ impl fmt::Display for ExchangeMgr {
 #[cfg(test)]
 mod tests {
 
+    use std::{
+        sync::Mutex,
+        task::{Context, Poll},
+        time::{Duration, Instant},
+    };
+
+    use async_channel::bounded;
+
     use crate::{
         error::Error,
+        limits::Limits,
         transport::{
             network::{Address, NetworkInterface},
-            session::{CloneData, SessionMgr, SessionMode, MAX_SESSIONS},
+            queue::RxMsg,
+            session::{CloneData, IdleTimeouts, SessionMgr, SessionMode},
         },
     };
 
     use super::{ExchangeMgr, Role};
 
+    #[test]
+    fn test_exchange_limit() {
+        let limits = Limits {
+            exchanges: 2,
+            ..Default::default()
+        };
+        let mut mgr = ExchangeMgr::new_with_limits(SessionMgr::new(), &limits);
+        ExchangeMgr::_get(&mut mgr.exchanges, 1, 2, Role::Responder, true).unwrap();
+        ExchangeMgr::_get(&mut mgr.exchanges, 1, 3, Role::Responder, true).unwrap();
+        assert_eq!(
+            ExchangeMgr::_get(&mut mgr.exchanges, 1, 4, Role::Responder, true).map(|_| ()),
+            Err(Error::NoSpace)
+        );
+        // The existing ones can still be looked up
+        ExchangeMgr::_get(&mut mgr.exchanges, 1, 2, Role::Responder, true).unwrap();
+
+        mgr.exchanges.get_mut(&2).unwrap().close();
+        mgr.purge();
+        ExchangeMgr::_get(&mut mgr.exchanges, 1, 4, Role::Responder, true).unwrap();
+    }
+
     #[test]
     fn test_purge() {
         let sess_mgr = SessionMgr::new();
@@ -416,6 +985,87 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_exchange_idle_timeout() {
+        let mut mgr = ExchangeMgr::new(SessionMgr::new());
+        let timeouts = IdleTimeouts {
+            exchange: Some(Duration::from_secs(30)),
+            ..Default::default()
+        };
+        let start = Instant::now();
+        ExchangeMgr::_get(&mut mgr.exchanges, 1, 2, Role::Responder, true).unwrap();
+        ExchangeMgr::_get(&mut mgr.exchanges, 1, 3, Role::Responder, true).unwrap();
+        mgr.get_with_id(2).unwrap().last_activity = start;
+        mgr.get_with_id(3).unwrap().last_activity = start + Duration::from_secs(20);
+
+        let at = |secs| start + Duration::from_secs(secs);
+        assert_eq!(
+            mgr.get_next_exch_expiry(&timeouts, at(10)),
+            Some(Duration::from_secs(20))
+        );
+        mgr.expire_exchanges(&timeouts, at(29));
+        assert!(mgr.get_with_id(2).unwrap().is_state_open());
+
+        // Only the one that was idle for long enough is closed
+        mgr.expire_exchanges(&timeouts, at(30));
+        assert!(!mgr.get_with_id(2).unwrap().is_state_open());
+        assert!(mgr.get_with_id(3).unwrap().is_state_open());
+        assert_eq!(
+            mgr.get_next_exch_expiry(&timeouts, at(30)),
+            Some(Duration::from_secs(20))
+        );
+
+        // Without a timeout, the exchanges stay open
+        let timeouts = IdleTimeouts {
+            exchange: None,
+            ..Default::default()
+        };
+        mgr.expire_exchanges(&timeouts, at(100));
+        assert!(mgr.get_with_id(3).unwrap().is_state_open());
+        assert_eq!(mgr.get_next_exch_expiry(&timeouts, at(100)), None);
+    }
+
+    #[test]
+    fn test_exchange_response_timeout() {
+        let mut mgr = ExchangeMgr::new(SessionMgr::new());
+        let timeouts = IdleTimeouts {
+            exchange: Some(Duration::from_secs(30)),
+            response: Some(Duration::from_secs(5)),
+            ..Default::default()
+        };
+        let start = Instant::now();
+        ExchangeMgr::_get(&mut mgr.exchanges, 1, 2, Role::Initiator, true).unwrap();
+        ExchangeMgr::_get(&mut mgr.exchanges, 1, 3, Role::Initiator, true).unwrap();
+        for exch_id in [2, 3] {
+            let exch = mgr.get_with_id(exch_id).unwrap();
+            exch.last_activity = start;
+            exch.awaiting_resp = Some(start);
+        }
+        // The peer responded on this one
+        mgr.get_with_id(3).unwrap().awaiting_resp = None;
+
+        let at = |secs| start + Duration::from_secs(secs);
+        assert_eq!(
+            mgr.get_next_exch_expiry(&timeouts, at(1)),
+            Some(Duration::from_secs(4))
+        );
+        assert!(mgr.expire_exchanges(&timeouts, at(4)).is_empty());
+
+        // The late exchange is reported, and left for the caller to close
+        assert_eq!(mgr.expire_exchanges(&timeouts, at(5)), vec![2]);
+        assert!(mgr.get_with_id(2).unwrap().is_state_open());
+        assert!(mgr.get_with_id(3).unwrap().is_state_open());
+
+        // Without a timeout, nobody is late
+        let timeouts = IdleTimeouts {
+            exchange: None,
+            response: None,
+            ..Default::default()
+        };
+        assert!(mgr.expire_exchanges(&timeouts, at(100)).is_empty());
+        assert_eq!(mgr.get_next_exch_expiry(&timeouts, at(100)), None);
+    }
+
     fn get_clone_data(peer_sess_id: u16, local_sess_id: u16) -> CloneData {
         CloneData::new(
             12341234,
@@ -452,8 +1102,12 @@ mod tests {
     }
 
     impl NetworkInterface for DummyNetwork {
-        fn recv(&self, _in_buf: &mut [u8]) -> Result<(usize, Address), Error> {
-            Ok((0, Address::default()))
+        fn poll_recv(
+            &self,
+            _cx: &mut Context<'_>,
+            _in_buf: &mut [u8],
+        ) -> Poll<Result<(usize, Address), Error>> {
+            Poll::Ready(Ok((0, Address::default())))
         }
 
         fn send(&self, _out_buf: &[u8], _addr: Address) -> Result<usize, Error> {
@@ -468,11 +1122,12 @@ mod tests {
     /// - The exchanges associated with those sessions are evicted too
     fn test_sess_evict() {
         let mut sess_mgr = SessionMgr::new();
+        let max_sessions = sess_mgr.capacity();
         let transport = Box::new(DummyNetwork::new());
         sess_mgr.add_network_interface(transport).unwrap();
         let mut mgr = ExchangeMgr::new(sess_mgr);
 
-        fill_sessions(&mut mgr, MAX_SESSIONS + 1);
+        fill_sessions(&mut mgr, max_sessions + 1);
         // Sessions are now full from local session id 1 to 16
 
         // Create exchanges for sessions 2 (i.e. session index 1) and 3 (session index 2)
@@ -480,8 +1135,8 @@ mod tests {
         let _ = ExchangeMgr::_get(&mut mgr.exchanges, 1, 20, Role::Responder, true).unwrap();
         let _ = ExchangeMgr::_get(&mut mgr.exchanges, 2, 30, Role::Responder, true).unwrap();
 
-        // Confirm that session ids 1 to MAX_SESSIONS exists
-        for i in 1..(MAX_SESSIONS + 1) {
+        // Confirm that session ids 1 to max_sessions exists
+        for i in 1..(max_sessions + 1) {
             assert_eq!(mgr.sess_mgr.get_with_id(i as u16).is_none(), false);
         }
         // Confirm that the exchanges are around
@@ -491,7 +1146,7 @@ mod tests {
         let mut new_local_sess_id = 100;
         let mut new_peer_sess_id = 200;
 
-        for i in 1..(MAX_SESSIONS + 1) {
+        for i in 1..(max_sessions + 1) {
             // Now purposefully overflow the sessions by adding another session
             let session = mgr
                 .add_session(get_clone_data(new_peer_sess_id, new_local_sess_id))
@@ -526,4 +1181,51 @@ mod tests {
         }
         //        println!("Session mgr {}", mgr.sess_mgr);
     }
+
+    // A network that has lost its connection to the peer at Address::default()
+    struct LostPeerNetwork {
+        lost: Mutex<bool>,
+    }
+
+    impl NetworkInterface for LostPeerNetwork {
+        fn poll_recv(
+            &self,
+            _cx: &mut Context<'_>,
+            _in_buf: &mut [u8],
+        ) -> Poll<Result<(usize, Address), Error>> {
+            Poll::Pending
+        }
+
+        fn send(&self, _out_buf: &[u8], _addr: Address) -> Result<usize, Error> {
+            Ok(0)
+        }
+
+        fn take_unreachable(&self) -> Vec<Address> {
+            if std::mem::take(&mut *self.lost.lock().unwrap()) {
+                vec![Address::default()]
+            } else {
+                vec![]
+            }
+        }
+    }
+
+    #[test]
+    fn test_drop_unreachable() {
+        let mut sess_mgr = SessionMgr::new();
+        let network = LostPeerNetwork {
+            lost: Mutex::new(true),
+        };
+        sess_mgr.add_network_interface(Box::new(network)).unwrap();
+        let mut mgr = ExchangeMgr::new(sess_mgr);
+        fill_sessions(&mut mgr, 2);
+
+        let (resp_tx, resp_rx) = bounded::<Result<RxMsg, Error>>(1);
+        let exchange = ExchangeMgr::_get(&mut mgr.exchanges, 0, 20, Role::Initiator, true).unwrap();
+        exchange.set_exchange_data(Box::new(resp_tx));
+
+        mgr.drop_unreachable();
+        assert!(mgr.sess_mgr.get_with_id(1).is_none());
+        assert!(mgr.get_with_id(20).is_none());
+        assert_eq!(resp_rx.try_recv().unwrap().err(), Some(Error::Network));
+    }
 }
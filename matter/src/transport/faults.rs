@@ -0,0 +1,448 @@
+use std::{
+    collections::VecDeque,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use log::info;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::error::*;
+
+use super::{
+    clock::{self, Timer},
+    network::{Address, NetworkInterface},
+};
+
+/// The direction of the messages that a FaultRule applies to
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum Direction {
+    Tx,
+    Rx,
+}
+
+/// What goes wrong with the messages
+///
+/// The probabilities are between 0.0 and 1.0, and are drawn independently for every
+/// message.
+#[derive(Clone, Debug, Default)]
+pub struct FaultRule {
+    /// The probability that a message is dropped
+    pub loss: f64,
+    /// The probability that a message is delivered twice
+    pub duplicate: f64,
+    /// The probability that a message is held back by `reorder_delay`, so that the
+    /// messages right behind it overtake it
+    pub reorder: f64,
+    pub reorder_delay: Duration,
+    /// The delay of every message, plus a random part of up to `jitter`
+    pub latency: Duration,
+    pub jitter: Duration,
+}
+
+impl FaultRule {
+    /// A rule that drops every message
+    pub fn blackhole() -> Self {
+        Self {
+            loss: 1.0,
+            ..Default::default()
+        }
+    }
+}
+
+struct Rules {
+    // The rules for a specific peer, or for everyone (None)
+    rules: Vec<(Direction, Option<Address>, FaultRule)>,
+    rng: StdRng,
+}
+
+impl Rules {
+    fn get(&self, dir: Direction, peer: Address) -> Option<&FaultRule> {
+        let mut rules = self.rules.iter().filter(|(d, _, _)| *d == dir);
+        rules
+            .clone()
+            .find(|(_, p, _)| *p == Some(peer))
+            .or_else(|| rules.find(|(_, p, _)| p.is_none()))
+            .map(|(_, _, r)| r)
+    }
+
+    // Returns the delays that the copies of a message are to be delivered after,
+    // there are none if it is lost
+    fn apply(&mut self, dir: Direction, peer: Address) -> Vec<Duration> {
+        let rule = match self.get(dir, peer) {
+            Some(rule) => rule.clone(),
+            None => return vec![Duration::ZERO],
+        };
+        let rng = &mut self.rng;
+        if rng.gen_bool(rule.loss) {
+            info!("Fault: Dropping {:?} message of {}", dir, peer);
+            return Vec::new();
+        }
+        let copies = if rng.gen_bool(rule.duplicate) { 2 } else { 1 };
+        (0..copies)
+            .map(|_| {
+                let mut delay = rule.latency;
+                if !rule.jitter.is_zero() {
+                    delay += rng.gen_range(Duration::ZERO..=rule.jitter);
+                }
+                if rng.gen_bool(rule.reorder) {
+                    delay += rule.reorder_delay;
+                }
+                delay
+            })
+            .collect()
+    }
+}
+
+/// A handle to change the rules of a FaultyInterface, while the transport owns it
+#[derive(Clone)]
+pub struct FaultRules {
+    rules: Arc<Mutex<Rules>>,
+}
+
+impl FaultRules {
+    /// Sets the rule for the messages in the direction `dir`, with the peer `peer`
+    ///
+    /// If `peer` is None, the rule applies to the peers that don't have a rule of
+    /// their own. This replaces any earlier rule for the same direction and peer.
+    pub fn set(&self, dir: Direction, peer: Option<Address>, rule: FaultRule) {
+        let mut rules = self.rules.lock().unwrap();
+        rules.rules.retain(|(d, p, _)| !(*d == dir && *p == peer));
+        rules.rules.push((dir, peer, rule));
+    }
+
+    /// Removes all the rules, the messages go through untouched
+    pub fn clear(&self) {
+        self.rules.lock().unwrap().rules.clear();
+    }
+}
+
+// A message that is held back
+struct Delayed {
+    due: Instant,
+    msg: Vec<u8>,
+    peer: Address,
+}
+
+// Keeps the messages in the order that they are due in
+fn hold(queue: &mut VecDeque<Delayed>, due: Instant, msg: &[u8], peer: Address) {
+    let pos = queue
+        .iter()
+        .position(|d| d.due > due)
+        .unwrap_or(queue.len());
+    queue.insert(
+        pos,
+        Delayed {
+            due,
+            msg: msg.to_vec(),
+            peer,
+        },
+    );
+}
+
+fn take_due(queue: &mut VecDeque<Delayed>, now: Instant) -> Option<Delayed> {
+    match queue.front() {
+        Some(d) if d.due <= now => queue.pop_front(),
+        _ => None,
+    }
+}
+
+struct Held {
+    tx: VecDeque<Delayed>,
+    rx: VecDeque<Delayed>,
+    timer: Timer,
+}
+
+/// A NetworkInterface that loses, duplicates, reorders and delays the messages of
+/// another one
+///
+/// The faults are drawn from a PRNG with a fixed seed, so a test sees the same
+/// faults every time. With no rules, the messages go through untouched.
+///
+/// The delayed messages are sent out while the transport waits on this interface,
+/// which it always does.
+pub struct FaultyInterface {
+    inner: Box<dyn NetworkInterface>,
+    rules: FaultRules,
+    held: Mutex<Held>,
+}
+
+impl FaultyInterface {
+    pub fn new(inner: Box<dyn NetworkInterface>, seed: u64) -> Self {
+        Self {
+            inner,
+            rules: FaultRules {
+                rules: Arc::new(Mutex::new(Rules {
+                    rules: Vec::new(),
+                    rng: StdRng::seed_from_u64(seed),
+                })),
+            },
+            held: Mutex::new(Held {
+                tx: VecDeque::new(),
+                rx: VecDeque::new(),
+                timer: Timer::never(),
+            }),
+        }
+    }
+
+    pub fn get_rules(&self) -> FaultRules {
+        self.rules.clone()
+    }
+
+    /// Sets a rule, see FaultRules::set()
+    pub fn set_rule(&self, dir: Direction, peer: Option<Address>, rule: FaultRule) {
+        self.rules.set(dir, peer, rule);
+    }
+
+    fn apply(&self, dir: Direction, peer: Address) -> Vec<Duration> {
+        self.rules.rules.lock().unwrap().apply(dir, peer)
+    }
+
+    fn send_due(&self, held: &mut Held, now: Instant) {
+        while let Some(d) = take_due(&mut held.tx, now) {
+            // Like on the network, nobody hears about a message that didn't make it
+            let _ = self.inner.send(&d.msg, d.peer);
+        }
+    }
+
+    // Arranges for a wake up when the earliest of the held messages is due, returns
+    // false if that is already the case
+    fn poll_timer(held: &mut Held, cx: &mut Context<'_>) -> bool {
+        let due = held
+            .tx
+            .front()
+            .map(|d| d.due)
+            .into_iter()
+            .chain(held.rx.front().map(|d| d.due))
+            .min();
+        match due {
+            Some(due) => {
+                held.timer.set_at(due);
+                Pin::new(&mut held.timer).poll(cx).is_pending()
+            }
+            None => true,
+        }
+    }
+}
+
+impl NetworkInterface for FaultyInterface {
+    fn poll_recv(
+        &self,
+        cx: &mut Context<'_>,
+        in_buf: &mut [u8],
+    ) -> Poll<Result<(usize, Address), Error>> {
+        let mut held = self.held.lock().unwrap();
+        loop {
+            let now = clock::now();
+            self.send_due(&mut held, now);
+            if let Some(d) = take_due(&mut held.rx, now) {
+                if d.msg.len() > in_buf.len() {
+                    return Poll::Ready(Err(Error::NoSpace));
+                }
+                in_buf[..d.msg.len()].copy_from_slice(&d.msg);
+                return Poll::Ready(Ok((d.msg.len(), d.peer)));
+            }
+
+            match self.inner.poll_recv(cx, in_buf) {
+                Poll::Ready(Ok((len, peer))) => {
+                    for delay in self.apply(Direction::Rx, peer) {
+                        hold(&mut held.rx, now + delay, &in_buf[..len], peer);
+                    }
+                    continue;
+                }
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => (),
+            }
+
+            if Self::poll_timer(&mut held, cx) {
+                return Poll::Pending;
+            }
+        }
+    }
+
+    fn send(&self, out_buf: &[u8], addr: Address) -> Result<usize, Error> {
+        let mut held = self.held.lock().unwrap();
+        let now = clock::now();
+        for delay in self.apply(Direction::Tx, addr) {
+            if delay.is_zero() {
+                self.inner.send(out_buf, addr)?;
+            } else {
+                hold(&mut held.tx, now + delay, out_buf, addr);
+            }
+        }
+        // The held messages only go out while the transport waits on us, and that
+        // wait has to be woken up to account for the new ones
+        if !held.tx.is_empty() {
+            held.timer.set_after(Duration::ZERO);
+        }
+        Ok(out_buf.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{IpAddr, Ipv6Addr, SocketAddr},
+        time::Duration,
+    };
+
+    use smol::{future, future::FutureExt};
+
+    use crate::transport::{
+        clock::{self, Timer},
+        loopback::LoopbackSwitch,
+        network::{Address, NetworkInterface},
+    };
+
+    use super::{Direction, FaultRule, FaultyInterface};
+
+    fn addr(node: u16) -> SocketAddr {
+        SocketAddr::new(
+            IpAddr::V6(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, node)),
+            5540,
+        )
+    }
+
+    // Receives everything that arrives within `wait`
+    fn recv_all(iface: &dyn NetworkInterface, wait: Duration) -> Vec<u8> {
+        let mut msgs = Vec::new();
+        let mut buf = [0u8; 8];
+        clock::simulate(
+            0,
+            async {
+                loop {
+                    let (len, _) = future::poll_fn(|cx| iface.poll_recv(cx, &mut buf))
+                        .await
+                        .unwrap();
+                    assert_eq!(len, 1);
+                    msgs.push(buf[0]);
+                }
+            }
+            .or(async {
+                Timer::after(wait).await;
+            }),
+        );
+        msgs
+    }
+
+    fn faulty_pair(seed: u64) -> (FaultyInterface, FaultyInterface) {
+        let switch = LoopbackSwitch::new();
+        (
+            FaultyInterface::new(Box::new(switch.connect(addr(1)).unwrap()), seed),
+            FaultyInterface::new(Box::new(switch.connect(addr(2)).unwrap()), seed),
+        )
+    }
+
+    #[test]
+    fn test_no_rules() {
+        let (a, b) = faulty_pair(1);
+        for i in 0..10 {
+            a.send(&[i], Address::Udp(addr(2))).unwrap();
+        }
+        assert_eq!(
+            recv_all(&b, Duration::from_millis(10)),
+            (0..10).collect::<Vec<u8>>()
+        );
+    }
+
+    fn lossy_run(seed: u64) -> Vec<u8> {
+        let (a, b) = faulty_pair(seed);
+        b.set_rule(
+            Direction::Rx,
+            None,
+            FaultRule {
+                loss: 0.3,
+                duplicate: 0.3,
+                ..Default::default()
+            },
+        );
+        for i in 0..100 {
+            a.send(&[i], Address::Udp(addr(2))).unwrap();
+        }
+        recv_all(&b, Duration::from_millis(10))
+    }
+
+    #[test]
+    fn test_loss_and_duplicates() {
+        let msgs = lossy_run(7);
+        let mut unique = msgs.clone();
+        unique.dedup();
+        assert!(unique.len() < 100);
+        assert!(msgs.len() > unique.len());
+        // The order is kept
+        assert!(unique.windows(2).all(|w| w[0] < w[1]));
+        // The same seed, the same faults
+        assert_eq!(lossy_run(7), msgs);
+    }
+
+    #[test]
+    fn test_per_peer_rules() {
+        let switch = LoopbackSwitch::new();
+        let a = FaultyInterface::new(Box::new(switch.connect(addr(1)).unwrap()), 3);
+        let b = switch.connect(addr(2)).unwrap();
+        let c = switch.connect(addr(3)).unwrap();
+        a.set_rule(Direction::Tx, None, FaultRule::blackhole());
+        a.set_rule(
+            Direction::Tx,
+            Some(Address::Udp(addr(2))),
+            FaultRule::default(),
+        );
+
+        a.send(&[1], Address::Udp(addr(2))).unwrap();
+        a.send(&[2], Address::Udp(addr(3))).unwrap();
+        assert_eq!(recv_all(&b, Duration::from_millis(10)), vec![1]);
+        assert!(recv_all(&c, Duration::from_millis(10)).is_empty());
+
+        a.get_rules().clear();
+        a.send(&[3], Address::Udp(addr(3))).unwrap();
+        assert_eq!(recv_all(&c, Duration::from_millis(10)), vec![3]);
+    }
+
+    #[test]
+    fn test_latency_and_reorder() {
+        let (a, b) = faulty_pair(5);
+        a.set_rule(
+            Direction::Tx,
+            None,
+            FaultRule {
+                latency: Duration::from_millis(20),
+                ..Default::default()
+            },
+        );
+        b.set_rule(
+            Direction::Rx,
+            Some(Address::Udp(addr(1))),
+            FaultRule {
+                reorder: 1.0,
+                reorder_delay: Duration::from_millis(20),
+                ..Default::default()
+            },
+        );
+        a.send(&[1], Address::Udp(addr(2))).unwrap();
+        // The held message goes out while `a` is waited on
+        assert!(recv_all(&a, Duration::from_millis(40)).is_empty());
+        assert!(recv_all(&b, Duration::from_millis(5)).is_empty());
+        assert_eq!(recv_all(&b, Duration::from_millis(40)), vec![1]);
+
+        // A message that is held back is overtaken by the next one
+        a.get_rules().clear();
+        b.get_rules().clear();
+        b.set_rule(
+            Direction::Rx,
+            None,
+            FaultRule {
+                reorder: 1.0,
+                reorder_delay: Duration::from_millis(20),
+                ..Default::default()
+            },
+        );
+        a.send(&[2], Address::Udp(addr(2))).unwrap();
+        let _ = recv_all(&b, Duration::from_millis(1));
+        b.get_rules().clear();
+        a.send(&[3], Address::Udp(addr(2))).unwrap();
+        assert_eq!(recv_all(&b, Duration::from_millis(40)), vec![3, 2]);
+    }
+}
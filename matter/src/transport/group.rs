@@ -0,0 +1,308 @@
+use std::net::{Ipv6Addr, SocketAddr};
+
+use boxslab::BoxSlab;
+use heapless::LinearMap;
+use log::{error, info};
+
+use crate::{
+    error::Error,
+    group_keys::{GroupKey, GroupKeys, KeySet},
+    limits::Limits,
+};
+
+use super::{
+    exchange::{Exchange, ExchangeCtx, Role},
+    network::Address,
+    packet::PacketPool,
+    queue::{Msg, WorkQ},
+    session::{CtrWindowMode, RxCtrState, Session, SessionMgr},
+    udp::MATTER_PORT,
+};
+
+// The number of group peers whose message counters we track
+const MAX_GROUP_PEERS: usize = 16;
+
+/// The IPv6 multicast address that the messages to a group are sent to
+pub fn multicast_addr(fabric_id: u64, group_id: u16) -> Ipv6Addr {
+    // FF35:0040:FD<Fabric ID>00:<Group ID>
+    let mut addr = [0u8; 16];
+    addr[..5].copy_from_slice(&[0xff, 0x35, 0x00, 0x40, 0xfd]);
+    addr[5..13].copy_from_slice(&fabric_id.to_be_bytes());
+    addr[14..].copy_from_slice(&group_id.to_be_bytes());
+    Ipv6Addr::from(addr)
+}
+
+/// The Matter address that the messages to a group are sent to
+pub fn group_address(fabric_id: u64, group_id: u16) -> Address {
+    Address::Udp(SocketAddr::new(
+        multicast_addr(fabric_id, group_id).into(),
+        MATTER_PORT,
+    ))
+}
+
+/// Adds the key of a group that we are a member of, and has the transport of
+/// `work_q` receive the messages to the group
+///
+/// This is what the handler of the group key set commands calls. Adding another key
+/// to a group that we are already a member of is how the keys are rotated.
+pub fn join_group(
+    work_q: &WorkQ,
+    fab_idx: u8,
+    fabric_id: u64,
+    group_id: u16,
+    key_set: &KeySet,
+) -> Result<(), Error> {
+    let group_keys = GroupKeys::get()?;
+    let mut group_keys = group_keys.lock()?;
+    let is_member = group_keys.get_key(fab_idx, group_id).is_some();
+    group_keys.insert_key(fab_idx, group_id, key_set)?;
+    if !is_member {
+        work_q.sync_send(Msg::JoinMulticast(
+            multicast_addr(fabric_id, group_id).into(),
+        ))?;
+    }
+    Ok(())
+}
+
+/// Removes the keys of a group, and has the transport of `work_q` stop receiving
+/// the messages to the group
+///
+/// This is what the handler of the group key remove commands calls.
+pub fn leave_group(
+    work_q: &WorkQ,
+    fab_idx: u8,
+    fabric_id: u64,
+    group_id: u16,
+) -> Result<(), Error> {
+    let group_keys = GroupKeys::get()?;
+    let mut group_keys = group_keys.lock()?;
+    if group_keys.get_key(fab_idx, group_id).is_some() {
+        group_keys.remove_keys(fab_idx, group_id);
+        work_q.sync_send(Msg::LeaveMulticast(
+            multicast_addr(fabric_id, group_id).into(),
+        ))?;
+    }
+    Ok(())
+}
+
+/// The sessions for the messages to groups
+///
+/// These aren't looked up like the unicast sessions, the messages carry the group
+/// and the source node, and are decrypted with whichever of the group's keys fits.
+/// The counters of the source nodes (the group peers) are tracked here.
+pub struct GroupSessionMgr {
+    // keys: (fabric index, source node id)
+    peers: LinearMap<(u8, u64), RxCtrState, MAX_GROUP_PEERS>,
+    // Holds the session of the group message being processed
+    sess_mgr: SessionMgr,
+    // The exchange of the group message being processed, nothing is sent on it
+    exch: Exchange,
+}
+
+impl Default for GroupSessionMgr {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl GroupSessionMgr {
+    pub fn new() -> Self {
+        Self::new_with_limits(&Limits::default())
+    }
+
+    pub fn new_with_limits(limits: &Limits) -> Self {
+        Self {
+            peers: LinearMap::new(),
+            // It only ever holds the session of the message being processed
+            sess_mgr: SessionMgr::new_with_limits(&Limits {
+                sessions: 1,
+                ..*limits
+            }),
+            exch: Exchange::new(0, 0, Role::Responder),
+        }
+    }
+
+    /// Decrypts a message to a group, and returns the context to process it in
+    ///
+    /// This returns None if the message is a duplicate.
+    pub fn post_recv(
+        &mut self,
+        mut proto_rx: BoxSlab<PacketPool>,
+    ) -> Result<Option<(BoxSlab<PacketPool>, ExchangeCtx<'_>)>, Error> {
+        let group_id = proto_rx.plain.get_dest_group().ok_or(Error::Invalid)?;
+        let src_nodeid = proto_rx.plain.get_src_u64().ok_or(Error::Invalid)?;
+
+        let candidates = GroupKeys::get()?
+            .lock()?
+            .get_candidates(proto_rx.plain.sess_id, group_id);
+        let key = candidates
+            .into_iter()
+            .find(|k| proto_rx.try_decrypt(src_nodeid, &k.op_key).is_ok())
+            .ok_or_else(|| {
+                error!("No key for the message to group {}", group_id);
+                Error::NotFound
+            })?;
+        proto_rx.proto_decode(src_nodeid, None)?;
+
+        if self.is_duplicate(key.fab_idx, src_nodeid, proto_rx.plain.ctr)? {
+            info!("Duplicate group message with ctr {}", proto_rx.plain.ctr);
+            return Ok(None);
+        }
+
+        self.sess_mgr.remove(0);
+        let session = Session::new_group(proto_rx.peer, Some(src_nodeid), 0, &key);
+        let sess_idx = self.sess_mgr.add_session(session)?;
+        self.exch = Exchange::new(proto_rx.proto.exch_id, sess_idx, Role::Responder);
+        Ok(Some((
+            proto_rx,
+            ExchangeCtx {
+                exch: &mut self.exch,
+                sess: self.sess_mgr.get_session_handle(sess_idx),
+            },
+        )))
+    }
+
+    // Only authenticated messages may move the counters
+    //
+    // The counter of a source that we don't know yet is taken from its first message
+    // (trust-first), we don't synchronise it with the Message Counter Synchronization
+    // Protocol. So the first message that we see from a source is accepted even if it
+    // is a replay, anything that the source sent before it isn't. The same goes for a
+    // source that was forgotten to make room for others.
+    fn is_duplicate(&mut self, fab_idx: u8, src_nodeid: u64, ctr: u32) -> Result<bool, Error> {
+        if let Some(rx_ctr_state) = self.peers.get_mut(&(fab_idx, src_nodeid)) {
+            return Ok(rx_ctr_state.recv(ctr));
+        }
+        if self.peers.len() == self.peers.capacity() {
+            // Forget some peer, it will be trusted afresh the next time
+            let peer = self.peers.keys().next().cloned();
+            if let Some(peer) = peer {
+                self.peers.remove(&peer);
+            }
+        }
+        let rx_ctr_state = RxCtrState::new_synced(ctr, CtrWindowMode::Rollover);
+        self.peers
+            .insert((fab_idx, src_nodeid), rx_ctr_state)
+            .map_err(|_| Error::NoSpace)?;
+        Ok(false)
+    }
+
+    /// Returns the session to send a message from us (`local_nodeid`) to a group with
+    ///
+    /// The messages take the global group counter, see msg_ctr::GlobalCtrs.
+    pub fn get_tx_session(&mut self, key: &GroupKey, local_nodeid: u64, dst: Address) -> Session {
+        Session::new_group(dst, None, local_nodeid, key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv6Addr};
+
+    use boxslab::{BoxSlab, Slab};
+
+    use crate::{
+        group_keys::{GroupKeys, KeySet},
+        transport::{
+            packet::{Packet, PacketPool},
+            queue::{Msg, WorkQ},
+            session::SessionMode,
+        },
+    };
+
+    use super::{group_address, join_group, leave_group, multicast_addr, GroupSessionMgr};
+
+    #[test]
+    fn test_multicast_addr() {
+        assert_eq!(
+            multicast_addr(0x2906_C908_D115_D362, 0x1234),
+            "ff35:40:fd29:6c9:8d1:15d3:6200:1234"
+                .parse::<Ipv6Addr>()
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_join_leave_group() {
+        const FAB_IDX: u8 = 4;
+        const GROUP_ID: u16 = 0x202;
+        let (work_q, work_rx) = WorkQ::new();
+        let group: IpAddr = multicast_addr(7, GROUP_ID).into();
+        let is_join = |msg| matches!(msg, Ok(Msg::JoinMulticast(g)) if g == group);
+        let is_leave = |msg| matches!(msg, Ok(Msg::LeaveMulticast(g)) if g == group);
+
+        let key_set = KeySet::new(&[0xa1; 16], &[0x87; 8]).unwrap();
+        join_group(&work_q, FAB_IDX, 7, GROUP_ID, &key_set).unwrap();
+        assert!(is_join(work_rx.try_recv()));
+        assert!(GroupKeys::get()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .get_key(FAB_IDX, GROUP_ID)
+            .is_some());
+
+        // Another key for the same group, we are in it already
+        let key_set = KeySet::new(&[0xa2; 16], &[0x87; 8]).unwrap();
+        join_group(&work_q, FAB_IDX, 7, GROUP_ID, &key_set).unwrap();
+        assert!(work_rx.try_recv().is_err());
+
+        leave_group(&work_q, FAB_IDX, 7, GROUP_ID).unwrap();
+        assert!(is_leave(work_rx.try_recv()));
+        leave_group(&work_q, FAB_IDX, 7, GROUP_ID).unwrap();
+        assert!(work_rx.try_recv().is_err());
+    }
+
+    fn to_rx(tx: &mut Packet) -> BoxSlab<PacketPool> {
+        let mut rx = Slab::<PacketPool>::new(Packet::new_rx().unwrap()).unwrap();
+        let msg = tx.as_borrow_slice();
+        rx.as_borrow_slice()[..msg.len()].copy_from_slice(msg);
+        rx.get_parsebuf().unwrap().set_len(msg.len());
+        rx.plain_hdr_decode().unwrap();
+        rx
+    }
+
+    #[test]
+    fn test_group_msg() {
+        const FAB_IDX: u8 = 3;
+        const GROUP_ID: u16 = 0x101;
+        let key_set = KeySet::new(&[0xa0; 16], &[0x87; 8]).unwrap();
+        let group_keys = GroupKeys::get().unwrap();
+        group_keys
+            .lock()
+            .unwrap()
+            .insert_key(FAB_IDX, GROUP_ID, &key_set)
+            .unwrap();
+        let key = group_keys
+            .lock()
+            .unwrap()
+            .get_key(FAB_IDX, GROUP_ID)
+            .unwrap();
+
+        let mut sender = GroupSessionMgr::new();
+        let mut receiver = GroupSessionMgr::new();
+        let mut tx = Slab::<PacketPool>::new(Packet::new_tx().unwrap()).unwrap();
+        tx.set_proto_id(1);
+        tx.set_proto_opcode(8);
+        tx.unset_reliable();
+        tx.get_writebuf().unwrap().append(&[1, 2, 3]).unwrap();
+        let mut session = sender.get_tx_session(&key, 0x1234, group_address(1, GROUP_ID));
+        session.pre_send(&mut tx).unwrap();
+        session.do_send(&mut tx).unwrap();
+
+        let (mut rx, ctx) = receiver.post_recv(to_rx(&mut tx)).unwrap().unwrap();
+        assert_eq!(
+            ctx.sess.get_session_mode(),
+            SessionMode::Group(FAB_IDX, GROUP_ID)
+        );
+        assert_eq!(ctx.sess.get_peer_node_id(), Some(0x1234));
+        assert_eq!(rx.get_proto_opcode(), 8);
+        assert_eq!(rx.as_borrow_slice(), &[1, 2, 3]);
+
+        // The same message again
+        assert!(receiver.post_recv(to_rx(&mut tx)).unwrap().is_none());
+
+        // We are no longer in the group
+        group_keys.lock().unwrap().remove_keys(FAB_IDX, GROUP_ID);
+        assert!(GroupSessionMgr::new().post_recv(to_rx(&mut tx)).is_err());
+    }
+}
@@ -0,0 +1,272 @@
+use std::{
+    collections::VecDeque,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+};
+
+use log::{error, info};
+
+use crate::error::*;
+
+use super::network::{Address, NetworkInterface};
+
+// The messages that an endpoint holds before it starts dropping them, like a socket's
+// receive buffer would
+const MAX_QUEUED_MSGS: usize = 64;
+
+// An endpoint's end of the switch
+struct Port {
+    addr: SocketAddr,
+    multicast_groups: Vec<IpAddr>,
+    rx_q: VecDeque<(Vec<u8>, SocketAddr)>,
+    waker: Option<Waker>,
+}
+
+impl Port {
+    fn new(addr: SocketAddr) -> Self {
+        Self {
+            addr,
+            multicast_groups: Vec::new(),
+            rx_q: VecDeque::new(),
+            waker: None,
+        }
+    }
+
+    fn accepts(&self, dst: SocketAddr) -> bool {
+        if dst.ip().is_multicast() {
+            dst.port() == self.addr.port() && self.multicast_groups.contains(&dst.ip())
+        } else {
+            dst == self.addr
+        }
+    }
+
+    fn deliver(&mut self, msg: &[u8], src: SocketAddr) {
+        if self.rx_q.len() == MAX_QUEUED_MSGS {
+            error!("Dropping message to {}, the queue is full", self.addr);
+            return;
+        }
+        self.rx_q.push_back((msg.to_vec(), src));
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// An in-memory network, that connects the transports within the same process
+///
+/// Each transport gets a LoopbackInterface with an address on the switch, and
+/// the messages sent to that address are delivered to it. The switch behaves like
+/// UDP: messages to unknown addresses are silently dropped, and a message to a
+/// multicast address reaches every endpoint that joined the group on that port.
+///
+/// The switch can be cloned, all the clones are the same network.
+#[derive(Clone, Default)]
+pub struct LoopbackSwitch {
+    ports: Arc<Mutex<Vec<Port>>>,
+}
+
+impl LoopbackSwitch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Attaches a new endpoint with the address `addr` to the switch
+    ///
+    /// The address is released once the returned interface is dropped.
+    pub fn connect(&self, addr: SocketAddr) -> Result<LoopbackInterface, Error> {
+        if addr.ip().is_multicast() || addr.ip().is_unspecified() {
+            return Err(Error::Invalid);
+        }
+        let mut ports = self.ports.lock()?;
+        if ports.iter().any(|p| p.addr == addr) {
+            error!("Address {} is already in use on the switch", addr);
+            return Err(Error::Invalid);
+        }
+        ports.push(Port::new(addr));
+        info!("Connected {} to the loopback switch", addr);
+        Ok(LoopbackInterface {
+            addr,
+            switch: self.clone(),
+        })
+    }
+}
+
+/// An endpoint of a LoopbackSwitch
+///
+/// The messages are sent and received with Address::Udp, so the transport treats
+/// the switch like any other unreliable network.
+pub struct LoopbackInterface {
+    addr: SocketAddr,
+    switch: LoopbackSwitch,
+}
+
+impl LoopbackInterface {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl NetworkInterface for LoopbackInterface {
+    fn poll_recv(
+        &self,
+        cx: &mut Context<'_>,
+        in_buf: &mut [u8],
+    ) -> Poll<Result<(usize, Address), Error>> {
+        let mut ports = match self.switch.ports.lock() {
+            Ok(ports) => ports,
+            Err(_) => return Poll::Ready(Err(Error::Network)),
+        };
+        let port = match ports.iter_mut().find(|p| p.addr == self.addr) {
+            Some(port) => port,
+            None => return Poll::Ready(Err(Error::Network)),
+        };
+        match port.rx_q.pop_front() {
+            Some((msg, src)) => {
+                if msg.len() > in_buf.len() {
+                    error!("Message of size {} from {} is too large", msg.len(), src);
+                    return Poll::Ready(Err(Error::NoSpace));
+                }
+                in_buf[..msg.len()].copy_from_slice(&msg);
+                Poll::Ready(Ok((msg.len(), Address::Udp(src))))
+            }
+            None => {
+                port.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+
+    fn send(&self, out_buf: &[u8], addr: Address) -> Result<usize, Error> {
+        let dst = match addr {
+            Address::Udp(dst) => dst,
+            _ => return Err(Error::Invalid),
+        };
+        let mut ports = self.switch.ports.lock()?;
+        for port in ports.iter_mut().filter(|p| p.accepts(dst)) {
+            port.deliver(out_buf, self.addr);
+        }
+        Ok(out_buf.len())
+    }
+
+    /// Joins the multicast group, so that the messages to it are received here
+    fn join_multicast(&self, group: IpAddr) -> Result<(), Error> {
+        if !group.is_multicast() {
+            return Err(Error::Invalid);
+        }
+        let mut ports = self.switch.ports.lock()?;
+        let port = ports
+            .iter_mut()
+            .find(|p| p.addr == self.addr)
+            .ok_or(Error::Network)?;
+        if !port.multicast_groups.contains(&group) {
+            port.multicast_groups.push(group);
+        }
+        Ok(())
+    }
+
+    fn leave_multicast(&self, group: IpAddr) -> Result<(), Error> {
+        let mut ports = self.switch.ports.lock()?;
+        let port = ports
+            .iter_mut()
+            .find(|p| p.addr == self.addr)
+            .ok_or(Error::Network)?;
+        port.multicast_groups.retain(|g| *g != group);
+        Ok(())
+    }
+}
+
+impl Drop for LoopbackInterface {
+    fn drop(&mut self) {
+        if let Ok(mut ports) = self.switch.ports.lock() {
+            ports.retain(|p| p.addr != self.addr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{IpAddr, Ipv6Addr, SocketAddr},
+        task::Poll,
+    };
+
+    use smol::future;
+
+    use crate::transport::network::{Address, NetworkInterface};
+
+    use super::{LoopbackInterface, LoopbackSwitch};
+
+    fn addr(node: u16, port: u16) -> SocketAddr {
+        SocketAddr::new(
+            IpAddr::V6(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, node)),
+            port,
+        )
+    }
+
+    // Returns whatever the endpoint has received, without waiting
+    fn try_recv(iface: &LoopbackInterface) -> Option<(Vec<u8>, Address)> {
+        let mut buf = [0u8; 16];
+        future::block_on(future::poll_fn(|cx| {
+            Poll::Ready(match iface.poll_recv(cx, &mut buf) {
+                Poll::Ready(Ok((len, src))) => Some((buf[..len].to_vec(), src)),
+                _ => None,
+            })
+        }))
+    }
+
+    #[test]
+    fn test_unicast() {
+        let switch = LoopbackSwitch::new();
+        let a = switch.connect(addr(1, 5540)).unwrap();
+        let b = switch.connect(addr(2, 5540)).unwrap();
+        assert!(switch.connect(addr(2, 5540)).is_err());
+
+        a.send(&[1, 2], Address::Udp(addr(2, 5540))).unwrap();
+        a.send(&[3], Address::Udp(addr(2, 5540))).unwrap();
+        // Nobody is at this address
+        a.send(&[4], Address::Udp(addr(3, 5540))).unwrap();
+        assert!(a.send(&[5], Address::Tcp(addr(2, 5540))).is_err());
+
+        assert_eq!(
+            try_recv(&b),
+            Some((vec![1, 2], Address::Udp(addr(1, 5540))))
+        );
+        assert_eq!(try_recv(&b), Some((vec![3], Address::Udp(addr(1, 5540)))));
+        assert_eq!(try_recv(&b), None);
+        assert_eq!(try_recv(&a), None);
+
+        // The address can be taken again, once its endpoint is gone
+        drop(b);
+        a.send(&[6], Address::Udp(addr(2, 5540))).unwrap();
+        let b = switch.connect(addr(2, 5540)).unwrap();
+        assert_eq!(try_recv(&b), None);
+    }
+
+    #[test]
+    fn test_multicast() {
+        let group: IpAddr = "ff35:40:fd00::1".parse().unwrap();
+        let switch = LoopbackSwitch::new();
+        let a = switch.connect(addr(1, 5540)).unwrap();
+        let b = switch.connect(addr(2, 5540)).unwrap();
+        let c = switch.connect(addr(3, 5540)).unwrap();
+        let d = switch.connect(addr(4, 5541)).unwrap();
+        for iface in [&a, &b, &d] {
+            iface.join_multicast(group).unwrap();
+        }
+
+        c.send(&[7], Address::Udp(SocketAddr::new(group, 5540)))
+            .unwrap();
+        assert_eq!(try_recv(&a), Some((vec![7], Address::Udp(addr(3, 5540)))));
+        assert_eq!(try_recv(&b), Some((vec![7], Address::Udp(addr(3, 5540)))));
+        // Not in the group, or not on the port
+        assert_eq!(try_recv(&c), None);
+        assert_eq!(try_recv(&d), None);
+
+        b.leave_multicast(group).unwrap();
+        c.send(&[8], Address::Udp(SocketAddr::new(group, 5540)))
+            .unwrap();
+        assert_eq!(try_recv(&a), Some((vec![8], Address::Udp(addr(3, 5540)))));
+        assert_eq!(try_recv(&b), None);
+    }
+}
@@ -1,35 +1,190 @@
-use async_channel::Receiver;
-use boxslab::{BoxSlab, Slab};
+use std::any::Any;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use async_channel::{bounded, unbounded, Receiver, RecvError, Sender};
+use boxslab::BoxSlab;
 use heapless::LinearMap;
 use log::{debug, error, info};
+use smol::future::FutureExt;
 
 use crate::error::*;
+use crate::limits::Limits;
 
 use crate::transport::mrp::ReliableMessage;
 use crate::transport::packet::PacketPool;
-use crate::transport::{exchange, packet::Packet, proto_demux, queue, session, udp};
+use crate::transport::{
+    exchange, group,
+    packet::{self, Packet},
+    proto_demux, session, tcp, udp,
+};
 
+use super::clock::{self, Timer};
+use super::exchange::Exchange;
+use super::msg_ctr::GlobalCtrs;
+use super::network::NetworkInterface;
 use super::proto_demux::ProtoCtx;
-use super::queue::Msg;
+use super::queue::{GroupTxMsg, Msg, RespSender, RxMsg, TxMsg, TxResp, UnsecuredTxMsg, WorkQ};
+use super::session::{ExpiredSession, IdleTimeouts};
+use super::stats::StatsHandle;
+
+// How long a shutdown waits for the queued up messages to go out
+const SHUTDOWN_FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The network configuration of the transport
+pub struct TransportConfig {
+    /// The port to listen on, for both UDP and TCP
+    pub port: u16,
+    /// The local addresses to bind to, a separate socket is created for each
+    ///
+    /// The unspecified addresses (0.0.0.0 and ::) bind to all the interfaces. If no
+    /// IPv4 address is listed, the IPv6 sockets take the IPv4 traffic as well.
+    pub bind_addrs: Vec<IpAddr>,
+    /// Whether to also accept Matter messages over TCP
+    pub tcp: bool,
+    /// The multicast groups to join, on every socket of the same address family
+    pub multicast_groups: Vec<IpAddr>,
+    /// Network interfaces to use besides the sockets, like the endpoints of a
+    /// loopback::LoopbackSwitch
+    ///
+    /// With no bind_addrs, the transport doesn't open any socket and only uses these.
+    pub interfaces: Vec<Box<dyn NetworkInterface>>,
+    /// How long the sessions may go without hearing from their peer, before they are
+    /// closed
+    pub idle_timeouts: IdleTimeouts,
+}
+
+impl Default for TransportConfig {
+    fn default() -> Self {
+        Self {
+            port: udp::MATTER_PORT,
+            bind_addrs: vec![IpAddr::V6(Ipv6Addr::UNSPECIFIED)],
+            tcp: true,
+            multicast_groups: Vec::new(),
+            interfaces: Vec::new(),
+            idle_timeouts: IdleTimeouts::default(),
+        }
+    }
+}
+
+/// A handle to stop a running transport
+///
+/// The handle can be cloned and used from any thread.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    // Nothing is ever sent on this, closing the channel is the signal
+    tx: Sender<()>,
+}
+
+impl ShutdownHandle {
+    /// Asks the transport to shut down, this doesn't wait for it to complete
+    pub fn shutdown(&self) {
+        self.tx.close();
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.tx.is_closed()
+    }
+}
+
+// What woke up the transport
+enum Event {
+    Rx(Result<BoxSlab<PacketPool>, Error>),
+    Msg(Result<Msg, RecvError>),
+    Timeout,
+    Shutdown,
+}
 
 pub struct Mgr {
+    port: u16,
     exch_mgr: exchange::ExchangeMgr,
     proto_demux: proto_demux::ProtoDemux,
+    work_q: WorkQ,
     rx_q: Receiver<Msg>,
+    // We hold on to a handle, so that the channel isn't closed when the application
+    // drops all of its handles
+    shutdown: ShutdownHandle,
+    shutdown_rx: Receiver<()>,
+    idle_timeouts: IdleTimeouts,
+    expired_tx: Option<Sender<ExpiredSession>>,
 }
 
 impl Mgr {
-    pub fn new() -> Result<Mgr, Error> {
-        let mut sess_mgr = session::SessionMgr::new();
-        let udp_transport = Box::new(udp::UdpListener::new()?);
-        sess_mgr.add_network_interface(udp_transport)?;
+    pub fn new(config: TransportConfig) -> Result<Mgr, Error> {
+        Mgr::new_with_limits(config, &Limits::default())
+    }
+
+    /// Creates the transport with the session and exchange tables sized by `limits`
+    pub fn new_with_limits(config: TransportConfig, limits: &Limits) -> Result<Mgr, Error> {
+        limits.validate()?;
+        packet::set_pool_size(limits.packets)?;
+        let mut sess_mgr = session::SessionMgr::new_with_limits(limits);
+        // The IPv6 sockets should leave the IPv4 traffic to the IPv4 sockets, if any
+        let v6_only = config.bind_addrs.iter().any(|a| a.is_ipv4());
+        let mut port = config.port;
+        for ip in &config.bind_addrs {
+            let udp_transport =
+                Box::new(udp::UdpListener::bind(SocketAddr::new(*ip, port), v6_only)?);
+            for group in &config.multicast_groups {
+                udp_transport.join_multicast(*group)?;
+            }
+            // If we were asked for any free port, use the same one everywhere else
+            let addr = udp_transport.local_addr()?;
+            port = addr.port();
+            info!("Listening on udp:{}", addr);
+            sess_mgr.add_network_interface(udp_transport)?;
+            if config.tcp {
+                let tcp_transport = Box::new(tcp::TcpListener::bind(addr, v6_only)?);
+                sess_mgr.add_network_interface(tcp_transport)?;
+            }
+        }
+        for interface in config.interfaces {
+            sess_mgr.add_network_interface(interface)?;
+        }
+        let (work_q, rx_q) = WorkQ::new();
+        let (shutdown_tx, shutdown_rx) = bounded(1);
         Ok(Mgr {
+            port,
+            shutdown: ShutdownHandle { tx: shutdown_tx },
+            shutdown_rx,
+            idle_timeouts: config.idle_timeouts,
+            expired_tx: None,
             proto_demux: proto_demux::ProtoDemux::new(),
-            exch_mgr: exchange::ExchangeMgr::new(sess_mgr),
-            rx_q: queue::WorkQ::init()?,
+            exch_mgr: exchange::ExchangeMgr::new_with_limits(sess_mgr, limits),
+            work_q,
+            rx_q,
         })
     }
 
+    /// The port that we are listening on
+    pub fn get_port(&self) -> u16 {
+        self.port
+    }
+
+    /// The queue to submit work to this transport, see WorkQ::request()
+    pub fn get_work_q(&self) -> WorkQ {
+        self.work_q.clone()
+    }
+
+    pub fn get_shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Returns a handle to the statistics of the transport
+    pub fn get_stats_handle(&self) -> StatsHandle {
+        self.exch_mgr.get_stats_handle()
+    }
+
+    /// Returns a receiver for the sessions that expire, as configured by
+    /// TransportConfig::idle_timeouts
+    ///
+    /// Only the receiver that was returned last gets to know of them.
+    pub fn subscribe_expired_sessions(&mut self) -> Receiver<ExpiredSession> {
+        let (tx, rx) = unbounded();
+        self.expired_tx = Some(tx);
+        rx
+    }
+
     // Allows registration of different protocols with the Transport/Protocol Demux
     pub fn register_protocol(
         &mut self,
@@ -46,8 +201,8 @@ impl Mgr {
         self.exch_mgr.send(exch_id, proto_tx)
     }
 
-    fn handle_rxtx(&mut self) -> Result<(), Error> {
-        let result = self.exch_mgr.recv().map_err(|e| {
+    fn handle_rx(&mut self, rx: BoxSlab<PacketPool>) -> Result<(), Error> {
+        let result = self.exch_mgr.post_recv(rx).map_err(|e| {
             error!("Error in recv: {:?}", e);
             e
         })?;
@@ -58,12 +213,17 @@ impl Mgr {
         }
         // result contains something worth processing, we can safely unwrap
         // as we already checked for none above
-        let (rx, exch_ctx) = result.unwrap();
+        let (mut rx, exch_ctx) = result.unwrap();
 
         debug!("Exchange is {:?}", exch_ctx.exch);
+        if Self::deliver_response(&mut rx, exch_ctx.exch) {
+            return Ok(());
+        }
+        // Nobody responds to a message to a group
+        let is_group = rx.plain.is_group();
         let tx = Self::new_tx()?;
 
-        let mut proto_ctx = ProtoCtx::new(exch_ctx, rx, tx);
+        let mut proto_ctx = ProtoCtx::new(exch_ctx, rx, tx, &self.work_q);
         // Proto Dispatch
         match self.proto_demux.handle(&mut proto_ctx) {
             Ok(r) => {
@@ -71,6 +231,9 @@ impl Mgr {
                     // We need to send the Ack if reliability is enabled, in this case
                     return Ok(());
                 }
+                if is_group {
+                    return Ok(());
+                }
             }
             Err(e) => {
                 error!("Error in proto_demux {:?}", e);
@@ -78,11 +241,7 @@ impl Mgr {
             }
         }
 
-        let ProtoCtx {
-            exch_ctx,
-            rx: _,
-            tx,
-        } = proto_ctx;
+        let ProtoCtx { exch_ctx, tx, .. } = proto_ctx;
 
         // tx_ctx now contains the response payload, send the packet
         let exch_id = exch_ctx.exch.get_id();
@@ -94,65 +253,755 @@ impl Mgr {
         Ok(())
     }
 
-    fn handle_queue_msgs(&mut self) -> Result<(), Error> {
-        if let Ok(msg) = self.rx_q.try_recv() {
-            match msg {
-                Msg::NewSession(clone_data) => {
-                    // If a new session was created, add it
-                    let _ = self
-                        .exch_mgr
-                        .add_session(clone_data)
-                        .map_err(|e| error!("Error adding new session {:?}", e));
+    // Hands over the response to a message that we initiated, to whoever is waiting
+    // for it. Returns false if this isn't such a response.
+    fn deliver_response(rx: &mut Packet, exch: &mut Exchange) -> bool {
+        if rx.is_standalone_ack() || exch.get_exchange_data::<RespSender>().is_none() {
+            return false;
+        }
+        if let Some(resp_tx) = exch.take_exchange_data::<RespSender>() {
+            let resp = RxMsg {
+                proto_vendor_id: rx.proto.proto_vendor_id,
+                proto_id: rx.get_proto_id(),
+                proto_opcode: rx.get_proto_opcode(),
+                payload: rx.as_borrow_slice().to_vec(),
+            };
+            let _ = resp_tx.try_send(Ok(resp));
+        }
+        exch.close();
+        true
+    }
+
+    fn handle_tx(&mut self, msg: TxMsg) -> Result<(), Error> {
+        let mut tx = Self::new_tx()?;
+        if let Some(proto_vendor_id) = msg.proto_vendor_id {
+            tx.set_proto_vendor_id(proto_vendor_id);
+        }
+        tx.set_proto_id(msg.proto_id);
+        tx.set_proto_opcode(msg.proto_opcode);
+        tx.get_writebuf()?.append(&msg.payload)?;
+        let data: Box<dyn Any> = match msg.resp {
+            TxResp::Sender(resp_tx) => Box::new(resp_tx),
+            TxResp::Handler(data) => data,
+        };
+        let exch_id = self.exch_mgr.initiate(msg.sess_id, tx, data)?;
+        info!("Initiated exch {}", exch_id);
+        Ok(())
+    }
+
+    fn handle_group_tx(&mut self, msg: GroupTxMsg) -> Result<(), Error> {
+        let mut tx = Self::new_tx()?;
+        tx.set_proto_id(msg.proto_id);
+        tx.set_proto_opcode(msg.proto_opcode);
+        tx.get_writebuf()?.append(&msg.payload)?;
+        let dst = group::group_address(msg.fabric_id, msg.group_id);
+        self.exch_mgr
+            .send_group(msg.fab_idx, msg.group_id, msg.local_nodeid, dst, tx)
+    }
+
+    fn handle_unsecured_tx(&mut self, msg: UnsecuredTxMsg) -> Result<(), Error> {
+        let mut tx = Self::new_tx()?;
+        tx.set_proto_id(msg.proto_id);
+        tx.set_proto_opcode(msg.proto_opcode);
+        tx.get_writebuf()?.append(&msg.payload)?;
+        let data: Box<dyn Any> = match msg.resp {
+            None => return self.exch_mgr.send_unsecured(msg.peer, tx),
+            Some(TxResp::Sender(resp_tx)) => Box::new(resp_tx),
+            Some(TxResp::Handler(data)) => data,
+        };
+        let exch_id = self.exch_mgr.initiate_unsecured(msg.peer, tx, data)?;
+        info!("Initiated unsecured exch {}", exch_id);
+        Ok(())
+    }
+
+    fn handle_queue_msg(&mut self, msg: Msg) -> Result<(), Error> {
+        match msg {
+            Msg::NewSession(clone_data) => {
+                // If a new session was created, add it
+                let _ = self
+                    .exch_mgr
+                    .add_session(clone_data)
+                    .map_err(|e| error!("Error adding new session {:?}", e));
+            }
+            Msg::Tx(tx_msg) => {
+                let resp_tx = match &tx_msg.resp {
+                    TxResp::Sender(resp_tx) => Some(resp_tx.clone()),
+                    // The handler's data is dropped, that tells whoever waits on it
+                    TxResp::Handler(_) => None,
+                };
+                if let Err(e) = self.handle_tx(tx_msg) {
+                    error!("Error in initiating exchange {:?}", e);
+                    if let Some(resp_tx) = resp_tx {
+                        let _ = resp_tx.try_send(Err(e));
+                    }
                 }
-                _ => {
-                    error!("Queue Message Type not yet handled {:?}", msg);
+            }
+            Msg::GroupTx(group_tx_msg) => {
+                if let Err(e) = self.handle_group_tx(group_tx_msg) {
+                    error!("Error in sending to group {:?}", e);
+                }
+            }
+            Msg::UnsecuredTx(unsecured_tx_msg) => {
+                let resp_tx = match &unsecured_tx_msg.resp {
+                    Some(TxResp::Sender(resp_tx)) => Some(resp_tx.clone()),
+                    _ => None,
+                };
+                if let Err(e) = self.handle_unsecured_tx(unsecured_tx_msg) {
+                    error!("Error in sending unsecured message {:?}", e);
+                    if let Some(resp_tx) = resp_tx {
+                        let _ = resp_tx.try_send(Err(e));
+                    }
                 }
             }
+            Msg::ReserveSessId(id_tx) => {
+                let sess_id = self.exch_mgr.get_sess_mgr().reserve_new_sess_id();
+                let _ = id_tx.try_send(sess_id);
+            }
+            Msg::JoinMulticast(group) => {
+                info!("Joining the multicast group {}", group);
+                if let Err(e) = self.exch_mgr.get_sess_mgr().join_multicast(group) {
+                    error!("Error in joining {}: {:?}", group, e);
+                }
+            }
+            Msg::LeaveMulticast(group) => {
+                info!("Leaving the multicast group {}", group);
+                if let Err(e) = self.exch_mgr.get_sess_mgr().leave_multicast(group) {
+                    error!("Error in leaving {}: {:?}", group, e);
+                }
+            }
+            _ => {
+                error!("Queue Message Type not yet handled {:?}", msg);
+            }
         }
         Ok(())
     }
 
-    pub fn start(&mut self) -> Result<(), Error> {
-        loop {
-            // Handle network operations
-            if self.handle_rxtx().is_err() {
-                error!("Error in handle_rxtx");
-                continue;
+    fn expire_sessions(&mut self) {
+        for expired in self.exch_mgr.expire_sessions(&self.idle_timeouts) {
+            if let Some(expired_tx) = &self.expired_tx {
+                let _ = expired_tx.try_send(expired);
+            }
+        }
+    }
+
+    // Closes the idle exchanges, and tells whoever waits on an exchange that the
+    // peer didn't respond in time
+    fn expire_exchanges(&mut self) {
+        let late = self
+            .exch_mgr
+            .expire_exchanges(&self.idle_timeouts, clock::now());
+        for exch_id in late {
+            if let Some(exch) = self.exch_mgr.get_with_id(exch_id) {
+                if let Some(resp_tx) = exch.take_exchange_data::<RespSender>() {
+                    let _ = resp_tx.try_send(Err(Error::Timeout));
+                } else {
+                    self.proto_demux
+                        .handle_response_timeout(exch.get_proto(), exch);
+                }
+                exch.close();
             }
+        }
+    }
 
-            if self.handle_queue_msgs().is_err() {
-                error!("Error in handle_queue_msg");
-                continue;
+    fn handle_acks(&mut self) {
+        let mut acks_to_send: LinearMap<u16, (), { exchange::MAX_MRP_ENTRIES }> = LinearMap::new();
+        self.exch_mgr.pending_acks(&mut acks_to_send);
+        for exch_id in acks_to_send.keys() {
+            info!("Sending MRP Standalone ACK for  exch {}", exch_id);
+            let mut proto_tx = match Self::new_tx() {
+                Ok(p) => p,
+                Err(e) => {
+                    error!("Error creating proto_tx {:?}", e);
+                    break;
+                }
+            };
+            ReliableMessage::prepare_ack(*exch_id, &mut proto_tx);
+            if let Err(e) = self.send_to_exchange(*exch_id, proto_tx) {
+                error!("Error in sending Ack {:?}", e);
             }
+        }
+    }
 
-            // Handle any pending acknowledgement send
-            let mut acks_to_send: LinearMap<u16, (), { exchange::MAX_MRP_ENTRIES }> =
-                LinearMap::new();
-            self.exch_mgr.pending_acks(&mut acks_to_send);
-            for exch_id in acks_to_send.keys() {
-                info!("Sending MRP Standalone ACK for  exch {}", exch_id);
-                let mut proto_tx = match Self::new_tx() {
-                    Ok(p) => p,
-                    Err(e) => {
-                        error!("Error creating proto_tx {:?}", e);
-                        break;
+    // Waits till a message arrives on the network or the work queue, or till the
+    // earliest ACK or retransmission is due, or a session expires
+    async fn wait_event(&self) -> Event {
+        let timeout = match (
+            self.exch_mgr.get_next_timeout(),
+            self.exch_mgr.get_next_expiry(&self.idle_timeouts),
+        ) {
+            (Some(t1), Some(t2)) => Some(t1.min(t2)),
+            (t1, t2) => t1.or(t2),
+        };
+        let rx = async { Event::Rx(self.exch_mgr.recv().await) };
+        let msg = async { Event::Msg(self.rx_q.recv().await) };
+        let timer = async {
+            match timeout {
+                Some(t) => Timer::after(t).await,
+                None => Timer::never().await,
+            };
+            Event::Timeout
+        };
+        let shutdown = async {
+            let _ = self.shutdown_rx.recv().await;
+            Event::Shutdown
+        };
+        shutdown.or(rx).or(msg).or(timer).await
+    }
+
+    // Leaves the peers in a clean state, and releases the network
+    async fn shutdown(&mut self) {
+        info!("Shutting down the transport");
+        self.exch_mgr.flush_acks();
+        self.exch_mgr.close_sessions();
+        // Give the interfaces that queue up messages a chance to send them out
+        let sess_mgr = self.exch_mgr.get_sess_mgr();
+        sess_mgr
+            .flush()
+            .or(async {
+                Timer::after(SHUTDOWN_FLUSH_TIMEOUT).await;
+            })
+            .await;
+        sess_mgr.remove_network_interfaces();
+        // The next run can then carry on from exactly where we stopped
+        let persisted = GlobalCtrs::get().and_then(|c| c.lock()?.persist());
+        if let Err(e) = persisted {
+            error!("Error in persisting the message counters {:?}", e);
+        }
+    }
+
+    /// Runs the transport, processing messages as they arrive
+    ///
+    /// This returns once the transport is shut down through a ShutdownHandle, or
+    /// if it can't proceed any further.
+    pub async fn run(&mut self) -> Result<(), Error> {
+        loop {
+            match self.wait_event().await {
+                Event::Shutdown => {
+                    self.shutdown().await;
+                    return Ok(());
+                }
+                Event::Rx(Ok(rx)) => {
+                    if self.handle_rx(rx).is_err() {
+                        error!("Error in handle_rx");
+                    }
+                }
+                Event::Rx(Err(e)) => error!("Error in recv: {:?}", e),
+                Event::Msg(Ok(msg)) => {
+                    if self.handle_queue_msg(msg).is_err() {
+                        error!("Error in handle_queue_msg");
                     }
-                };
-                ReliableMessage::prepare_ack(*exch_id, &mut proto_tx);
-                if let Err(e) = self.send_to_exchange(*exch_id, proto_tx) {
-                    error!("Error in sending Ack {:?}", e);
                 }
+                Event::Msg(Err(e)) => return Err(e.into()),
+                Event::Timeout => (),
             }
 
+            // Handle any pending acknowledgement send
+            self.handle_acks();
+
+            // Handle any pending retransmissions
+            self.exch_mgr.pending_retrans();
+
+            // Drop the sessions whose peers can't be reached any more
+            self.exch_mgr.drop_unreachable();
+
             // Handle exchange purging
             //    This need not be done in each turn of the loop, maybe once in 5 times or so?
             self.exch_mgr.purge();
 
-            info!("Exchange Mgr: {}", self.exch_mgr);
+            // Close the sessions whose peers went quiet, and the exchanges that
+            // nobody carried on with
+            self.expire_sessions();
+            self.expire_exchanges();
         }
     }
 
+    /// Runs the transport on the current thread, see run()
+    pub fn start(&mut self) -> Result<(), Error> {
+        smol::block_on(self.run())
+    }
+
     fn new_tx() -> Result<BoxSlab<PacketPool>, Error> {
-        Slab::<PacketPool>::new(Packet::new_tx()?).ok_or(Error::PacketPoolExhaust)
+        Packet::alloc_tx()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::ErrorKind,
+        net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+        sync::atomic::{AtomicBool, Ordering},
+        task::{Context, Poll},
+        time::Duration,
+    };
+
+    use smol::future::FutureExt;
+
+    use crate::{
+        error::Error,
+        transport::{
+            clock,
+            faults::{Direction, FaultRule, FaultyInterface},
+            loopback::{LoopbackInterface, LoopbackSwitch},
+            network::{Address, NetworkInterface},
+            proto_demux::{HandleProto, ProtoCtx, ResponseRequired, STANDARD_VENDOR_ID},
+            queue::RxMsg,
+            session::{CloneData, ExpiredSession, IdleTimeouts, SessionMode},
+            stats::Stats,
+            udp,
+        },
+    };
+
+    use super::{Mgr, TransportConfig};
+
+    const TEST_PROTO_ID: usize = 1;
+
+    // Responds to every message with the same opcode and payload
+    struct Echo;
+
+    impl HandleProto for Echo {
+        fn handle_proto_id(&mut self, ctx: &mut ProtoCtx) -> Result<ResponseRequired, Error> {
+            ctx.tx.set_proto_id(TEST_PROTO_ID as u16);
+            ctx.tx.set_proto_opcode(ctx.rx.get_proto_opcode());
+            let payload = ctx.rx.as_borrow_slice().to_vec();
+            ctx.tx.get_writebuf()?.append(&payload)?;
+            ctx.exch_ctx.exch.close();
+            Ok(ResponseRequired::Yes)
+        }
+
+        fn get_proto_id(&self) -> usize {
+            TEST_PROTO_ID
+        }
+    }
+
+    // Takes every message, and never responds
+    struct Silent;
+
+    impl HandleProto for Silent {
+        fn handle_proto_id(&mut self, _ctx: &mut ProtoCtx) -> Result<ResponseRequired, Error> {
+            Ok(ResponseRequired::No)
+        }
+
+        fn get_proto_id(&self) -> usize {
+            TEST_PROTO_ID
+        }
+    }
+
+    const TEST_VENDOR_ID: u16 = 0xFFF1;
+
+    // A vendor's protocol with the same protocol id, responds with the next opcode
+    struct VendorEcho;
+
+    impl HandleProto for VendorEcho {
+        fn handle_proto_id(&mut self, ctx: &mut ProtoCtx) -> Result<ResponseRequired, Error> {
+            ctx.tx.set_proto_vendor_id(TEST_VENDOR_ID);
+            ctx.tx.set_proto_id(TEST_PROTO_ID as u16);
+            ctx.tx.set_proto_opcode(ctx.rx.get_proto_opcode() + 1);
+            ctx.exch_ctx.exch.close();
+            Ok(ResponseRequired::Yes)
+        }
+
+        fn get_proto_id(&self) -> usize {
+            TEST_PROTO_ID
+        }
+
+        fn get_vendor_id(&self) -> u16 {
+            TEST_VENDOR_ID
+        }
+    }
+
+    // Each node encrypts with a key of its own
+    fn add_session(mgr: &mut Mgr, local: (u64, u16), peer: (u64, u16), peer_addr: Address) {
+        let mut clone_data = CloneData::new(
+            local.0,
+            peer.0,
+            peer.1,
+            local.1,
+            peer_addr,
+            SessionMode::Pase,
+        );
+        clone_data.enc_key = [local.0 as u8; 16];
+        clone_data.dec_key = [peer.0 as u8; 16];
+        mgr.exch_mgr.add_session(clone_data).unwrap();
+    }
+
+    fn localhost(port: u16) -> Address {
+        Address::Udp(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port))
+    }
+
+    fn test_config(port: u16) -> TransportConfig {
+        TransportConfig {
+            port,
+            bind_addrs: vec![IpAddr::V4(Ipv4Addr::LOCALHOST)],
+            tcp: false,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_shutdown_releases_socket() {
+        let mut mgr = Mgr::new(test_config(0)).unwrap();
+        let port = mgr.get_port();
+        let handle = mgr.get_shutdown_handle();
+        let other_handle = handle.clone();
+        assert!(!handle.is_shutdown());
+
+        other_handle.shutdown();
+        assert!(handle.is_shutdown());
+        assert!(smol::block_on(mgr.run()).is_ok());
+
+        // Another instance can take over the port, even while the first one is around
+        let mgr2 = Mgr::new(test_config(port)).unwrap();
+        assert_eq!(mgr2.get_port(), port);
+    }
+
+    #[test]
+    fn test_request_response() {
+        let mut initiator = Mgr::new(test_config(0)).unwrap();
+        let mut responder = Mgr::new(test_config(0)).unwrap();
+        responder.register_protocol(Box::new(Echo)).unwrap();
+        let (initiator_port, responder_port) = (initiator.get_port(), responder.get_port());
+        add_session(&mut initiator, (1, 10), (2, 20), localhost(responder_port));
+        add_session(&mut responder, (2, 20), (1, 10), localhost(initiator_port));
+
+        let work_q = initiator.get_work_q();
+        let result = smol::block_on(
+            async {
+                let resp = work_q
+                    .request(10, TEST_PROTO_ID as u16, 5, &[1, 2, 3])
+                    .await;
+                let no_session = work_q.request(11, TEST_PROTO_ID as u16, 5, &[]).await;
+                Ok((resp, no_session))
+            }
+            .or(async {
+                initiator.run().or(responder.run()).await?;
+                Err(Error::Invalid)
+            }),
+        );
+        let (resp, no_session) = result.unwrap();
+        assert_eq!(
+            resp,
+            Ok(RxMsg {
+                proto_vendor_id: None,
+                proto_id: TEST_PROTO_ID as u16,
+                proto_opcode: 5,
+                payload: vec![1, 2, 3],
+            })
+        );
+        assert_eq!(no_session, Err(Error::NoSession));
+    }
+
+    fn addr(node: u16) -> SocketAddr {
+        SocketAddr::new(
+            IpAddr::V6(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, node)),
+            5540,
+        )
+    }
+
+    fn loopback_config(switch: &LoopbackSwitch, addr: SocketAddr) -> TransportConfig {
+        TransportConfig {
+            port: addr.port(),
+            bind_addrs: Vec::new(),
+            tcp: false,
+            multicast_groups: Vec::new(),
+            interfaces: vec![Box::new(switch.connect(addr).unwrap())],
+            idle_timeouts: IdleTimeouts::default(),
+        }
+    }
+
+    #[test]
+    fn test_loopback() {
+        let switch = LoopbackSwitch::new();
+        let addrs = [addr(1), addr(2), addr(3)];
+        let mut initiator = Mgr::new(loopback_config(&switch, addrs[0])).unwrap();
+        let mut responder1 = Mgr::new(loopback_config(&switch, addrs[1])).unwrap();
+        let mut responder2 = Mgr::new(loopback_config(&switch, addrs[2])).unwrap();
+        responder1.register_protocol(Box::new(Echo)).unwrap();
+        responder2.register_protocol(Box::new(Echo)).unwrap();
+        add_session(&mut initiator, (1, 10), (2, 20), Address::Udp(addrs[1]));
+        add_session(&mut initiator, (1, 11), (3, 30), Address::Udp(addrs[2]));
+        add_session(&mut responder1, (2, 20), (1, 10), Address::Udp(addrs[0]));
+        add_session(&mut responder2, (3, 30), (1, 11), Address::Udp(addrs[0]));
+
+        let work_q = initiator.get_work_q();
+        let result = smol::block_on(
+            async {
+                let resp1 = work_q.request(10, TEST_PROTO_ID as u16, 5, &[1, 2]).await;
+                let resp2 = work_q.request(11, TEST_PROTO_ID as u16, 6, &[3]).await;
+                Ok((resp1, resp2))
+            }
+            .or(async {
+                initiator
+                    .run()
+                    .or(responder1.run())
+                    .or(responder2.run())
+                    .await?;
+                Err(Error::Invalid)
+            }),
+        );
+        let (resp1, resp2) = result.unwrap();
+        assert_eq!(resp1.unwrap().payload, vec![1, 2]);
+        assert_eq!(resp2.unwrap().proto_opcode, 6);
+    }
+
+    #[test]
+    fn test_lossy_network() {
+        // The same faults and the same timing, the same outcome
+        let stats = lossy_run();
+        assert_eq!(lossy_run(), stats);
+
+        let echo = &stats.per_protocol[&(STANDARD_VENDOR_ID, TEST_PROTO_ID as u16)];
+        assert_eq!(echo.exchanges_opened, 10);
+        assert_eq!(echo.rx, 10 + echo.duplicates);
+        assert!(stats.pase.retransmissions > 0);
+        assert_eq!(
+            stats.pase.tx,
+            echo.tx + stats.per_protocol[&(STANDARD_VENDOR_ID, 0)].tx
+        );
+        assert_eq!(stats.pase.sessions_opened, 1);
+        assert_eq!(stats.case, Default::default());
+    }
+
+    // Ten requests over a network that loses, duplicates and reorders messages, on a
+    // simulated clock
+    fn lossy_run() -> Stats {
+        let switch = LoopbackSwitch::new();
+        let addrs = [addr(1), addr(2)];
+        let faulty = FaultyInterface::new(Box::new(switch.connect(addrs[0]).unwrap()), 42);
+        let rule = FaultRule {
+            loss: 0.2,
+            duplicate: 0.2,
+            reorder: 0.2,
+            reorder_delay: Duration::from_millis(50),
+            ..Default::default()
+        };
+        faulty.set_rule(Direction::Tx, None, rule.clone());
+        faulty.set_rule(Direction::Rx, None, rule);
+        let mut initiator = Mgr::new(TransportConfig {
+            bind_addrs: Vec::new(),
+            tcp: false,
+            interfaces: vec![Box::new(faulty)],
+            ..Default::default()
+        })
+        .unwrap();
+        let mut responder = Mgr::new(loopback_config(&switch, addrs[1])).unwrap();
+        responder.register_protocol(Box::new(Echo)).unwrap();
+        add_session(&mut initiator, (1, 10), (2, 20), Address::Udp(addrs[1]));
+        add_session(&mut responder, (2, 20), (1, 10), Address::Udp(addrs[0]));
+        let stats = initiator.get_stats_handle();
+
+        let work_q = initiator.get_work_q();
+        let result = clock::simulate(
+            42,
+            async {
+                let mut resps = Vec::new();
+                for i in 0..10 {
+                    resps.push(work_q.request(10, TEST_PROTO_ID as u16, i, &[i]).await?);
+                }
+                Ok(resps)
+            }
+            .or(async {
+                initiator.run().or(responder.run()).await?;
+                Err(Error::Invalid)
+            }),
+        );
+        for (i, resp) in result.unwrap().iter().enumerate() {
+            assert_eq!(resp.payload, vec![i as u8]);
+        }
+        stats.get()
+    }
+
+    // A network whose send buffer is full for the first message
+    struct BusyOnce {
+        inner: LoopbackInterface,
+        busy: AtomicBool,
+    }
+
+    impl NetworkInterface for BusyOnce {
+        fn poll_recv(
+            &self,
+            cx: &mut Context<'_>,
+            in_buf: &mut [u8],
+        ) -> Poll<Result<(usize, Address), Error>> {
+            self.inner.poll_recv(cx, in_buf)
+        }
+
+        fn send(&self, out_buf: &[u8], addr: Address) -> Result<usize, Error> {
+            match addr {
+                Address::Udp(a) if self.busy.swap(false, Ordering::SeqCst) => {
+                    udp::send_result(Err(ErrorKind::WouldBlock.into()), out_buf.len(), a)
+                }
+                _ => self.inner.send(out_buf, addr),
+            }
+        }
+    }
+
+    #[test]
+    fn test_send_buffer_full() {
+        let switch = LoopbackSwitch::new();
+        let addrs = [addr(1), addr(2)];
+        let mut initiator = Mgr::new(TransportConfig {
+            bind_addrs: Vec::new(),
+            tcp: false,
+            interfaces: vec![Box::new(BusyOnce {
+                inner: switch.connect(addrs[0]).unwrap(),
+                busy: AtomicBool::new(true),
+            })],
+            ..Default::default()
+        })
+        .unwrap();
+        let mut responder = Mgr::new(loopback_config(&switch, addrs[1])).unwrap();
+        responder.register_protocol(Box::new(Echo)).unwrap();
+        add_session(&mut initiator, (1, 10), (2, 20), Address::Udp(addrs[1]));
+        add_session(&mut responder, (2, 20), (1, 10), Address::Udp(addrs[0]));
+        let stats = initiator.get_stats_handle();
+
+        // The request that didn't make it out is retransmitted
+        let work_q = initiator.get_work_q();
+        let result = clock::simulate(
+            1,
+            async { work_q.request(10, TEST_PROTO_ID as u16, 5, &[1]).await }.or(async {
+                initiator.run().or(responder.run()).await?;
+                Err(Error::Invalid)
+            }),
+        );
+        assert_eq!(result.unwrap().payload, vec![1]);
+        assert_eq!(stats.get().pase.retransmissions, 1);
+    }
+
+    #[test]
+    fn test_idle_timeout() {
+        let switch = LoopbackSwitch::new();
+        let addrs = [addr(1), addr(2), addr(3)];
+        let mut initiator = Mgr::new(TransportConfig {
+            idle_timeouts: IdleTimeouts {
+                pase: Some(Duration::from_millis(300)),
+                ..Default::default()
+            },
+            ..loopback_config(&switch, addrs[0])
+        })
+        .unwrap();
+        let mut responder1 = Mgr::new(loopback_config(&switch, addrs[1])).unwrap();
+        let mut responder2 = Mgr::new(loopback_config(&switch, addrs[2])).unwrap();
+        responder1.register_protocol(Box::new(Echo)).unwrap();
+        add_session(&mut initiator, (1, 10), (2, 20), Address::Udp(addrs[1]));
+        add_session(&mut initiator, (1, 11), (3, 30), Address::Udp(addrs[2]));
+        add_session(&mut responder1, (2, 20), (1, 10), Address::Udp(addrs[0]));
+        add_session(&mut responder2, (3, 30), (1, 11), Address::Udp(addrs[0]));
+        let expired_rx = initiator.subscribe_expired_sessions();
+
+        let work_q = initiator.get_work_q();
+        let result = clock::simulate(
+            1,
+            async {
+                // The first session is kept alive, the second one expires
+                for i in 0..6 {
+                    work_q.request(10, TEST_PROTO_ID as u16, i, &[]).await?;
+                    clock::Timer::after(Duration::from_millis(100)).await;
+                }
+                let expired = expired_rx.recv().await.map_err(|_| Error::Invalid)?;
+                let gone = work_q.request(11, TEST_PROTO_ID as u16, 0, &[]).await;
+                Ok((expired, gone, expired_rx.try_recv().is_err()))
+            }
+            .or(async {
+                initiator
+                    .run()
+                    .or(responder1.run())
+                    .or(responder2.run())
+                    .await?;
+                Err(Error::Invalid)
+            }),
+        );
+        let (expired, gone, only_one) = result.unwrap();
+        assert_eq!(
+            expired,
+            ExpiredSession {
+                local_sess_id: 11,
+                peer_nodeid: Some(3),
+                peer_addr: Address::Udp(addrs[2]),
+                mode: SessionMode::Pase,
+            }
+        );
+        assert_eq!(gone, Err(Error::NoSession));
+        assert!(only_one);
+    }
+
+    #[test]
+    fn test_response_timeout() {
+        let switch = LoopbackSwitch::new();
+        let addrs = [addr(1), addr(2)];
+        let mut initiator = Mgr::new(TransportConfig {
+            idle_timeouts: IdleTimeouts {
+                response: Some(Duration::from_millis(200)),
+                ..Default::default()
+            },
+            ..loopback_config(&switch, addrs[0])
+        })
+        .unwrap();
+        let mut responder = Mgr::new(loopback_config(&switch, addrs[1])).unwrap();
+        responder.register_protocol(Box::new(Silent)).unwrap();
+        add_session(&mut initiator, (1, 10), (2, 20), Address::Udp(addrs[1]));
+        add_session(&mut responder, (2, 20), (1, 10), Address::Udp(addrs[0]));
+
+        let work_q = initiator.get_work_q();
+        let result = smol::block_on(
+            async {
+                // The peer acknowledges the request, but never responds to it
+                let resp = work_q.request(10, TEST_PROTO_ID as u16, 1, &[]).await;
+                Ok(resp)
+            }
+            .or(async {
+                initiator.run().or(responder.run()).await?;
+                Err(Error::Invalid)
+            })
+            .or(async {
+                smol::Timer::after(Duration::from_secs(10)).await;
+                Err(Error::Invalid)
+            }),
+        );
+        assert_eq!(result, Ok(Err(Error::Timeout)));
+        assert_eq!(initiator.get_stats_handle().get().pase.retransmissions, 0);
+    }
+
+    #[test]
+    fn test_vendor_protocols() {
+        let switch = LoopbackSwitch::new();
+        let addrs = [addr(1), addr(2)];
+        let mut initiator = Mgr::new(loopback_config(&switch, addrs[0])).unwrap();
+        let mut responder = Mgr::new(loopback_config(&switch, addrs[1])).unwrap();
+        responder.register_protocol(Box::new(Echo)).unwrap();
+        responder.register_protocol(Box::new(VendorEcho)).unwrap();
+        add_session(&mut initiator, (1, 10), (2, 20), Address::Udp(addrs[1]));
+        add_session(&mut responder, (2, 20), (1, 10), Address::Udp(addrs[0]));
+
+        let work_q = initiator.get_work_q();
+        let proto_id = TEST_PROTO_ID as u16;
+        let result = smol::block_on(
+            async {
+                let standard = work_q.request(10, proto_id, 5, &[]).await?;
+                let vendor = work_q
+                    .vendor_request(10, TEST_VENDOR_ID, proto_id, 5, &[])
+                    .await?;
+                let unknown = work_q.vendor_request(10, 0xFFF2, proto_id, 5, &[]).await?;
+                Ok((standard, vendor, unknown))
+            }
+            .or(async {
+                initiator.run().or(responder.run()).await?;
+                Err(Error::Invalid)
+            }),
+        );
+        let (standard, vendor, unknown) = result.unwrap();
+        assert_eq!((standard.proto_vendor_id, standard.proto_opcode), (None, 5));
+        assert_eq!(
+            (vendor.proto_vendor_id, vendor.proto_id, vendor.proto_opcode),
+            (Some(TEST_VENDOR_ID), proto_id, 6)
+        );
+
+        // A StatusReport that the protocol is unsupported
+        assert_eq!((unknown.proto_id, unknown.proto_opcode), (0, 0x40));
+        assert_eq!(
+            unknown.payload,
+            vec![0x05, 0x00, 0x01, 0x00, 0xF2, 0xFF, 0x00, 0x00]
+        );
     }
 }
@@ -1,6 +1,11 @@
+pub mod clock;
 pub mod exchange;
+pub mod faults;
+pub mod group;
+pub mod loopback;
 pub mod mgr;
 pub mod mrp;
+pub mod msg_ctr;
 pub mod network;
 pub mod packet;
 pub mod plain_hdr;
@@ -8,4 +13,6 @@ pub mod proto_demux;
 pub mod proto_hdr;
 pub mod queue;
 pub mod session;
+pub mod stats;
+pub mod tcp;
 pub mod udp;
@@ -1,27 +1,134 @@
-use std::time::Duration;
-use std::time::SystemTime;
+use std::fmt;
+use std::time::{Duration, Instant};
 
-use crate::{error::*, secure_channel, transport::packet::Packet};
-use log::error;
+use crate::{
+    error::*,
+    secure_channel,
+    tlv::{FromTLV, TLVElement, TLVWriter, TagType, ToTLV},
+    transport::packet::Packet,
+};
+use boxslab::BoxSlab;
+use log::{error, info};
+
+use super::{clock, packet::PacketPool};
 
 // 200 ms
 const MRP_STANDALONE_ACK_TIMEOUT: u64 = 200;
 
-#[derive(Debug)]
+// The retransmission parameters as defined in the Matter spec
+// Maximum number of transmissions (the first one included) before we give up
+pub const MRP_MAX_TRANSMISSIONS: u8 = 5;
+const MRP_BACKOFF_BASE: f64 = 1.6;
+const MRP_BACKOFF_JITTER: f64 = 0.25;
+const MRP_BACKOFF_MARGIN: f64 = 1.1;
+const MRP_BACKOFF_THRESHOLD: u8 = 1;
+
+// The default retransmission intervals of a peer, if it doesn't tell us its own
+const MRP_DEFAULT_IDLE_INTERVAL: u32 = 500;
+const MRP_DEFAULT_ACTIVE_INTERVAL: u32 = 300;
+// The time for which a peer stays active after we receive a message from it (4000 ms)
+pub const MRP_ACTIVE_THRESHOLD: Duration = Duration::from_millis(4000);
+
+// The intervals we advertise for ourselves, we are always-on, so they are the same as the defaults
+const MRP_LOCAL_IDLE_INTERVAL: u32 = MRP_DEFAULT_IDLE_INTERVAL;
+const MRP_LOCAL_ACTIVE_INTERVAL: u32 = MRP_DEFAULT_ACTIVE_INTERVAL;
+
+/// The MRP parameters of a node
+///
+/// This is exchanged as the sleepy parameters (SLEEPY_IDLE_INTERVAL and
+/// SLEEPY_ACTIVE_INTERVAL, in milliseconds) in the PASE and CASE session
+/// establishment messages.
+#[derive(FromTLV, ToTLV, Debug, Default, Copy, Clone, PartialEq)]
+#[tlvargs(start = 1)]
+pub struct MrpParams {
+    idle_interval: Option<u32>,
+    active_interval: Option<u32>,
+}
+
+impl MrpParams {
+    pub fn new(idle_interval: u32, active_interval: u32) -> Self {
+        Self {
+            idle_interval: Some(idle_interval),
+            active_interval: Some(active_interval),
+        }
+    }
+
+    /// The parameters that we advertise to our peers
+    pub fn local() -> Self {
+        Self::new(MRP_LOCAL_IDLE_INTERVAL, MRP_LOCAL_ACTIVE_INTERVAL)
+    }
+
+    /// The retransmission interval when the node is idle
+    pub fn idle_interval(&self) -> Duration {
+        Duration::from_millis(self.idle_interval.unwrap_or(MRP_DEFAULT_IDLE_INTERVAL) as u64)
+    }
+
+    /// The retransmission interval when the node is active
+    pub fn active_interval(&self) -> Duration {
+        Duration::from_millis(self.active_interval.unwrap_or(MRP_DEFAULT_ACTIVE_INTERVAL) as u64)
+    }
+}
+
+/// Returns how long to wait for an acknowledgement after the `send_count`th
+/// transmission of a message, where the first transmission has a `send_count` of 0
+fn get_backoff_time(base_interval: Duration, send_count: u8) -> Duration {
+    let exponent = send_count.saturating_sub(MRP_BACKOFF_THRESHOLD) as i32;
+    let jitter = 1.0 + clock::random_fraction() * MRP_BACKOFF_JITTER;
+    base_interval.mul_f64(MRP_BACKOFF_MARGIN * MRP_BACKOFF_BASE.powi(exponent) * jitter)
+}
+
 pub struct RetransEntry {
     // The msg counter that we are waiting to be acknowledged
     msg_ctr: u32,
-    // This will additionally have retransmission count and periods once we implement it
+    // The number of times this message has been sent so far
+    send_count: u8,
+    // The base interval that the backoff is calculated from
+    base_interval: Duration,
+    // The time after which this message must be sent again
+    retrans_timeout: Instant,
+    // The message as it was sent out on the wire, so it can be sent again as is
+    packet: BoxSlab<PacketPool>,
 }
 
 impl RetransEntry {
-    pub fn new(msg_ctr: u32) -> Self {
-        Self { msg_ctr }
+    pub fn new(packet: BoxSlab<PacketPool>, base_interval: Duration) -> Self {
+        let mut entry = Self {
+            msg_ctr: packet.plain.ctr,
+            send_count: 0,
+            base_interval,
+            retrans_timeout: clock::now(),
+            packet,
+        };
+        entry.set_timeout();
+        entry
     }
 
     pub fn get_msg_ctr(&self) -> u32 {
         self.msg_ctr
     }
+
+    pub fn get_send_count(&self) -> u8 {
+        self.send_count
+    }
+
+    pub fn has_timed_out(&self) -> bool {
+        clock::now() >= self.retrans_timeout
+    }
+
+    fn set_timeout(&mut self) {
+        self.retrans_timeout = clock::now() + get_backoff_time(self.base_interval, self.send_count);
+        self.send_count += 1;
+    }
+}
+
+impl fmt::Debug for RetransEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{{msg_ctr: {}, send_count: {}, timeout: {:?}}}",
+            self.msg_ctr, self.send_count, self.retrans_timeout
+        )
+    }
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -29,13 +136,13 @@ pub struct AckEntry {
     // The msg counter that we should acknowledge
     msg_ctr: u32,
     // The max time after which this entry must be ACK
-    ack_timeout: SystemTime,
+    ack_timeout: Instant,
 }
 
 impl AckEntry {
     pub fn new(msg_ctr: u32) -> Result<Self, Error> {
         if let Some(ack_timeout) =
-            SystemTime::now().checked_add(Duration::from_millis(MRP_STANDALONE_ACK_TIMEOUT))
+            clock::now().checked_add(Duration::from_millis(MRP_STANDALONE_ACK_TIMEOUT))
         {
             Ok(Self {
                 msg_ctr,
@@ -46,15 +153,31 @@ impl AckEntry {
         }
     }
 
+    /// An entry that must be acknowledged right away
+    pub fn new_immediate(msg_ctr: u32) -> Self {
+        Self {
+            msg_ctr,
+            ack_timeout: clock::now(),
+        }
+    }
+
     pub fn get_msg_ctr(&self) -> u32 {
         self.msg_ctr
     }
 
     pub fn has_timed_out(&self) -> bool {
-        self.ack_timeout > SystemTime::now()
+        clock::now() >= self.ack_timeout
     }
 }
 
+/// The action to be taken for a reliable message whose acknowledgement is overdue
+pub enum RetransAction<'a> {
+    /// Send this (already encoded) message again
+    Resend(&'a mut Packet<'static>),
+    /// The peer never acknowledged the message, even after all the retransmissions
+    GiveUp,
+}
+
 #[derive(Default, Debug)]
 pub struct ReliableMessage {
     retrans: Option<RetransEntry>,
@@ -82,6 +205,47 @@ impl ReliableMessage {
         }
     }
 
+    pub fn has_pending_ack(&self) -> bool {
+        self.ack.is_some()
+    }
+
+    /// Returns the earliest time at which an ACK or a retransmission is due
+    pub fn get_next_timeout(&self) -> Option<Instant> {
+        let ack = self.ack.map(|a| a.ack_timeout);
+        let retrans = self.retrans.as_ref().map(|r| r.retrans_timeout);
+        match (ack, retrans) {
+            (Some(a), Some(r)) => Some(a.min(r)),
+            (a, r) => a.or(r),
+        }
+    }
+
+    /// Checks if the pending reliable message is due for a retransmission
+    ///
+    /// If the message has already been sent MRP_MAX_TRANSMISSIONS times, the
+    /// retransmission entry is dropped and we ask the caller to give up.
+    pub fn get_retrans(&mut self) -> Option<RetransAction<'_>> {
+        if !self.retrans.as_ref()?.has_timed_out() {
+            return None;
+        }
+        if self.retrans.as_ref()?.get_send_count() >= MRP_MAX_TRANSMISSIONS {
+            let entry = self.retrans.take()?;
+            error!(
+                "No ACK for msg ctr {} after {} transmissions, giving up",
+                entry.get_msg_ctr(),
+                entry.get_send_count()
+            );
+            return Some(RetransAction::GiveUp);
+        }
+        let entry = self.retrans.as_mut()?;
+        info!(
+            "Retransmitting msg ctr {}, attempt {}",
+            entry.get_msg_ctr(),
+            entry.get_send_count()
+        );
+        entry.set_timeout();
+        Some(RetransAction::Resend(&mut entry.packet))
+    }
+
     pub fn prepare_ack(_exch_id: u16, proto_tx: &mut Packet) {
         secure_channel::common::create_mrp_standalone_ack(proto_tx);
     }
@@ -105,11 +269,18 @@ impl ReliableMessage {
             error!("Previous retrans entry for this exchange already exists");
             return Err(Error::Invalid);
         }
-
-        self.retrans = Some(RetransEntry::new(proto_tx.plain.ctr));
         Ok(())
     }
 
+    /// Holds on to a reliable message that has just been sent, so that it can be
+    /// retransmitted till the peer acknowledges it. The `base_interval` is the
+    /// peer's retransmission interval that the backoff is computed from.
+    pub fn post_send(&mut self, mut proto_tx: BoxSlab<PacketPool>, base_interval: Duration) {
+        if proto_tx.is_reliable() {
+            self.retrans = Some(RetransEntry::new(proto_tx, base_interval));
+        }
+    }
+
     /* A note about Message ACKs, it is a bit asymmetric in the sense that:
      * -  there can be only one pending ACK per exchange (so this is per-exchange)
      * -  there can be only one pending retransmission per exchange (so this is per-exchange)
@@ -120,14 +291,32 @@ impl ReliableMessage {
             // Handle received Acks
             let ack_msg_ctr = proto_rx.proto.get_ack_msg_ctr().ok_or(Error::Invalid)?;
             if let Some(entry) = &self.retrans {
-                if entry.get_msg_ctr() != ack_msg_ctr {
-                    // TODO: XXX Fix this
-                    error!("Mismatch in retrans-table's msg counter and received msg counter: received {}, expected {}. This is expected for the timebeing", ack_msg_ctr, entry.get_msg_ctr());
+                if entry.get_msg_ctr() == ack_msg_ctr {
+                    self.retrans = None;
+                } else {
+                    error!(
+                        "Mismatch in retrans-table's msg counter and received msg counter: received {}, expected {}",
+                        ack_msg_ctr,
+                        entry.get_msg_ctr()
+                    );
                 }
-                self.retrans = None;
             }
         }
 
+        if proto_rx.is_duplicate() {
+            // The peer likely didn't get our ACK, so it needs to be sent again. There
+            // is no point in waiting for a piggyback, as this message will be dropped
+            if proto_rx.proto.is_reliable() {
+                match self.ack {
+                    Some(ack_entry) if ack_entry.get_msg_ctr() != proto_rx.plain.ctr => {
+                        error!("Another ACK is pending, not acknowledging the duplicate");
+                    }
+                    _ => self.ack = Some(AckEntry::new_immediate(proto_rx.plain.ctr)),
+                }
+            }
+            return Ok(());
+        }
+
         if proto_rx.proto.is_reliable() {
             if self.ack.is_some() {
                 // This indicates there was some existing entry for same sess-id/exch-id, which shouldnt happen
@@ -141,3 +330,113 @@ impl ReliableMessage {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use boxslab::Slab;
+
+    use super::{
+        get_backoff_time, MrpParams, ReliableMessage, RetransAction, MRP_MAX_TRANSMISSIONS,
+    };
+    use crate::{
+        tlv::{get_root_node_struct, FromTLV, TLVWriter, TagType, ToTLV},
+        transport::{
+            clock,
+            packet::{Packet, PacketPool},
+        },
+        utils::writebuf::WriteBuf,
+    };
+
+    const BASE_INTERVAL: Duration = Duration::from_millis(300);
+
+    #[test]
+    fn test_backoff_time() {
+        let base = Duration::from_millis(1000);
+        for send_count in 0..MRP_MAX_TRANSMISSIONS {
+            let exponent = send_count.saturating_sub(1) as i32;
+            let min = base.mul_f64(1.1 * 1.6_f64.powi(exponent));
+            let max = min.mul_f64(1.25);
+            let t = get_backoff_time(base, send_count);
+            assert!(t >= min && t <= max);
+        }
+    }
+
+    #[test]
+    fn test_retrans_and_give_up() {
+        let mut mrp = ReliableMessage::new();
+        let mut tx = Slab::<PacketPool>::new(Packet::new_tx().unwrap()).unwrap();
+        tx.plain.ctr = 10;
+        mrp.pre_send(&mut tx).unwrap();
+        mrp.post_send(tx, BASE_INTERVAL);
+        assert!(!mrp.is_empty());
+        assert!(mrp.get_next_timeout().unwrap() > clock::now());
+        assert!(mrp.get_retrans().is_none());
+
+        for attempt in 1..MRP_MAX_TRANSMISSIONS {
+            // Fake an expiry of the retransmission timer
+            mrp.retrans.as_mut().unwrap().retrans_timeout = clock::now();
+            match mrp.get_retrans() {
+                Some(RetransAction::Resend(p)) => assert_eq!(p.plain.ctr, 10),
+                _ => panic!("Expected a retransmission"),
+            }
+            assert_eq!(mrp.retrans.as_ref().unwrap().get_send_count(), attempt + 1);
+            assert!(mrp.get_next_timeout().unwrap() >= clock::now() + BASE_INTERVAL);
+        }
+
+        mrp.retrans.as_mut().unwrap().retrans_timeout = clock::now();
+        assert!(matches!(mrp.get_retrans(), Some(RetransAction::GiveUp)));
+        assert!(mrp.is_empty());
+    }
+
+    #[test]
+    fn test_ack_clears_retrans() {
+        let mut mrp = ReliableMessage::new();
+        let mut tx = Slab::<PacketPool>::new(Packet::new_tx().unwrap()).unwrap();
+        tx.plain.ctr = 20;
+        mrp.pre_send(&mut tx).unwrap();
+        mrp.post_send(tx, BASE_INTERVAL);
+
+        let mut rx = Packet::new_rx().unwrap();
+        rx.proto.set_ack(19);
+        mrp.recv(&rx).unwrap();
+        assert!(mrp.retrans.is_some());
+
+        rx.proto.set_ack(20);
+        mrp.recv(&rx).unwrap();
+        assert!(mrp.is_empty());
+    }
+
+    #[test]
+    fn test_duplicate_acked_immediately() {
+        let mut mrp = ReliableMessage::new();
+        let mut rx = Packet::new_rx().unwrap();
+        rx.plain.ctr = 30;
+        rx.proto.set_reliable();
+        mrp.recv(&rx).unwrap();
+        assert!(!mrp.is_ack_ready());
+
+        rx.set_duplicate();
+        mrp.recv(&rx).unwrap();
+        assert!(mrp.is_ack_ready());
+    }
+
+    #[test]
+    fn test_mrp_params() {
+        let defaults = MrpParams::default();
+        assert_eq!(defaults.idle_interval(), Duration::from_millis(500));
+        assert_eq!(defaults.active_interval(), Duration::from_millis(300));
+
+        let mut buf = [0u8; 20];
+        let mut wb = WriteBuf::new(&mut buf, 20);
+        let mut tw = TLVWriter::new(&mut wb);
+        MrpParams::new(5000, 800)
+            .to_tlv(&mut tw, TagType::Anonymous)
+            .unwrap();
+        let root = get_root_node_struct(wb.as_slice()).unwrap();
+        let params = MrpParams::from_tlv(&root).unwrap();
+        assert_eq!(params.idle_interval(), Duration::from_millis(5000));
+        assert_eq!(params.active_interval(), Duration::from_millis(800));
+    }
+}
@@ -0,0 +1,274 @@
+use std::sync::{Arc, Mutex, Once};
+
+use log::{error, info};
+use rand::Rng;
+
+use crate::{error::Error, sys::Psm};
+
+// The counters start at a random value in this range, the rest of the range is
+// left for them to grow in
+const MSG_CTR_INIT_RANGE: u32 = 0x0fffffff;
+
+// The number of counter values that are reserved at a time. The end of the block is
+// persisted before its first value is used, so after a crash, we resume from there
+// and never reuse a value. This saves us a write for every message.
+const MSG_CTR_EPOCH: u32 = 1000;
+
+/// The storage that the counters are kept in
+pub trait CtrStore {
+    /// Fails with Error::NotFound if nothing is stored for the key
+    fn get_ctr(&self, key: &str) -> Result<u64, Error>;
+    fn set_ctr(&self, key: &str, val: u64) -> Result<(), Error>;
+}
+
+// The persistent storage of the system
+struct PsmStore;
+
+impl CtrStore for PsmStore {
+    fn get_ctr(&self, key: &str) -> Result<u64, Error> {
+        let mut val = 0;
+        Psm::get()?.lock()?.get_kv_u64(key, &mut val)?;
+        Ok(val)
+    }
+
+    fn set_ctr(&self, key: &str, val: u64) -> Result<(), Error> {
+        Psm::get()?.lock()?.set_kv_u64(key, val)
+    }
+}
+
+/// A message counter that never goes back, even across reboots
+///
+/// The counter reserves blocks of values in the persistent storage, a crash only
+/// costs us the rest of the block that we were in.
+pub struct PersistentCtr {
+    key: &'static str,
+    store: Box<dyn CtrStore + Send>,
+    // The next value to be used, and the end of the block that it is in
+    next: u32,
+    limit: u32,
+    loaded: bool,
+}
+
+impl PersistentCtr {
+    pub fn new(key: &'static str) -> Self {
+        Self::new_with_store(key, Box::new(PsmStore))
+    }
+
+    /// A counter that is kept in `store` instead of the persistent storage
+    pub fn new_with_store(key: &'static str, store: Box<dyn CtrStore + Send>) -> Self {
+        Self {
+            key,
+            store,
+            next: 0,
+            limit: 0,
+            loaded: false,
+        }
+    }
+
+    // Resumes from where the previous run left off, the stored value is beyond
+    // anything that was used
+    //
+    // Only a counter that was never stored starts afresh. If the stored value can't
+    // be read, we can't tell which values were used, and fail rather than risk
+    // reusing them.
+    fn load(&mut self) -> Result<(), Error> {
+        self.next = match self.store.get_ctr(self.key) {
+            Ok(stored) => stored as u32,
+            Err(Error::NotFound) => {
+                info!("No {} counter stored, starting afresh", self.key);
+                rand::thread_rng().gen_range(1..=MSG_CTR_INIT_RANGE)
+            }
+            Err(e) => {
+                error!("Couldn't load the {} counter: {:?}", self.key, e);
+                return Err(e);
+            }
+        };
+        self.limit = self.next;
+        self.loaded = true;
+        Ok(())
+    }
+
+    fn reserve(&mut self) -> Result<(), Error> {
+        let limit = self.next.wrapping_add(MSG_CTR_EPOCH);
+        self.store.set_ctr(self.key, limit as u64)?;
+        self.limit = limit;
+        Ok(())
+    }
+
+    /// Returns the counter for the next message
+    pub fn get_next(&mut self) -> Result<u32, Error> {
+        if !self.loaded {
+            self.load()?;
+        }
+        if self.next == self.limit {
+            self.reserve().map_err(|e| {
+                error!("Couldn't reserve {} counters: {:?}", self.key, e);
+                e
+            })?;
+        }
+        let ctr = self.next;
+        self.next = self.next.wrapping_add(1);
+        Ok(ctr)
+    }
+
+    /// Stores exactly where the counter is, so that a restart doesn't skip the rest
+    /// of the block
+    ///
+    /// The counter can still be used after this, it then reserves a new block.
+    pub fn persist(&mut self) -> Result<(), Error> {
+        if !self.loaded {
+            return Ok(());
+        }
+        self.store.set_ctr(self.key, self.next as u64)?;
+        self.limit = self.next;
+        Ok(())
+    }
+}
+
+/// The global message counters, that aren't tied to any session
+pub struct GlobalCtrs {
+    /// For the messages on the unencrypted sessions
+    pub unencrypted: PersistentCtr,
+    /// For the data messages to groups
+    pub group_data: PersistentCtr,
+    /// For the control messages to groups
+    pub group_ctrl: PersistentCtr,
+}
+
+static mut G_CTRS: Option<Arc<Mutex<GlobalCtrs>>> = None;
+static INIT: Once = Once::new();
+
+impl GlobalCtrs {
+    fn new() -> Self {
+        Self {
+            unencrypted: PersistentCtr::new("msg_ctr_unencrypted"),
+            group_data: PersistentCtr::new("msg_ctr_group_data"),
+            group_ctrl: PersistentCtr::new("msg_ctr_group_ctrl"),
+        }
+    }
+
+    pub fn get() -> Result<Arc<Mutex<Self>>, Error> {
+        unsafe {
+            INIT.call_once(|| {
+                G_CTRS = Some(Arc::new(Mutex::new(GlobalCtrs::new())));
+            });
+            Ok(G_CTRS.as_ref().ok_or(Error::Invalid)?.clone())
+        }
+    }
+
+    /// Stores all the counters, on a clean shutdown
+    pub fn persist(&mut self) -> Result<(), Error> {
+        self.unencrypted.persist()?;
+        self.group_data.persist()?;
+        self.group_ctrl.persist()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        convert::TryInto,
+        sync::{Arc, Mutex},
+    };
+
+    use crate::error::Error;
+
+    use super::{CtrStore, PersistentCtr, MSG_CTR_EPOCH, MSG_CTR_INIT_RANGE};
+
+    // A storage in memory, that the tests can look into
+    #[derive(Clone, Default)]
+    struct MemStore(Arc<Mutex<HashMap<String, Vec<u8>>>>);
+
+    impl CtrStore for MemStore {
+        fn get_ctr(&self, key: &str) -> Result<u64, Error> {
+            let store = self.0.lock()?;
+            let val = store.get(key).ok_or(Error::NotFound)?;
+            Ok(u64::from_be_bytes(val.as_slice().try_into()?))
+        }
+
+        fn set_ctr(&self, key: &str, val: u64) -> Result<(), Error> {
+            self.0
+                .lock()?
+                .insert(key.to_string(), val.to_be_bytes().to_vec());
+            Ok(())
+        }
+    }
+
+    const KEY: &str = "test_ctr";
+
+    impl MemStore {
+        fn ctr(&self) -> PersistentCtr {
+            PersistentCtr::new_with_store(KEY, Box::new(self.clone()))
+        }
+
+        fn stored(&self) -> u64 {
+            self.get_ctr(KEY).unwrap()
+        }
+    }
+
+    fn store(val: u64) -> MemStore {
+        let store = MemStore::default();
+        store.set_ctr(KEY, val).unwrap();
+        store
+    }
+
+    #[test]
+    fn test_crash_recovery() {
+        let store = store(5000);
+        let mut ctr = store.ctr();
+        assert_eq!(ctr.get_next(), Ok(5000));
+        // The block is reserved before it is used
+        assert_eq!(store.stored(), 5000 + MSG_CTR_EPOCH as u64);
+        for i in 1..MSG_CTR_EPOCH + 10 {
+            assert_eq!(ctr.get_next(), Ok(5000 + i));
+        }
+        assert_eq!(store.stored(), 5000 + 2 * MSG_CTR_EPOCH as u64);
+
+        // Without a clean shutdown, we skip what remains of the block
+        let mut ctr = store.ctr();
+        assert_eq!(ctr.get_next(), Ok(5000 + 2 * MSG_CTR_EPOCH));
+    }
+
+    #[test]
+    fn test_first_boot() {
+        let store = MemStore::default();
+        let mut ctr = store.ctr();
+        let first = ctr.get_next().unwrap();
+        assert!((1..=MSG_CTR_INIT_RANGE).contains(&first));
+        assert_eq!(store.stored(), (first + MSG_CTR_EPOCH) as u64);
+    }
+
+    #[test]
+    fn test_corrupt_ctr() {
+        let store = MemStore::default();
+        store
+            .0
+            .lock()
+            .unwrap()
+            .insert(KEY.to_string(), vec![1, 2, 3]);
+        let mut ctr = store.ctr();
+        assert_eq!(ctr.get_next(), Err(Error::Invalid));
+        // Nothing was overwritten
+        assert_eq!(store.0.lock().unwrap()[KEY], vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_clean_shutdown() {
+        let store = store(0xffff_fffe);
+        let mut ctr = store.ctr();
+        assert_eq!(ctr.get_next(), Ok(0xffff_fffe));
+        ctr.persist().unwrap();
+        assert_eq!(store.stored(), 0xffff_ffff);
+
+        // A value used after persisting is safe as well
+        assert_eq!(ctr.get_next(), Ok(0xffff_ffff));
+        assert_eq!(store.stored(), (MSG_CTR_EPOCH - 1) as u64);
+        ctr.persist().unwrap();
+
+        // The counter wraps around
+        let mut ctr = store.ctr();
+        assert_eq!(ctr.get_next(), Ok(0));
+        assert_eq!(ctr.get_next(), Ok(1));
+    }
+}
@@ -1,6 +1,7 @@
 use std::{
     fmt::{Debug, Display},
     net::{IpAddr, Ipv4Addr, SocketAddr},
+    task::{Context, Poll},
 };
 
 use crate::error::Error;
@@ -8,6 +9,17 @@ use crate::error::Error;
 #[derive(PartialEq, Copy, Clone)]
 pub enum Address {
     Udp(SocketAddr),
+    Tcp(SocketAddr),
+}
+
+impl Address {
+    /// Whether the transport already guarantees delivery, MRP isn't used on such transports
+    pub fn is_reliable(&self) -> bool {
+        match self {
+            Address::Udp(_) => false,
+            Address::Tcp(_) => true,
+        }
+    }
 }
 
 impl Default for Address {
@@ -20,6 +32,7 @@ impl Display for Address {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Address::Udp(addr) => writeln!(f, "{}", addr),
+            Address::Tcp(addr) => writeln!(f, "tcp:{}", addr),
         }
     }
 }
@@ -28,11 +41,47 @@ impl Debug for Address {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Address::Udp(addr) => writeln!(f, "{}", addr),
+            Address::Tcp(addr) => writeln!(f, "tcp:{}", addr),
         }
     }
 }
 
 pub trait NetworkInterface {
-    fn recv(&self, in_buf: &mut [u8]) -> Result<(usize, Address), Error>;
+    /// Attempts to receive a message into `in_buf`
+    ///
+    /// If nothing is available yet, this returns Poll::Pending and arranges for
+    /// the waker in `cx` to be woken once the interface has data to read. This
+    /// lets the transport wait on the network and its timers at the same time.
+    fn poll_recv(
+        &self,
+        cx: &mut Context<'_>,
+        in_buf: &mut [u8],
+    ) -> Poll<Result<(usize, Address), Error>>;
+    /// Sends out a message to `addr`
+    ///
+    /// This must not block, an interface that can't send right away queues the
+    /// message up and writes it out through poll_recv() or poll_flush().
     fn send(&self, out_buf: &[u8], addr: Address) -> Result<usize, Error>;
+    /// Writes out whatever send() has queued up, this is Poll::Ready once nothing
+    /// is left
+    fn poll_flush(&self, _cx: &mut Context<'_>) -> Poll<()> {
+        Poll::Ready(())
+    }
+    /// Returns the peers that the interface lost its connection to, since the
+    /// last call
+    ///
+    /// poll_recv() returns Error::Network when it loses a peer, the sessions with
+    /// such peers can't be used any more.
+    fn take_unreachable(&self) -> Vec<Address> {
+        Vec::new()
+    }
+    /// Starts receiving the messages to the multicast group, the interfaces that
+    /// don't do multicast ignore this
+    fn join_multicast(&self, _group: IpAddr) -> Result<(), Error> {
+        Ok(())
+    }
+    /// Stops receiving the messages to the multicast group
+    fn leave_multicast(&self, _group: IpAddr) -> Result<(), Error> {
+        Ok(())
+    }
 }
@@ -1,10 +1,14 @@
 use log::{error, trace};
-use std::sync::Mutex;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Mutex,
+};
 
 use boxslab::box_slab;
 
 use crate::{
     error::Error,
+    secure_channel::common::{OpCode, PROTO_ID_SECURE_CHANNEL},
     sys::MAX_PACKET_POOL_SIZE,
     utils::{parsebuf::ParseBuf, writebuf::WriteBuf},
 };
@@ -12,6 +16,7 @@ use crate::{
 use super::{
     network::Address,
     plain_hdr::{self, PlainHdr},
+    proto_demux::STANDARD_VENDOR_ID,
     proto_hdr::{self, ProtoHdr},
 };
 
@@ -21,6 +26,8 @@ type Buffer = [u8; MAX_RX_BUF_SIZE];
 // TODO: I am not very happy with this construction, need to find another way to do this
 pub struct BufferPool {
     buffers: [Option<Buffer>; MAX_PACKET_POOL_SIZE],
+    // The buffers that are handed out, see set_pool_size()
+    size: usize,
 }
 
 impl BufferPool {
@@ -32,6 +39,7 @@ impl BufferPool {
             ONCE.call_once(|| {
                 BUFFER_HOLDER = Some(Mutex::new(BufferPool {
                     buffers: [BufferPool::INIT; MAX_PACKET_POOL_SIZE],
+                    size: MAX_PACKET_POOL_SIZE,
                 }));
             });
             BUFFER_HOLDER.as_ref().unwrap()
@@ -42,7 +50,7 @@ impl BufferPool {
         trace!("Buffer Alloc called\n");
 
         let mut pool = BufferPool::get().lock().unwrap();
-        for i in 0..MAX_PACKET_POOL_SIZE {
+        for i in 0..pool.size {
             if pool.buffers[i].is_none() {
                 pool.buffers[i] = Some([0; MAX_RX_BUF_SIZE]);
                 // Sigh! to by-pass the borrow-checker telling us we are stealing a mutable reference
@@ -81,27 +89,32 @@ pub struct Packet<'a> {
     pub plain: PlainHdr,
     pub proto: ProtoHdr,
     pub peer: Address,
+    // The index of the network interface that this packet came in on
+    pub iface: usize,
     data: Direction<'a>,
     buffer_index: usize,
+    duplicate: bool,
 }
 
 impl<'a> Packet<'a> {
     const HDR_RESERVE: usize = plain_hdr::max_plain_hdr_len() + proto_hdr::max_proto_hdr_len();
 
     pub fn new_rx() -> Result<Self, Error> {
-        let (buffer_index, buffer) = BufferPool::alloc().ok_or(Error::NoSpace)?;
+        let (buffer_index, buffer) = BufferPool::alloc().ok_or_else(pool_exhausted)?;
         let buf_len = buffer.len();
         Ok(Self {
             plain: Default::default(),
             proto: Default::default(),
             buffer_index,
             peer: Address::default(),
+            iface: 0,
             data: Direction::Rx(ParseBuf::new(buffer, buf_len), RxState::Uninit),
+            duplicate: false,
         })
     }
 
     pub fn new_tx() -> Result<Self, Error> {
-        let (buffer_index, buffer) = BufferPool::alloc().ok_or(Error::NoSpace)?;
+        let (buffer_index, buffer) = BufferPool::alloc().ok_or_else(pool_exhausted)?;
         let buf_len = buffer.len();
 
         let mut wb = WriteBuf::new(buffer, buf_len);
@@ -112,7 +125,9 @@ impl<'a> Packet<'a> {
             proto: Default::default(),
             buffer_index,
             peer: Address::default(),
+            iface: 0,
             data: Direction::Tx(wb),
+            duplicate: false,
         };
         // Reliability on by default
         p.proto.set_reliable();
@@ -150,6 +165,21 @@ impl<'a> Packet<'a> {
         self.proto.proto_id = proto_id;
     }
 
+    /// The vendor of the protocol, this is the standard vendor unless the message is of
+    /// a vendor specific protocol
+    pub fn get_proto_vendor_id(&self) -> u16 {
+        self.proto.proto_vendor_id.unwrap_or(STANDARD_VENDOR_ID)
+    }
+
+    pub fn set_proto_vendor_id(&mut self, proto_vendor_id: u16) {
+        self.proto.set_vendor(proto_vendor_id);
+    }
+
+    /// The (vendor id, protocol id) of the message
+    pub fn get_proto(&self) -> (u16, u16) {
+        (self.get_proto_vendor_id(), self.get_proto_id())
+    }
+
     pub fn get_proto_opcode(&self) -> u8 {
         self.proto.proto_opcode
     }
@@ -158,6 +188,12 @@ impl<'a> Packet<'a> {
         self.proto.proto_opcode = proto_opcode;
     }
 
+    /// Whether this is an MRP standalone acknowledgement, that carries nothing else
+    pub fn is_standalone_ack(&self) -> bool {
+        self.get_proto_id() == PROTO_ID_SECURE_CHANNEL as u16
+            && self.get_proto_opcode() == OpCode::MRPStandAloneAck as u8
+    }
+
     pub fn set_reliable(&mut self) {
         self.proto.set_reliable()
     }
@@ -170,6 +206,15 @@ impl<'a> Packet<'a> {
         self.proto.is_reliable()
     }
 
+    /// Marks a received packet as one that we have already seen on the session
+    pub fn set_duplicate(&mut self) {
+        self.duplicate = true;
+    }
+
+    pub fn is_duplicate(&self) -> bool {
+        self.duplicate
+    }
+
     pub fn proto_decode(&mut self, peer_nodeid: u64, dec_key: Option<&[u8]>) -> Result<(), Error> {
         match &mut self.data {
             Direction::Rx(pb, state) => {
@@ -186,6 +231,19 @@ impl<'a> Packet<'a> {
         }
     }
 
+    /// Decrypts the message with `dec_key`, if it was encrypted with it
+    ///
+    /// The message is left as it was if that fails, so that another key can be tried.
+    /// Once it succeeds, the message is decoded with proto_decode() without a key.
+    pub fn try_decrypt(&mut self, peer_nodeid: u64, dec_key: &[u8]) -> Result<(), Error> {
+        match &mut self.data {
+            Direction::Rx(pb, RxState::PlainDecode) => {
+                proto_hdr::try_decrypt_in_place(self.plain.ctr, peer_nodeid, pb, dec_key)
+            }
+            _ => Err(Error::InvalidState),
+        }
+    }
+
     pub fn is_plain_hdr_decoded(&self) -> Result<bool, Error> {
         match &self.data {
             Direction::Rx(_, state) => match state {
@@ -220,3 +278,41 @@ impl<'a> Drop for Packet<'a> {
 }
 
 box_slab!(PacketPool, Packet<'static>, MAX_PACKET_POOL_SIZE);
+
+// The number of times that the pool ran out, for the statistics
+static POOL_EXHAUSTED: AtomicU64 = AtomicU64::new(0);
+
+fn pool_exhausted() -> Error {
+    POOL_EXHAUSTED.fetch_add(1, Ordering::Relaxed);
+    Error::PacketPoolExhaust
+}
+
+/// The number of times that no packet was left in the pool
+pub fn get_pool_exhausted() -> u64 {
+    POOL_EXHAUSTED.load(Ordering::Relaxed)
+}
+
+/// Sets how many packets the pool hands out, this can't be more than
+/// sys::MAX_PACKET_POOL_SIZE
+///
+/// The pool is shared by all the transports of the process, see Limits::packets.
+pub fn set_pool_size(size: usize) -> Result<(), Error> {
+    if size == 0 || size > MAX_PACKET_POOL_SIZE {
+        error!("The packet pool can't have {} packets", size);
+        return Err(Error::Invalid);
+    }
+    BufferPool::get().lock()?.size = size;
+    Ok(())
+}
+
+impl Packet<'static> {
+    /// Takes a packet for receiving from the pool
+    pub fn alloc_rx() -> Result<BoxSlab<PacketPool>, Error> {
+        Slab::<PacketPool>::new(Packet::new_rx()?).ok_or_else(pool_exhausted)
+    }
+
+    /// Takes a packet for sending from the pool
+    pub fn alloc_tx() -> Result<BoxSlab<PacketPool>, Error> {
+        Slab::<PacketPool>::new(Packet::new_tx()?).ok_or_else(pool_exhausted)
+    }
+}
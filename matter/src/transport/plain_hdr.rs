@@ -8,6 +8,8 @@ use log::info;
 pub enum SessionType {
     None,
     Encrypted,
+    // Encrypted with a group key, for a message sent to a group
+    Group,
 }
 
 impl Default for SessionType {
@@ -25,6 +27,10 @@ bitflags! {
     }
 }
 
+// The session type, in the security flags
+const SEC_FLAGS_SESS_TYPE_MASK: u8 = 0x03;
+const SEC_FLAGS_SESS_TYPE_GROUP: u8 = 0x01;
+
 // This is the unencrypted message
 #[derive(Debug, Default)]
 pub struct PlainHdr {
@@ -33,6 +39,7 @@ pub struct PlainHdr {
     pub sess_id: u16,
     pub ctr: u32,
     peer_nodeid: Option<u64>,
+    group_id: Option<u16>,
 }
 
 impl PlainHdr {
@@ -41,6 +48,11 @@ impl PlainHdr {
         self.peer_nodeid = Some(id);
     }
 
+    pub fn set_src_u64(&mut self, id: u64) {
+        self.flags |= MsgFlags::SRC_ADDR_PRESENT;
+        self.peer_nodeid = Some(id);
+    }
+
     pub fn get_src_u64(&self) -> Option<u64> {
         if self.flags.contains(MsgFlags::SRC_ADDR_PRESENT) {
             self.peer_nodeid
@@ -48,6 +60,22 @@ impl PlainHdr {
             None
         }
     }
+
+    pub fn set_dest_group(&mut self, group_id: u16) {
+        self.flags |= MsgFlags::DSIZ_GROUPCAST_NODEID;
+        self.group_id = Some(group_id);
+    }
+
+    pub fn get_dest_group(&self) -> Option<u16> {
+        self.group_id
+    }
+
+    pub fn get_sec_flags(&self) -> u8 {
+        match self.sess_type {
+            SessionType::Group => SEC_FLAGS_SESS_TYPE_GROUP,
+            _ => 0,
+        }
+    }
 }
 
 impl PlainHdr {
@@ -55,8 +83,10 @@ impl PlainHdr {
     pub fn decode(&mut self, msg: &mut ParseBuf) -> Result<(), Error> {
         self.flags = MsgFlags::from_bits(msg.le_u8()?).ok_or(Error::Invalid)?;
         self.sess_id = msg.le_u16()?;
-        let _sec_flags = msg.le_u8()?;
-        self.sess_type = if self.sess_id != 0 {
+        let sec_flags = msg.le_u8()?;
+        self.sess_type = if sec_flags & SEC_FLAGS_SESS_TYPE_MASK == SEC_FLAGS_SESS_TYPE_GROUP {
+            SessionType::Group
+        } else if self.sess_id != 0 {
             SessionType::Encrypted
         } else {
             SessionType::None
@@ -67,6 +97,13 @@ impl PlainHdr {
             self.peer_nodeid = Some(msg.le_u64()?);
         }
 
+        if self.flags.contains(MsgFlags::DSIZ_UNICAST_NODEID) {
+            // This can only be us
+            let _dest_nodeid = msg.le_u64()?;
+        } else if self.flags.contains(MsgFlags::DSIZ_GROUPCAST_NODEID) {
+            self.group_id = Some(msg.le_u16()?);
+        }
+
         info!(
             "[decode] flags: {:?}, session type: {:#?}, sess_id: {}, ctr: {}",
             self.flags, self.sess_type, self.sess_id, self.ctr
@@ -77,16 +114,23 @@ impl PlainHdr {
     pub fn encode(&mut self, resp_buf: &mut WriteBuf) -> Result<(), Error> {
         resp_buf.le_u8(self.flags.bits())?;
         resp_buf.le_u16(self.sess_id)?;
-        resp_buf.le_u8(0)?;
+        resp_buf.le_u8(self.get_sec_flags())?;
         resp_buf.le_u32(self.ctr)?;
         if let Some(d) = self.peer_nodeid {
             resp_buf.le_u64(d)?;
         }
+        if let Some(g) = self.group_id {
+            resp_buf.le_u16(g)?;
+        }
         Ok(())
     }
 
     pub fn is_encrypted(&self) -> bool {
-        self.sess_type == SessionType::Encrypted
+        self.sess_type != SessionType::None
+    }
+
+    pub fn is_group(&self) -> bool {
+        self.sess_type == SessionType::Group
     }
 }
 
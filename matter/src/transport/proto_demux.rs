@@ -1,11 +1,21 @@
+use std::collections::BTreeMap;
+
 use boxslab::BoxSlab;
+use log::error;
 
 use crate::error::*;
+use crate::secure_channel::common::{OpCode, PROTO_ID_SECURE_CHANNEL};
+use crate::secure_channel::status_report::{create_status_report, GeneralCode, StatusReport};
 
-use super::exchange::ExchangeCtx;
+use super::exchange::{Exchange, ExchangeCtx};
 use super::packet::PacketPool;
+use super::queue::WorkQ;
+
+/// The vendor id of the protocols that the Matter specification defines
+pub const STANDARD_VENDOR_ID: u16 = 0x0000;
 
-const MAX_PROTOCOLS: usize = 4;
+// The handlers are keyed by (vendor id, protocol id)
+type ProtoKey = (u16, u16);
 
 #[derive(PartialEq)]
 pub enum ResponseRequired {
@@ -13,7 +23,7 @@ pub enum ResponseRequired {
     No,
 }
 pub struct ProtoDemux {
-    proto_id_handlers: [Option<Box<dyn HandleProto>>; MAX_PROTOCOLS],
+    proto_id_handlers: BTreeMap<ProtoKey, Box<dyn HandleProto>>,
 }
 
 /// This is the context in which a receive packet is being processed
@@ -24,6 +34,8 @@ pub struct ProtoCtx<'a> {
     pub rx: BoxSlab<PacketPool>,
     /// This is the transmit buffer for this transaction
     pub tx: BoxSlab<PacketPool>,
+    /// This is the work queue of the transport that received the packet
+    pub work_q: &'a WorkQ,
 }
 
 impl<'a> ProtoCtx<'a> {
@@ -31,8 +43,14 @@ impl<'a> ProtoCtx<'a> {
         exch_ctx: ExchangeCtx<'a>,
         rx: BoxSlab<PacketPool>,
         tx: BoxSlab<PacketPool>,
+        work_q: &'a WorkQ,
     ) -> Self {
-        Self { exch_ctx, rx, tx }
+        Self {
+            exch_ctx,
+            rx,
+            tx,
+            work_q,
+        }
     }
 }
 
@@ -41,9 +59,18 @@ pub trait HandleProto {
 
     fn get_proto_id(&self) -> usize;
 
+    /// The vendor of the protocol, only the vendor specific protocols override this
+    fn get_vendor_id(&self) -> u16 {
+        STANDARD_VENDOR_ID
+    }
+
     fn handle_session_event(&self) -> Result<(), Error> {
         Ok(())
     }
+
+    /// The peer didn't respond in time on an exchange that this protocol initiated,
+    /// the exchange is closed right after this
+    fn handle_response_timeout(&mut self, _exch: &mut Exchange) {}
 }
 
 impl Default for ProtoDemux {
@@ -55,24 +82,62 @@ impl Default for ProtoDemux {
 impl ProtoDemux {
     pub fn new() -> ProtoDemux {
         ProtoDemux {
-            proto_id_handlers: [None, None, None, None],
+            proto_id_handlers: BTreeMap::new(),
         }
     }
 
+    /// Registers the handler of a protocol, replacing any previous handler of the
+    /// same protocol
     pub fn register(&mut self, proto_id_handle: Box<dyn HandleProto>) -> Result<(), Error> {
-        let proto_id = proto_id_handle.get_proto_id();
-        self.proto_id_handlers[proto_id] = Some(proto_id_handle);
+        let key = (
+            proto_id_handle.get_vendor_id(),
+            proto_id_handle.get_proto_id() as u16,
+        );
+        self.proto_id_handlers.insert(key, proto_id_handle);
         Ok(())
     }
 
+    /// Tells the handler of protocol `key` that the peer didn't respond in time on
+    /// `exch`
+    pub fn handle_response_timeout(&mut self, key: ProtoKey, exch: &mut Exchange) {
+        if let Some(handler) = self.proto_id_handlers.get_mut(&key) {
+            handler.handle_response_timeout(exch);
+        }
+    }
+
     pub fn handle(&mut self, proto_ctx: &mut ProtoCtx) -> Result<ResponseRequired, Error> {
-        let proto_id = proto_ctx.rx.get_proto_id() as usize;
-        if proto_id >= MAX_PROTOCOLS {
-            return Err(Error::Invalid);
+        let sc_key = (STANDARD_VENDOR_ID, PROTO_ID_SECURE_CHANNEL as u16);
+        let mut key = (
+            proto_ctx.rx.get_proto_vendor_id(),
+            proto_ctx.rx.get_proto_id(),
+        );
+        if key == sc_key && proto_ctx.rx.get_proto_opcode() == OpCode::StatusReport as u8 {
+            // A status report goes to the protocol that it is about
+            if let Ok(report) = StatusReport::parse(proto_ctx.rx.as_borrow_slice()) {
+                let about = ((report.proto_id >> 16) as u16, report.proto_id as u16);
+                if self.proto_id_handlers.contains_key(&about) {
+                    key = about;
+                }
+            }
+        }
+        match self.proto_id_handlers.get_mut(&key) {
+            Some(handler) => handler.handle_proto_id(proto_ctx),
+            // Nobody answers for the secure channel, that carries the status reports
+            // and the acknowledgements
+            None if key == sc_key => Err(Error::NoHandler),
+            None => {
+                error!("No handler for protocol {:04x}:{:04x}", key.0, key.1);
+                let proto_id = (key.0 as u32) << 16 | key.1 as u32;
+                create_status_report(
+                    &mut proto_ctx.tx,
+                    GeneralCode::Unsupported,
+                    proto_id,
+                    0,
+                    None,
+                )?;
+                proto_ctx.exch_ctx.exch.close();
+                Ok(ResponseRequired::Yes)
+            }
         }
-        return self.proto_id_handlers[proto_id]
-            .as_mut()
-            .ok_or(Error::NoHandler)?
-            .handle_proto_id(proto_ctx);
     }
 }
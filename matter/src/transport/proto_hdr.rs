@@ -35,7 +35,7 @@ impl ProtoHdr {
     }
 
     pub fn set_vendor(&mut self, proto_vendor_id: u16) {
-        self.exch_flags |= ExchFlags::RELIABLE;
+        self.exch_flags |= ExchFlags::VENDOR;
         self.proto_vendor_id = Some(proto_vendor_id);
     }
 
@@ -146,11 +146,15 @@ impl fmt::Display for ProtoHdr {
     }
 }
 
-fn get_iv(recvd_ctr: u32, peer_nodeid: u64, iv: &mut [u8]) -> Result<(), Error> {
-    // The IV is the source address (64-bit) followed by the message counter (32-bit)
+// The security flags are the fourth byte of the plain header
+const SEC_FLAGS_OFFSET: usize = 3;
+
+fn get_iv(plain_hdr: &[u8], recvd_ctr: u32, peer_nodeid: u64, iv: &mut [u8]) -> Result<(), Error> {
+    // The IV is the security flags, followed by the message counter (32-bit) and the
+    // source address (64-bit)
+    let sec_flags = *plain_hdr.get(SEC_FLAGS_OFFSET).ok_or(Error::InvalidAAD)?;
     let mut write_buf = WriteBuf::new(iv, iv.len());
-    // For some reason, this is 0 in the 'bypass' mode
-    write_buf.le_u8(0)?;
+    write_buf.le_u8(sec_flags)?;
     write_buf.le_u32(recvd_ctr)?;
     write_buf.le_u64(peer_nodeid)?;
     Ok(())
@@ -165,7 +169,7 @@ pub fn encrypt_in_place(
 ) -> Result<(), Error> {
     // IV
     let mut iv = [0_u8; crypto::AEAD_NONCE_LEN_BYTES];
-    get_iv(send_ctr, peer_nodeid, &mut iv)?;
+    get_iv(plain_hdr, send_ctr, peer_nodeid, &mut iv)?;
 
     // Cipher Text
     let tag_space = [0u8; crypto::AEAD_MIC_LEN_BYTES];
@@ -191,21 +195,16 @@ fn decrypt_in_place(
     key: &[u8],
 ) -> Result<(), Error> {
     // AAD:
-    //    the unencrypted header of this packet
-    let mut aad = [0_u8; crypto::AEAD_AAD_LEN_BYTES];
+    //    the unencrypted header of this packet, which is variable sized
+    let mut aad = [0_u8; plain_hdr::max_plain_hdr_len()];
     let parsed_slice = parsebuf.parsed_as_slice();
-    if parsed_slice.len() == aad.len() {
-        // The plain_header is variable sized in length, I wonder if the AAD is fixed at 8, or the variable size.
-        // If so, we need to handle it cleanly here.
-        aad.copy_from_slice(parsed_slice);
-    } else {
-        return Err(Error::InvalidAAD);
-    }
+    let aad = aad.get_mut(..parsed_slice.len()).ok_or(Error::InvalidAAD)?;
+    aad.copy_from_slice(parsed_slice);
 
     // IV:
     //   the specific way for creating IV is in get_iv
     let mut iv = [0_u8; crypto::AEAD_NONCE_LEN_BYTES];
-    get_iv(recvd_ctr, peer_nodeid, &mut iv)?;
+    get_iv(aad, recvd_ctr, peer_nodeid, &mut iv)?;
 
     let cipher_text = parsebuf.as_borrow_slice();
     //println!("AAD: {:x?}", aad);
@@ -213,12 +212,36 @@ fn decrypt_in_place(
     //println!("IV: {:x?}", iv);
     //println!("Key: {:x?}", key);
 
-    crypto::decrypt_in_place(key, &iv, &aad, cipher_text)?;
+    crypto::decrypt_in_place(key, &iv, aad, cipher_text)?;
     // println!("Plain Text: {:x?}", cipher_text);
     parsebuf.tail(crypto::AEAD_MIC_LEN_BYTES)?;
     Ok(())
 }
 
+/// Decrypts the message with `key`, the message is left as it was if that fails
+///
+/// This is for messages that could have been encrypted with any of a few keys, like
+/// the group messages.
+pub fn try_decrypt_in_place(
+    recvd_ctr: u32,
+    peer_nodeid: u64,
+    parsebuf: &mut ParseBuf,
+    key: &[u8],
+) -> Result<(), Error> {
+    let aad = parsebuf.parsed_as_slice().to_vec();
+    let mut text = parsebuf.as_borrow_slice().to_vec();
+    if text.len() < crypto::AEAD_MIC_LEN_BYTES {
+        return Err(Error::Invalid);
+    }
+    let mut iv = [0_u8; crypto::AEAD_NONCE_LEN_BYTES];
+    get_iv(&aad, recvd_ctr, peer_nodeid, &mut iv)?;
+    crypto::decrypt_in_place(key, &iv, &aad, &mut text)?;
+    let len = text.len() - crypto::AEAD_MIC_LEN_BYTES;
+    parsebuf.as_borrow_slice()[..len].copy_from_slice(&text[..len]);
+    parsebuf.tail(crypto::AEAD_MIC_LEN_BYTES)?;
+    Ok(())
+}
+
 pub const fn max_proto_hdr_len() -> usize {
     // exchange flags
     1 +
@@ -1,16 +1,91 @@
-use std::sync::Once;
+use std::{any::Any, fmt, net::IpAddr};
 
-use async_channel::{bounded, Receiver, Sender};
+use async_channel::{bounded, unbounded, Receiver, Sender};
 
 use crate::error::Error;
 
-use super::session::CloneData;
+use super::{network::Address, session::CloneData};
+
+/// A message that we initiate on a new exchange
+#[derive(Debug)]
+pub struct TxMsg {
+    /// Our (local) id of the session to send the message on
+    pub sess_id: u16,
+    /// The vendor of the protocol, for a vendor specific protocol
+    pub proto_vendor_id: Option<u16>,
+    pub proto_id: u16,
+    pub proto_opcode: u8,
+    pub payload: Vec<u8>,
+    pub resp: TxResp,
+}
+
+pub type RespSender = Sender<Result<RxMsg, Error>>;
+
+/// What becomes of the messages that the peer sends back on the exchange
+pub enum TxResp {
+    /// The first one is delivered here, and the exchange is then closed
+    Sender(RespSender),
+    /// They go to the handler of the protocol, with this as the exchange data. The
+    /// handler closes the exchange once it is done with it.
+    Handler(Box<dyn Any + Send>),
+}
+
+impl fmt::Debug for TxResp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TxResp::Sender(_) => write!(f, "Sender"),
+            TxResp::Handler(_) => write!(f, "Handler"),
+        }
+    }
+}
+
+/// The peer's response to a TxMsg
+#[derive(Debug, PartialEq)]
+pub struct RxMsg {
+    pub proto_vendor_id: Option<u16>,
+    pub proto_id: u16,
+    pub proto_opcode: u8,
+    pub payload: Vec<u8>,
+}
+
+/// A message that we send to a group, on a new exchange
+#[derive(Debug)]
+pub struct GroupTxMsg {
+    pub fab_idx: u8,
+    /// The id of the fabric, that the group's multicast address is derived from
+    pub fabric_id: u64,
+    /// Our node id on the fabric
+    pub local_nodeid: u64,
+    pub group_id: u16,
+    pub proto_id: u16,
+    pub proto_opcode: u8,
+    pub payload: Vec<u8>,
+}
+
+/// A message that we send to a peer outside of any secure session, on a new exchange
+#[derive(Debug)]
+pub struct UnsecuredTxMsg {
+    pub peer: Address,
+    pub proto_id: u16,
+    pub proto_opcode: u8,
+    pub payload: Vec<u8>,
+    /// None if the peer neither acknowledges nor responds to the message. Otherwise
+    /// the message is sent reliably, like a TxMsg.
+    pub resp: Option<TxResp>,
+}
 
 #[derive(Debug)]
 pub enum Msg {
-    Tx(),
+    Tx(TxMsg),
+    GroupTx(GroupTxMsg),
+    UnsecuredTx(UnsecuredTxMsg),
     Rx(),
     NewSession(CloneData),
+    /// Reserves a local session id, for a session that we are establishing
+    ReserveSessId(Sender<u16>),
+    /// Starts receiving the messages to the multicast group, see group::join_group()
+    JoinMulticast(IpAddr),
+    LeaveMulticast(IpAddr),
 }
 
 #[derive(Clone)]
@@ -18,33 +93,90 @@ pub struct WorkQ {
     tx: Sender<Msg>,
 }
 
-static mut G_WQ: Option<WorkQ> = None;
-static INIT: Once = Once::new();
-
 impl WorkQ {
-    pub fn init() -> Result<Receiver<Msg>, Error> {
-        let (tx, rx) = bounded::<Msg>(3);
-        WorkQ::configure(tx);
-        Ok(rx)
+    /// Creates a work queue, along with the receiver that the transport takes the
+    /// work from
+    ///
+    /// Every transport has its own queue. The protocol handlers get to the queue of
+    /// the transport that they run in through ProtoCtx::work_q.
+    pub fn new() -> (WorkQ, Receiver<Msg>) {
+        // The transport sends to itself on this queue, it must never block on it
+        let (tx, rx) = unbounded::<Msg>();
+        (WorkQ { tx }, rx)
     }
 
-    fn configure(tx: Sender<Msg>) {
-        unsafe {
-            INIT.call_once(|| {
-                G_WQ = Some(WorkQ { tx });
-            });
-        }
+    /// Queues up the message without waiting, the queue is unbounded so this only
+    /// fails if the transport is gone
+    pub fn sync_send(&self, msg: Msg) -> Result<(), Error> {
+        Ok(self.tx.try_send(msg)?)
     }
 
-    pub fn get() -> Result<WorkQ, Error> {
-        unsafe { G_WQ.as_ref().cloned().ok_or(Error::Invalid) }
+    pub async fn send(&self, msg: Msg) -> Result<(), Error> {
+        self.tx.send(msg).await.map_err(|e| e.into())
     }
 
-    pub fn sync_send(&self, msg: Msg) -> Result<(), Error> {
-        smol::block_on(self.send(msg))
+    /// Reserves a local session id, that no other session gets till the id wraps
+    /// around
+    ///
+    /// The initiators of the session establishment protocols send this id to the
+    /// peer, before the session is added.
+    pub async fn reserve_sess_id(&self) -> Result<u16, Error> {
+        let (id_tx, id_rx) = bounded(1);
+        self.send(Msg::ReserveSessId(id_tx)).await?;
+        id_rx.recv().await.map_err(|_| Error::NoSession)
     }
 
-    pub async fn send(&self, msg: Msg) -> Result<(), Error> {
-        self.tx.send(msg).await.map_err(|e| e.into())
+    /// Sends a message to the peer of a session on a new exchange, and waits for its
+    /// response
+    ///
+    /// The message is sent reliably, this fails with Error::NoExchange if the peer
+    /// never acknowledges it, or if the exchange is closed without a response. It
+    /// fails with Error::Timeout if the peer doesn't respond within the transport's
+    /// IdleTimeouts::response.
+    pub async fn request(
+        &self,
+        sess_id: u16,
+        proto_id: u16,
+        proto_opcode: u8,
+        payload: &[u8],
+    ) -> Result<RxMsg, Error> {
+        self.request_with_vendor(sess_id, None, proto_id, proto_opcode, payload)
+            .await
+    }
+
+    /// The same as request(), for a message of the vendor specific protocol
+    /// (`proto_vendor_id`, `proto_id`)
+    pub async fn vendor_request(
+        &self,
+        sess_id: u16,
+        proto_vendor_id: u16,
+        proto_id: u16,
+        proto_opcode: u8,
+        payload: &[u8],
+    ) -> Result<RxMsg, Error> {
+        let vendor = Some(proto_vendor_id);
+        self.request_with_vendor(sess_id, vendor, proto_id, proto_opcode, payload)
+            .await
+    }
+
+    async fn request_with_vendor(
+        &self,
+        sess_id: u16,
+        proto_vendor_id: Option<u16>,
+        proto_id: u16,
+        proto_opcode: u8,
+        payload: &[u8],
+    ) -> Result<RxMsg, Error> {
+        let (resp_tx, resp_rx) = bounded(1);
+        self.send(Msg::Tx(TxMsg {
+            sess_id,
+            proto_vendor_id,
+            proto_id,
+            proto_opcode,
+            payload: payload.to_vec(),
+            resp: TxResp::Sender(resp_tx),
+        }))
+        .await?;
+        resp_rx.recv().await.map_err(|_| Error::NoExchange)?
     }
 }
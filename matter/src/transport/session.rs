@@ -1,33 +1,47 @@
 use core::fmt;
 use std::{
     any::Any,
+    net::IpAddr,
     ops::{Deref, DerefMut},
-    time::SystemTime,
+    task::{Context, Poll},
+    time::{Duration, Instant},
 };
 
 use crate::{
     error::*,
+    group_keys::GroupKey,
+    limits::Limits,
     transport::{plain_hdr, proto_hdr},
     utils::writebuf::WriteBuf,
 };
-use boxslab::{BoxSlab, Slab};
+use boxslab::BoxSlab;
 use colored::*;
 use log::{info, trace};
 use rand::Rng;
+use smol::future;
 
 use super::{
+    clock::{self, Timer},
+    mrp::{MrpParams, MRP_ACTIVE_THRESHOLD},
+    msg_ctr::GlobalCtrs,
     network::{Address, NetworkInterface},
     packet::{Packet, PacketPool},
+    stats::StatsHandle,
 };
 
 const MATTER_AES128_KEY_SIZE: usize = 16;
 
+// How long to wait before trying again, when the packet pool is empty
+const ALLOC_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
 #[derive(Debug, PartialEq, Copy, Clone)]
 pub enum SessionMode {
     // The Case session will capture the local fabric index
     Case(u8),
     Pase,
     PlainText,
+    // A message to a group captures the local fabric index and the group id
+    Group(u8, u16),
 }
 
 impl Default for SessionMode {
@@ -36,9 +50,165 @@ impl Default for SessionMode {
     }
 }
 
+/// How long the sessions of each mode may go without hearing from their peer,
+/// before they expire
+///
+/// With None, the sessions of that mode stay till they are evicted to make room for
+/// others.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct IdleTimeouts {
+    pub plain_text: Option<Duration>,
+    pub pase: Option<Duration>,
+    pub case: Option<Duration>,
+    /// How long an exchange may stay open with nothing sent or received on it,
+    /// before it is closed
+    pub exchange: Option<Duration>,
+    /// How long we wait for the peer to respond on an exchange that we initiated,
+    /// before it is closed with Error::Timeout
+    pub response: Option<Duration>,
+}
+
+impl Default for IdleTimeouts {
+    fn default() -> Self {
+        Self {
+            // Nothing is left pending on an unsecured session for long, other than
+            // a PASE or CASE handshake that the peer gave up on
+            plain_text: Some(Duration::from_secs(60)),
+            pase: None,
+            case: None,
+            exchange: Some(Duration::from_secs(30)),
+            response: Some(Duration::from_secs(30)),
+        }
+    }
+}
+
+impl IdleTimeouts {
+    pub fn get(&self, mode: SessionMode) -> Option<Duration> {
+        match mode {
+            SessionMode::PlainText => self.plain_text,
+            SessionMode::Pase => self.pase,
+            SessionMode::Case(_) => self.case,
+            // These live with the group keys, not with the peers
+            SessionMode::Group(_, _) => None,
+        }
+    }
+}
+
+/// A session that was closed, because its peer was quiet for too long
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct ExpiredSession {
+    pub local_sess_id: u16,
+    pub peer_nodeid: Option<u64>,
+    pub peer_addr: Address,
+    pub mode: SessionMode,
+}
+
+// The number of message counters behind the max counter that we track
+const MSG_CTR_WINDOW_SIZE: u32 = 32;
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum CtrWindowMode {
+    /// The peer's counter only ever moves forward (secure unicast sessions), so anything
+    /// that falls behind the window is considered a replay
+    NoRollover,
+    /// The peer's counter may roll over or restart (unsecured sessions and group peers),
+    /// so a counter that falls behind the window re-synchronises the window instead
+    Rollover,
+}
+
+/// The receive side state of a peer's message counter
+///
+/// This keeps the largest counter received so far, and a bitmap of which of the
+/// MSG_CTR_WINDOW_SIZE counters preceding it have been received, so that we can
+/// detect duplicates even if messages arrive out of order. The same state is used
+/// to track the counters of group peers, which start at the first message that we
+/// receive from them, see `RxCtrState::new_synced()`.
+#[derive(Debug, Clone)]
+pub struct RxCtrState {
+    // The largest counter received so far, None till we receive the first message
+    max_ctr: Option<u32>,
+    // Bit n is set if counter (max_ctr - n - 1) has been received
+    ctr_bitmap: u32,
+    mode: CtrWindowMode,
+}
+
+impl RxCtrState {
+    /// The first counter that we receive is trusted as is
+    pub fn new(mode: CtrWindowMode) -> Self {
+        Self {
+            max_ctr: None,
+            ctr_bitmap: 0,
+            mode,
+        }
+    }
+
+    /// We already know the peer's counter, anything at or before this is a duplicate
+    pub fn new_synced(max_ctr: u32, mode: CtrWindowMode) -> Self {
+        Self {
+            max_ctr: Some(max_ctr),
+            ctr_bitmap: u32::MAX,
+            mode,
+        }
+    }
+
+    pub fn get_max_ctr(&self) -> Option<u32> {
+        self.max_ctr
+    }
+
+    /// Records the received message counter, returns true if it is a duplicate
+    pub fn recv(&mut self, ctr: u32) -> bool {
+        let max_ctr = match self.max_ctr {
+            Some(m) => m,
+            None => {
+                self.max_ctr = Some(ctr);
+                self.ctr_bitmap = 0;
+                return false;
+            }
+        };
+
+        let is_ahead = match self.mode {
+            CtrWindowMode::NoRollover => ctr > max_ctr,
+            // Anything in the 2^31 counters that follow the max counter is ahead of it
+            CtrWindowMode::Rollover => ctr != max_ctr && ctr.wrapping_sub(max_ctr) < (1 << 31),
+        };
+
+        if is_ahead {
+            // Slide the window forward, the old max counter is also part of the window now
+            let shift = ctr.wrapping_sub(max_ctr);
+            self.ctr_bitmap = if shift > MSG_CTR_WINDOW_SIZE {
+                0
+            } else {
+                self.ctr_bitmap.checked_shl(shift).unwrap_or(0) | (1 << (shift - 1))
+            };
+            self.max_ctr = Some(ctr);
+            return false;
+        }
+
+        let offset = max_ctr.wrapping_sub(ctr);
+        if offset == 0 {
+            true
+        } else if offset <= MSG_CTR_WINDOW_SIZE {
+            let bit = 1 << (offset - 1);
+            let is_duplicate = (self.ctr_bitmap & bit) != 0;
+            self.ctr_bitmap |= bit;
+            is_duplicate
+        } else if self.mode == CtrWindowMode::Rollover {
+            // The peer has moved on, start a fresh window
+            self.max_ctr = Some(ctr);
+            self.ctr_bitmap = 0;
+            false
+        } else {
+            // Too old to be tracked, this must be a replay
+            true
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Session {
     peer_addr: Address,
+    // The index of the network interface through which the peer is reached
+    iface: usize,
     local_nodeid: u64,
     peer_nodeid: Option<u64>,
     // I find the session initiator/responder role getting confused with exchange initiator/responder
@@ -50,8 +220,13 @@ pub struct Session {
     peer_sess_id: u16,
     msg_ctr: u32,
     mode: SessionMode,
+    rx_ctr_state: RxCtrState,
+    // The peer's MRP parameters, as negotiated during session establishment
+    mrp_params: MrpParams,
     data: Option<Box<dyn Any>>,
-    last_use: SystemTime,
+    last_use: Instant,
+    // The last time we heard from the peer, this decides if the peer is active or idle
+    last_rx: Instant,
 }
 
 #[derive(Debug)]
@@ -65,6 +240,8 @@ pub struct CloneData {
     peer_nodeid: u64,
     peer_addr: Address,
     mode: SessionMode,
+    pub mrp_params: MrpParams,
+    pub iface: usize,
 }
 impl CloneData {
     pub fn new(
@@ -85,6 +262,8 @@ impl CloneData {
             peer_sess_id,
             local_sess_id,
             mode,
+            mrp_params: MrpParams::default(),
+            iface: 0,
         }
     }
 }
@@ -95,6 +274,7 @@ impl Session {
     pub fn new(peer_addr: Address, peer_nodeid: Option<u64>) -> Session {
         Session {
             peer_addr,
+            iface: 0,
             local_nodeid: 0,
             peer_nodeid,
             dec_key: [0; MATTER_AES128_KEY_SIZE],
@@ -104,15 +284,39 @@ impl Session {
             local_sess_id: 0,
             msg_ctr: rand::thread_rng().gen_range(0..MATTER_MSG_CTR_RANGE),
             mode: SessionMode::PlainText,
+            rx_ctr_state: RxCtrState::new(CtrWindowMode::Rollover),
+            mrp_params: MrpParams::default(),
             data: None,
-            last_use: SystemTime::now(),
+            last_use: clock::now(),
+            last_rx: clock::now(),
         }
     }
 
+    /// A session for the messages from a peer to a group, or from us to a group
+    ///
+    /// The local node id is our node id on the fabric, and the key is the operational
+    /// key of the group.
+    pub fn new_group(
+        peer_addr: Address,
+        peer_nodeid: Option<u64>,
+        local_nodeid: u64,
+        key: &GroupKey,
+    ) -> Session {
+        let mut session = Session::new(peer_addr, peer_nodeid);
+        session.local_nodeid = local_nodeid;
+        session.dec_key = key.op_key;
+        session.enc_key = key.op_key;
+        session.peer_sess_id = key.sess_id;
+        session.local_sess_id = key.sess_id;
+        session.mode = SessionMode::Group(key.fab_idx, key.group_id);
+        session
+    }
+
     // A new encrypted session always clones from a previous 'new' session
     pub fn clone(clone_from: &CloneData) -> Session {
         Session {
             peer_addr: clone_from.peer_addr,
+            iface: clone_from.iface,
             local_nodeid: clone_from.local_nodeid,
             peer_nodeid: Some(clone_from.peer_nodeid),
             dec_key: clone_from.dec_key,
@@ -122,8 +326,11 @@ impl Session {
             peer_sess_id: clone_from.peer_sess_id,
             msg_ctr: rand::thread_rng().gen_range(0..MATTER_MSG_CTR_RANGE),
             mode: clone_from.mode,
+            rx_ctr_state: RxCtrState::new(CtrWindowMode::NoRollover),
+            mrp_params: clone_from.mrp_params,
             data: None,
-            last_use: SystemTime::now(),
+            last_use: clock::now(),
+            last_rx: clock::now(),
         }
     }
 
@@ -160,9 +367,13 @@ impl Session {
         self.peer_addr
     }
 
+    pub fn get_iface(&self) -> usize {
+        self.iface
+    }
+
     pub fn is_encrypted(&self) -> bool {
         match self.mode {
-            SessionMode::Case(_) | SessionMode::Pase => true,
+            SessionMode::Case(_) | SessionMode::Pase | SessionMode::Group(_, _) => true,
             SessionMode::PlainText => false,
         }
     }
@@ -173,7 +384,7 @@ impl Session {
 
     pub fn get_local_fabric_idx(&self) -> Option<u8> {
         match self.mode {
-            SessionMode::Case(a) => Some(a),
+            SessionMode::Case(a) | SessionMode::Group(a, _) => Some(a),
             _ => None,
         }
     }
@@ -182,22 +393,61 @@ impl Session {
         self.mode
     }
 
-    pub fn get_msg_ctr(&mut self) -> u32 {
-        let ctr = self.msg_ctr;
-        self.msg_ctr += 1;
-        ctr
+    pub fn get_msg_ctr(&mut self) -> Result<u32, Error> {
+        match self.mode {
+            // These counters are shared with the other sessions of their kind
+            SessionMode::PlainText => GlobalCtrs::get()?.lock()?.unencrypted.get_next(),
+            SessionMode::Group(_, _) => GlobalCtrs::get()?.lock()?.group_data.get_next(),
+            SessionMode::Case(_) | SessionMode::Pase => {
+                let ctr = self.msg_ctr;
+                self.msg_ctr = self.msg_ctr.wrapping_add(1);
+                Ok(ctr)
+            }
+        }
+    }
+
+    pub fn set_mrp_params(&mut self, mrp_params: MrpParams) {
+        self.mrp_params = mrp_params;
+    }
+
+    pub fn get_mrp_params(&self) -> MrpParams {
+        self.mrp_params
+    }
+
+    /// The time since we last heard from the peer
+    pub fn get_idle_time(&self) -> Duration {
+        clock::now().saturating_duration_since(self.last_rx)
+    }
+
+    // The time left till the session expires, if it does
+    fn get_time_to_expiry(&self, timeouts: &IdleTimeouts) -> Option<Duration> {
+        let timeout = timeouts.get(self.mode)?;
+        Some(timeout.saturating_sub(self.get_idle_time()))
+    }
+
+    /// The interval after which an unacknowledged message to the peer must be retransmitted
+    pub fn get_mrp_interval(&self) -> Duration {
+        if self.get_idle_time() < MRP_ACTIVE_THRESHOLD {
+            self.mrp_params.active_interval()
+        } else {
+            self.mrp_params.idle_interval()
+        }
     }
 
     pub fn get_dec_key(&self) -> Option<&[u8]> {
         match self.mode {
-            SessionMode::Case(_) | SessionMode::Pase => Some(&self.dec_key),
+            SessionMode::Case(_) | SessionMode::Pase | SessionMode::Group(_, _) => {
+                Some(&self.dec_key)
+            }
             SessionMode::PlainText => None,
         }
     }
 
     pub fn get_enc_key(&self) -> Option<&[u8]> {
         match self.mode {
-            SessionMode::Case(_) | SessionMode::Pase => Some(&self.enc_key),
+            SessionMode::Case(_) | SessionMode::Pase | SessionMode::Group(_, _) => {
+                Some(&self.enc_key)
+            }
             SessionMode::PlainText => None,
         }
     }
@@ -207,23 +457,36 @@ impl Session {
     }
 
     pub fn recv(&mut self, proto_rx: &mut Packet) -> Result<(), Error> {
-        self.last_use = SystemTime::now();
-        proto_rx.proto_decode(self.peer_nodeid.unwrap_or_default(), self.get_dec_key())
+        self.last_use = clock::now();
+        proto_rx.proto_decode(self.peer_nodeid.unwrap_or_default(), self.get_dec_key())?;
+        self.last_rx = self.last_use;
+        // Only authenticated messages may move the counter window
+        if self.rx_ctr_state.recv(proto_rx.plain.ctr) {
+            info!("Duplicate message with ctr {}", proto_rx.plain.ctr);
+            proto_rx.set_duplicate();
+        }
+        Ok(())
     }
 
     pub fn pre_send(&mut self, proto_tx: &mut Packet) -> Result<(), Error> {
         proto_tx.plain.sess_id = self.get_peer_sess_id();
-        proto_tx.plain.ctr = self.get_msg_ctr();
-        if self.is_encrypted() {
+        proto_tx.plain.ctr = self.get_msg_ctr()?;
+        if let SessionMode::Group(_, group_id) = self.mode {
+            proto_tx.plain.sess_type = plain_hdr::SessionType::Group;
+            // The group members need to know who it is from, to pick the nonce
+            proto_tx.plain.set_src_u64(self.local_nodeid);
+            proto_tx.plain.set_dest_group(group_id);
+        } else if self.is_encrypted() {
             proto_tx.plain.sess_type = plain_hdr::SessionType::Encrypted;
         }
         Ok(())
     }
 
     // TODO: Most of this can now be moved into the 'Packet' module
-    fn do_send(&mut self, proto_tx: &mut Packet) -> Result<(), Error> {
-        self.last_use = SystemTime::now();
+    pub fn do_send(&mut self, proto_tx: &mut Packet) -> Result<(), Error> {
+        self.last_use = clock::now();
         proto_tx.peer = self.peer_addr;
+        proto_tx.iface = self.iface;
 
         // Generate encrypted header
         let mut tmp_buf: [u8; proto_hdr::max_proto_hdr_len()] = [0; proto_hdr::max_proto_hdr_len()];
@@ -277,11 +540,12 @@ impl fmt::Display for Session {
     }
 }
 
-pub const MAX_SESSIONS: usize = 16;
 pub struct SessionMgr {
     next_sess_id: u16,
-    sessions: [Option<Session>; MAX_SESSIONS],
-    network: Option<Box<dyn NetworkInterface>>,
+    // As many slots as the limit on the sessions
+    sessions: Vec<Option<Session>>,
+    networks: Vec<Box<dyn NetworkInterface>>,
+    stats: StatsHandle,
 }
 
 impl Default for SessionMgr {
@@ -292,27 +556,86 @@ impl Default for SessionMgr {
 
 impl SessionMgr {
     pub fn new() -> SessionMgr {
+        SessionMgr::new_with_limits(&Limits::default())
+    }
+
+    pub fn new_with_limits(limits: &Limits) -> SessionMgr {
         SessionMgr {
-            sessions: Default::default(),
+            sessions: (0..limits.sessions).map(|_| None).collect(),
             next_sess_id: 1,
-            network: None,
+            networks: Vec::new(),
+            stats: StatsHandle::new(),
         }
     }
 
+    /// The statistics of the sessions, and of the messages on them
+    pub fn get_stats_handle(&self) -> StatsHandle {
+        self.stats.clone()
+    }
+
+    /// The number of sessions that can be held at a time
+    pub fn capacity(&self) -> usize {
+        self.sessions.len()
+    }
+
+    /// Messages are received on all the network interfaces, sessions send on the
+    /// interface that their peer reached us on
     pub fn add_network_interface(
         &mut self,
         interface: Box<dyn NetworkInterface>,
     ) -> Result<(), Error> {
-        if self.network.is_none() {
-            self.network = Some(interface);
-            Ok(())
-        } else {
-            Err(Error::Invalid)
+        self.networks.push(interface);
+        Ok(())
+    }
+
+    /// Starts receiving the messages to the multicast group, on all the network
+    /// interfaces
+    pub fn join_multicast(&self, group: IpAddr) -> Result<(), Error> {
+        for network in &self.networks {
+            network.join_multicast(group)?;
+        }
+        Ok(())
+    }
+
+    pub fn leave_multicast(&self, group: IpAddr) -> Result<(), Error> {
+        for network in &self.networks {
+            network.leave_multicast(group)?;
+        }
+        Ok(())
+    }
+
+    /// Drops all the network interfaces, releasing the sockets
+    pub fn remove_network_interfaces(&mut self) {
+        self.networks.clear();
+    }
+
+    fn get_network(&self, iface: usize) -> Result<&dyn NetworkInterface, Error> {
+        self.networks
+            .get(iface)
+            .map(|n| n.as_ref())
+            .ok_or(Error::NoNetworkInterface)
+    }
+
+    fn poll_recv(
+        networks: &[Box<dyn NetworkInterface>],
+        cx: &mut Context<'_>,
+        in_buf: &mut [u8],
+    ) -> Poll<Result<(usize, Address, usize), Error>> {
+        for (iface, network) in networks.iter().enumerate() {
+            if let Poll::Ready(result) = network.poll_recv(cx, in_buf) {
+                return Poll::Ready(result.map(|(len, src)| (len, src, iface)));
+            }
         }
+        Poll::Pending
     }
 
     pub fn mut_by_index(&mut self, index: usize) -> Option<&mut Session> {
-        self.sessions[index].as_mut()
+        self.sessions.get_mut(index)?.as_mut()
+    }
+
+    /// A local session id that isn't in use, for a new session
+    pub fn reserve_new_sess_id(&mut self) -> u16 {
+        self.get_next_sess_id()
     }
 
     fn get_next_sess_id(&mut self) -> u16 {
@@ -340,9 +663,9 @@ impl SessionMgr {
 
     pub fn get_lru(&mut self) -> usize {
         let mut lru_index = 0;
-        let mut lru_ts = SystemTime::now();
-        for i in 0..MAX_SESSIONS {
-            if let Some(s) = &self.sessions[i] {
+        let mut lru_ts = clock::now();
+        for (i, s) in self.sessions.iter().enumerate() {
+            if let Some(s) = s {
                 if s.last_use < lru_ts {
                     lru_ts = s.last_use;
                     lru_index = i;
@@ -352,6 +675,27 @@ impl SessionMgr {
         lru_index
     }
 
+    /// The indices of the sessions that were idle for longer than `timeouts` allow
+    pub fn get_expired(&self, timeouts: &IdleTimeouts) -> Vec<usize> {
+        self.sessions
+            .iter()
+            .enumerate()
+            .filter_map(|(i, s)| match s.as_ref()?.get_time_to_expiry(timeouts) {
+                Some(t) if t.is_zero() => Some(i),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Returns the time left till the earliest session expires
+    pub fn get_next_expiry(&self, timeouts: &IdleTimeouts) -> Option<Duration> {
+        self.sessions
+            .iter()
+            .flatten()
+            .filter_map(|s| s.get_time_to_expiry(timeouts))
+            .min()
+    }
+
     pub fn add(&mut self, peer_addr: Address, peer_nodeid: Option<u64>) -> Result<usize, Error> {
         let session = Session::new(peer_addr, peer_nodeid);
         self.add_session(session)
@@ -360,7 +704,9 @@ impl SessionMgr {
     /// This assumes that the higher layer has taken care of doing anything required
     /// as per the spec before the session is erased
     pub fn remove(&mut self, idx: usize) {
-        self.sessions[idx] = None;
+        if let Some(session) = self.sessions.get_mut(idx) {
+            *session = None;
+        }
     }
 
     /// We could have returned a SessionHandle here. But the borrow checker doesn't support
@@ -368,6 +714,8 @@ impl SessionMgr {
     /// action in the error return path
     pub fn add_session(&mut self, session: Session) -> Result<usize, Error> {
         if let Some(index) = self.get_empty_slot() {
+            self.stats
+                .count(session.mode, None, |c| c.sessions_opened += 1);
             self.sessions[index] = Some(session);
             Ok(index)
         } else {
@@ -405,13 +753,16 @@ impl SessionMgr {
     }
 
     pub fn get_with_id(&mut self, sess_id: u16) -> Option<SessionHandle> {
-        let index = self
-            .sessions
-            .iter_mut()
-            .position(|x| x.as_ref().map(|s| s.local_sess_id) == Some(sess_id))?;
+        let index = self.get_index_with_id(sess_id)?;
         Some(self.get_session_handle(index))
     }
 
+    pub fn get_index_with_id(&self, sess_id: u16) -> Option<usize> {
+        self.sessions
+            .iter()
+            .position(|x| x.as_ref().map(|s| s.local_sess_id) == Some(sess_id))
+    }
+
     pub fn get_or_add(
         &mut self,
         sess_id: u16,
@@ -439,7 +790,15 @@ impl SessionMgr {
             rx.plain.get_src_u64(),
             rx.plain.is_encrypted(),
         ) {
-            Ok(s) => Some(s),
+            Ok(s) => {
+                // An unsecured session replies on the interface the peer last reached us on
+                if let Some(session) = self.sessions[s].as_mut() {
+                    if !session.is_encrypted() {
+                        session.iface = rx.iface;
+                    }
+                }
+                Some(s)
+            }
             Err(Error::NoSpace) => None,
             Err(e) => {
                 return Err(e);
@@ -448,43 +807,115 @@ impl SessionMgr {
         Ok(sess_index)
     }
 
-    pub fn recv(&mut self) -> Result<(BoxSlab<PacketPool>, Option<usize>), Error> {
-        let mut rx = Slab::<PacketPool>::new(Packet::new_rx()?).ok_or(Error::PacketPoolExhaust)?;
+    /// Waits for a message from any of the network interfaces
+    ///
+    /// Only the plain header of the message is decoded, the session for the message
+    /// is looked up with post_recv(). Nothing is lost if this future is dropped before
+    /// it completes.
+    pub async fn recv(&self) -> Result<BoxSlab<PacketPool>, Error> {
+        let mut rx = alloc_wait(Packet::alloc_rx).await?;
 
-        let network = self.network.as_ref().ok_or(Error::NoNetworkInterface)?;
+        if self.networks.is_empty() {
+            return Err(Error::NoNetworkInterface);
+        }
+        let networks = &self.networks;
 
-        let (len, src) = network.recv(rx.as_borrow_slice())?;
+        let in_buf = rx.as_borrow_slice();
+        let (len, src, iface) = future::poll_fn(|cx| Self::poll_recv(networks, cx, in_buf)).await?;
         rx.get_parsebuf()?.set_len(len);
         rx.peer = src;
+        rx.iface = iface;
 
         info!("{} from src: {}", "Received".blue(), src);
         trace!("payload: {:x?}", rx.as_borrow_slice());
 
         // Read unencrypted packet header
         rx.plain_hdr_decode()?;
+        Ok(rx)
+    }
 
-        // Get session
-        let sess_handle = self.post_recv(&rx)?;
-        Ok((rx, sess_handle))
+    /// Waits till the network interfaces have written out what they queued up
+    pub async fn flush(&self) {
+        let networks = &self.networks;
+        future::poll_fn(|cx| {
+            let mut done = true;
+            for network in networks {
+                done &= network.poll_flush(cx).is_ready();
+            }
+            if done {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+        .await
     }
 
-    pub fn send(
-        &mut self,
-        sess_idx: usize,
-        mut proto_tx: BoxSlab<PacketPool>,
-    ) -> Result<(), Error> {
-        self.sessions[sess_idx]
-            .as_mut()
-            .ok_or(Error::NoSession)?
-            .do_send(&mut proto_tx)?;
+    /// Returns the peers that the network interfaces lost their connection to
+    pub fn take_unreachable(&self) -> Vec<Address> {
+        self.networks
+            .iter()
+            .flat_map(|n| n.take_unreachable())
+            .collect()
+    }
+
+    /// The indices of the sessions with the peer at `peer_addr`
+    pub fn get_with_peer_addr(&self, peer_addr: Address) -> Vec<usize> {
+        self.sessions
+            .iter()
+            .enumerate()
+            .filter_map(|(i, s)| match s {
+                Some(s) if s.peer_addr == peer_addr => Some(i),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn send(&mut self, sess_idx: usize, proto_tx: &mut Packet) -> Result<(), Error> {
+        let session = self.mut_by_index(sess_idx).ok_or(Error::NoSession)?;
+        session.do_send(proto_tx)?;
+        let mode = session.mode;
 
-        let network = self.network.as_ref().ok_or(Error::NoNetworkInterface)?;
+        let network = self.get_network(proto_tx.iface)?;
         let peer = proto_tx.peer;
         network.send(proto_tx.as_borrow_slice(), peer)?;
-        println!("Message Sent to {}", peer);
+        info!("Message Sent to {}", peer);
+        self.stats
+            .count(mode, Some(proto_tx.get_proto()), |c| c.tx += 1);
         Ok(())
     }
 
+    /// Sends out a message that was already encoded (and encrypted) by a previous send
+    pub fn retransmit(&mut self, sess_idx: usize, proto_tx: &mut Packet) -> Result<(), Error> {
+        let mode = self
+            .mut_by_index(sess_idx)
+            .ok_or(Error::NoSession)?
+            .get_session_mode();
+        let network = self.get_network(proto_tx.iface)?;
+        let peer = proto_tx.peer;
+        network.send(proto_tx.as_borrow_slice(), peer)?;
+        info!("Message Resent to {}", peer);
+        self.stats.count(mode, Some(proto_tx.get_proto()), |c| {
+            c.tx += 1;
+            c.retransmissions += 1;
+        });
+        Ok(())
+    }
+
+    /// Sends out an encoded message on the first network interface that can reach
+    /// its peer, for messages that aren't on any of our sessions
+    pub fn send_encoded(&self, proto_tx: &mut Packet) -> Result<(), Error> {
+        let peer = proto_tx.peer;
+        for (iface, network) in self.networks.iter().enumerate() {
+            if network.send(proto_tx.as_borrow_slice(), peer).is_ok() {
+                proto_tx.iface = iface;
+                info!("Message Sent to {}", peer);
+                return Ok(());
+            }
+        }
+        Err(Error::NoNetworkInterface)
+    }
+
     pub fn get_session_handle(&mut self, sess_idx: usize) -> SessionHandle {
         SessionHandle {
             sess_mgr: self,
@@ -493,6 +924,18 @@ impl SessionMgr {
     }
 }
 
+// Takes a packet with `alloc`, waiting for one to be returned if the pool is empty
+async fn alloc_wait<T>(mut alloc: impl FnMut() -> Result<T, Error>) -> Result<T, Error> {
+    loop {
+        match alloc() {
+            Err(Error::PacketPoolExhaust) => {
+                Timer::after(ALLOC_RETRY_INTERVAL).await;
+            }
+            result => return result,
+        }
+    }
+}
+
 impl fmt::Display for SessionMgr {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         writeln!(f, "{{[")?;
@@ -511,10 +954,10 @@ pub struct SessionHandle<'a> {
 
 impl<'a> SessionHandle<'a> {
     pub fn reserve_new_sess_id(&mut self) -> u16 {
-        self.sess_mgr.get_next_sess_id()
+        self.sess_mgr.reserve_new_sess_id()
     }
 
-    pub fn send(&mut self, proto_tx: BoxSlab<PacketPool>) -> Result<(), Error> {
+    pub fn send(&mut self, proto_tx: &mut Packet) -> Result<(), Error> {
         self.sess_mgr.send(self.sess_idx, proto_tx)
     }
 }
@@ -537,9 +980,55 @@ impl<'a> DerefMut for SessionHandle<'a> {
 #[cfg(test)]
 mod tests {
 
-    use crate::transport::network::Address;
+    use std::{
+        net::{IpAddr, Ipv4Addr, SocketAddr},
+        sync::{Arc, Mutex},
+        task::{Context, Poll},
+        time::Duration,
+    };
+
+    use boxslab::Slab;
+    use smol::{
+        future::{self, FutureExt},
+        Timer,
+    };
+
+    use crate::{
+        error::Error,
+        transport::{
+            network::{Address, NetworkInterface},
+            packet::{Packet, PacketPool},
+        },
+    };
+
+    use super::{alloc_wait, CtrWindowMode, RxCtrState, SessionMgr};
+
+    // A network interface that has a single message to deliver, and counts what is sent on it
+    struct TestNetwork {
+        rx: Mutex<Option<(Vec<u8>, Address)>>,
+        tx_count: Arc<Mutex<usize>>,
+    }
+
+    impl NetworkInterface for TestNetwork {
+        fn poll_recv(
+            &self,
+            _cx: &mut Context<'_>,
+            in_buf: &mut [u8],
+        ) -> Poll<Result<(usize, Address), Error>> {
+            match self.rx.lock().unwrap().take() {
+                Some((msg, addr)) => {
+                    in_buf[..msg.len()].copy_from_slice(&msg);
+                    Poll::Ready(Ok((msg.len(), addr)))
+                }
+                None => Poll::Pending,
+            }
+        }
 
-    use super::SessionMgr;
+        fn send(&self, out_buf: &[u8], _addr: Address) -> Result<usize, Error> {
+            *self.tx_count.lock().unwrap() += 1;
+            Ok(out_buf.len())
+        }
+    }
 
     #[test]
     fn test_next_sess_id_doesnt_reuse() {
@@ -567,4 +1056,135 @@ mod tests {
         assert_eq!(sm.get_next_sess_id(), 65535);
         assert_eq!(sm.get_next_sess_id(), 2);
     }
+
+    #[test]
+    fn test_reply_on_arrival_iface() {
+        let peer = Address::Udp(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 5541));
+        // An unencrypted message with a counter of 1
+        let msg = vec![0, 0, 0, 0, 1, 0, 0, 0];
+        let mut tx_counts = Vec::new();
+        let mut sm = SessionMgr::new();
+        for rx in [None, Some((msg, peer))] {
+            let tx_count = Arc::new(Mutex::new(0));
+            tx_counts.push(tx_count.clone());
+            let network = TestNetwork {
+                rx: Mutex::new(rx),
+                tx_count,
+            };
+            sm.add_network_interface(Box::new(network)).unwrap();
+        }
+
+        let rx = smol::block_on(sm.recv()).unwrap();
+        assert_eq!(rx.iface, 1);
+        let sess_idx = sm.post_recv(&rx).unwrap().unwrap();
+        assert_eq!(sm.get_session_handle(sess_idx).get_iface(), 1);
+
+        let mut tx = Slab::<PacketPool>::new(Packet::new_tx().unwrap()).unwrap();
+        sm.send(sess_idx, &mut tx).unwrap();
+        assert_eq!(*tx_counts[0].lock().unwrap(), 0);
+        assert_eq!(*tx_counts[1].lock().unwrap(), 1);
+
+        // Nothing more to receive
+        let timeout = async {
+            Timer::after(Duration::from_millis(10)).await;
+            None
+        };
+        assert!(smol::block_on(async { Some(sm.recv().await) }.or(timeout)).is_none());
+    }
+
+    #[test]
+    fn test_alloc_waits_for_packets() {
+        let mut failures = 2;
+        let alloc = || {
+            if failures > 0 {
+                failures -= 1;
+                Err(Error::PacketPoolExhaust)
+            } else {
+                Ok(7)
+            }
+        };
+        let mut wait = Box::pin(alloc_wait(alloc));
+        // With the pool empty, this has to wait instead of failing or spinning
+        assert_eq!(smol::block_on(future::poll_once(&mut wait)), None);
+        assert_eq!(smol::block_on(wait), Ok(7));
+
+        // Any other error is returned
+        let wait = alloc_wait(|| Err::<u8, _>(Error::NoSpace));
+        assert_eq!(smol::block_on(wait), Err(Error::NoSpace));
+    }
+
+    #[test]
+    fn test_rx_ctr_in_order() {
+        let mut s = RxCtrState::new(CtrWindowMode::NoRollover);
+        assert!(!s.recv(1000));
+        assert!(!s.recv(1001));
+        assert!(!s.recv(1002));
+        assert!(s.recv(1002));
+        assert!(s.recv(1001));
+        assert!(s.recv(1000));
+        assert_eq!(s.get_max_ctr(), Some(1002));
+    }
+
+    #[test]
+    fn test_rx_ctr_out_of_order() {
+        let mut s = RxCtrState::new(CtrWindowMode::NoRollover);
+        assert!(!s.recv(1000));
+        assert!(!s.recv(1005));
+        // The skipped counters are still acceptable, but only once
+        assert!(!s.recv(1003));
+        assert!(s.recv(1003));
+        assert!(!s.recv(1001));
+        assert!(s.recv(1000));
+        // Jump exactly by the window size, 1005 is at the edge of the window
+        assert!(!s.recv(1037));
+        assert!(s.recv(1005));
+        assert!(!s.recv(1006));
+        // 1004 is just behind the window
+        assert!(s.recv(1004));
+    }
+
+    #[test]
+    fn test_rx_ctr_behind_window() {
+        let mut s = RxCtrState::new(CtrWindowMode::NoRollover);
+        assert!(!s.recv(1000));
+        assert!(!s.recv(2000));
+        // Replays from far behind are rejected for secure sessions
+        assert!(s.recv(1500));
+        assert!(s.recv(1));
+        assert_eq!(s.get_max_ctr(), Some(2000));
+
+        // While the unsecured session re-synchronises to the new counter
+        let mut s = RxCtrState::new(CtrWindowMode::Rollover);
+        assert!(!s.recv(1000));
+        assert!(!s.recv(2000));
+        assert!(!s.recv(1500));
+        assert_eq!(s.get_max_ctr(), Some(1500));
+        assert!(s.recv(1500));
+        assert!(!s.recv(1501));
+    }
+
+    #[test]
+    fn test_rx_ctr_rollover() {
+        let mut s = RxCtrState::new(CtrWindowMode::Rollover);
+        assert!(!s.recv(u32::MAX - 1));
+        assert!(!s.recv(1));
+        assert_eq!(s.get_max_ctr(), Some(1));
+        assert!(s.recv(u32::MAX - 1));
+        assert!(!s.recv(u32::MAX));
+        assert!(!s.recv(0));
+        assert!(s.recv(0));
+
+        // No rollover for secure sessions
+        let mut s = RxCtrState::new(CtrWindowMode::NoRollover);
+        assert!(!s.recv(u32::MAX - 1));
+        assert!(s.recv(1));
+    }
+
+    #[test]
+    fn test_rx_ctr_synced() {
+        let mut s = RxCtrState::new_synced(500, CtrWindowMode::Rollover);
+        assert!(s.recv(500));
+        assert!(s.recv(490));
+        assert!(!s.recv(501));
+    }
 }
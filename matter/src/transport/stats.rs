@@ -0,0 +1,136 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+use super::{packet, session::SessionMode};
+
+/// The counters of the transport, for a protocol or for a session mode
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Counters {
+    /// Messages received, that could be decrypted, including the duplicates
+    pub rx: u64,
+    /// Messages sent, including the retransmissions
+    pub tx: u64,
+    /// Messages that couldn't be decoded or decrypted, these are only counted per
+    /// session mode
+    pub decrypt_failures: u64,
+    /// Messages received that we had already seen
+    pub duplicates: u64,
+    pub retransmissions: u64,
+    /// Messages that the peer never acknowledged, these are only counted per session
+    /// mode
+    pub ack_timeouts: u64,
+    pub sessions_opened: u64,
+    /// Sessions that were evicted to make room for new ones
+    pub sessions_evicted: u64,
+    /// Sessions that were closed because their peer went quiet
+    pub sessions_expired: u64,
+    pub exchanges_opened: u64,
+    /// Exchanges that were dropped along with their evicted session
+    pub exchanges_evicted: u64,
+}
+
+/// A snapshot of the statistics of a transport
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Stats {
+    /// The counters of each protocol, keyed by the (vendor id, protocol id)
+    ///
+    /// The counters of the sessions aren't broken down per protocol.
+    pub per_protocol: BTreeMap<(u16, u16), Counters>,
+    pub plain_text: Counters,
+    pub pase: Counters,
+    pub case: Counters,
+    pub group: Counters,
+    /// The number of times that no packet was left in the pool
+    ///
+    /// The pool is shared by all the transports of the process, and so is this count.
+    pub packet_pool_exhausted: u64,
+}
+
+impl Stats {
+    pub fn per_mode(&self, mode: SessionMode) -> &Counters {
+        match mode {
+            SessionMode::PlainText => &self.plain_text,
+            SessionMode::Pase => &self.pase,
+            SessionMode::Case(_) => &self.case,
+            SessionMode::Group(_, _) => &self.group,
+        }
+    }
+
+    fn per_mode_mut(&mut self, mode: SessionMode) -> &mut Counters {
+        match mode {
+            SessionMode::PlainText => &mut self.plain_text,
+            SessionMode::Pase => &mut self.pase,
+            SessionMode::Case(_) => &mut self.case,
+            SessionMode::Group(_, _) => &mut self.group,
+        }
+    }
+}
+
+/// A handle to the statistics of a transport
+///
+/// The handle can be cloned and used from any thread, while the transport runs.
+#[derive(Clone, Default)]
+pub struct StatsHandle {
+    stats: Arc<Mutex<Stats>>,
+}
+
+impl StatsHandle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a snapshot of the statistics
+    pub fn get(&self) -> Stats {
+        let mut stats = self.stats.lock().unwrap().clone();
+        stats.packet_pool_exhausted = packet::get_pool_exhausted();
+        stats
+    }
+
+    /// Counts an event on a session of `mode`, and for the (vendor id, protocol id)
+    /// `proto` if the event has one
+    pub fn count<F>(&self, mode: SessionMode, proto: Option<(u16, u16)>, f: F)
+    where
+        F: Fn(&mut Counters),
+    {
+        let mut stats = self.stats.lock().unwrap();
+        f(stats.per_mode_mut(mode));
+        if let Some(proto) = proto {
+            f(stats.per_protocol.entry(proto).or_default());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::transport::session::SessionMode;
+
+    use super::{Counters, StatsHandle};
+
+    #[test]
+    fn test_count() {
+        let handle = StatsHandle::new();
+        let other_handle = handle.clone();
+        handle.count(SessionMode::Pase, Some((0, 1)), |c| c.rx += 1);
+        handle.count(SessionMode::Case(1), Some((0, 1)), |c| c.rx += 1);
+        // The same protocol id of another vendor is another protocol
+        handle.count(SessionMode::Group(1, 1), Some((0xFFF1, 1)), |c| c.rx += 1);
+        other_handle.count(SessionMode::Case(2), None, |c| c.decrypt_failures += 1);
+
+        let stats = handle.get();
+        assert_eq!(stats.pase.rx, 1);
+        assert_eq!(
+            *stats.per_mode(SessionMode::Case(3)),
+            Counters {
+                rx: 1,
+                decrypt_failures: 1,
+                ..Default::default()
+            }
+        );
+        assert_eq!(stats.per_protocol.len(), 2);
+        assert_eq!(stats.per_protocol[&(0, 1)].rx, 2);
+        assert_eq!(stats.per_protocol[&(0, 1)].decrypt_failures, 0);
+        assert_eq!(stats.per_protocol[&(0xFFF1, 1)].rx, 1);
+    }
+}
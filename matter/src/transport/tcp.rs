@@ -0,0 +1,447 @@
+use std::{
+    future::Future,
+    io::{self, ErrorKind, Read, Write},
+    net::{IpAddr, SocketAddr, TcpListener as StdTcpListener, TcpStream},
+    pin::Pin,
+    sync::Mutex,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use crate::error::*;
+use byteorder::{ByteOrder, LittleEndian};
+use log::{error, info};
+use smol::{future::FutureExt, net::Ipv6Addr, Async, Timer};
+use socket2::{Domain, Protocol, Socket, Type};
+
+use super::{
+    network::{Address, NetworkInterface},
+    packet::MAX_RX_BUF_SIZE,
+    udp::MATTER_PORT,
+};
+
+// Every message on a TCP stream is preceded by its length
+const MSG_LEN_SIZE: usize = 4;
+
+const LISTEN_BACKLOG: i32 = 8;
+
+// How long a peer may take to accept our connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How much may be queued up for a peer that isn't reading what we send
+const MAX_TX_BUF_SIZE: usize = 16 * (MSG_LEN_SIZE + MAX_RX_BUF_SIZE);
+
+type ConnectFuture = Pin<Box<dyn Future<Output = io::Result<Async<TcpStream>>> + Send>>;
+
+enum Stream {
+    Connecting(ConnectFuture),
+    Connected(Async<TcpStream>),
+}
+
+// A connection to a peer, along with the part of a message that we have read so far,
+// and what is yet to be written out
+struct Connection {
+    peer: SocketAddr,
+    stream: Stream,
+    rx_buf: Vec<u8>,
+    tx_buf: Vec<u8>,
+}
+
+impl Connection {
+    fn new(peer: SocketAddr, stream: Async<TcpStream>) -> Self {
+        Self::new_with_stream(peer, Stream::Connected(stream))
+    }
+
+    // Starts connecting to the peer, this completes as the connection is polled
+    fn connect(peer: SocketAddr) -> Self {
+        let connect = Async::<TcpStream>::connect(peer).or(async {
+            Timer::after(CONNECT_TIMEOUT).await;
+            Err(ErrorKind::TimedOut.into())
+        });
+        Self::new_with_stream(peer, Stream::Connecting(Box::pin(connect)))
+    }
+
+    fn new_with_stream(peer: SocketAddr, stream: Stream) -> Self {
+        Self {
+            peer,
+            stream,
+            rx_buf: Vec::with_capacity(MSG_LEN_SIZE + MAX_RX_BUF_SIZE),
+            tx_buf: Vec::new(),
+        }
+    }
+
+    // Queues up a message, to be written out by poll_send()
+    fn queue_msg(&mut self, msg: &[u8]) -> Result<(), Error> {
+        if self.tx_buf.len() + MSG_LEN_SIZE + msg.len() > MAX_TX_BUF_SIZE {
+            error!("Send queue to {} is full", self.peer);
+            return Err(Error::NoSpace);
+        }
+        let mut len = [0u8; MSG_LEN_SIZE];
+        LittleEndian::write_u32(&mut len, msg.len() as u32);
+        self.tx_buf.extend_from_slice(&len);
+        self.tx_buf.extend_from_slice(msg);
+        Ok(())
+    }
+
+    // Returns the message at the head of the rx buffer, if it is complete
+    fn take_msg(&mut self, in_buf: &mut [u8]) -> Result<Option<usize>, Error> {
+        if self.rx_buf.len() < MSG_LEN_SIZE {
+            return Ok(None);
+        }
+        let len = LittleEndian::read_u32(&self.rx_buf[..MSG_LEN_SIZE]) as usize;
+        if len > MAX_RX_BUF_SIZE || len > in_buf.len() {
+            error!("Message of size {} from {} is too large", len, self.peer);
+            return Err(Error::NoSpace);
+        }
+        if self.rx_buf.len() < MSG_LEN_SIZE + len {
+            return Ok(None);
+        }
+        in_buf[..len].copy_from_slice(&self.rx_buf[MSG_LEN_SIZE..MSG_LEN_SIZE + len]);
+        self.rx_buf.drain(..MSG_LEN_SIZE + len);
+        Ok(Some(len))
+    }
+
+    // Completes the connect, if it is still in progress
+    fn poll_connected(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        if let Stream::Connecting(connect) = &mut self.stream {
+            match connect.as_mut().poll(cx) {
+                Poll::Ready(Ok(stream)) => {
+                    info!("Connected to {}", self.peer);
+                    self.stream = Stream::Connected(stream);
+                }
+                Poll::Ready(Err(e)) => {
+                    error!("Error in connecting to {}: {:?}", self.peer, e);
+                    return Poll::Ready(Err(Error::Network));
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    // Writes out as much of the queued messages as the stream takes
+    fn poll_send(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        if self.tx_buf.is_empty() {
+            return Poll::Ready(Ok(()));
+        }
+        match self.poll_connected(cx) {
+            Poll::Ready(Ok(())) => (),
+            other => return other,
+        }
+        let stream = match &self.stream {
+            Stream::Connected(stream) => stream,
+            Stream::Connecting(_) => return Poll::Pending,
+        };
+        while !self.tx_buf.is_empty() {
+            match stream.get_ref().write(&self.tx_buf) {
+                Ok(0) => return Poll::Ready(Err(Error::Network)),
+                Ok(len) => {
+                    self.tx_buf.drain(..len);
+                    continue;
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => (),
+                Err(e) => {
+                    error!("Error in sending to {}: {:?}", self.peer, e);
+                    return Poll::Ready(Err(Error::Network));
+                }
+            }
+            match stream.poll_writable(cx) {
+                Poll::Ready(Ok(())) => (),
+                Poll::Ready(Err(_)) => return Poll::Ready(Err(Error::Network)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_recv(&mut self, cx: &mut Context<'_>, in_buf: &mut [u8]) -> Poll<Result<usize, Error>> {
+        let mut chunk = [0u8; 512];
+        loop {
+            match self.take_msg(in_buf) {
+                Ok(Some(len)) => return Poll::Ready(Ok(len)),
+                Ok(None) => (),
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+            let stream = match &self.stream {
+                Stream::Connected(stream) => stream,
+                // Nothing to read till we are connected
+                Stream::Connecting(_) => return Poll::Pending,
+            };
+            match stream.get_ref().read(&mut chunk) {
+                // The peer closed the connection
+                Ok(0) => return Poll::Ready(Err(Error::Network)),
+                Ok(len) => {
+                    self.rx_buf.extend_from_slice(&chunk[..len]);
+                    continue;
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => (),
+                Err(_) => return Poll::Ready(Err(Error::Network)),
+            }
+            match stream.poll_readable(cx) {
+                Poll::Ready(Ok(())) => (),
+                Poll::Ready(Err(_)) => return Poll::Ready(Err(Error::Network)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+/// Matter over TCP
+///
+/// This accepts connections from peers, and connects to peers that we have to
+/// send a message to but aren't connected with yet. The connection is identified
+/// by the peer's Address::Tcp, so a session bound to such an address goes over
+/// its connection.
+///
+/// Sending only queues up the message on the connection, it is written out as
+/// the transport polls the interface. If the connection to a peer fails, the peer
+/// is reported through take_unreachable().
+pub struct TcpListener {
+    listener: Async<StdTcpListener>,
+    connections: Mutex<Vec<Connection>>,
+    unreachable: Mutex<Vec<Address>>,
+}
+
+impl TcpListener {
+    pub fn new() -> Result<TcpListener, Error> {
+        TcpListener::bind(
+            SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), MATTER_PORT),
+            false,
+        )
+    }
+
+    /// Listens for connections on `addr`
+    ///
+    /// An IPv6 listener also accepts IPv4 connections, unless `v6_only` is set.
+    pub fn bind(addr: SocketAddr, v6_only: bool) -> Result<TcpListener, Error> {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        if addr.is_ipv6() {
+            socket.set_only_v6(v6_only)?;
+        }
+        socket.set_reuse_address(true)?;
+        socket.bind(&addr.into())?;
+        socket.listen(LISTEN_BACKLOG)?;
+        let listener: StdTcpListener = socket.into();
+        Ok(TcpListener {
+            listener: Async::new(listener)?,
+            connections: Mutex::new(Vec::new()),
+            unreachable: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// The address that we are listening on
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        Ok(self.listener.get_ref().local_addr()?)
+    }
+
+    fn poll_accept(&self, cx: &mut Context<'_>, connections: &mut Vec<Connection>) {
+        loop {
+            match self.listener.get_ref().accept() {
+                Ok((stream, peer)) => match Async::new(stream) {
+                    Ok(stream) => {
+                        info!("Accepted TCP connection from {}", peer);
+                        connections.push(Connection::new(peer, stream));
+                        continue;
+                    }
+                    Err(e) => error!("Error in accepting connection: {:?}", e),
+                },
+                Err(e) if e.kind() == ErrorKind::WouldBlock => (),
+                Err(e) => error!("Error in accepting connection: {:?}", e),
+            }
+            if !matches!(self.listener.poll_readable(cx), Poll::Ready(Ok(()))) {
+                return;
+            }
+        }
+    }
+
+    // Drops the connection, and remembers that its peer can't be reached any more
+    fn close(&self, connections: &mut Vec<Connection>, index: usize) {
+        let peer = connections.remove(index).peer;
+        info!("Closing TCP connection with {}", peer);
+        self.unreachable.lock().unwrap().push(Address::Tcp(peer));
+    }
+
+    // Writes out what is queued up on all the connections. Returns whether all of
+    // it went out, and whether any connection was lost.
+    fn poll_send(&self, cx: &mut Context<'_>, connections: &mut Vec<Connection>) -> (bool, bool) {
+        let mut done = true;
+        let mut lost = false;
+        let mut i = 0;
+        while i < connections.len() {
+            match connections[i].poll_send(cx) {
+                Poll::Ready(Ok(())) => i += 1,
+                Poll::Ready(Err(_)) => {
+                    self.close(connections, i);
+                    lost = true;
+                }
+                Poll::Pending => {
+                    done = false;
+                    i += 1;
+                }
+            }
+        }
+        (done, lost)
+    }
+}
+
+impl NetworkInterface for TcpListener {
+    fn poll_recv(
+        &self,
+        cx: &mut Context<'_>,
+        in_buf: &mut [u8],
+    ) -> Poll<Result<(usize, Address), Error>> {
+        let mut connections = self.connections.lock().unwrap();
+        self.poll_accept(cx, &mut connections);
+        let (_, mut lost) = self.poll_send(cx, &mut connections);
+
+        let mut i = 0;
+        while i < connections.len() {
+            match connections[i].poll_recv(cx, in_buf) {
+                Poll::Ready(Ok(len)) => {
+                    return Poll::Ready(Ok((len, Address::Tcp(connections[i].peer))))
+                }
+                Poll::Ready(Err(_)) => {
+                    self.close(&mut connections, i);
+                    lost = true;
+                }
+                Poll::Pending => i += 1,
+            }
+        }
+        if lost {
+            // Let the transport know, so that it drops the sessions with the peer
+            Poll::Ready(Err(Error::Network))
+        } else {
+            Poll::Pending
+        }
+    }
+
+    fn send(&self, out_buf: &[u8], addr: Address) -> Result<usize, Error> {
+        let peer = match addr {
+            Address::Tcp(peer) => peer,
+            _ => return Err(Error::Invalid),
+        };
+        let mut connections = self.connections.lock().unwrap();
+        let index = match connections.iter().position(|c| c.peer == peer) {
+            Some(index) => index,
+            None => {
+                info!("Connecting to {}", peer);
+                connections.push(Connection::connect(peer));
+                connections.len() - 1
+            }
+        };
+        connections[index].queue_msg(out_buf)?;
+        Ok(out_buf.len())
+    }
+
+    fn poll_flush(&self, cx: &mut Context<'_>) -> Poll<()> {
+        let mut connections = self.connections.lock().unwrap();
+        match self.poll_send(cx, &mut connections) {
+            (true, _) => Poll::Ready(()),
+            (false, _) => Poll::Pending,
+        }
+    }
+
+    fn take_unreachable(&self) -> Vec<Address> {
+        std::mem::take(&mut *self.unreachable.lock().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{TcpListener, TcpStream},
+        task::Poll,
+        time::{Duration, Instant},
+    };
+
+    use smol::{future, Async};
+
+    use super::{Connection, TcpListener as MatterTcpListener};
+    use crate::{
+        error::Error,
+        transport::network::{Address, NetworkInterface},
+    };
+
+    #[test]
+    fn test_framing() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer = listener.local_addr().unwrap();
+        let stream = Async::new(TcpStream::connect(peer).unwrap()).unwrap();
+        let mut conn = Connection::new(peer, stream);
+        let mut in_buf = [0u8; 10];
+
+        // Two messages, and the first part of the length of another one
+        conn.rx_buf
+            .extend_from_slice(&[3, 0, 0, 0, 1, 2, 3, 1, 0, 0, 0, 9, 2, 0]);
+        assert_eq!(conn.take_msg(&mut in_buf), Ok(Some(3)));
+        assert_eq!(&in_buf[..3], &[1, 2, 3]);
+        assert_eq!(conn.take_msg(&mut in_buf), Ok(Some(1)));
+        assert_eq!(in_buf[0], 9);
+        assert_eq!(conn.take_msg(&mut in_buf), Ok(None));
+
+        // Rest of the length, and part of the message
+        conn.rx_buf.extend_from_slice(&[0, 0, 5]);
+        assert_eq!(conn.take_msg(&mut in_buf), Ok(None));
+        conn.rx_buf.extend_from_slice(&[6]);
+        assert_eq!(conn.take_msg(&mut in_buf), Ok(Some(2)));
+        assert_eq!(&in_buf[..2], &[5, 6]);
+
+        // Larger than what we can take
+        conn.rx_buf.extend_from_slice(&[0xff, 0xff, 0, 0]);
+        assert!(conn.take_msg(&mut in_buf).is_err());
+    }
+
+    fn bind_local() -> MatterTcpListener {
+        MatterTcpListener::bind("127.0.0.1:0".parse().unwrap(), false).unwrap()
+    }
+
+    fn recv(listener: &MatterTcpListener) -> Result<(Vec<u8>, Address), Error> {
+        let mut in_buf = [0u8; 64];
+        let (len, src) = smol::block_on(future::poll_fn(|cx| listener.poll_recv(cx, &mut in_buf)))?;
+        Ok((in_buf[..len].to_vec(), src))
+    }
+
+    #[test]
+    fn test_send_recv() {
+        let a = bind_local();
+        let b = bind_local();
+        let b_addr = Address::Tcp(b.local_addr().unwrap());
+
+        // Nothing goes out till the connection is polled
+        assert_eq!(a.send(&[1, 2, 3], b_addr), Ok(3));
+        assert_eq!(a.send(&[4], b_addr), Ok(1));
+        smol::block_on(future::poll_fn(|cx| a.poll_flush(cx)));
+
+        let (msg, a_addr) = recv(&b).unwrap();
+        assert_eq!(msg, vec![1, 2, 3]);
+        assert_eq!(recv(&b).unwrap(), (vec![4], a_addr));
+
+        // The reply goes back over the same connection
+        assert_eq!(b.send(&[5, 6], a_addr), Ok(2));
+        smol::block_on(future::poll_fn(|cx| b.poll_flush(cx)));
+        assert_eq!(recv(&a).unwrap(), (vec![5, 6], b_addr));
+    }
+
+    #[test]
+    fn test_unreachable_peer() {
+        // A port that nobody listens on
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer = Address::Tcp(closed.local_addr().unwrap());
+        drop(closed);
+
+        let a = bind_local();
+        let start = Instant::now();
+        assert_eq!(a.send(&[1], peer), Ok(1));
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(a.take_unreachable(), vec![]);
+
+        // The failed connect is reported once the connection is polled
+        assert_eq!(recv(&a), Err(Error::Network));
+        assert_eq!(a.take_unreachable(), vec![peer]);
+        let mut in_buf = [0u8; 4];
+        assert!(smol::block_on(future::poll_fn(|cx| Poll::Ready(
+            a.poll_recv(cx, &mut in_buf)
+        )))
+        .is_pending());
+    }
+}
@@ -1,12 +1,20 @@
+use std::{
+    io::{self, ErrorKind},
+    net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket},
+    task::{Context, Poll},
+};
+
 use crate::error::*;
-use smol::net::{Ipv6Addr, UdpSocket};
+use log::{error, warn};
+use smol::{net::Ipv6Addr, Async};
+use socket2::{Domain, Protocol, Socket, Type};
 
 use super::network::{Address, NetworkInterface};
 
 // We could get rid of the smol here, but keeping it around in case we have to process
 // any other events in this thread's context
 pub struct UdpListener {
-    socket: UdpSocket,
+    socket: Async<UdpSocket>,
 }
 
 // Currently matches with the one in connectedhomeip repo
@@ -17,24 +25,118 @@ pub const MATTER_PORT: u16 = 5540;
 
 impl UdpListener {
     pub fn new() -> Result<UdpListener, Error> {
+        UdpListener::bind(
+            SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), MATTER_PORT),
+            false,
+        )
+    }
+
+    /// Creates a UDP socket bound to `addr`
+    ///
+    /// An IPv6 socket also receives IPv4 traffic, unless `v6_only` is set. This is
+    /// required if a separate IPv4 socket is to be bound to the same port.
+    pub fn bind(addr: SocketAddr, v6_only: bool) -> Result<UdpListener, Error> {
+        let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+        if addr.is_ipv6() {
+            socket.set_only_v6(v6_only)?;
+        }
+        socket.bind(&addr.into())?;
         Ok(UdpListener {
-            socket: smol::block_on(UdpSocket::bind((Ipv6Addr::UNSPECIFIED, MATTER_PORT)))?,
+            socket: Async::new(socket.into())?,
         })
     }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        Ok(self.socket.get_ref().local_addr()?)
+    }
+}
+
+/// The result of sending `len` bytes to `addr` on a non-blocking socket
+///
+/// If the buffer of the socket is full, the message is dropped like it would be
+/// anywhere else on the way. This still counts as sent, so that MRP keeps the
+/// message around and sends it again.
+pub fn send_result(
+    result: io::Result<usize>,
+    len: usize,
+    addr: SocketAddr,
+) -> Result<usize, Error> {
+    match result {
+        Ok(len) => Ok(len),
+        Err(e) if e.kind() == ErrorKind::WouldBlock => {
+            warn!("The send buffer is full, dropping the message to {}", addr);
+            Ok(len)
+        }
+        Err(e) => {
+            error!("Error in sending to {}: {:?}", addr, e);
+            Err(Error::Network)
+        }
+    }
 }
 
 impl NetworkInterface for UdpListener {
-    fn recv(&self, in_buf: &mut [u8]) -> Result<(usize, Address), Error> {
-        let (size, addr) = smol::block_on(self.socket.recv_from(in_buf)).map_err(|e| {
-            println!("Error on the network: {:?}", e);
-            Error::Network
-        })?;
-        Ok((size, Address::Udp(addr)))
+    fn poll_recv(
+        &self,
+        cx: &mut Context<'_>,
+        in_buf: &mut [u8],
+    ) -> Poll<Result<(usize, Address), Error>> {
+        loop {
+            // The socket is non-blocking, so try reading first and only wait
+            // for readiness if there is nothing to be read
+            match self.socket.get_ref().recv_from(in_buf) {
+                Ok((size, addr)) => return Poll::Ready(Ok((size, Address::Udp(addr)))),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => (),
+                Err(e) => {
+                    error!("Error on the network: {:?}", e);
+                    return Poll::Ready(Err(Error::Network));
+                }
+            }
+            match self.socket.poll_readable(cx) {
+                Poll::Ready(Ok(())) => (),
+                Poll::Ready(Err(e)) => {
+                    error!("Error on the network: {:?}", e);
+                    return Poll::Ready(Err(Error::Network));
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
     }
 
     fn send(&self, out_buf: &[u8], addr: Address) -> Result<usize, Error> {
         match addr {
-            Address::Udp(addr) => Ok(smol::block_on(self.socket.send_to(out_buf, addr))?),
+            Address::Udp(addr) => send_result(
+                self.socket.get_ref().send_to(out_buf, addr),
+                out_buf.len(),
+                addr,
+            ),
+            _ => Err(Error::Invalid),
+        }
+    }
+
+    /// Joins the multicast group, on the default interface
+    ///
+    /// The group is ignored if it doesn't belong to the same address family as the socket.
+    fn join_multicast(&self, group: IpAddr) -> Result<(), Error> {
+        let socket = self.socket.get_ref();
+        match (group, socket.local_addr()?) {
+            (IpAddr::V6(group), SocketAddr::V6(_)) => socket.join_multicast_v6(&group, 0)?,
+            (IpAddr::V4(group), SocketAddr::V4(_)) => {
+                socket.join_multicast_v4(&group, &Ipv4Addr::UNSPECIFIED)?
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn leave_multicast(&self, group: IpAddr) -> Result<(), Error> {
+        let socket = self.socket.get_ref();
+        match (group, socket.local_addr()?) {
+            (IpAddr::V6(group), SocketAddr::V6(_)) => socket.leave_multicast_v6(&group, 0)?,
+            (IpAddr::V4(group), SocketAddr::V4(_)) => {
+                socket.leave_multicast_v4(&group, &Ipv4Addr::UNSPECIFIED)?
+            }
+            _ => (),
         }
+        Ok(())
     }
 }
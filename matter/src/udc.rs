@@ -0,0 +1,326 @@
+//! User Directed Commissioning
+//!
+//! A commissionable node tells a commissioner that it wants to be commissioned, by
+//! sending it an IdentificationDeclaration. The commissioner then looks the node up
+//! by its commissionable mDNS instance name, and commissions it as usual.
+
+use log::{error, info};
+use num_derive::FromPrimitive;
+
+use crate::{
+    error::Error,
+    mdns::Mdns,
+    tlv::{get_root_node_struct, FromTLV, TLVElement, TLVWriter, TagType, ToTLV},
+    transport::{
+        network::Address,
+        proto_demux::{self, ProtoCtx, ResponseRequired},
+        queue::{Msg, UnsecuredTxMsg, WorkQ},
+    },
+    utils::writebuf::WriteBuf,
+};
+
+pub const PROTO_ID_UDC: usize = 0x03;
+
+/// The port that the commissioners listen on for the IdentificationDeclarations
+pub const UDC_PORT: u16 = 5550;
+
+const MAX_INSTANCE_NAME_LEN: usize = 16;
+// The instance name along with its NUL terminator
+const INSTANCE_NAME_FIELD_LEN: usize = MAX_INSTANCE_NAME_LEN + 1;
+const MAX_DECLARATION_LEN: usize = 128;
+
+#[derive(FromPrimitive, Debug, Copy, Clone, PartialEq)]
+pub enum OpCode {
+    IdentificationDeclaration = 0x00,
+}
+
+/// What a commissionable node tells the commissioner about itself
+#[derive(Debug, Clone, PartialEq)]
+pub struct IdentificationDeclaration {
+    /// The instance name of the node's commissionable mDNS service
+    pub instance_name: String,
+    pub vendor_id: Option<u16>,
+    pub product_id: Option<u16>,
+    pub device_name: Option<String>,
+}
+
+// The TLV that follows the instance name, with the tags of the spec. The ones that
+// we don't know of are skipped.
+#[derive(FromTLV, ToTLV)]
+#[tlvargs(start = 1, unordered)]
+struct DeclarationTLV {
+    vendor_id: Option<u16>,
+    product_id: Option<u16>,
+    device_name: Option<String>,
+}
+
+impl IdentificationDeclaration {
+    /// A declaration for the commissionable service that we published over mDNS
+    ///
+    /// This fails with Error::Invalid if the commissioning window isn't open.
+    pub fn from_mdns() -> Result<Self, Error> {
+        let mdns = Mdns::get()?;
+        let instance_name = mdns.get_instance_name().ok_or_else(|| {
+            error!("No commissionable service was published");
+            Error::Invalid
+        })?;
+        let (vid, pid) = mdns.get_vid_pid();
+        Ok(Self {
+            instance_name,
+            vendor_id: Some(vid),
+            product_id: Some(pid),
+            device_name: None,
+        })
+    }
+
+    /// Writes the instance name, NUL padded to a fixed length, followed by the TLV
+    pub fn write(&self, wb: &mut WriteBuf) -> Result<(), Error> {
+        let name = self.instance_name.as_bytes();
+        if name.len() > MAX_INSTANCE_NAME_LEN {
+            return Err(Error::InvalidData);
+        }
+        let mut name_field = [0; INSTANCE_NAME_FIELD_LEN];
+        name_field[..name.len()].copy_from_slice(name);
+        wb.copy_from_slice(&name_field)?;
+
+        let decl = DeclarationTLV {
+            vendor_id: self.vendor_id,
+            product_id: self.product_id,
+            device_name: self.device_name.clone(),
+        };
+        decl.to_tlv(&mut TLVWriter::new(wb), TagType::Anonymous)
+    }
+
+    pub fn parse(buf: &[u8]) -> Result<Self, Error> {
+        let (name_field, tlv) = buf.split_at(buf.len().min(INSTANCE_NAME_FIELD_LEN));
+        let name_len = name_field
+            .iter()
+            .position(|c| *c == 0)
+            .unwrap_or(name_field.len());
+        if name_len > MAX_INSTANCE_NAME_LEN {
+            return Err(Error::InvalidData);
+        }
+        let instance_name =
+            String::from_utf8(name_field[..name_len].to_vec()).map_err(|_| Error::InvalidData)?;
+        // The declaration may end with the instance name
+        let decl = if tlv.is_empty() {
+            DeclarationTLV {
+                vendor_id: None,
+                product_id: None,
+                device_name: None,
+            }
+        } else {
+            DeclarationTLV::from_tlv(&get_root_node_struct(tlv)?)?
+        };
+        Ok(Self {
+            instance_name,
+            vendor_id: decl.vendor_id,
+            product_id: decl.product_id,
+            device_name: decl.device_name,
+        })
+    }
+}
+
+/// Sends an IdentificationDeclaration to the commissioner at `commissioner`
+///
+/// The declaration goes out on an unsecured session, the commissioner neither
+/// acknowledges nor responds to it. The commissioner typically listens on UDC_PORT.
+pub async fn send_identification_declaration(
+    work_q: &WorkQ,
+    commissioner: Address,
+    decl: &IdentificationDeclaration,
+) -> Result<(), Error> {
+    let mut buf = [0; MAX_DECLARATION_LEN];
+    let mut wb = WriteBuf::new(&mut buf, MAX_DECLARATION_LEN);
+    decl.write(&mut wb)?;
+    work_q
+        .send(Msg::UnsecuredTx(UnsecuredTxMsg {
+            peer: commissioner,
+            proto_id: PROTO_ID_UDC as u16,
+            proto_opcode: OpCode::IdentificationDeclaration as u8,
+            payload: wb.as_slice().to_vec(),
+            resp: None,
+        }))
+        .await
+}
+
+/// Where the commissioner gets to know of the nodes that want to be commissioned
+pub trait UdcCallback {
+    /// The node at `peer` sent us `decl`
+    fn identification_declaration(&mut self, peer: Address, decl: IdentificationDeclaration);
+}
+
+/// The commissioner side of User Directed Commissioning
+pub struct Udc {
+    callback: Box<dyn UdcCallback>,
+}
+
+impl Udc {
+    pub fn new(callback: Box<dyn UdcCallback>) -> Self {
+        Self { callback }
+    }
+}
+
+impl proto_demux::HandleProto for Udc {
+    fn handle_proto_id(&mut self, ctx: &mut ProtoCtx) -> Result<ResponseRequired, Error> {
+        // Nothing else happens on the exchange
+        ctx.exch_ctx.exch.close();
+        if ctx.exch_ctx.sess.is_encrypted() {
+            error!("Dropping a UDC message on a secure session");
+            return Err(Error::Invalid);
+        }
+        let opcode = num::FromPrimitive::from_u8(ctx.rx.get_proto_opcode());
+        if opcode != Some(OpCode::IdentificationDeclaration) {
+            error!("Invalid UDC opcode {}", ctx.rx.get_proto_opcode());
+            return Err(Error::Invalid);
+        }
+        let decl = IdentificationDeclaration::parse(ctx.rx.as_borrow_slice())?;
+        info!("Received {:?} from {}", decl, ctx.rx.peer);
+        self.callback.identification_declaration(ctx.rx.peer, decl);
+        Ok(ResponseRequired::No)
+    }
+
+    fn get_proto_id(&self) -> usize {
+        PROTO_ID_UDC
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+
+    use async_channel::{unbounded, Sender};
+    use smol::future::FutureExt;
+
+    use crate::{
+        error::Error,
+        transport::{
+            loopback::LoopbackSwitch,
+            mgr::{Mgr, TransportConfig},
+            network::Address,
+        },
+        utils::writebuf::WriteBuf,
+    };
+
+    use super::{
+        send_identification_declaration, IdentificationDeclaration, Udc, UdcCallback, UDC_PORT,
+    };
+
+    struct Announcements(Sender<(Address, IdentificationDeclaration)>);
+
+    impl UdcCallback for Announcements {
+        fn identification_declaration(&mut self, peer: Address, decl: IdentificationDeclaration) {
+            self.0.try_send((peer, decl)).unwrap();
+        }
+    }
+
+    fn addr(node: u16, port: u16) -> SocketAddr {
+        SocketAddr::new(
+            IpAddr::V6(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, node)),
+            port,
+        )
+    }
+
+    fn node(switch: &LoopbackSwitch, addr: SocketAddr) -> Mgr {
+        Mgr::new(TransportConfig {
+            bind_addrs: Vec::new(),
+            tcp: false,
+            interfaces: vec![Box::new(switch.connect(addr).unwrap())],
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    fn decl(instance_name: &str) -> IdentificationDeclaration {
+        IdentificationDeclaration {
+            instance_name: instance_name.to_owned(),
+            vendor_id: Some(0xFFF1),
+            product_id: None,
+            device_name: Some("Lamp".to_owned()),
+        }
+    }
+
+    #[test]
+    fn test_declaration() {
+        let mut buf = [0; 128];
+        let mut wb = WriteBuf::new(&mut buf, 128);
+        decl("0123456789ABCDEF").write(&mut wb).unwrap();
+        assert_eq!(
+            IdentificationDeclaration::parse(wb.as_slice()),
+            Ok(decl("0123456789ABCDEF"))
+        );
+
+        // The instance name is at most 16 characters
+        let mut wb = WriteBuf::new(&mut buf, 128);
+        assert_eq!(
+            decl("0123456789ABCDEF0").write(&mut wb),
+            Err(Error::InvalidData)
+        );
+    }
+
+    #[test]
+    fn test_sdk_declaration() {
+        // As the SDK sends it, with the tags that we skip
+        let mut msg = b"A1B2C3D4E5F60718\0".to_vec();
+        msg.extend_from_slice(&[
+            0x15, // Structure
+            0x25, 0x01, 0xF1, 0xFF, // Vendor ID
+            0x25, 0x02, 0x01, 0x80, // Product ID
+            0x2C, 0x03, 0x04, b'L', b'a', b'm', b'p', // Device Name
+            0x24, 0x04, 0x23, // Device Type
+            0x2C, 0x05, 0x00, // Pairing Instruction
+            0x24, 0x06, 0x00, // Pairing Hint
+            0x30, 0x07, 0x00, // Rotating ID
+            0x25, 0x08, 0xAE, 0x15, // Port
+            0x18,
+        ]);
+        assert_eq!(
+            IdentificationDeclaration::parse(&msg),
+            Ok(IdentificationDeclaration {
+                instance_name: "A1B2C3D4E5F60718".to_owned(),
+                vendor_id: Some(0xFFF1),
+                product_id: Some(0x8001),
+                device_name: Some("Lamp".to_owned()),
+            })
+        );
+
+        // Only the instance name
+        assert_eq!(
+            IdentificationDeclaration::parse(b"0123456789ABCDEF\0"),
+            Ok(IdentificationDeclaration {
+                instance_name: "0123456789ABCDEF".to_owned(),
+                vendor_id: None,
+                product_id: None,
+                device_name: None,
+            })
+        );
+    }
+
+    #[test]
+    fn test_announcement() {
+        let switch = LoopbackSwitch::new();
+        let mut device = node(&switch, addr(1, 5540));
+        let mut commissioner = node(&switch, addr(2, UDC_PORT));
+        let (tx, rx) = unbounded();
+        commissioner
+            .register_protocol(Box::new(Udc::new(Box::new(Announcements(tx)))))
+            .unwrap();
+
+        let work_q = device.get_work_q();
+        let to = Address::Udp(addr(2, UDC_PORT));
+        let result = smol::block_on(
+            async {
+                send_identification_declaration(&work_q, to, &decl("A1B2C3D4E5F60718")).await?;
+                rx.recv().await.map_err(|_| Error::Invalid)
+            }
+            .or(async {
+                device.run().or(commissioner.run()).await?;
+                Err(Error::Invalid)
+            }),
+        );
+        assert_eq!(
+            result,
+            Ok((Address::Udp(addr(1, 5540)), decl("A1B2C3D4E5F60718")))
+        );
+    }
+}
@@ -12,6 +12,7 @@ use matter::{
     error::Error,
     fabric::FabricMgr,
     interaction_model::{core::OpCode, messages::ib::CmdPath, messages::msg, InteractionModel},
+    secure_channel::{pake::PaseMgr, spake2p::VerifierData},
     tlv::{TLVWriter, TagType, ToTLV},
     transport::packet::Packet,
     transport::proto_demux::HandleProto,
@@ -20,6 +21,7 @@ use matter::{
         network::Address,
         packet::PacketPool,
         proto_demux::ProtoCtx,
+        queue::WorkQ,
         session::{CloneData, SessionMgr, SessionMode},
     },
     utils::writebuf::WriteBuf,
@@ -81,7 +83,15 @@ impl ImEngine {
         // Only allow the standard peer node id of the IM Engine
         default_acl.add_subject(IM_ENGINE_PEER_ID).unwrap();
         acl_mgr.add(default_acl).unwrap();
-        let dm = DataModel::new(dev_det, dev_att, fabric_mgr.clone(), acl_mgr.clone()).unwrap();
+        let pase_mgr = PaseMgr::new(VerifierData::new_with_pw(123456, &[0; 16], 1000).unwrap());
+        let dm = DataModel::new(
+            dev_det,
+            dev_att,
+            fabric_mgr.clone(),
+            acl_mgr.clone(),
+            pase_mgr,
+        )
+        .unwrap();
 
         {
             let mut d = dm.node.write().unwrap();
@@ -131,7 +141,8 @@ impl ImEngine {
         rx_buf[..in_data_len].copy_from_slice(input.data_in);
         rx.get_parsebuf().unwrap().set_len(in_data_len);
 
-        let mut ctx = ProtoCtx::new(exch_ctx, rx, tx);
+        let (work_q, _work_rx) = WorkQ::new();
+        let mut ctx = ProtoCtx::new(exch_ctx, rx, tx, &work_q);
         self.im.handle_proto_id(&mut ctx).unwrap();
         let out_data_len = ctx.tx.as_borrow_slice().len();
         data_out[..out_data_len].copy_from_slice(ctx.tx.as_borrow_slice());
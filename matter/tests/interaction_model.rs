@@ -15,6 +15,7 @@ use matter::transport::packet::Packet;
 use matter::transport::packet::PacketPool;
 use matter::transport::proto_demux::HandleProto;
 use matter::transport::proto_demux::ProtoCtx;
+use matter::transport::queue::WorkQ;
 use matter::transport::session::SessionMgr;
 use std::net::Ipv4Addr;
 use std::net::SocketAddr;
@@ -129,7 +130,8 @@ fn handle_data(action: OpCode, data_in: &[u8], data_out: &mut [u8]) -> (DataMode
     let rx_buf = rx.as_borrow_slice();
     rx_buf[..in_data_len].copy_from_slice(data_in);
 
-    let mut ctx = ProtoCtx::new(exch_ctx, rx, tx);
+    let (work_q, _work_rx) = WorkQ::new();
+    let mut ctx = ProtoCtx::new(exch_ctx, rx, tx, &work_q);
 
     interaction_model.handle_proto_id(&mut ctx).unwrap();
 
@@ -0,0 +1,69 @@
+extern crate clap;
+use clap::{App, Arg};
+use matter::secure_channel::spake2p::{is_valid_passcode, VerifierData};
+use rand::prelude::*;
+use std::process;
+
+const DEFAULT_SALT_LEN: usize = 32;
+const DEFAULT_ITERATIONS: &str = "1000";
+
+fn main() {
+    let m = App::new("spake2p_verifier")
+        .arg(
+            Arg::with_name("salt")
+                .short("s")
+                .long("salt")
+                .takes_value(true)
+                .help("The salt in Hexadecimal (Default: random)"),
+        )
+        .arg(
+            Arg::with_name("iterations")
+                .short("i")
+                .long("iterations")
+                .takes_value(true)
+                .default_value(DEFAULT_ITERATIONS)
+                .help("The PBKDF2 iterations"),
+        )
+        .arg(
+            Arg::with_name("passcode")
+                .help("The commissioning passcode")
+                .required(true),
+        )
+        .get_matches();
+
+    let passcode: u32 = m.value_of("passcode").unwrap().parse().unwrap_or_else(|_| {
+        eprintln!("The passcode must be a number");
+        process::exit(1);
+    });
+    if !is_valid_passcode(passcode) {
+        eprintln!("The passcode must be at most 99999998, and not a trivial one like 12345678");
+        process::exit(1);
+    }
+    let iterations: u32 = m
+        .value_of("iterations")
+        .unwrap()
+        .parse()
+        .unwrap_or_else(|_| {
+            eprintln!("The iterations must be a number");
+            process::exit(1);
+        });
+    let salt = match m.value_of("salt") {
+        Some(s) => hex::decode(s).unwrap_or_else(|_| {
+            eprintln!("The salt must be in Hexadecimal");
+            process::exit(1);
+        }),
+        None => {
+            let mut salt = vec![0; DEFAULT_SALT_LEN];
+            rand::thread_rng().fill_bytes(&mut salt);
+            salt
+        }
+    };
+
+    let verifier = VerifierData::new_with_pw(passcode, &salt, iterations).unwrap_or_else(|_| {
+        eprintln!("Invalid salt length or iterations");
+        process::exit(1);
+    });
+    println!("Verifier:   {}", hex::encode(verifier.verifier));
+    println!("Salt:       {}", hex::encode(&verifier.salt));
+    println!("Iterations: {}", verifier.iterations);
+}